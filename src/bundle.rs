@@ -0,0 +1,426 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use crate::clone::{Advertisement, Ref};
+use crate::gc;
+use crate::graph::CommitGraph;
+use crate::objects::{self, AnyObject, Commit, EntryMode, HashAlgorithm, Object, ObjectId};
+use crate::packfile::{self, PackObject, Packfile};
+use crate::refs;
+use crate::revision;
+
+const V2_SIGNATURE: &[u8] = b"# v2 git bundle\n";
+const V3_SIGNATURE: &[u8] = b"# v3 git bundle\n";
+
+/// Whether `path` is a bundle file rather than the URL of a remote.
+pub fn is_bundle(path: &Path) -> bool {
+    let mut signature = [0u8; 16];
+    match fs::File::open(path).and_then(|mut file| file.read_exact(&mut signature)) {
+        Ok(()) => signature == V2_SIGNATURE || signature == V3_SIGNATURE,
+        Err(_) => false,
+    }
+}
+
+/// A bundle: the refs it carries and a pack of their history, leaving out
+/// what the prerequisite commits (and everything they reach) already have.
+pub struct Bundle {
+    pub version: u32,
+    pub object_format: HashAlgorithm,
+    /// Commits the receiving repository must have, with their subjects.
+    pub prerequisites: Vec<(ObjectId, String)>,
+    pub refs: Vec<(ObjectId, String)>,
+    pack: Vec<u8>,
+}
+
+impl Bundle {
+    pub fn open(path: &Path) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("could not open '{}'", path.display()))?;
+        if !data.starts_with(V2_SIGNATURE) && !data.starts_with(V3_SIGNATURE) {
+            bail!("'{}' does not look like a v2 or v3 bundle file", path.display());
+        }
+
+        Self::parse(data)
+    }
+
+    fn parse(data: Vec<u8>) -> Result<Self> {
+        let version = if data.starts_with(V3_SIGNATURE) { 3 } else { 2 };
+        let mut bundle = Self {
+            version,
+            object_format: HashAlgorithm::Sha1,
+            prerequisites: Vec::new(),
+            refs: Vec::new(),
+            pack: Vec::new(),
+        };
+
+        let mut pos = V2_SIGNATURE.len();
+        loop {
+            let end = match data[pos..].iter().position(|&b| b == b'\n') {
+                Some(len) => pos + len,
+                None => bail!("bundle header is not terminated"),
+            };
+            let line = std::str::from_utf8(&data[pos..end]).context("bundle header is not valid UTF-8")?;
+            pos = end + 1;
+
+            if line.is_empty() {
+                break;
+            }
+
+            // v3 capabilities come before the refs
+            if let Some(capability) = line.strip_prefix('@') {
+                match capability.split_once('=') {
+                    Some(("object-format", name)) if version == 3 => {
+                        bundle.object_format = HashAlgorithm::from_name(name)?
+                    }
+                    _ => bail!("unknown capability '{}'", capability),
+                }
+                continue;
+            }
+
+            let (prerequisite, line) = match line.strip_prefix('-') {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            let (oid, name) = line.split_once(' ').unwrap_or((line, ""));
+            let oid = ObjectId::from_hex_in(oid, bundle.object_format)?;
+
+            if prerequisite {
+                bundle.prerequisites.push((oid, name.to_string()));
+            } else if name.is_empty() {
+                bail!("unrecognized header: {}", line);
+            } else {
+                bundle.refs.push((oid, name.to_string()));
+            }
+        }
+
+        bundle.pack = data[pos..].to_vec();
+        Ok(bundle)
+    }
+
+    // the header, up to and including the blank line before the pack
+    fn header(&self) -> Vec<u8> {
+        let mut header = Vec::new();
+        if self.version == 3 {
+            header.extend_from_slice(V3_SIGNATURE);
+            header.extend(format!("@object-format={}\n", self.object_format.name()).into_bytes());
+        } else {
+            header.extend_from_slice(V2_SIGNATURE);
+        }
+
+        for (oid, subject) in &self.prerequisites {
+            header.extend(format!("-{} {}\n", hex::encode(oid), subject).into_bytes());
+        }
+        for (oid, refname) in &self.refs {
+            header.extend(format!("{} {}\n", hex::encode(oid), refname).into_bytes());
+        }
+        header.push(b'\n');
+
+        header
+    }
+
+    fn check_object_format(&self) -> Result<()> {
+        let current = HashAlgorithm::current();
+        if self.object_format != current {
+            bail!(
+                "the bundle uses the {} hash algorithm, but the repository uses {}",
+                self.object_format.name(),
+                current.name()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The prerequisite commits the repository lacks.
+    pub fn missing_prerequisites(&self) -> Result<Vec<ObjectId>> {
+        self.check_object_format()?;
+
+        let mut missing = Vec::new();
+        for (oid, _) in &self.prerequisites {
+            if !objects::has_object(*oid)? {
+                missing.push(*oid);
+            }
+        }
+
+        Ok(missing)
+    }
+
+    /// Stores the objects of the pack, once the repository is known to have
+    /// what the pack's deltas and history build on.
+    pub fn unbundle(&self) -> Result<()> {
+        let missing = self.missing_prerequisites()?;
+        if !missing.is_empty() {
+            let lines: Vec<String> = missing.iter().map(|oid| format!("{} ", hex::encode(oid))).collect();
+            bail!("Repository lacks these prerequisite commits:\n{}", lines.join("\n"));
+        }
+
+        // git packs bundles thin, against objects of the prerequisites
+        let packfile = Packfile::parse_thin_data(&self.pack, |base| match objects::read_stored(*base)? {
+            Some(write_data) => Ok(Some(PackObject::from_write_data(&write_data)?)),
+            None => Ok(None),
+        })
+        .context("Invalid packfile")?;
+
+        let objects: Vec<_> = packfile.into_objects().into_iter().map(|object| (object, String::new())).collect();
+        if !objects.is_empty() {
+            gc::store_pack(objects, None)?;
+        }
+
+        Ok(())
+    }
+
+    /// The refs of the bundle as a remote would advertise them, so that
+    /// `clone` and `fetch` can take a bundle for a URL.
+    pub fn advertisement(&self) -> Advertisement {
+        Advertisement {
+            refs: self
+                .refs
+                .iter()
+                .map(|(oid, refname)| Ref {
+                    name: refname.clone(),
+                    hash: hex::encode(oid),
+                })
+                .collect(),
+            capabilities: vec![format!("object-format={}", self.object_format.name())],
+        }
+    }
+}
+
+// what `create` was asked for: commits (and the refs naming them) to
+// include, and commits whose history to leave out
+#[derive(Default)]
+struct Revisions {
+    include: Vec<(ObjectId, Option<String>)>,
+    exclude: Vec<ObjectId>,
+}
+
+impl Revisions {
+    async fn parse(all: bool, args: &[String]) -> Result<Self> {
+        let mut revisions = Self::default();
+
+        if all {
+            for (refname, oid) in refs::list_refs("refs/")? {
+                revisions.include.push((oid, Some(refname)));
+            }
+            if let Some(oid) = refs::head_sha()? {
+                revisions.include.push((oid, Some(String::from("HEAD"))));
+            }
+        }
+
+        for arg in args {
+            if let Some((from, to)) = arg.split_once("..") {
+                let from = if from.is_empty() { "HEAD" } else { from };
+                let to = if to.is_empty() { "HEAD" } else { to };
+                revisions.exclude.push(revision::resolve(from).await?);
+                revisions.push(to).await?;
+            } else if let Some(spec) = arg.strip_prefix('^') {
+                revisions.exclude.push(revision::resolve(spec).await?);
+            } else {
+                revisions.push(arg).await?;
+            }
+        }
+
+        Ok(revisions)
+    }
+
+    async fn push(&mut self, spec: &str) -> Result<()> {
+        let oid = revision::resolve(spec).await?;
+        // only a name that is a ref can be recorded as one
+        let refname = match refs::dwim(spec)? {
+            Some(refname) if refs::resolve(&refname)? == Some(oid) => Some(refname),
+            _ => None,
+        };
+
+        self.include.push((oid, refname));
+        Ok(())
+    }
+}
+
+// every object reachable from `roots` that `have` lacks, with the path it
+// was reached by; commits only bring their trees, their parents are listed
+async fn collect_objects(
+    roots: Vec<(ObjectId, String)>,
+    have: &HashSet<ObjectId>,
+    objects: &mut Vec<(PackObject, String)>,
+    seen: &mut HashSet<ObjectId>,
+) -> Result<()> {
+    let mut pending = roots;
+
+    while let Some((oid, name)) = pending.pop() {
+        if have.contains(&oid) || !seen.insert(oid) {
+            continue;
+        }
+
+        let write_data = objects::read_object(oid).await?;
+        let object = PackObject::from_write_data(&write_data)?;
+        match AnyObject::from_write_data(oid, write_data)? {
+            AnyObject::Commit(commit) => pending.push((commit.tree_sha(), String::new())),
+            AnyObject::Tree(tree) => {
+                for entry in tree.entries() {
+                    if entry.mode() == EntryMode::Gitlink {
+                        continue;
+                    }
+
+                    let path = match name.as_str() {
+                        "" => entry.name().to_string(),
+                        dir => format!("{}/{}", dir, entry.name()),
+                    };
+                    pending.push((entry.sha1_hash(), path));
+                }
+            }
+            AnyObject::Tag(tag) => pending.push((tag.object(), name.clone())),
+            AnyObject::Blob(_) => {}
+        }
+
+        objects.push((object, name));
+    }
+
+    Ok(())
+}
+
+// every tree and blob of the given commits' snapshots
+async fn snapshot_objects(commits: &[ObjectId]) -> Result<HashSet<ObjectId>> {
+    let mut trees = Vec::new();
+    for oid in commits {
+        trees.push((Commit::from_object_sha(hex::encode(oid)).await?.tree_sha(), String::new()));
+    }
+
+    let mut objects = Vec::new();
+    let mut seen = HashSet::new();
+    collect_objects(trees, &HashSet::new(), &mut objects, &mut seen).await?;
+
+    Ok(seen)
+}
+
+/// Writes a bundle of the history `args` select (`A..B`, `^A`, `B` or
+/// `--all`, as for `git rev-list`) to `path`. The commits just outside the
+/// range become its prerequisites.
+pub async fn create(path: &Path, version: Option<u32>, all: bool, args: &[String]) -> Result<()> {
+    let object_format = HashAlgorithm::current();
+    let version = match version {
+        Some(2) if object_format != HashAlgorithm::Sha1 => {
+            bail!("a version 2 bundle cannot hold {} objects", object_format.name())
+        }
+        Some(version @ (2 | 3)) => version,
+        Some(version) => bail!("unsupported bundle version {}", version),
+        None if object_format == HashAlgorithm::Sha1 => 2,
+        None => 3,
+    };
+
+    let revisions = Revisions::parse(all, args).await?;
+
+    let mut tips = Vec::new();
+    let mut others = Vec::new();
+    for (oid, _) in &revisions.include {
+        let peeled = revision::peel(*oid).await?;
+        match revision::object_type(peeled).await?.as_str() {
+            "commit" => tips.push(peeled),
+            _ => others.push((peeled, String::new())),
+        }
+    }
+    let mut exclude = Vec::new();
+    for oid in &revisions.exclude {
+        exclude.push(revision::peel(*oid).await?);
+    }
+
+    let mut graph = CommitGraph::new();
+    let commits = graph.range(&tips, &exclude).await?;
+    let included: HashSet<ObjectId> = commits.iter().copied().collect();
+
+    let mut prerequisites = Vec::new();
+    for oid in &commits {
+        for parent in graph.parents(*oid).await? {
+            if !included.contains(&parent) && !prerequisites.contains(&parent) {
+                prerequisites.push(parent);
+            }
+        }
+    }
+
+    // refs whose history the range leaves out are not recorded; the tags
+    // among the rest are carried along with what they point at
+    let mut bundle_refs: Vec<(ObjectId, String)> = Vec::new();
+    let mut tags = Vec::new();
+    for (oid, refname) in &revisions.include {
+        let refname = match refname {
+            Some(refname) => refname,
+            None => continue,
+        };
+        let peeled = revision::peel(*oid).await?;
+        let in_range = included.contains(&peeled) || others.iter().any(|(other, _)| *other == peeled);
+        if in_range && !bundle_refs.iter().any(|(_, name)| name == refname) {
+            bundle_refs.push((*oid, refname.clone()));
+            if peeled != *oid {
+                tags.push((*oid, String::new()));
+            }
+        }
+    }
+    if bundle_refs.is_empty() {
+        bail!("Refusing to create empty bundle.");
+    }
+
+    // the receiver has the snapshots of the prerequisites to build on
+    let have = snapshot_objects(&prerequisites).await?;
+    let mut objects = Vec::new();
+    let mut seen = HashSet::new();
+    let roots = tags
+        .into_iter()
+        .chain(others)
+        .chain(commits.iter().map(|oid| (*oid, String::new())))
+        .collect();
+    collect_objects(roots, &have, &mut objects, &mut seen).await?;
+
+    let mut subjects = Vec::new();
+    for oid in prerequisites {
+        let commit = Commit::from_object_sha(hex::encode(oid)).await?;
+        subjects.push((oid, commit.summary().to_string()));
+    }
+
+    let bundle = Bundle {
+        version,
+        object_format,
+        prerequisites: subjects,
+        refs: bundle_refs,
+        pack: Vec::new(),
+    };
+    let mut data = bundle.header();
+    data.extend(packfile::write_pack(objects)?.data);
+
+    // like git, leave no half-written bundle behind
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".lock");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_header() -> Result<()> {
+        let oid = "41e639f0ae2be64effa65e941c6aa4415f8d58f1";
+        let tip = "2073b23259798de5c971383ead0b8a9cc5313c0a";
+
+        let data = format!("# v2 git bundle\n-{} topic three\n{} refs/heads/topic\n\nPACK", oid, tip);
+        let bundle = Bundle::parse(data.clone().into_bytes())?;
+        assert_eq!(bundle.version, 2);
+        assert_eq!(bundle.prerequisites, vec![(ObjectId::from_hex(oid)?, String::from("topic three"))]);
+        assert_eq!(bundle.refs, vec![(ObjectId::from_hex(tip)?, String::from("refs/heads/topic"))]);
+        assert_eq!(bundle.pack, b"PACK");
+        assert_eq!(bundle.header(), data.strip_suffix("PACK").unwrap().as_bytes());
+
+        let data = format!("# v3 git bundle\n@object-format=sha1\n{} HEAD\n\n", tip);
+        let bundle = Bundle::parse(data.clone().into_bytes())?;
+        assert_eq!(bundle.version, 3);
+        assert_eq!(bundle.header(), data.as_bytes());
+
+        assert!(Bundle::parse(format!("# v3 git bundle\n@filter=blob:none\n{} HEAD\n\n", tip).into_bytes()).is_err());
+        assert!(Bundle::parse(format!("# v2 git bundle\n{}\n\n", tip).into_bytes()).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,892 @@
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::Path;
+
+use crate::diff::matches_pathspec;
+use crate::index::{Index, IndexEntry};
+use crate::objects::{self, Blob, EntryMode, Object, ObjectId, Tree, TreeEntry};
+use crate::promisor;
+use crate::sparse::Cone;
+use crate::submodule;
+
+const GITLINK_MODE: u32 = 0o160000;
+
+/// A repository path, kept as the raw bytes git stores, as a path in the
+/// working tree.
+pub fn worktree_path(path: &[u8]) -> &Path {
+    Path::new(OsStr::from_bytes(path))
+}
+
+pub async fn flatten(tree_sha: Option<ObjectId>) -> Result<BTreeMap<Vec<u8>, TreeEntry>> {
+    match tree_sha {
+        Some(sha) => Tree::from_object_sha(hex::encode(sha)).await?.flatten().await,
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+fn same_entry(a: Option<&TreeEntry>, b: Option<&TreeEntry>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.mode() == b.mode() && a.sha1_hash() == b.sha1_hash(),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+fn index_matches(entry: Option<&IndexEntry>, tree_entry: Option<&TreeEntry>) -> bool {
+    match (entry, tree_entry) {
+        (Some(e), Some(t)) => e.mode == t.mode().bits() && e.sha1_hash == t.sha1_hash(),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Hashes whatever is at `path` in the working tree the way it would be
+/// stored: symlinks by their target, regular files by their contents.
+pub async fn hash_worktree_path(path: &[u8], metadata: &fs::Metadata) -> Result<ObjectId> {
+    let path = worktree_path(path);
+    if metadata.file_type().is_symlink() {
+        let target = fs::read_link(path)?;
+        return Ok(Blob::from_contents(target.into_os_string().into_vec())?.sha1_hash());
+    }
+
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || Blob::hash_file(&path)).await?
+}
+
+/// Whether the file at `path` differs from what the index records for it.
+/// Files a sparse checkout leaves out never do.
+pub async fn is_modified(path: &[u8], entry: &IndexEntry) -> Result<bool> {
+    if entry.skip_worktree {
+        return Ok(false);
+    }
+
+    let metadata = match fs::symlink_metadata(worktree_path(path)) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(true),
+    };
+
+    // submodule contents are not tracked by the superproject's index
+    if entry.mode == GITLINK_MODE {
+        return Ok(!metadata.is_dir());
+    }
+    if metadata.is_dir() {
+        return Ok(true);
+    }
+
+    let is_symlink = metadata.file_type().is_symlink();
+    if is_symlink != (entry.mode == 0o120000) {
+        return Ok(true);
+    }
+    if !is_symlink {
+        let executable = metadata.permissions().mode() & 0o100 != 0;
+        if executable != (entry.mode == 0o100755) {
+            return Ok(true);
+        }
+    }
+
+    if entry.stat_matches(&metadata) {
+        return Ok(false);
+    }
+
+    Ok(hash_worktree_path(path, &metadata).await? != entry.sha1_hash)
+}
+
+pub fn remove_path(path: &[u8]) -> Result<()> {
+    let path = worktree_path(path);
+
+    match fs::symlink_metadata(path) {
+        // a submodule directory is only removed once it is empty
+        Ok(metadata) if metadata.is_dir() => {
+            let _ = fs::remove_dir(path);
+        }
+        Ok(_) => fs::remove_file(path)?,
+        Err(_) => {}
+    }
+
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        if d.as_os_str().is_empty() || fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+
+    Ok(())
+}
+
+// removes `dir` along with the empty directories under it, failing at the
+// first thing that is not a directory
+fn remove_empty_dir(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_empty_dir(&entry.path())?;
+        }
+    }
+    fs::remove_dir(dir)?;
+
+    Ok(())
+}
+
+// a directory in the way is only removed once the tracked files in it have
+// been, unless `force` allows losing what is left
+async fn write_entry(path: &[u8], entry: &TreeEntry, force: bool) -> Result<IndexEntry> {
+    let file_path = worktree_path(path);
+    if let Some(parent) = file_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mode = entry.mode().bits();
+
+    if mode == GITLINK_MODE {
+        if !file_path.is_dir() {
+            if fs::symlink_metadata(file_path).is_ok() {
+                fs::remove_file(file_path)?;
+            }
+            fs::create_dir(file_path)?;
+        }
+
+        return Ok(IndexEntry::new(path, mode, entry.sha1_hash()));
+    }
+
+    match fs::symlink_metadata(file_path) {
+        Ok(metadata) if metadata.is_dir() => {
+            if force {
+                fs::remove_dir_all(file_path)?;
+            } else if remove_empty_dir(file_path).is_err() {
+                bail!("cannot replace '{}' with a file: the directory has untracked files in it", file_path.display());
+            }
+        }
+        Ok(_) => fs::remove_file(file_path)?,
+        Err(_) => {}
+    }
+
+    let mut object = objects::stream_object(entry.sha1_hash()).await?;
+
+    if mode == 0o120000 {
+        let mut target = Vec::new();
+        object.copy_body(&mut target)?;
+        symlink(OsString::from_vec(target), file_path)?;
+    } else {
+        // copied a chunk at a time into a file beside it, which is renamed
+        // into place once whole
+        let mut tmp_name = OsString::from(".");
+        tmp_name.push(file_path.file_name().unwrap_or_default());
+        tmp_name.push(format!(".{}.tmp", std::process::id()));
+        let tmp = file_path.with_file_name(tmp_name);
+
+        let written = fs::File::create(&tmp).map_err(anyhow::Error::from).and_then(|mut file| {
+            object.copy_body(&mut file)?;
+            let perms = if mode == 0o100755 { 0o755 } else { 0o644 };
+            file.set_permissions(fs::Permissions::from_mode(perms))?;
+            Ok(())
+        });
+        if let Err(err) = written {
+            let _ = fs::remove_file(&tmp);
+            return Err(err);
+        }
+        fs::rename(&tmp, file_path)?;
+    }
+
+    let mut index_entry = IndexEntry::new(path, mode, entry.sha1_hash());
+    index_entry.refresh_stat(&fs::symlink_metadata(file_path)?);
+
+    Ok(index_entry)
+}
+
+/// Stores whatever is at `path` in the working tree and returns its index
+/// entry. A nested repository is staged as a gitlink to the commit it has
+/// checked out.
+pub async fn stage_worktree_path(path: &[u8]) -> Result<IndexEntry> {
+    let file_path = worktree_path(path);
+    let metadata = fs::symlink_metadata(file_path)?;
+
+    if metadata.is_dir() {
+        return match submodule::head_of(file_path)? {
+            Some(sha1_hash) => Ok(IndexEntry::new(path, GITLINK_MODE, sha1_hash)),
+            None => bail!("'{}/' does not have a commit checked out", file_path.display()),
+        };
+    }
+
+    let (mode, sha1_hash) = if metadata.file_type().is_symlink() {
+        let blob = Blob::from_contents(fs::read_link(file_path)?.into_os_string().into_vec())?;
+        blob.write().await?;
+        (0o120000, blob.sha1_hash())
+    } else {
+        let mode = if metadata.permissions().mode() & 0o100 != 0 { 0o100755 } else { 0o100644 };
+        // a whole file of blocking reads, kept off the async threads
+        let file_path = file_path.to_path_buf();
+        (mode, tokio::task::spawn_blocking(move || Blob::write_file(&file_path)).await??)
+    };
+
+    let mut entry = IndexEntry::new(path, mode, sha1_hash);
+    entry.refresh_stat(&metadata);
+
+    Ok(entry)
+}
+
+// every file below `dir`, `.git` aside, with nested repositories listed as
+// themselves rather than by what they contain
+fn worktree_paths(dir: &Path, paths: &mut Vec<Vec<u8>>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name() == ".git" {
+            continue;
+        }
+
+        let path = entry.path();
+        let name = path.strip_prefix(".").unwrap_or(&path).as_os_str().as_bytes().to_vec();
+        if entry.file_type()?.is_dir() && !path.join(".git").exists() {
+            worktree_paths(&path, paths)?;
+        } else {
+            paths.push(name);
+        }
+    }
+
+    Ok(())
+}
+
+// the index entry for a file a sparse checkout leaves out of the working tree
+fn skipped_entry(path: &[u8], entry: &TreeEntry) -> IndexEntry {
+    let mut index_entry = IndexEntry::new(path, entry.mode().bits(), entry.sha1_hash());
+    index_entry.skip_worktree = true;
+
+    index_entry
+}
+
+// downloads, in one go, the blobs a partial clone has yet to fetch of
+// those about to be written
+async fn prefetch<'a, I: IntoIterator<Item = &'a TreeEntry>>(entries: I) -> Result<()> {
+    let mut missing = Vec::new();
+    for entry in entries {
+        if entry.mode() != EntryMode::Gitlink && !objects::has_object(entry.sha1_hash())? {
+            missing.push(entry.sha1_hash());
+        }
+    }
+
+    if !missing.is_empty() {
+        promisor::fetch(&missing).await?;
+    }
+
+    Ok(())
+}
+
+/// Materializes trees and tree entries into the working tree, keeping the
+/// index in step with what was written. Under a sparse checkout, files
+/// outside the cone are only staged, marked to stay out of the working tree.
+pub struct Checkout {
+    index: Index,
+    cone: Option<Cone>,
+}
+
+impl Checkout {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            index: Index::load()?,
+            cone: Cone::load()?,
+        })
+    }
+
+    // whether `path` belongs in the working tree
+    fn includes(&self, path: &[u8]) -> bool {
+        self.cone.as_ref().is_none_or(|cone| cone.includes(path))
+    }
+
+    // writes `entry` to `path` if it belongs in the working tree, returning
+    // its index entry either way
+    async fn place(&self, path: &[u8], entry: &TreeEntry, force: bool) -> Result<IndexEntry> {
+        if self.includes(path) {
+            write_entry(path, entry, force).await
+        } else {
+            Ok(skipped_entry(path, entry))
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        self.index.save()
+    }
+
+    pub fn index_mut(&mut self) -> &mut Index {
+        &mut self.index
+    }
+
+    /// The merged (stage 0) index entries, as tree entries.
+    pub fn staged_files(&self) -> BTreeMap<Vec<u8>, TreeEntry> {
+        self.index
+            .paths()
+            .into_iter()
+            .filter_map(|path| {
+                let entry = self.index.entry(&path)?;
+                let mode = EntryMode::from_bits(entry.mode).ok()?;
+                Some((path.clone(), TreeEntry::new(mode, path, entry.sha1_hash)))
+            })
+            .collect()
+    }
+
+    /// Writes a single entry unless the working tree already has it, staging
+    /// it at `path` either way. Untracked files in a directory where the
+    /// entry goes are only removed with `force`.
+    pub async fn checkout_entry(&mut self, path: &[u8], entry: &TreeEntry, force: bool) -> Result<()> {
+        if let Some(existing) = self.index.entry(path) {
+            let placed = existing.skip_worktree != self.includes(path);
+            if index_matches(Some(existing), Some(entry)) && placed && !is_modified(path, existing).await? {
+                // the contents matched even if the stat data did not
+                if let (Some(existing), Ok(metadata)) =
+                    (self.index.entry_mut(path), fs::symlink_metadata(worktree_path(path)))
+                {
+                    if existing.mode != GITLINK_MODE && !existing.skip_worktree {
+                        existing.refresh_stat(&metadata);
+                    }
+                }
+                return Ok(());
+            }
+        }
+
+        let index_entry = self.place(path, entry, force).await?;
+        self.index.add(index_entry);
+
+        Ok(())
+    }
+
+    /// Makes the index and working tree match `tree_sha` exactly, discarding
+    /// local changes and removing paths the tree no longer tracks. Untracked
+    /// files in the way of the tree's are only removed with `force`.
+    pub async fn checkout_tree(&mut self, tree_sha: ObjectId, force: bool) -> Result<()> {
+        self.checkout_files(&flatten(Some(tree_sha)).await?, force).await
+    }
+
+    async fn checkout_files(&mut self, files: &BTreeMap<Vec<u8>, TreeEntry>, force: bool) -> Result<()> {
+        let stale: Vec<Vec<u8>> = self
+            .index
+            .paths()
+            .into_iter()
+            .filter(|p| !files.contains_key(p))
+            .collect();
+        for path in stale {
+            if self.includes(&path) {
+                remove_path(&path)?;
+            }
+            self.index.remove(&path);
+        }
+
+        prefetch(files.iter().filter(|(path, _)| self.includes(path)).map(|(_, entry)| entry)).await?;
+        for (path, entry) in files {
+            self.checkout_entry(path, entry, force).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Points the index entries under `paths` at `files`, leaving the
+    /// working tree alone, as `git reset` does. Entries that do not change
+    /// keep their stat data; new ones get it when the file on disk already
+    /// matches.
+    pub async fn reset_index<S: AsRef<[u8]>>(&mut self, files: &BTreeMap<Vec<u8>, TreeEntry>, paths: &[S]) -> Result<()> {
+        let stale: Vec<Vec<u8>> = self
+            .index
+            .paths()
+            .into_iter()
+            .filter(|p| matches_pathspec(p, paths) && !files.contains_key(p))
+            .collect();
+        for path in stale {
+            self.index.remove(&path);
+        }
+
+        for (path, entry) in files.iter().filter(|(p, _)| matches_pathspec(p, paths)) {
+            if index_matches(self.index.entry(path), Some(entry)) {
+                continue;
+            }
+
+            if !self.includes(path) {
+                self.index.add(skipped_entry(path, entry));
+                continue;
+            }
+
+            let mut index_entry = IndexEntry::new(path.clone(), entry.mode().bits(), entry.sha1_hash());
+            if !is_modified(path, &index_entry).await? {
+                index_entry.refresh_stat(&fs::symlink_metadata(worktree_path(path))?);
+            }
+            self.index.add(index_entry);
+        }
+
+        Ok(())
+    }
+
+    /// Writes `files` under `paths` into the working tree, and removes
+    /// tracked files under `paths` that `files` lacks. The index is only
+    /// touched to refresh the stat data of entries that match what was
+    /// written. Paths outside a sparse checkout are left alone.
+    pub async fn restore_worktree<S: AsRef<[u8]>>(
+        &mut self,
+        files: &BTreeMap<Vec<u8>, TreeEntry>,
+        paths: &[S],
+    ) -> Result<()> {
+        let wanted = |path: &[u8]| matches_pathspec(path, paths) && self.includes(path);
+
+        let stale: Vec<Vec<u8>> = self
+            .index
+            .paths()
+            .into_iter()
+            .filter(|p| wanted(p) && !files.contains_key(p))
+            .collect();
+        for path in stale {
+            remove_path(&path)?;
+        }
+
+        let files: Vec<(&Vec<u8>, &TreeEntry)> = files.iter().filter(|(p, _)| wanted(p)).collect();
+        prefetch(files.iter().map(|(_, entry)| *entry)).await?;
+        for (path, entry) in files {
+            let staged = index_matches(self.index.entry(path), Some(entry));
+            if staged && !is_modified(path, self.index.entry(path).unwrap()).await? {
+                continue;
+            }
+
+            let index_entry = write_entry(path, entry, false).await?;
+            if staged {
+                self.index.add(index_entry);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stages the working tree's version of everything under `paths`, as
+    /// `git add` does: new and modified files are stored, and tracked files
+    /// that are gone are staged as removed. Staging a conflicted path marks
+    /// it resolved.
+    pub async fn add(&mut self, paths: &[String]) -> Result<()> {
+        let mut found = Vec::new();
+        worktree_paths(Path::new("."), &mut found)?;
+        found.retain(|path| matches_pathspec(path, paths));
+
+        let tracked: Vec<Vec<u8>> = self
+            .index
+            .paths()
+            .into_iter()
+            .filter(|path| matches_pathspec(path, paths))
+            .collect();
+        for spec in paths {
+            let spec = std::slice::from_ref(spec);
+            if !found.iter().chain(&tracked).any(|path| matches_pathspec(path, spec)) {
+                bail!("pathspec '{}' did not match any files", spec[0]);
+            }
+        }
+
+        // files a sparse checkout leaves out are not missing
+        for path in &tracked {
+            let skipped = self.index.entry(path).is_some_and(|entry| entry.skip_worktree);
+            if !skipped && fs::symlink_metadata(worktree_path(path)).is_err() {
+                self.index.remove(path);
+            }
+        }
+
+        for path in &found {
+            // a submodule's commit is not covered by the stat data
+            if let Some(entry) = self.index.entry(path) {
+                if entry.mode != GITLINK_MODE && !is_modified(path, entry).await? {
+                    continue;
+                }
+            }
+
+            let entry = stage_worktree_path(path).await?;
+            self.index.add(entry);
+        }
+
+        Ok(())
+    }
+
+    /// Stops tracking everything under `paths`, deleting it from the
+    /// working tree too unless `cached` is set. Unless `force` is, changes
+    /// that would be lost are refused: what is staged differently from
+    /// `head` and, when deleting, what was modified since it was staged.
+    /// Returns the paths removed.
+    pub async fn remove(
+        &mut self,
+        head: &BTreeMap<Vec<u8>, TreeEntry>,
+        paths: &[String],
+        cached: bool,
+        force: bool,
+        recursive: bool,
+    ) -> Result<Vec<Vec<u8>>> {
+        let tracked = self.index.paths();
+        let mut removed = Vec::new();
+
+        for spec in paths {
+            let spec = std::slice::from_ref(spec);
+            let matched: Vec<&Vec<u8>> = tracked.iter().filter(|path| matches_pathspec(path, spec)).collect();
+            if matched.is_empty() {
+                bail!("pathspec '{}' did not match any files", spec[0]);
+            }
+            if !recursive && matched.iter().any(|path| path.as_slice() != spec[0].trim_end_matches('/').as_bytes()) {
+                bail!("not removing '{}' recursively without -r", spec[0]);
+            }
+
+            removed.extend(matched.into_iter().cloned());
+        }
+        removed.sort();
+        removed.dedup();
+
+        if !force {
+            let mut conflicting = Vec::new();
+            let mut staged_only = Vec::new();
+            let mut modified = Vec::new();
+
+            // conflicted paths have nothing staged to lose
+            for path in &removed {
+                let entry = match self.index.entry(path) {
+                    Some(entry) if !entry.skip_worktree => entry,
+                    _ => continue,
+                };
+
+                let staged = !index_matches(Some(entry), head.get(path));
+                let local = fs::symlink_metadata(worktree_path(path)).is_ok() && is_modified(path, entry).await?;
+                let path = String::from_utf8_lossy(path);
+                if staged && local {
+                    conflicting.push(path);
+                } else if !cached && staged {
+                    staged_only.push(path);
+                } else if !cached && local {
+                    modified.push(path);
+                }
+            }
+
+            for (files, problem, hint) in &[
+                (conflicting, "staged content different from both the\nfile and the HEAD", "-f to force removal"),
+                (staged_only, "changes staged in the index", "--cached to keep the file, or -f to force removal"),
+                (modified, "local modifications", "--cached to keep the file, or -f to force removal"),
+            ] {
+                if !files.is_empty() {
+                    bail!(
+                        "the following file{} {} {}:\n    {}\n(use {})",
+                        if files.len() == 1 { "" } else { "s" },
+                        if files.len() == 1 { "has" } else { "have" },
+                        problem,
+                        files.join("\n    "),
+                        hint
+                    );
+                }
+            }
+        }
+
+        for path in &removed {
+            self.index.remove(path);
+            if !cached {
+                remove_path(path)?;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Moves the index and working tree from `old_tree` to `new_tree`. Local
+    /// changes to paths that are the same in both are carried over, and paths
+    /// with uncommitted changes are refused. With `force`, local changes are
+    /// instead discarded, leaving the index and working tree at `new_tree`.
+    pub async fn switch_trees(
+        &mut self,
+        old_tree: Option<ObjectId>,
+        new_tree: ObjectId,
+        force: bool,
+    ) -> Result<()> {
+        self.update_trees(old_tree, new_tree, force, ("checkout", "switch branches"))
+            .await
+    }
+
+    /// Like `switch_trees`, but for bringing in the result of a merge.
+    pub async fn merge_trees(&mut self, old_tree: Option<ObjectId>, new_tree: ObjectId) -> Result<()> {
+        self.update_trees(old_tree, new_tree, false, ("merge", "merge"))
+            .await
+    }
+
+    // `action` names the command in errors: what would overwrite the
+    // changes, and what to stash them before
+    async fn update_trees(
+        &mut self,
+        old_tree: Option<ObjectId>,
+        new_tree: ObjectId,
+        force: bool,
+        action: (&str, &str),
+    ) -> Result<()> {
+        let old_files = flatten(old_tree).await?;
+        let new_files = flatten(Some(new_tree)).await?;
+
+        let paths: BTreeSet<&Vec<u8>> = old_files.keys().chain(new_files.keys()).collect();
+        let changed: Vec<&Vec<u8>> = paths
+            .into_iter()
+            .filter(|p| !same_entry(old_files.get(*p), new_files.get(*p)))
+            .collect();
+
+        // tracked files the switch deletes make way for what replaces them
+        let removing = |path: &Vec<u8>| {
+            old_files.contains_key(path) && !new_files.contains_key(path) && self.index.entry(path).is_some()
+        };
+
+        if !force {
+            let mut dirty = Vec::new();
+            let mut untracked = Vec::new();
+            let mut untracked_dirs = Vec::new();
+
+            for path in &changed {
+                let target = new_files.get(*path);
+
+                match self.index.entry(path) {
+                    Some(entry) => {
+                        let staged = !index_matches(Some(entry), old_files.get(*path))
+                            && !index_matches(Some(entry), target);
+                        if staged || is_modified(path, entry).await? {
+                            dirty.push(String::from_utf8_lossy(path));
+                        }
+                    }
+                    None if target.is_some() && self.includes(path) => match fs::symlink_metadata(worktree_path(path)) {
+                        Ok(metadata) if metadata.is_dir() => {
+                            let mut inside = Vec::new();
+                            worktree_paths(worktree_path(path), &mut inside)?;
+                            if !inside.iter().all(removing) {
+                                untracked_dirs.push(String::from_utf8_lossy(path));
+                            }
+                        }
+                        Ok(_) => untracked.push(String::from_utf8_lossy(path)),
+                        Err(_) => {}
+                    },
+                    None => {}
+                }
+            }
+
+            if !dirty.is_empty() {
+                bail!(
+                    "Your local changes to the following files would be overwritten by {}:\n\t{}\nPlease commit your changes or stash them before you {}.",
+                    action.0,
+                    dirty.join("\n\t"),
+                    action.1
+                );
+            }
+            if !untracked.is_empty() {
+                bail!(
+                    "The following untracked working tree files would be overwritten by {}:\n\t{}\nPlease move or remove them before you {}.",
+                    action.0,
+                    untracked.join("\n\t"),
+                    action.1
+                );
+            }
+            if !untracked_dirs.is_empty() {
+                bail!(
+                    "Updating the following directories would lose untracked files in them:\n\t{}\nPlease move or remove them before you {}.",
+                    untracked_dirs.join("\n\t"),
+                    action.1
+                );
+            }
+        }
+
+        // removals first, so a file can replace a directory of the same name
+        for path in changed.iter().filter(|p| !new_files.contains_key(**p)) {
+            if self.includes(path) {
+                remove_path(path)?;
+            }
+            self.index.remove(path);
+        }
+
+        prefetch(changed.iter().filter(|p| self.includes(p)).filter_map(|p| new_files.get(*p))).await?;
+        for path in changed.iter().filter(|p| new_files.contains_key(**p)) {
+            let index_entry = self.place(path, &new_files[*path], force).await?;
+            self.index.add(index_entry);
+        }
+
+        if force {
+            self.checkout_files(&new_files, true).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Brings the working tree in line with the sparse checkout: files
+    /// that now belong in it are written, and unmodified files that no
+    /// longer do are removed. Returns the modified files left in place.
+    pub async fn apply_sparsity(&mut self) -> Result<Vec<Vec<u8>>> {
+        let entries: Vec<IndexEntry> = self.index.entries().iter().filter(|entry| entry.stage == 0).cloned().collect();
+
+        let mut wanted = Vec::new();
+        for entry in &entries {
+            if entry.skip_worktree && self.includes(&entry.path) {
+                if let Ok(mode) = EntryMode::from_bits(entry.mode) {
+                    wanted.push(TreeEntry::new(mode, entry.path.clone(), entry.sha1_hash));
+                }
+            }
+        }
+        prefetch(&wanted).await?;
+        for entry in &wanted {
+            let index_entry = write_entry(entry.name_bytes(), entry, false).await?;
+            self.index.add(index_entry);
+        }
+
+        let mut left = Vec::new();
+        for entry in &entries {
+            if entry.skip_worktree || self.includes(&entry.path) {
+                continue;
+            }
+            if fs::symlink_metadata(worktree_path(&entry.path)).is_ok() && is_modified(&entry.path, entry).await? {
+                left.push(entry.path.clone());
+                continue;
+            }
+
+            remove_path(&entry.path)?;
+            if let Some(existing) = self.index.entry_mut(&entry.path) {
+                existing.skip_worktree = true;
+            }
+        }
+
+        Ok(left)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_repo::TestRepo;
+
+    // names are bytes to git, so one that is not UTF-8 comes back from a
+    // checkout and a reload of the index exactly as committed
+    #[tokio::test]
+    async fn test_non_utf8_path() -> Result<()> {
+        let repo = TestRepo::new("non-utf8").await?;
+        let name = b"dir/caf\xff".to_vec();
+        fs::create_dir("dir")?;
+        fs::write(worktree_path(&name), "contents\n")?;
+        fs::write("plain", "plain\n")?;
+        let commit = repo.commit("add files").await?;
+
+        let files = flatten(Some(commit.tree_sha())).await?;
+        assert_eq!(files.keys().collect::<Vec<_>>(), vec![&name, &b"plain".to_vec()]);
+
+        // from an empty index and working tree
+        fs::remove_dir_all("dir")?;
+        fs::remove_file("plain")?;
+        fs::remove_file(".git/index")?;
+        let mut checkout = Checkout::new()?;
+        checkout.checkout_tree(commit.tree_sha(), false).await?;
+        checkout.save()?;
+        assert_eq!(fs::read(worktree_path(&name))?, b"contents\n");
+
+        let mut checkout = Checkout::new()?;
+        let entry = checkout.index_mut().entry(&name).unwrap().clone();
+        assert!(!is_modified(&name, &entry).await?);
+
+        // staging the working tree again changes nothing
+        let before = checkout.index_mut().entries().to_vec();
+        checkout.add(&[String::from(".")]).await?;
+        assert_eq!(checkout.index_mut().entries(), &before[..]);
+        assert_eq!(Tree::new(".".into()).await?.sha1_hash(), commit.tree_sha());
+
+        Ok(())
+    }
+
+    // a directory of tracked files gives way to a file of the same name and
+    // back, unless something untracked is in it
+    #[tokio::test]
+    async fn test_directory_replaced_by_file() -> Result<()> {
+        let repo = TestRepo::new("dir-to-file").await?;
+        fs::create_dir("d")?;
+        fs::write("d/x", "x\n")?;
+        let dir_version = repo.commit("d/x").await?;
+        fs::remove_dir_all("d")?;
+        fs::write("d", "file\n")?;
+        let file_version = repo.commit("d").await?;
+
+        let mut checkout = Checkout::new()?;
+        checkout.switch_trees(Some(file_version.tree_sha()), dir_version.tree_sha(), false).await?;
+        checkout.save()?;
+        assert_eq!(fs::read("d/x")?, b"x\n");
+        assert!(checkout.index_mut().entry(b"d").is_none());
+
+        let mut checkout = Checkout::new()?;
+        checkout.switch_trees(Some(dir_version.tree_sha()), file_version.tree_sha(), false).await?;
+        checkout.save()?;
+        assert_eq!(fs::read("d")?, b"file\n");
+        assert!(checkout.index_mut().entry(b"d/x").is_none());
+
+        let mut checkout = Checkout::new()?;
+        checkout.switch_trees(Some(file_version.tree_sha()), dir_version.tree_sha(), false).await?;
+        checkout.save()?;
+        fs::write("d/untracked", "u\n")?;
+        let mut checkout = Checkout::new()?;
+        let err = checkout
+            .switch_trees(Some(dir_version.tree_sha()), file_version.tree_sha(), false)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("would lose untracked files in them:\n\td\n"));
+        assert_eq!(fs::read("d/untracked")?, b"u\n");
+        assert_eq!(fs::read("d/x")?, b"x\n");
+
+        Ok(())
+    }
+
+    // -f throws away changes to paths the two trees share as well
+    #[tokio::test]
+    async fn test_force_switch_discards_changes() -> Result<()> {
+        let repo = TestRepo::new("force-switch").await?;
+        fs::write("a", "a\n")?;
+        fs::write("b", "b\n")?;
+        let first = repo.commit("a and b").await?;
+        fs::write("a", "changed\n")?;
+        let second = repo.commit("change a").await?;
+
+        fs::write("b", "dirty\n")?;
+        fs::write("new", "new\n")?;
+        let mut checkout = Checkout::new()?;
+        checkout.add(&[String::from("new")]).await?;
+        checkout.save()?;
+
+        let mut checkout = Checkout::new()?;
+        checkout.switch_trees(Some(second.tree_sha()), first.tree_sha(), true).await?;
+        checkout.save()?;
+        assert_eq!(fs::read("a")?, b"a\n");
+        assert_eq!(fs::read("b")?, b"b\n");
+        assert!(!Path::new("new").exists());
+
+        let checkout = Checkout::new()?;
+        let ids = |files: BTreeMap<Vec<u8>, TreeEntry>| files.into_values().map(|entry| entry.sha1_hash()).collect::<Vec<_>>();
+        assert_eq!(ids(checkout.staged_files()), ids(flatten(Some(first.tree_sha())).await?));
+        for entry in checkout.index.entries() {
+            assert!(!is_modified(&entry.path, entry).await?);
+        }
+
+        Ok(())
+    }
+
+    // writing a file where a directory is takes only the tracked files
+    // with it, and the rest only when forced to
+    #[tokio::test]
+    async fn test_untracked_files_keep_directory() -> Result<()> {
+        let repo = TestRepo::new("keep-dir").await?;
+        fs::create_dir("d")?;
+        fs::write("d/x", "x\n")?;
+        let dir_version = repo.commit("d/x").await?;
+        fs::remove_dir_all("d")?;
+        fs::write("d", "file\n")?;
+        let file_version = repo.commit("d").await?;
+
+        let files = flatten(Some(file_version.tree_sha())).await?;
+        let mut checkout = Checkout::new()?;
+        checkout.checkout_tree(dir_version.tree_sha(), false).await?;
+        fs::write("d/untracked", "u\n")?;
+        let err = checkout.restore_worktree(&files, crate::diff::ALL_PATHS).await.unwrap_err();
+        assert!(err.to_string().contains("untracked files"));
+        assert_eq!(fs::read("d/untracked")?, b"u\n");
+        assert!(checkout.checkout_tree(file_version.tree_sha(), false).await.is_err());
+        assert_eq!(fs::read("d/untracked")?, b"u\n");
+
+        checkout.checkout_tree(file_version.tree_sha(), true).await?;
+        assert_eq!(fs::read("d")?, b"file\n");
+
+        // directories left empty are no loss
+        checkout.checkout_tree(dir_version.tree_sha(), false).await?;
+        fs::create_dir_all("d/empty/nested")?;
+        checkout.restore_worktree(&files, crate::diff::ALL_PATHS).await?;
+        assert_eq!(fs::read("d")?, b"file\n");
+
+        Ok(())
+    }
+}
@@ -1,4 +1,7 @@
 use crate::commands;
+use crate::diff;
+use crate::sequencer::PickOptions;
+use crate::stash;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
@@ -8,7 +11,12 @@ use anyhow::Result;
 #[structopt(name = "TGit", about = "HedonHermDev's implementation of Git")]
 pub enum CLI {
     #[structopt(name = "init", about = "Initialize an empty git repository")]
-    Init { git_dir: Option<PathBuf> },
+    Init {
+        git_dir: Option<PathBuf>,
+
+        #[structopt(long = "object-format", about = "The hash algorithm to name objects by (sha1 or sha256)")]
+        object_format: Option<String>,
+    },
 
     #[structopt(name = "cat-file", about = "Cat the contents of a git object")]
     CatFile {
@@ -37,11 +45,29 @@ pub enum CLI {
 
     #[structopt(name = "ls-tree", about = "List a git tree")]
     ListTree {
-        #[structopt(name = "TREE SHA")]
-        tree_sha: String,
+        #[structopt(short = "r", about = "Recurse into subtrees")]
+        recursive: bool,
 
-        #[structopt(long = "name-only")]
+        #[structopt(short = "t", about = "Show the trees walked through, even when recursing")]
+        show_trees: bool,
+
+        #[structopt(short = "l", long = "long", about = "Show the size of each blob")]
+        long: bool,
+
+        #[structopt(short = "z", about = "End entries with NUL and don't quote paths")]
+        nul_terminated: bool,
+
+        #[structopt(long = "name-only", alias = "name-status")]
         name_only: bool,
+
+        #[structopt(long = "format")]
+        format: Option<String>,
+
+        #[structopt(name = "TREE-ISH")]
+        tree_ish: String,
+
+        #[structopt(name = "PATH")]
+        paths: Vec<String>,
     },
 
     #[structopt(name = "write-tree", about = "Write the working tree")]
@@ -65,31 +91,956 @@ pub enum CLI {
 
         #[structopt(name = "CLONE DIR")]
         clone_dir: PathBuf,
+
+        #[structopt(long = "recurse-submodules", alias = "recursive", about = "Clone and check out every submodule too")]
+        recurse_submodules: bool,
+
+        #[structopt(long = "filter", about = "Leave out objects by filter-spec, fetching them when needed")]
+        filter: Option<String>,
+
+        #[structopt(long = "depth", about = "Only fetch this many commits of history")]
+        depth: Option<i32>,
+
+        #[structopt(long = "shallow-since", about = "Only fetch history after this date")]
+        shallow_since: Option<String>,
+
+        #[structopt(long = "shallow-exclude", about = "Leave out history reachable from this ref")]
+        shallow_exclude: Vec<String>,
+    },
+
+    #[structopt(name = "fetch", about = "Download objects and refs from another repository")]
+    Fetch {
+        #[structopt(name = "REMOTE", about = "A configured remote, a URL or a bundle file")]
+        remote: Option<String>,
+
+        #[structopt(name = "REFSPEC")]
+        refspecs: Vec<String>,
+
+        #[structopt(long = "depth", about = "Cut the history off this many commits from each tip")]
+        depth: Option<i32>,
+
+        #[structopt(long = "deepen", about = "Fetch this many more commits of a shallow history")]
+        deepen: Option<i32>,
+
+        #[structopt(long = "unshallow", about = "Fetch the rest of a shallow history")]
+        unshallow: bool,
+
+        #[structopt(long = "shallow-since", about = "Deepen a shallow history back to this date")]
+        shallow_since: Option<String>,
+
+        #[structopt(long = "shallow-exclude", about = "Deepen a shallow history up to this ref")]
+        shallow_exclude: Vec<String>,
+    },
+
+    #[structopt(name = "branch", about = "List, create, or delete branches")]
+    Branch {
+        #[structopt(short = "v", long = "verbose")]
+        verbose: bool,
+
+        #[structopt(short = "d", long = "delete")]
+        delete: bool,
+
+        #[structopt(short = "D", about = "Delete a branch even if it is not merged")]
+        force_delete: bool,
+
+        #[structopt(short = "m", long = "move")]
+        rename: bool,
+
+        #[structopt(short = "u", long = "set-upstream-to", name = "UPSTREAM")]
+        set_upstream_to: Option<String>,
+
+        #[structopt(name = "ARGS")]
+        args: Vec<String>,
+    },
+
+    #[structopt(name = "tag", about = "Create, list, delete or verify tags")]
+    Tag {
+        #[structopt(short = "a", long = "annotate", about = "Make an annotated tag object")]
+        annotate: bool,
+
+        #[structopt(short = "m", long = "message", about = "Message for an annotated tag")]
+        message: Option<String>,
+
+        #[structopt(short = "f", long = "force", about = "Replace an existing tag")]
+        force: bool,
+
+        #[structopt(short = "d", long = "delete")]
+        delete: bool,
+
+        #[structopt(short = "l", long = "list", about = "List tags, optionally those matching patterns")]
+        list: bool,
+
+        #[structopt(short = "v", long = "verify", about = "Verify the GPG signature of tags")]
+        verify: bool,
+
+        #[structopt(long = "sort", name = "key", about = "Sort by refname or version:refname")]
+        sort: Option<String>,
+
+        #[structopt(name = "ARGS")]
+        args: Vec<String>,
+    },
+
+    #[structopt(name = "switch", about = "Switch branches")]
+    Switch {
+        #[structopt(short = "c", long = "create", name = "NEW BRANCH")]
+        create: Option<String>,
+
+        #[structopt(long = "detach")]
+        detach: bool,
+
+        #[structopt(short = "f", long = "discard-changes")]
+        force: bool,
+
+        #[structopt(name = "TARGET")]
+        target: Option<String>,
+    },
+
+    #[structopt(name = "checkout", about = "Switch branches or detach HEAD")]
+    Checkout {
+        #[structopt(short = "b", name = "NEW BRANCH")]
+        new_branch: Option<String>,
+
+        #[structopt(short = "f", long = "force")]
+        force: bool,
+
+        #[structopt(name = "TARGET")]
+        target: Option<String>,
+    },
+
+    #[structopt(name = "diff-tree", about = "Compare the content and mode of two trees")]
+    DiffTree {
+        #[structopt(short = "r", about = "Recurse into subtrees")]
+        recursive: bool,
+
+        #[structopt(long = "name-only")]
+        name_only: bool,
+
+        #[structopt(long = "name-status")]
+        name_status: bool,
+
+        #[structopt(short = "M", long = "find-renames", name = "RENAME THRESHOLD", require_equals = true)]
+        find_renames: Option<Option<String>>,
+
+        #[structopt(short = "C", long = "find-copies", name = "COPY THRESHOLD", require_equals = true)]
+        find_copies: Option<Option<String>>,
+
+        #[structopt(long = "find-copies-harder")]
+        find_copies_harder: bool,
+
+        #[structopt(long = "root", about = "Show the root commit as a big creation event")]
+        root: bool,
+
+        #[structopt(name = "TREE-ISH")]
+        trees: Vec<String>,
+    },
+
+    #[structopt(name = "diff", about = "Show changes between commits, the index and the working tree")]
+    Diff {
+        #[structopt(long = "cached", alias = "staged", about = "Compare the index instead of the working tree")]
+        cached: bool,
+
+        #[structopt(short = "U", long = "unified", default_value = "3", about = "Lines of context")]
+        unified: usize,
+
+        #[structopt(long = "stat")]
+        stat: bool,
+
+        #[structopt(long = "numstat")]
+        numstat: bool,
+
+        #[structopt(long = "patience")]
+        patience: bool,
+
+        #[structopt(long = "histogram")]
+        histogram: bool,
+
+        #[structopt(long = "diff-algorithm")]
+        diff_algorithm: Option<String>,
+
+        #[structopt(short = "w", long = "ignore-all-space")]
+        ignore_all_space: bool,
+
+        #[structopt(short = "b", long = "ignore-space-change")]
+        ignore_space_change: bool,
+
+        #[structopt(long = "ignore-space-at-eol")]
+        ignore_space_at_eol: bool,
+
+        #[structopt(long = "ignore-cr-at-eol")]
+        ignore_cr_at_eol: bool,
+
+        #[structopt(long = "no-renames")]
+        no_renames: bool,
+
+        #[structopt(name = "COMMIT")]
+        revisions: Vec<String>,
+
+        #[structopt(name = "PATH", last = true)]
+        paths: Vec<String>,
+    },
+
+    #[structopt(name = "log", about = "Show commit logs")]
+    Log {
+        #[structopt(long = "stat")]
+        stat: bool,
+
+        #[structopt(long = "oneline")]
+        oneline: bool,
+
+        #[structopt(short = "n", long = "max-count")]
+        max_count: Option<usize>,
+
+        #[structopt(name = "REVISION")]
+        revision: Option<String>,
+    },
+
+    #[structopt(name = "merge", about = "Join two development histories together")]
+    Merge {
+        #[structopt(long = "no-ff", about = "Create a merge commit even when a fast-forward is possible")]
+        no_ff: bool,
+
+        #[structopt(long = "ff-only", about = "Refuse to merge unless a fast-forward is possible")]
+        ff_only: bool,
+
+        #[structopt(short = "m", name = "message")]
+        message: Option<String>,
+
+        #[structopt(long = "allow-unrelated-histories")]
+        allow_unrelated_histories: bool,
+
+        #[structopt(long = "continue", about = "Conclude a merge once its conflicts are resolved")]
+        continue_merge: bool,
+
+        #[structopt(long = "abort", about = "Abort a conflicted merge")]
+        abort: bool,
+
+        #[structopt(name = "COMMIT")]
+        commit: Option<String>,
+    },
+
+    #[structopt(name = "merge-base", about = "Find as good common ancestors as possible for a merge")]
+    MergeBase {
+        #[structopt(short = "a", long = "all", about = "Output all merge bases instead of just one")]
+        all: bool,
+
+        #[structopt(long = "octopus", about = "Compute the best common ancestors of all supplied commits")]
+        octopus: bool,
+
+        #[structopt(long = "is-ancestor", about = "Check if the first commit is an ancestor of the second")]
+        is_ancestor: bool,
+
+        #[structopt(long = "fork-point", about = "Find where a commit forked from a ref's history")]
+        fork_point: bool,
+
+        #[structopt(name = "COMMIT")]
+        commits: Vec<String>,
+    },
+
+    #[structopt(name = "commit-graph", about = "Write and verify the commit-graph file")]
+    CommitGraph(CommitGraphCommand),
+
+    #[structopt(name = "submodule", about = "Initialize, update or inspect submodules")]
+    Submodule {
+        #[structopt(subcommand)]
+        command: Option<SubmoduleCommand>,
+    },
+
+    #[structopt(name = "sparse-checkout", about = "Limit the working tree to some directories")]
+    SparseCheckout(SparseCheckoutCommand),
+
+    #[structopt(name = "bundle", about = "Move objects and refs by archive")]
+    Bundle(BundleCommand),
+
+    #[structopt(name = "fsck", about = "Verify the connectivity and validity of the objects in the database")]
+    Fsck {
+        #[structopt(long = "connectivity-only", about = "Only check that reachable objects are present")]
+        connectivity_only: bool,
+
+        #[structopt(long = "unreachable", about = "List every unreachable object, not just dangling ones")]
+        unreachable: bool,
+    },
+
+    #[structopt(name = "gc", about = "Pack refs and objects and prune unreachable objects")]
+    Gc {
+        #[structopt(long = "prune", name = "date", about = "Prune unreachable objects older than this (gc.pruneExpire)")]
+        prune: Option<String>,
+    },
+
+    #[structopt(name = "repack", about = "Pack unpacked objects in a repository")]
+    Repack {
+        #[structopt(short = "a", about = "Pack everything reachable into a single pack")]
+        all: bool,
+
+        #[structopt(short = "d", about = "Remove the packs and loose objects the new pack makes redundant")]
+        delete: bool,
+    },
+
+    #[structopt(name = "prune", about = "Remove unreachable loose objects")]
+    Prune {
+        #[structopt(long = "expire", name = "time", about = "Only remove objects older than this")]
+        expire: Option<String>,
+
+        #[structopt(short = "n", long = "dry-run", about = "List what would be removed without removing it")]
+        dry_run: bool,
+
+        #[structopt(short = "v", long = "verbose", about = "List every object removed")]
+        verbose: bool,
+    },
+
+    #[structopt(name = "rebase", about = "Reapply commits on top of another base tip")]
+    Rebase {
+        #[structopt(short = "i", long = "interactive", about = "Edit the list of commits to rebase first")]
+        interactive: bool,
+
+        #[structopt(long = "autosquash", about = "Move fixup! and squash! commits after the commits they amend")]
+        autosquash: bool,
+
+        #[structopt(long = "onto", name = "newbase", about = "Replay onto this commit instead of the upstream")]
+        onto: Option<String>,
+
+        #[structopt(long = "continue", about = "Carry on once the stopped step is resolved")]
+        continue_rebase: bool,
+
+        #[structopt(long = "skip", about = "Drop the stopped commit and carry on")]
+        skip: bool,
+
+        #[structopt(long = "abort", about = "Go back to the branch as it was before the rebase")]
+        abort: bool,
+
+        #[structopt(name = "UPSTREAM")]
+        upstream: Option<String>,
+    },
+
+    #[structopt(name = "cherry-pick", about = "Apply the changes introduced by some existing commits")]
+    CherryPick {
+        #[structopt(short = "x", about = "Record which commit was cherry-picked in the message")]
+        record_origin: bool,
+
+        #[structopt(flatten)]
+        options: SequenceOptions,
+    },
+
+    #[structopt(name = "revert", about = "Revert some existing commits")]
+    Revert {
+        #[structopt(flatten)]
+        options: SequenceOptions,
+    },
+
+    #[structopt(name = "reset", about = "Reset the current HEAD, or some index entries, to a commit")]
+    Reset {
+        #[structopt(long = "soft", about = "Only move HEAD, keeping the index and working tree")]
+        soft: bool,
+
+        #[structopt(long = "mixed", about = "Reset the index but not the working tree (the default)")]
+        mixed: bool,
+
+        #[structopt(long = "hard", about = "Reset the index and working tree, discarding local changes")]
+        hard: bool,
+
+        #[structopt(name = "ARGS")]
+        args: Vec<String>,
+
+        #[structopt(name = "PATH", last = true)]
+        paths: Vec<String>,
+    },
+
+    #[structopt(name = "restore", about = "Restore working tree files")]
+    Restore {
+        #[structopt(short = "s", long = "source", name = "tree", about = "Restore from this tree instead of the index or HEAD")]
+        source: Option<String>,
+
+        #[structopt(short = "S", long = "staged", about = "Restore the index")]
+        staged: bool,
+
+        #[structopt(short = "W", long = "worktree", about = "Restore the working tree (the default)")]
+        worktree: bool,
+
+        #[structopt(name = "PATH")]
+        paths: Vec<String>,
+    },
+
+    #[structopt(name = "add", about = "Add file contents to the index")]
+    Add {
+        #[structopt(name = "PATH")]
+        paths: Vec<String>,
+    },
+
+    #[structopt(name = "rm", about = "Remove files from the working tree and from the index")]
+    Rm {
+        #[structopt(long = "cached", about = "Only remove from the index")]
+        cached: bool,
+
+        #[structopt(short = "f", long = "force", about = "Override the up-to-date check")]
+        force: bool,
+
+        #[structopt(short = "r", about = "Allow recursive removal")]
+        recursive: bool,
+
+        #[structopt(name = "PATH")]
+        paths: Vec<String>,
+    },
+
+    #[structopt(name = "stash", about = "Stash the changes in a dirty working directory away")]
+    Stash {
+        #[structopt(flatten)]
+        options: StashOptions,
+
+        #[structopt(subcommand)]
+        command: Option<StashCommand>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SequenceOptions {
+    #[structopt(short = "m", long = "mainline", name = "parent-number", about = "The parent of a merge to take the change against")]
+    mainline: Option<usize>,
+
+    #[structopt(short = "n", long = "no-commit", about = "Apply the changes without committing them")]
+    no_commit: bool,
+
+    #[structopt(long = "continue", about = "Carry on once the stopped commit is resolved")]
+    continue_sequence: bool,
+
+    #[structopt(long = "skip", about = "Drop the stopped commit and carry on")]
+    skip: bool,
+
+    #[structopt(long = "abort", about = "Go back to where HEAD was before the first commit")]
+    abort: bool,
+
+    #[structopt(name = "COMMIT")]
+    commits: Vec<String>,
+}
+
+impl SequenceOptions {
+    async fn run(self, revert: bool, record_origin: bool) -> Result<()> {
+        let action = if self.continue_sequence {
+            Some(commands::Resume::Continue)
+        } else if self.skip {
+            Some(commands::Resume::Skip)
+        } else if self.abort {
+            Some(commands::Resume::Abort)
+        } else {
+            None
+        };
+
+        let options = PickOptions {
+            record_origin,
+            mainline: self.mainline,
+            no_commit: self.no_commit,
+        };
+        commands::sequence(revert, self.commits, options, action).await
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum CommitGraphCommand {
+    #[structopt(name = "write", about = "Write a commit-graph of every commit reachable from a ref")]
+    Write {
+        #[structopt(long = "reachable", about = "Start from all refs (always the case)")]
+        _reachable: bool,
+
+        #[structopt(long = "changed-paths", about = "Also write changed-path Bloom filters")]
+        changed_paths: bool,
+    },
+
+    #[structopt(name = "verify", about = "Check the commit-graph against the commits it describes")]
+    Verify,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum SubmoduleCommand {
+    #[structopt(name = "init", about = "Register the submodules' URLs from .gitmodules in .git/config")]
+    Init {
+        #[structopt(name = "PATH")]
+        paths: Vec<String>,
+    },
+
+    #[structopt(name = "update", about = "Clone missing submodules and check out the recorded commits")]
+    Update {
+        #[structopt(long = "init", about = "Initialize submodules that are not yet")]
+        init: bool,
+
+        #[structopt(long = "recursive", about = "Update the submodules' own submodules too")]
+        recursive: bool,
+
+        #[structopt(name = "PATH")]
+        paths: Vec<String>,
+    },
+
+    #[structopt(name = "status", about = "Show the commit each submodule has checked out")]
+    Status {
+        #[structopt(long = "recursive")]
+        recursive: bool,
+
+        #[structopt(name = "PATH")]
+        paths: Vec<String>,
+    },
+
+    #[structopt(name = "sync", about = "Copy the URLs in .gitmodules to the registered submodules")]
+    Sync {
+        #[structopt(long = "recursive")]
+        recursive: bool,
+
+        #[structopt(name = "PATH")]
+        paths: Vec<String>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum SparseCheckoutCommand {
+    #[structopt(name = "set", about = "Limit the working tree to these directories")]
+    Set {
+        #[structopt(name = "DIR")]
+        dirs: Vec<String>,
+    },
+
+    #[structopt(name = "add", about = "Add directories to the sparse checkout")]
+    Add {
+        #[structopt(name = "DIR")]
+        dirs: Vec<String>,
+    },
+
+    #[structopt(name = "list", about = "List the directories in the sparse checkout")]
+    List,
+
+    #[structopt(name = "reapply", about = "Apply the sparse checkout to the working tree again")]
+    Reapply,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum BundleCommand {
+    #[structopt(name = "create", about = "Write a bundle of the history the revisions select")]
+    Create {
+        #[structopt(long = "version", about = "The bundle format version to write (2 or 3)")]
+        version: Option<u32>,
+
+        #[structopt(long = "all", about = "Bundle every ref, and HEAD")]
+        all: bool,
+
+        #[structopt(name = "FILE")]
+        file: PathBuf,
+
+        #[structopt(name = "REV", allow_hyphen_values = true)]
+        revs: Vec<String>,
+    },
+
+    #[structopt(name = "verify", about = "Check the repository has what a bundle builds on")]
+    Verify {
+        #[structopt(short = "q", long = "quiet", about = "Do not describe the bundle")]
+        quiet: bool,
+
+        #[structopt(name = "FILE")]
+        file: PathBuf,
+    },
+
+    #[structopt(name = "list-heads", about = "List the refs a bundle carries")]
+    ListHeads {
+        #[structopt(name = "FILE")]
+        file: PathBuf,
+    },
+
+    #[structopt(name = "unbundle", about = "Store the objects of a bundle and print its refs")]
+    Unbundle {
+        #[structopt(name = "FILE")]
+        file: PathBuf,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub struct StashOptions {
+    #[structopt(short = "k", long = "keep-index", about = "Leave staged changes in the index and working tree")]
+    keep_index: bool,
+
+    #[structopt(short = "u", long = "include-untracked", about = "Stash untracked files too")]
+    include_untracked: bool,
+
+    #[structopt(short = "m", long = "message")]
+    message: Option<String>,
+}
+
+impl StashOptions {
+    fn push(self, paths: Vec<String>) -> stash::PushOptions {
+        stash::PushOptions {
+            paths,
+            keep_index: self.keep_index,
+            include_untracked: self.include_untracked,
+            message: self.message,
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum StashCommand {
+    #[structopt(name = "push", about = "Save local changes as a new stash entry and reset them")]
+    Push {
+        #[structopt(flatten)]
+        options: StashOptions,
+
+        #[structopt(name = "PATH")]
+        paths: Vec<String>,
+    },
+
+    #[structopt(name = "list", about = "List the stash entries")]
+    List,
+
+    #[structopt(name = "show", about = "Show the changes recorded in a stash entry")]
+    Show {
+        #[structopt(short = "p", long = "patch", about = "Show a diff rather than a diffstat")]
+        patch: bool,
+
+        #[structopt(name = "STASH")]
+        stash: Option<String>,
+    },
+
+    #[structopt(name = "apply", about = "Apply a stash entry on top of the working tree")]
+    Apply {
+        #[structopt(long = "index", about = "Restore the staged changes too")]
+        index: bool,
+
+        #[structopt(name = "STASH")]
+        stash: Option<String>,
+    },
+
+    #[structopt(name = "pop", about = "Apply a stash entry and drop it")]
+    Pop {
+        #[structopt(long = "index", about = "Restore the staged changes too")]
+        index: bool,
+
+        #[structopt(name = "STASH")]
+        stash: Option<String>,
+    },
+
+    #[structopt(name = "drop", about = "Remove a stash entry")]
+    Drop {
+        #[structopt(name = "STASH")]
+        stash: Option<String>,
     },
 }
 
+impl StashCommand {
+    async fn run(self) -> Result<()> {
+        let applied = match self {
+            StashCommand::Push { options, paths } => return commands::stash_push(options.push(paths)).await,
+            StashCommand::List => return commands::stash_list(),
+            StashCommand::Show { patch, stash } => return commands::stash_show(patch, stash).await,
+            StashCommand::Drop { stash } => return commands::stash_drop(stash).await,
+            StashCommand::Apply { index, stash } => commands::stash_apply(index, false, stash).await?,
+            StashCommand::Pop { index, stash } => commands::stash_apply(index, true, stash).await?,
+        };
+
+        // conflicts were reported as the merge went
+        if !applied {
+            std::process::exit(1);
+        }
+
+        Ok(())
+    }
+}
+
+// git spells thresholds as "-M50%"; clap only accepts them as "--find-renames=50%"
+fn expand_thresholds(arg: String) -> String {
+    for (short, long) in &[("-M", "--find-renames="), ("-C", "--find-copies=")] {
+        if let Some(value) = arg.strip_prefix(short) {
+            if !value.is_empty() {
+                return format!("{}{}", long, value);
+            }
+        }
+    }
+
+    arg
+}
+
 impl CLI {
     pub async fn run() -> Result<()> {
-        let args: Self = Self::from_args();
+        let args: Self = Self::from_iter(std::env::args().map(expand_thresholds));
 
         match args {
-            CLI::Init { git_dir } => commands::init(git_dir).await,
+            CLI::Init { git_dir, object_format } => commands::init(git_dir, object_format).await,
             CLI::CatFile {
                 pretty_print,
                 object_sha,
             } => commands::cat_file(pretty_print, object_sha).await,
             CLI::HashObject { file, write } => commands::hash_object(file, write).await,
             CLI::ListTree {
-                tree_sha,
+                recursive,
+                show_trees,
+                long,
+                nul_terminated,
                 name_only,
-            } => commands::list_tree(tree_sha, name_only).await,
+                format,
+                tree_ish,
+                paths,
+            } => {
+                let options = commands::ListTreeOptions {
+                    recursive,
+                    show_trees,
+                    long,
+                    nul_terminated,
+                    name_only,
+                    format,
+                    paths,
+                };
+                commands::list_tree(tree_ish, options).await
+            }
             CLI::WriteTree => commands::write_tree().await,
             CLI::CommitTree {
                 tree_sha,
                 parent_sha,
                 message,
             } => commands::commit_tree(tree_sha, parent_sha, message).await,
-            CLI::Clone { url, clone_dir } => commands::clone(url, clone_dir).await,
+            CLI::Clone {
+                url,
+                clone_dir,
+                recurse_submodules,
+                filter,
+                depth,
+                shallow_since,
+                shallow_exclude,
+            } => {
+                let deepen = commands::deepen_options(depth, None, false, shallow_since, shallow_exclude)?;
+                commands::clone(url, clone_dir, recurse_submodules, filter, deepen).await
+            }
+            CLI::Fetch {
+                remote,
+                refspecs,
+                depth,
+                deepen,
+                unshallow,
+                shallow_since,
+                shallow_exclude,
+            } => {
+                let deepen = commands::deepen_options(depth, deepen, unshallow, shallow_since, shallow_exclude)?;
+                commands::fetch(remote, refspecs, deepen).await
+            }
+            CLI::Branch {
+                verbose,
+                delete,
+                force_delete,
+                rename,
+                set_upstream_to,
+                args,
+            } => {
+                commands::branch(verbose, delete, force_delete, rename, set_upstream_to, args)
+                    .await
+            }
+            CLI::Tag {
+                annotate,
+                message,
+                force,
+                delete,
+                list,
+                verify,
+                sort,
+                args,
+            } => commands::tag(annotate, message, force, delete, list, verify, sort, args).await,
+            CLI::Switch {
+                create,
+                detach,
+                force,
+                target,
+            } => commands::switch(create, detach, force, target).await,
+            CLI::Checkout {
+                new_branch,
+                force,
+                target,
+            } => commands::checkout(new_branch, force, target).await,
+            CLI::DiffTree {
+                recursive,
+                name_only,
+                name_status,
+                find_renames,
+                find_copies,
+                find_copies_harder,
+                root,
+                trees,
+            } => {
+                let options = commands::tree_diff_options(
+                    recursive,
+                    find_renames,
+                    find_copies,
+                    find_copies_harder,
+                )?;
+                commands::diff_tree(options, name_only, name_status, root, trees).await
+            }
+            CLI::Diff {
+                cached,
+                unified,
+                stat,
+                numstat,
+                patience,
+                histogram,
+                diff_algorithm,
+                ignore_all_space,
+                ignore_space_change,
+                ignore_space_at_eol,
+                ignore_cr_at_eol,
+                no_renames,
+                revisions,
+                paths,
+            } => {
+                let options = diff::DiffOptions {
+                    algorithm: commands::diff_algorithm(patience, histogram, diff_algorithm)?,
+                    context: unified,
+                    ignore_all_space,
+                    ignore_space_change,
+                    ignore_space_at_eol,
+                    ignore_cr_at_eol,
+                    ..diff::DiffOptions::default()
+                };
+                commands::diff(options, !no_renames, cached, stat, numstat, revisions, paths).await
+            }
+            CLI::Log {
+                stat,
+                oneline,
+                max_count,
+                revision,
+            } => commands::log(stat, oneline, max_count, revision).await,
+            CLI::Merge {
+                no_ff,
+                ff_only,
+                message,
+                allow_unrelated_histories,
+                continue_merge,
+                abort,
+                commit,
+            } => {
+                if continue_merge {
+                    return commands::merge_continue().await;
+                }
+                if abort {
+                    return commands::merge_abort().await;
+                }
+
+                let fast_forward = if no_ff {
+                    commands::FastForward::Never
+                } else if ff_only {
+                    commands::FastForward::Only
+                } else {
+                    commands::FastForward::Allow
+                };
+                let commit = match commit {
+                    Some(commit) => commit,
+                    None => anyhow::bail!("No commit specified to merge"),
+                };
+                commands::merge(commit, message, fast_forward, allow_unrelated_histories).await
+            }
+            CLI::MergeBase {
+                all,
+                octopus,
+                is_ancestor,
+                fork_point,
+                commits,
+            } => {
+                // like git, answer "no" through the exit status alone
+                if !commands::merge_base(all, octopus, is_ancestor, fork_point, commits).await? {
+                    std::process::exit(1);
+                }
+                Ok(())
+            }
+            CLI::CommitGraph(CommitGraphCommand::Write { changed_paths, .. }) => {
+                commands::commit_graph_write(changed_paths).await
+            }
+            CLI::CommitGraph(CommitGraphCommand::Verify) => commands::commit_graph_verify().await,
+            CLI::Submodule { command } => match command {
+                Some(SubmoduleCommand::Init { paths }) => commands::submodule_init(paths),
+                Some(SubmoduleCommand::Update { init, recursive, paths }) => {
+                    commands::submodule_update(paths, init, recursive).await
+                }
+                Some(SubmoduleCommand::Status { recursive, paths }) => commands::submodule_status(paths, recursive).await,
+                Some(SubmoduleCommand::Sync { recursive, paths }) => commands::submodule_sync(paths, recursive),
+                None => commands::submodule_status(Vec::new(), false).await,
+            },
+            CLI::SparseCheckout(command) => match command {
+                SparseCheckoutCommand::Set { dirs } => commands::sparse_checkout_set(dirs).await,
+                SparseCheckoutCommand::Add { dirs } => commands::sparse_checkout_add(dirs).await,
+                SparseCheckoutCommand::List => commands::sparse_checkout_list(),
+                SparseCheckoutCommand::Reapply => commands::sparse_checkout_reapply().await,
+            },
+            CLI::Bundle(command) => match command {
+                BundleCommand::Create { version, all, file, revs } => {
+                    commands::bundle_create(file, version, all, revs).await
+                }
+                BundleCommand::Verify { quiet, file } => {
+                    if !commands::bundle_verify(file, quiet)? {
+                        std::process::exit(1);
+                    }
+                    Ok(())
+                }
+                BundleCommand::ListHeads { file } => commands::bundle_list_heads(file),
+                BundleCommand::Unbundle { file } => commands::bundle_unbundle(file),
+            },
+            CLI::Fsck {
+                connectivity_only,
+                unreachable,
+            } => {
+                if !commands::fsck(connectivity_only, unreachable).await? {
+                    std::process::exit(1);
+                }
+                Ok(())
+            }
+            CLI::Gc { prune } => commands::gc(prune).await,
+            CLI::Repack { all, delete } => commands::repack(all, delete).await,
+            CLI::Prune {
+                expire,
+                dry_run,
+                verbose,
+            } => commands::prune(expire, dry_run, verbose).await,
+            CLI::Rebase {
+                interactive,
+                autosquash,
+                onto,
+                continue_rebase,
+                skip,
+                abort,
+                upstream,
+            } => {
+                let action = if continue_rebase {
+                    Some(commands::Resume::Continue)
+                } else if skip {
+                    Some(commands::Resume::Skip)
+                } else if abort {
+                    Some(commands::Resume::Abort)
+                } else {
+                    None
+                };
+                commands::rebase(upstream, onto, interactive, autosquash, action).await
+            }
+            CLI::CherryPick { record_origin, options } => options.run(false, record_origin).await,
+            CLI::Revert { options } => options.run(true, false).await,
+            CLI::Reset {
+                soft,
+                mixed,
+                hard,
+                args,
+                paths,
+            } => {
+                let mode = match (soft, mixed, hard) {
+                    (false, false, false) => None,
+                    (true, false, false) => Some(commands::ResetMode::Soft),
+                    (false, true, false) => Some(commands::ResetMode::Mixed),
+                    (false, false, true) => Some(commands::ResetMode::Hard),
+                    _ => anyhow::bail!("--soft, --mixed and --hard are mutually exclusive"),
+                };
+                commands::reset(mode, args, paths).await
+            }
+            // a bare `stash` pushes, taking push's options
+            CLI::Stash { options, command } => match command {
+                Some(command) => command.run().await,
+                None => commands::stash_push(options.push(Vec::new())).await,
+            },
+            CLI::Restore {
+                source,
+                staged,
+                worktree,
+                paths,
+            } => commands::restore(source, staged, worktree, paths).await,
+            CLI::Add { paths } => commands::add(paths).await,
+            CLI::Rm {
+                cached,
+                force,
+                recursive,
+                paths,
+            } => commands::rm(cached, force, recursive, paths).await,
         }
     }
 }
@@ -1,24 +1,21 @@
-use anyhow::{bail, Result};
-use bytes;
+use anyhow::{bail, Context, Result};
 use reqwest::header::{HeaderMap, CONTENT_TYPE};
 use reqwest::{Client, StatusCode, Url};
 use std::i32;
 
+use crate::objects::{AnyObject, HashAlgorithm, Object, ObjectId};
 use crate::packfile;
-use crate::utils;
+use crate::promisor;
+use crate::shallow;
 
 pub struct Ref {
-    name: String,
-    hash: String,
+    pub name: String,
+    pub hash: String,
 }
 
 impl Ref {
     pub fn parse_pkt_line(data: &str) -> Self {
-        let (len, rest) = data.split_at(4);
-
-        let _len = i32::from_str_radix(len, 16);
-
-        let mut iter = rest.split(" ");
+        let mut iter = data.trim_end().split(' ');
         let hash = iter.next().expect("Invalid pkt line").to_string();
         let name = iter.next().expect("Invalid pkt line").to_string();
 
@@ -26,6 +23,149 @@ impl Ref {
     }
 }
 
+/// Maps a remote ref to the local one a fetch refspec such as
+/// `+refs/heads/*:refs/remotes/origin/*` stores it as, if any does.
+pub fn map_ref(refspecs: &[String], remote_ref: &str) -> Option<String> {
+    for spec in refspecs {
+        let (src, dst) = match spec.trim_start_matches('+').split_once(':') {
+            Some(sides) => sides,
+            None => continue,
+        };
+
+        match (src.split_once('*'), dst.split_once('*')) {
+            (Some((src_prefix, src_suffix)), Some((dst_prefix, dst_suffix))) => {
+                let matched = remote_ref
+                    .strip_prefix(src_prefix)
+                    .and_then(|rest| rest.strip_suffix(src_suffix));
+                if let Some(matched) = matched {
+                    return Some(format!("{}{}{}", dst_prefix, matched, dst_suffix));
+                }
+            }
+            (None, None) if src == remote_ref => return Some(dst.to_string()),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+pub fn pkt_line(data: &str) -> String {
+    format!("{:04x}{}", data.len() + 4, data)
+}
+
+// splits pkt-lines until a line that does not parse as one; `None` is a flush packet
+fn read_pkt_lines(data: &[u8]) -> Result<(Vec<Option<Vec<u8>>>, usize)> {
+    let mut lines = Vec::new();
+    let mut pos = 0;
+
+    while pos + 4 <= data.len() {
+        let len = match std::str::from_utf8(&data[pos..pos + 4])
+            .ok()
+            .and_then(|len| i32::from_str_radix(len, 16).ok())
+        {
+            Some(len) => len as usize,
+            None => break,
+        };
+
+        if len == 0 {
+            lines.push(None);
+            pos += 4;
+            continue;
+        }
+        if len < 4 || pos + len > data.len() {
+            bail!("Invalid pkt-line length {}", len);
+        }
+
+        lines.push(Some(data[pos + 4..pos + len].to_vec()));
+        pos += len;
+    }
+
+    Ok((lines, pos))
+}
+
+/// What the remote advertised: its refs, capabilities and where its HEAD points.
+pub struct Advertisement {
+    pub refs: Vec<Ref>,
+    pub capabilities: Vec<String>,
+}
+
+impl Advertisement {
+    pub fn head_symref(&self) -> Option<String> {
+        self.capabilities
+            .iter()
+            .find_map(|cap| cap.strip_prefix("symref=HEAD:"))
+            .map(String::from)
+    }
+
+    /// The hash algorithm the remote names its objects by.
+    pub fn object_format(&self) -> Result<HashAlgorithm> {
+        match self.capabilities.iter().find_map(|cap| cap.strip_prefix("object-format=")) {
+            Some(name) => HashAlgorithm::from_name(name),
+            None => Ok(HashAlgorithm::Sha1),
+        }
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|cap| cap == capability)
+    }
+}
+
+/// How far back a shallow history is cut off.
+#[derive(Debug, Default, Clone)]
+pub struct Deepen {
+    /// How many commits to keep from each tip (`--depth`), or with
+    /// `relative` how many more than the repository has (`--deepen`).
+    pub depth: Option<u32>,
+    pub relative: bool,
+    /// Leave out commits older than this (`--shallow-since`).
+    pub since: Option<i64>,
+    /// Leave out commits reachable from these refs (`--shallow-exclude`).
+    pub not: Vec<String>,
+}
+
+impl Deepen {
+    pub fn is_some(&self) -> bool {
+        self.depth.is_some() || self.since.is_some() || !self.not.is_empty()
+    }
+
+    /// Fails the way git does when the remote cannot cut history this way.
+    pub fn check(&self, advertisement: &Advertisement) -> Result<()> {
+        if self.is_some() && !advertisement.supports("shallow") {
+            bail!("Server does not support shallow clients");
+        }
+        if self.relative && !advertisement.supports("deepen-relative") {
+            bail!("Server does not support --deepen");
+        }
+        if self.since.is_some() && !advertisement.supports("deepen-since") {
+            bail!("Server does not support --shallow-since");
+        }
+        if !self.not.is_empty() && !advertisement.supports("deepen-not") {
+            bail!("Server does not support --shallow-exclude");
+        }
+
+        Ok(())
+    }
+}
+
+/// What to ask `git-upload-pack` for.
+#[derive(Debug, Default)]
+pub struct PackRequest {
+    pub wants: Vec<String>,
+    // commits already here, whose history the remote can leave out
+    pub haves: Vec<String>,
+    pub include_tag: bool,
+    // a `--filter` spec the remote supports
+    pub filter: Option<String>,
+    pub deepen: Deepen,
+}
+
+/// A pack, and how it moved the boundary of a shallow history.
+pub struct PackResponse {
+    pub pack: Vec<u8>,
+    pub shallow: Vec<ObjectId>,
+    pub unshallow: Vec<ObjectId>,
+}
+
 pub struct CloneClient {
     url: String,
     client: Client,
@@ -37,7 +177,7 @@ impl CloneClient {
 
         let mut url = url;
         if url.ends_with(".git") {
-            url.push_str("/");
+            url.push('/');
         }
         if !url.ends_with(".git/") {
             url.push_str(".git/");
@@ -46,37 +186,109 @@ impl CloneClient {
         Self { url, client }
     }
 
-    pub async fn discover_refs(&self) -> Result<Vec<Ref>> {
-        println!("{}", self.url);
+    pub async fn discover_refs(&self) -> Result<Advertisement> {
         let url = Url::parse(&self.url)?.join("info/refs?service=git-upload-pack")?;
-        println!("{}", url.as_str());
 
         let resp = self.client.get(url).send().await?;
-        println!("{}", resp.status());
 
         if !(resp.status() == StatusCode::OK || resp.status() == StatusCode::NOT_MODIFIED) {
             bail!("Unable to find repository!");
         }
 
-        let content = resp.text().await?;
+        let content = resp.bytes().await?;
+        let (lines, _) = read_pkt_lines(&content)?;
 
         let mut refs: Vec<Ref> = vec![];
+        let mut capabilities = Vec::new();
+
+        for line in lines.into_iter().flatten() {
+            if line.starts_with(b"# service=") {
+                continue;
+            }
+
+            // the first ref carries the capability list after a NUL
+            let (line, caps) = match line.iter().position(|b| *b == 0x00u8) {
+                Some(pos) => (&line[..pos], Some(&line[pos + 1..])),
+                None => (&line[..], None),
+            };
+
+            if let Some(caps) = caps {
+                capabilities = String::from_utf8_lossy(caps)
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect();
+            }
 
-        for line in content.lines() {
-            if line.contains("refs/") {
-                let branch_ref = Ref::parse_pkt_line(line);
+            let line = String::from_utf8_lossy(line);
+            let branch_ref = Ref::parse_pkt_line(&line);
+
+            // capabilities^{} stands in for the refs of an empty repository
+            if branch_ref.name != "capabilities^{}" && !branch_ref.name.ends_with("^{}") {
                 refs.push(branch_ref);
             }
         }
 
-        Ok(refs)
+        Ok(Advertisement { refs, capabilities })
     }
 
-    pub async fn request_ref(&self, req_ref: Ref) -> Result<()> {
-        let mut pkt_line = String::from("0032want ");
-        pkt_line.push_str(&req_ref.hash);
-        pkt_line.push('\n');
-        pkt_line.push_str("00000009done\n");
+    /// Asks for a pack of what `request` wants and leads to, less what it
+    /// has or leaves out.
+    pub async fn request_pack(&self, request: &PackRequest, object_format: HashAlgorithm) -> Result<PackResponse> {
+        // the remote must know where a shallow repository's history stops
+        let shallow = shallow::load()?;
+        let deepen = &request.deepen;
+
+        let mut capabilities = String::from("ofs-delta no-progress");
+        if request.include_tag {
+            capabilities.push_str(" include-tag");
+        }
+        if request.filter.is_some() {
+            capabilities.push_str(" filter");
+        }
+        if deepen.is_some() || !shallow.is_empty() {
+            capabilities.push_str(" shallow");
+        }
+        if deepen.relative {
+            capabilities.push_str(" deepen-relative");
+        }
+        if deepen.since.is_some() {
+            capabilities.push_str(" deepen-since");
+        }
+        if !deepen.not.is_empty() {
+            capabilities.push_str(" deepen-not");
+        }
+        if object_format != HashAlgorithm::Sha1 {
+            capabilities.push_str(&format!(" object-format={}", object_format.name()));
+        }
+
+        let mut body = String::new();
+        for (i, want) in request.wants.iter().enumerate() {
+            if i == 0 {
+                body.push_str(&pkt_line(&format!("want {} {}\n", want, capabilities)));
+            } else {
+                body.push_str(&pkt_line(&format!("want {}\n", want)));
+            }
+        }
+        for sha1_hash in &shallow {
+            body.push_str(&pkt_line(&format!("shallow {}\n", hex::encode(sha1_hash))));
+        }
+        if let Some(depth) = deepen.depth {
+            body.push_str(&pkt_line(&format!("deepen {}\n", depth)));
+        }
+        if let Some(since) = deepen.since {
+            body.push_str(&pkt_line(&format!("deepen-since {}\n", since)));
+        }
+        for refname in &deepen.not {
+            body.push_str(&pkt_line(&format!("deepen-not {}\n", refname)));
+        }
+        if let Some(filter) = &request.filter {
+            body.push_str(&pkt_line(&format!("filter {}\n", filter)));
+        }
+        body.push_str("0000");
+        for have in &request.haves {
+            body.push_str(&pkt_line(&format!("have {}\n", have)));
+        }
+        body.push_str(&pkt_line("done\n"));
 
         let url = Url::parse(&self.url)?.join("git-upload-pack")?;
 
@@ -91,27 +303,106 @@ impl CloneClient {
         let response = self
             .client
             .post(url)
-            .body(pkt_line)
+            .body(body)
             .headers(headers)
             .send()
             .await?;
 
+        if response.status() != StatusCode::OK {
+            bail!("git-upload-pack failed with status {}", response.status());
+        }
+
         let data = response.bytes().await?;
-        let packfile = packfile::Packfile::parse_data(&data);
 
-        Ok(())
+        // upload-pack gives up without a word when, say, a shallow request
+        // selects no commits
+        if data.is_empty() {
+            bail!("the remote end hung up unexpectedly");
+        }
+
+        // the pack follows the shallow updates and the NAK/ACK negotiation lines
+        let (lines, pack_start) = read_pkt_lines(&data)?;
+        let mut shallow = Vec::new();
+        let mut unshallow = Vec::new();
+        for line in lines.into_iter().flatten() {
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end();
+            if let Some(message) = line.strip_prefix("ERR ") {
+                bail!("remote error: {}", message);
+            } else if let Some(sha) = line.strip_prefix("shallow ") {
+                shallow.push(ObjectId::from_hex_in(sha, object_format)?);
+            } else if let Some(sha) = line.strip_prefix("unshallow ") {
+                unshallow.push(ObjectId::from_hex_in(sha, object_format)?);
+            }
+        }
+
+        let pack = &data[pack_start..];
+        if !pack.starts_with(b"PACK") {
+            bail!("Remote did not send a packfile");
+        }
+
+        Ok(PackResponse {
+            pack: pack.to_vec(),
+            shallow,
+            unshallow,
+        })
     }
 
-    pub async fn clone(&self) -> Result<()> {
-        let branch_refs = self.discover_refs().await?;
+    /// Downloads the advertised branches, plus any tags pointing into them.
+    /// A history cut short only has the branch HEAD points at, as git's
+    /// `--depth` implies `--single-branch`. With a filter, what the remote
+    /// sends is kept as a promisor pack.
+    pub async fn clone(&self, mut request: PackRequest) -> Result<Advertisement> {
+        let advertisement = self.discover_refs().await?;
+        // the new repository takes the remote's format before anything is stored
+        let object_format = advertisement.object_format()?;
+        object_format.make_current();
+        request.deepen.check(&advertisement)?;
 
-        for branch in branch_refs {
-            println!("{}", branch.name);
-            let data = self.request_ref(branch).await?;
+        let single_branch = request.deepen.is_some();
+        for branch in &advertisement.refs {
+            if branch.name != "HEAD" && (single_branch || !branch.name.starts_with("refs/heads/")) {
+                continue;
+            }
+            if !request.wants.contains(&branch.hash) {
+                request.wants.push(branch.hash.clone());
+            }
         }
 
-        Ok(())
+        if request.wants.is_empty() {
+            return Ok(advertisement);
+        }
+
+        request.include_tag = advertisement.supports("include-tag");
+        if request.filter.is_some() && !advertisement.supports("filter") {
+            eprintln!("warning: filtering not recognized by server, ignoring");
+            request.filter = None;
+        }
+
+        let response = self.request_pack(&request, object_format).await?;
+        store_pack(&response.pack, request.filter.is_some()).await?;
+        shallow::update(&response.shallow, &response.unshallow)?;
+
+        Ok(advertisement)
+    }
+}
+
+/// Stores the objects of a pack received from a remote loose, or as it is
+/// when they come from a promisor remote.
+pub async fn store_pack(data: &[u8], promisor: bool) -> Result<()> {
+    if promisor {
+        return promisor::store_pack(data);
     }
+
+    let packfile = packfile::Packfile::parse_data(data).context("Invalid packfile")?;
+
+    // parse everything received before storing it, as git's index-pack does
+    for pack_object in packfile.objects() {
+        let object = AnyObject::parse(pack_object.write_data())?;
+        object.write().await?;
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -125,4 +416,29 @@ mod tests {
             "./tempgit/.git/objects/pack/pack-4e3c870cda81214366531c32ed63a52dbebc56fd.pack",
         );
     }
+
+    #[test]
+    fn test_map_ref() {
+        let refspecs = vec![
+            String::from("+refs/heads/*:refs/remotes/origin/*"),
+            String::from("refs/tags/v1:refs/tags/v1"),
+        ];
+        assert_eq!(map_ref(&refspecs, "refs/heads/main").as_deref(), Some("refs/remotes/origin/main"));
+        assert_eq!(map_ref(&refspecs, "refs/heads/a/b").as_deref(), Some("refs/remotes/origin/a/b"));
+        assert_eq!(map_ref(&refspecs, "refs/tags/v1").as_deref(), Some("refs/tags/v1"));
+        assert_eq!(map_ref(&refspecs, "refs/tags/v2"), None);
+        assert_eq!(map_ref(&refspecs, "HEAD"), None);
+    }
+
+    #[test]
+    fn test_read_pkt_lines() -> Result<()> {
+        let data = b"001e# service=git-upload-pack\n00000029abc HEAD\0symref=HEAD:refs/heads/main\n0000PACK";
+        let (lines, end) = read_pkt_lines(data)?;
+
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].is_none());
+        assert_eq!(&data[end..], b"PACK");
+
+        Ok(())
+    }
 }
@@ -1,12 +1,38 @@
-use anyhow::Result;
-use std::path::PathBuf;
+use anyhow::{bail, Result};
+use async_recursion::async_recursion;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use tokio::fs;
 
-use crate::objects::{Object, Blob, Tree, Commit};
-use crate::clone::CloneClient;
+use crate::bundle::{self, Bundle};
+use crate::checkout::{self, Checkout};
+use crate::clone::{self, CloneClient, Deepen, PackRequest};
+use crate::commit_graph::{self, CommitGraphFile};
+use crate::config::{self, Config};
+use crate::diff::{self, Change, TreeDiffOptions};
+use crate::fsck::Fsck;
+use crate::gc;
+use crate::graph::CommitGraph;
+use crate::index::Index;
+use crate::merge;
+use crate::promisor;
+use crate::objects::{self, AnyObject, EntryMode, HashAlgorithm, Object, ObjectId, Blob, Tree, TreeEntry, Commit, Tag, User};
+use crate::refs;
+use crate::revision;
+use crate::sequencer::{self, Action, PickOptions, Rebase, Sequence, Step};
+use crate::shallow;
+use crate::sparse::Cone;
+use crate::stash::{self, StashRef};
+use crate::submodule;
+use crate::utils;
 
 
-pub async fn init(git_dir: Option<PathBuf>) -> Result<()> {
+pub async fn init(git_dir: Option<PathBuf>, object_format: Option<String>) -> Result<()> {
+    let algorithm = match object_format {
+        Some(name) => HashAlgorithm::from_name(&name)?,
+        None => HashAlgorithm::Sha1,
+    };
+
     let mut git_dir = git_dir;
 
     if git_dir.is_none() {
@@ -26,42 +52,244 @@ pub async fn init(git_dir: Option<PathBuf>) -> Result<()> {
 
     path.pop();
     path.push("HEAD");
-    fs::write(path, "ref: refs/heads/master\n").await?; // .git/HEAD
+    fs::write(&path, "ref: refs/heads/master\n").await?; // .git/HEAD
+
+    path.pop();
+    write_object_format(path.join("config"), algorithm)?;
+    algorithm.make_current();
 
     Ok(())
 }
 
+// SHA-1 repositories keep git's default format version and say nothing
+fn write_object_format(config_path: PathBuf, algorithm: HashAlgorithm) -> Result<()> {
+    if algorithm == HashAlgorithm::Sha1 {
+        return Ok(());
+    }
+
+    let mut config = Config::load_from(config_path)?;
+    config.set("core.repositoryformatversion", "1")?;
+    config.set("extensions.objectformat", algorithm.name())?;
+
+    config.save()
+}
+
 pub async fn cat_file(pretty_print: bool, object_sha: String) -> Result<()> {
-    let blob = Blob::from_object_sha(object_sha).await?;
+    let sha1_hash = ObjectId::from_hex(&object_sha)?;
 
     if pretty_print {
-        print!("{}", blob);
+        let stdout = std::io::stdout();
+        write_pretty(sha1_hash, &mut stdout.lock()).await?;
+    } else {
+        objects::stream_object(sha1_hash).await?;
+    }
+
+    Ok(())
+}
+
+// what `cat-file -p` shows: a blob as it is, a line per tree entry, and a
+// commit or tag as it is stored. Blobs, however large, are streamed; the
+// other types are small enough to parse.
+async fn write_pretty(sha1_hash: ObjectId, out: &mut dyn Write) -> Result<()> {
+    let mut object = objects::stream_object(sha1_hash).await?;
+    if object.kind == "blob" {
+        return object.copy_body(out);
+    }
+
+    match AnyObject::from_object_sha(hex::encode(sha1_hash)).await? {
+        AnyObject::Tree(tree) => {
+            for entry in tree.entries() {
+                let mode = entry.mode();
+                let mut line = format!("{:06o} {} {}\t", mode.bits(), mode.object_type(), entry.sha1_hash()).into_bytes();
+                line.extend_from_slice(entry.name_bytes());
+                line.push(b'\n');
+                out.write_all(&line)?;
+            }
+        }
+        object => {
+            let (_, body_start) = objects::parse_header(object.write_data())?;
+            out.write_all(&object.write_data()[body_start..])?;
+        }
     }
 
     Ok(())
 }
 
 pub async fn hash_object(file: PathBuf, write: bool) -> Result<()> {
-    let blob = Blob::new(file).await?;
+    // reads the whole file, so on a blocking thread as Tree::new hashes
+    let sha1_hash = tokio::task::spawn_blocking(move || {
+        if write {
+            Blob::write_file(&file)
+        } else {
+            Blob::hash_file(&file)
+        }
+    })
+    .await??;
+    print!("{}", sha1_hash);
+
+    Ok(())
+}
+
+pub struct ListTreeOptions {
+    pub recursive: bool,
+    pub show_trees: bool,
+    pub long: bool,
+    pub nul_terminated: bool,
+    pub name_only: bool,
+    pub format: Option<String>,
+    pub paths: Vec<String>,
+}
+
+impl ListTreeOptions {
+    /// Whether `path` is named by one of the paths, or lies below one.
+    fn matches(&self, path: &[u8]) -> bool {
+        self.paths.is_empty()
+            || self.paths.iter().any(|spec| {
+                let spec = spec.as_bytes();
+                match path.strip_prefix(spec) {
+                    Some(rest) => rest.is_empty() || spec.ends_with(b"/") || rest.starts_with(b"/"),
+                    None => false,
+                }
+            })
+    }
+
+    /// Whether one of the paths lies below the tree at `path`.
+    fn leads_to(&self, path: &[u8]) -> bool {
+        self.paths.iter().any(|spec| {
+            spec.as_bytes()
+                .strip_prefix(path)
+                .is_some_and(|rest| rest.starts_with(b"/"))
+        })
+    }
+}
+
+pub async fn list_tree(tree_ish: String, options: ListTreeOptions) -> Result<()> {
+    if options.format.is_some() && (options.long || options.name_only) {
+        bail!("--format can't be combined with other format-altering options");
+    }
+    if options.long && options.name_only {
+        bail!("switch `l' is incompatible with --name-only");
+    }
+
+    let tree_sha = revision::resolve_tree(&tree_ish).await?;
+    let mut listed = Vec::new();
+    list_entries(tree_sha, Vec::new(), &options, &mut listed).await?;
+
+    let needs_size = options.long
+        || options.format.as_ref().is_some_and(|format| format.contains("%(objectsize"));
+    let terminator = if options.nul_terminated { b'\0' } else { b'\n' };
+    let mut stdout = std::io::stdout();
+
+    for (path, entry) in listed {
+        let size = match entry.mode() {
+            EntryMode::Blob | EntryMode::Executable | EntryMode::Symlink if needs_size => {
+                Some(objects::stream_object(entry.sha1_hash()).await?.size)
+            }
+            _ => None,
+        };
+        let path = if options.nul_terminated {
+            path
+        } else {
+            diff::quote_path_bytes(&path).into_bytes()
+        };
+
+        let mut line = match &options.format {
+            Some(format) => expand_tree_format(format, &entry, &path, size)?,
+            None if options.name_only => path,
+            None => {
+                let mode = entry.mode();
+                let mut line = format!("{:06o} {} {}", mode.bits(), mode.object_type(), entry.sha1_hash());
+                if options.long {
+                    let size = size.map_or_else(|| String::from("-"), |size| size.to_string());
+                    line.push_str(&format!(" {:>7}", size));
+                }
+                line.push('\t');
 
-    if write {
-        blob.write().await?;
+                let mut line = line.into_bytes();
+                line.extend_from_slice(&path);
+                line
+            }
+        };
+        line.push(terminator);
+        stdout.write_all(&line)?;
     }
-    print!("{}", blob.encoded_hash());
 
     Ok(())
 }
 
-pub async fn list_tree(tree_sha: String, name_only: bool) -> Result<()> {
-    let tree = Tree::from_object_sha(tree_sha).await?;
+/// Collects the entries `ls-tree` shows, in order, each with its full path.
+#[async_recursion]
+async fn list_entries(
+    tree_sha: ObjectId,
+    prefix: Vec<u8>,
+    options: &ListTreeOptions,
+    listed: &mut Vec<(Vec<u8>, TreeEntry)>,
+) -> Result<()> {
+    let tree = Tree::from_object_sha(hex::encode(tree_sha)).await?;
 
-    if name_only {
-        println!("{}", tree);
+    for entry in tree.entries() {
+        let mut path = prefix.clone();
+        path.extend_from_slice(entry.name_bytes());
+
+        if !entry.is_tree() {
+            if options.matches(&path) {
+                listed.push((path, entry.clone()));
+            }
+            continue;
+        }
+
+        let matches = options.matches(&path);
+        let leads_to = options.leads_to(&path);
+        let descend = (options.recursive && matches) || leads_to;
+        if (!descend && matches) || (options.show_trees && (matches || leads_to)) {
+            listed.push((path.clone(), entry.clone()));
+        }
+        if descend {
+            path.push(b'/');
+            list_entries(entry.sha1_hash(), path, options, listed).await?;
+        }
     }
 
     Ok(())
 }
 
+/// Expands the `%(field)` placeholders of `ls-tree --format` for one entry.
+fn expand_tree_format(format: &str, entry: &TreeEntry, path: &[u8], size: Option<u64>) -> Result<Vec<u8>> {
+    let mut line = Vec::new();
+    let mut rest = format;
+
+    while let Some(start) = rest.find('%') {
+        line.extend_from_slice(&rest.as_bytes()[..start]);
+        rest = &rest[start + 1..];
+
+        if let Some(after) = rest.strip_prefix('%') {
+            line.push(b'%');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('(') {
+            let end = match after.find(')') {
+                Some(end) => end,
+                None => bail!("bad ls-tree format: element '{}' does not end in ')'", rest),
+            };
+            let size_or_dash = || size.map_or_else(|| String::from("-"), |size| size.to_string());
+            match &after[..end] {
+                "objectmode" => line.extend_from_slice(format!("{:06o}", entry.mode().bits()).as_bytes()),
+                "objecttype" => line.extend_from_slice(entry.mode().object_type().as_bytes()),
+                "objectname" => line.extend_from_slice(entry.sha1_hash().to_string().as_bytes()),
+                "objectsize" => line.extend_from_slice(size_or_dash().as_bytes()),
+                "objectsize:padded" => line.extend_from_slice(format!("{:>7}", size_or_dash()).as_bytes()),
+                "path" => line.extend_from_slice(path),
+                field => bail!("bad ls-tree format: %({})", field),
+            }
+            rest = &after[end + 1..];
+        } else {
+            bail!("bad ls-tree format: element '{}' does not start with '('", rest);
+        }
+    }
+    line.extend_from_slice(rest.as_bytes());
+
+    Ok(line)
+}
+
 pub async fn write_tree() -> Result<()> {
     let tree = Tree::new(PathBuf::from("./")).await?;
 
@@ -73,10 +301,18 @@ pub async fn write_tree() -> Result<()> {
 }
 
 pub async fn commit_tree(tree_sha: String, parent_sha: String, message: String) -> Result<()> {
-    let name = String::from("Tirth Jain");
-    let email = String::from("jaintirth24@gmail.com");
+    let (name, email) = config::identity("AUTHOR")?;
+    let author = User::now(name, email);
+    let (name, email) = config::identity("COMMITTER")?;
+    let committer = User::now(name, email);
 
-    let commit = Commit::new(tree_sha, parent_sha, message, name, email)?;
+    let commit = Commit::create(
+        ObjectId::from_hex(&tree_sha)?,
+        vec![ObjectId::from_hex(&parent_sha)?],
+        message,
+        author,
+        committer,
+    )?;
 
     commit.write().await?;
     commit.update_refs()?;
@@ -86,10 +322,2855 @@ pub async fn commit_tree(tree_sha: String, parent_sha: String, message: String)
     Ok(())
 }
 
-pub async fn clone(url: String, clone_dir: PathBuf) -> Result<()> {
-    let client = CloneClient::new(url);
+pub async fn clone(
+    url: String,
+    clone_dir: PathBuf,
+    recurse_submodules: bool,
+    filter: Option<String>,
+    deepen: Deepen,
+) -> Result<()> {
+    if let Some(filter) = &filter {
+        promisor::check_filter(filter)?;
+    }
+    // a bundle stands in for the remote, read before moving into the clone;
+    // it has nothing to filter or cut short
+    let (bundle, url, filter, deepen) = if bundle::is_bundle(Path::new(&url)) {
+        let bundle = Bundle::open(Path::new(&url))?;
+        let url = std::fs::canonicalize(&url)?.to_string_lossy().to_string();
+        (Some(bundle), url, None, Deepen::default())
+    } else {
+        (None, url, filter, deepen)
+    };
+    if clone_dir.exists() && std::fs::read_dir(&clone_dir)?.next().is_some() {
+        bail!(
+            "destination path '{}' already exists and is not an empty directory.",
+            clone_dir.display()
+        );
+    }
+
+    println!("Cloning into '{}'...", clone_dir.display());
+
+    fs::create_dir_all(&clone_dir).await?;
+    init(Some(clone_dir.join(".git")), None).await?;
+    std::env::set_current_dir(&clone_dir)?;
+
+    let single_branch = deepen.is_some();
+    let request = PackRequest {
+        filter: filter.clone(),
+        deepen,
+        ..PackRequest::default()
+    };
+    let advertisement = match &bundle {
+        Some(bundle) => {
+            bundle.object_format.make_current();
+            bundle.unbundle()?;
+            bundle.advertisement()
+        }
+        None => CloneClient::new(url.clone()).clone(request).await?,
+    };
+    write_object_format(PathBuf::from(".git/config"), HashAlgorithm::current())?;
+
+    let head_sha = match advertisement.refs.iter().find(|r| r.name == "HEAD") {
+        Some(head) => Some(ObjectId::from_hex(&head.hash)?),
+        None => None,
+    };
+    // older servers do not advertise symref; guess the branch HEAD points at
+    let default_branch = advertisement.head_symref().or_else(|| {
+        advertisement
+            .refs
+            .iter()
+            .find(|r| r.name.starts_with("refs/heads/") && ObjectId::from_hex(&r.hash).ok() == head_sha)
+            .map(|r| r.name.clone())
+    });
+
+    let mut config = Config::load()?;
+    config.set("remote.origin.url", &url)?;
+    match &default_branch {
+        // a history cut short only follows the branch it was cloned for
+        Some(refname) if single_branch => {
+            let branch = refs::shorten(refname);
+            config.set("remote.origin.fetch", &format!("+{}:refs/remotes/origin/{}", refname, branch))?;
+        }
+        _ => config.set("remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*")?,
+    }
+    // objects the filter left out are fetched from origin as needed
+    if let Some(filter) = &filter {
+        config.set("core.repositoryformatversion", "1")?;
+        config.set("remote.origin.promisor", "true")?;
+        config.set("remote.origin.partialclonefilter", filter)?;
+        config.set("extensions.partialclone", "origin")?;
+    }
+    config.save()?;
+
+    for remote_ref in &advertisement.refs {
+        let sha1_hash = ObjectId::from_hex(&remote_ref.hash)?;
+
+        if let Some(branch) = remote_ref.name.strip_prefix("refs/heads/") {
+            if single_branch && default_branch.as_ref() != Some(&remote_ref.name) {
+                continue;
+            }
+            refs::update_ref(&format!("refs/remotes/origin/{}", branch), sha1_hash)?;
+        } else if remote_ref.name.starts_with("refs/tags/") && objects::has_object(sha1_hash)? {
+            // only tags the server included alongside the branches
+            refs::update_ref(&remote_ref.name, sha1_hash)?;
+        }
+    }
+
+    let head_sha = match head_sha {
+        Some(sha) => sha,
+        None if advertisement.refs.is_empty() => {
+            println!("warning: You appear to have cloned an empty repository.");
+            return Ok(());
+        }
+        None => {
+            eprintln!("warning: remote HEAD refers to nonexistent ref, unable to checkout");
+            return Ok(());
+        }
+    };
+
+    match default_branch {
+        Some(refname) => {
+            let branch = refs::shorten(&refname).to_string();
+
+            refs::update_ref(&refname, head_sha)?;
+            refs::set_head_branch(&refname)?;
+            refs::write_symref(
+                "refs/remotes/origin/HEAD",
+                &format!("refs/remotes/origin/{}", branch),
+            )?;
+
+            let mut config = Config::load()?;
+            config.set(&format!("branch.{}.remote", branch), "origin")?;
+            config.set(&format!("branch.{}.merge", branch), &refname)?;
+            config.save()?;
+        }
+        None => refs::set_head_detached(head_sha)?,
+    }
+
+    let commit = Commit::from_object_sha(hex::encode(head_sha)).await?;
+
+    let mut checkout = Checkout::new()?;
+    checkout.checkout_tree(commit.tree_sha(), false).await?;
+    checkout.save()?;
+
+    if recurse_submodules {
+        update_submodules(&[], true, true, "").await?;
+    }
+
+    Ok(())
+}
+
+// git's upload-pack takes this depth as "the whole history"
+const INFINITE_DEPTH: u32 = 0x7fffffff;
+
+/// Turns the shallow options of `clone` and `fetch` into how far back to
+/// cut history off, refusing the combinations git refuses.
+pub fn deepen_options(
+    depth: Option<i32>,
+    deepen: Option<i32>,
+    unshallow: bool,
+    shallow_since: Option<String>,
+    shallow_exclude: Vec<String>,
+) -> Result<Deepen> {
+    if deepen.is_some() && depth.is_some() {
+        bail!("options '--deepen' and '--depth' cannot be used together");
+    }
+    if unshallow && (depth.is_some() || deepen.is_some()) {
+        bail!("options '--depth' and '--unshallow' cannot be used together");
+    }
+    if (depth.is_some() || deepen.is_some()) && (shallow_since.is_some() || !shallow_exclude.is_empty()) {
+        bail!("deepen and deepen-since (or deepen-not) cannot be used together");
+    }
+
+    let mut options = Deepen {
+        not: shallow_exclude,
+        ..Deepen::default()
+    };
+    if let Some(depth) = depth.or(deepen) {
+        if depth <= 0 {
+            bail!("depth {} is not a positive number", depth);
+        }
+        options.depth = Some(depth as u32);
+        options.relative = deepen.is_some();
+    }
+    if unshallow {
+        if !shallow::is_shallow() {
+            bail!("--unshallow on a complete repository does not make sense");
+        }
+        options.depth = Some(INFINITE_DEPTH);
+    }
+    if let Some(since) = shallow_since {
+        options.since = Some(utils::parse_date(&since, chrono::Utc::now().timestamp())?);
+    }
+
+    Ok(options)
+}
+
+// how many of our most recent commits to offer the remote as common ground
+const MAX_HAVES: usize = 256;
+
+// the most recent commits of the local branches and remote-tracking refs
+async fn recent_commits() -> Result<Vec<String>> {
+    let mut walk = revision::RevWalk::new();
+    for (_, sha1_hash) in refs::list_refs("refs/heads/")?.into_iter().chain(refs::list_refs("refs/remotes/")?) {
+        walk.push(sha1_hash).await?;
+    }
+
+    let mut haves = Vec::new();
+    while haves.len() < MAX_HAVES {
+        match walk.next().await? {
+            Some(commit) => haves.push(commit.encoded_hash()),
+            None => break,
+        }
+    }
+
+    Ok(haves)
+}
+
+/// Downloads what `remote` has that the repository lacks, updating the refs
+/// its fetch refspecs (or the given `refspecs`) map the remote's branches
+/// to, plus tags pointing into what was fetched. `remote` may also be a URL
+/// or a bundle file; without refspecs its HEAD is fetched into FETCH_HEAD.
+/// `deepen` moves the boundary of a shallow history.
+pub async fn fetch(remote: Option<String>, refspecs: Vec<String>, deepen: Deepen) -> Result<()> {
+    let remote = remote.unwrap_or_else(|| String::from("origin"));
+    let config = Config::load()?;
+    let (url, configured) = match config.get(&format!("remote.{}.url", remote)) {
+        Some(url) => (url, config.get_all(&format!("remote.{}.fetch", remote))),
+        None if remote.contains("://") || bundle::is_bundle(Path::new(&remote)) => (remote.clone(), Vec::new()),
+        None => bail!("'{}' does not appear to be a git repository", remote),
+    };
+    let refspecs = if refspecs.is_empty() { configured } else { refspecs };
+
+    let bundle = if bundle::is_bundle(Path::new(&url)) {
+        Some(Bundle::open(Path::new(&url))?)
+    } else {
+        None
+    };
+    let client = CloneClient::new(url.clone());
+    let advertisement = match &bundle {
+        Some(bundle) => bundle.advertisement(),
+        None => client.discover_refs().await?,
+    };
+    deepen.check(&advertisement)?;
+
+    let mut updates = Vec::new();
+    for remote_ref in &advertisement.refs {
+        if let Some(local) = clone::map_ref(&refspecs, &remote_ref.name) {
+            updates.push((remote_ref.name.clone(), local, ObjectId::from_hex(&remote_ref.hash)?));
+        }
+    }
+    // with nothing to map, what the remote's HEAD points at is only noted
+    let fetch_head = if refspecs.is_empty() {
+        match advertisement.refs.iter().find(|r| r.name == "HEAD") {
+            Some(head) => Some(ObjectId::from_hex(&head.hash)?),
+            None => bail!("couldn't find remote ref HEAD"),
+        }
+    } else {
+        None
+    };
+
+    let mut request = PackRequest {
+        include_tag: advertisement.supports("include-tag"),
+        deepen,
+        ..PackRequest::default()
+    };
+    for sha1_hash in updates.iter().map(|(_, _, sha1_hash)| sha1_hash).chain(&fetch_head) {
+        // deepening asks again for what is here, to reach further back
+        let want = hex::encode(sha1_hash);
+        if (request.deepen.is_some() || !objects::has_object(*sha1_hash)?) && !request.wants.contains(&want) {
+            request.wants.push(want);
+        }
+    }
+
+    // a partial clone keeps filtering what it fetches from its promisor
+    let promisor = config.get("extensions.partialclone").as_deref() == Some(remote.as_str());
+    if promisor && advertisement.supports("filter") {
+        request.filter = config.get(&format!("remote.{}.partialclonefilter", remote));
+    }
+
+    if !request.wants.is_empty() {
+        match &bundle {
+            Some(bundle) => bundle.unbundle()?,
+            None => {
+                request.haves = recent_commits().await?;
+                let response = client.request_pack(&request, HashAlgorithm::current()).await?;
+                clone::store_pack(&response.pack, promisor).await?;
+                shallow::update(&response.shallow, &response.unshallow)?;
+            }
+        }
+    }
+
+    // tags come along when what they point at did, unless nothing is kept
+    // under a ref but FETCH_HEAD
+    let follow_tags = fetch_head.is_none();
+    for remote_ref in advertisement.refs.iter().filter(|r| follow_tags && r.name.starts_with("refs/tags/")) {
+        let sha1_hash = ObjectId::from_hex(&remote_ref.hash)?;
+        if !updates.iter().any(|(_, local, _)| *local == remote_ref.name)
+            && !refs::exists(&remote_ref.name)?
+            && objects::has_object(sha1_hash)?
+        {
+            updates.push((remote_ref.name.clone(), remote_ref.name.clone(), sha1_hash));
+        }
+    }
+
+    let mut reported = false;
+    if let Some(sha1_hash) = fetch_head {
+        fs::write(".git/FETCH_HEAD", format!("{}\t\t{}\n", hex::encode(sha1_hash), url)).await?;
+        eprintln!("From {}", url);
+        eprintln!(" * {:<17} {:<10} -> FETCH_HEAD", "branch", "HEAD");
+        reported = true;
+    }
+
+    let mut graph = CommitGraph::new();
+    for (remote_ref, local, sha1_hash) in updates {
+        let old = refs::resolve(&local)?;
+        if old == Some(sha1_hash) {
+            continue;
+        }
+        refs::update_ref(&local, sha1_hash)?;
+
+        let kind = if remote_ref.starts_with("refs/tags/") { "tag" } else { "branch" };
+        let line = match old {
+            None => format!(" * {:<17} {:<10} -> {}", format!("[new {}]", kind), refs::shorten(&remote_ref), refs::shorten(&local)),
+            Some(old) if graph.is_ancestor(old, sha1_hash).await.unwrap_or(false) => format!(
+                "   {:<17} {:<10} -> {}",
+                format!("{}..{}", short_sha(&old), short_sha(&sha1_hash)),
+                refs::shorten(&remote_ref),
+                refs::shorten(&local)
+            ),
+            Some(old) => format!(
+                " + {:<17} {:<10} -> {}  (forced update)",
+                format!("{}...{}", short_sha(&old), short_sha(&sha1_hash)),
+                refs::shorten(&remote_ref),
+                refs::shorten(&local)
+            ),
+        };
+
+        if !reported {
+            eprintln!("From {}", url);
+            reported = true;
+        }
+        eprintln!("{}", line);
+    }
+
+    Ok(())
+}
+
+fn short_sha(sha1_hash: &ObjectId) -> String {
+    hex::encode(sha1_hash)[..7].to_string()
+}
+
+// "origin/main" -> Some("refs/remotes/origin/main") when exactly one remote has `name`
+fn guess_remote_branch(name: &str) -> Result<Option<String>> {
+    let candidates: Vec<String> = refs::list_refs("refs/remotes/")?
+        .into_iter()
+        .map(|(refname, _)| refname)
+        .filter(|refname| {
+            let short = refs::shorten(refname);
+            match short.find('/') {
+                Some(pos) => &short[pos + 1..] == name,
+                None => false,
+            }
+        })
+        .collect();
+
+    if candidates.len() == 1 {
+        Ok(candidates.into_iter().next())
+    } else {
+        Ok(None)
+    }
+}
+
+fn set_upstream(branch: &str, upstream: &str) -> Result<()> {
+    if !refs::exists(&format!("refs/heads/{}", branch))? {
+        bail!("branch '{}' does not exist", branch);
+    }
+
+    let (remote, merge) = if refs::exists(&format!("refs/remotes/{}", upstream))? {
+        match upstream.find('/') {
+            Some(pos) => (
+                upstream[..pos].to_string(),
+                format!("refs/heads/{}", &upstream[pos + 1..]),
+            ),
+            None => bail!("the requested upstream branch '{}' does not exist", upstream),
+        }
+    } else if refs::exists(&format!("refs/heads/{}", upstream))? {
+        (String::from("."), format!("refs/heads/{}", upstream))
+    } else {
+        bail!("the requested upstream branch '{}' does not exist", upstream);
+    };
+
+    let mut config = Config::load()?;
+    config.set(&format!("branch.{}.remote", branch), &remote)?;
+    config.set(&format!("branch.{}.merge", branch), &merge)?;
+    config.save()?;
+
+    println!("branch '{}' set up to track '{}'.", branch, upstream);
+
+    Ok(())
+}
+
+// the remote-tracking ref a branch's upstream config points at, if any
+fn upstream_ref(branch: &str) -> Result<Option<String>> {
+    let config = Config::load()?;
+
+    let remote = config.get(&format!("branch.{}.remote", branch));
+    let merge = config.get(&format!("branch.{}.merge", branch));
+
+    match (remote, merge) {
+        (Some(remote), Some(merge)) => {
+            if remote == "." {
+                return Ok(Some(merge));
+            }
+            let short = merge.trim_start_matches("refs/heads/");
+            Ok(Some(format!("refs/remotes/{}/{}", remote, short)))
+        }
+        _ => Ok(None),
+    }
+}
+
+async fn create_branch(name: &str, start: Option<&String>) -> Result<ObjectId> {
+    refs::check_branch_name(name)?;
+
+    let refname = format!("refs/heads/{}", name);
+    if refs::exists(&refname)? {
+        bail!("a branch named '{}' already exists", name);
+    }
+
+    let start = start.map(String::as_str).unwrap_or("HEAD");
+    let commit = revision::resolve_commit(start).await?;
+    refs::update_ref(&refname, commit.sha1_hash())?;
+
+    if let Some(start_ref) = refs::dwim(start)? {
+        if start_ref.starts_with("refs/remotes/") {
+            set_upstream(name, refs::shorten(&start_ref))?;
+        }
+    }
+
+    Ok(commit.sha1_hash())
+}
+
+async fn list_branches(verbose: bool) -> Result<()> {
+    let branches = refs::list_refs("refs/heads/")?;
+    let head = refs::read_head()?;
+
+    if let refs::Head::Detached(sha) = &head {
+        println!("* (HEAD detached at {})", short_sha(sha));
+    }
+
+    let width = branches
+        .iter()
+        .map(|(name, _)| refs::shorten(name).len())
+        .max()
+        .unwrap_or(0);
+
+    for (refname, sha) in &branches {
+        let marker = match &head {
+            refs::Head::Branch(current) if current == refname => "*",
+            _ => " ",
+        };
+        let name = refs::shorten(refname);
+
+        if verbose {
+            let commit = Commit::from_object_sha(hex::encode(sha)).await?;
+            println!(
+                "{} {:width$} {} {}",
+                marker,
+                name,
+                short_sha(sha),
+                commit.summary(),
+                width = width
+            );
+        } else {
+            println!("{} {}", marker, name);
+        }
+    }
+
+    Ok(())
+}
+
+async fn delete_branch(name: &str, force: bool) -> Result<()> {
+    let refname = format!("refs/heads/{}", name);
+    let sha1_hash = match refs::resolve(&refname)? {
+        Some(sha) => sha,
+        None => bail!("branch '{}' not found.", name),
+    };
+
+    if refs::current_branch()?.as_deref() == Some(name) {
+        bail!("Cannot delete branch '{}' checked out", name);
+    }
+
+    if !force {
+        // a branch is merged if its upstream, or failing that HEAD, contains it
+        let mut target = None;
+        if let Some(upstream) = upstream_ref(name)? {
+            target = refs::resolve(&upstream)?;
+        }
+        if target.is_none() {
+            target = refs::head_sha()?;
+        }
+
+        let merged = match target {
+            Some(target) => CommitGraph::new().is_ancestor(sha1_hash, target).await?,
+            None => false,
+        };
+        if !merged {
+            bail!(
+                "The branch '{}' is not fully merged.\nIf you are sure you want to delete it, run 'tgit branch -D {}'.",
+                name,
+                name
+            );
+        }
+    }
+
+    refs::delete_ref(&refname)?;
+
+    let mut config = Config::load()?;
+    config.remove_section("branch", Some(name));
+    config.save()?;
+
+    println!("Deleted branch {} (was {}).", name, short_sha(&sha1_hash));
+
+    Ok(())
+}
+
+fn rename_branch(old: &str, new: &str) -> Result<()> {
+    refs::check_branch_name(new)?;
+
+    let old_ref = format!("refs/heads/{}", old);
+    let new_ref = format!("refs/heads/{}", new);
+
+    let is_current = refs::current_branch()?.as_deref() == Some(old);
+
+    // an unborn current branch has no ref to move, only HEAD
+    if !is_current || refs::exists(&old_ref)? {
+        refs::rename_ref(&old_ref, &new_ref)?;
+    }
+
+    let mut config = Config::load()?;
+    config.rename_section("branch", Some(old), Some(new));
+    config.save()?;
+
+    if is_current {
+        refs::set_head_branch(&new_ref)?;
+    }
+
+    Ok(())
+}
+
+pub async fn branch(
+    verbose: bool,
+    delete: bool,
+    force_delete: bool,
+    rename: bool,
+    set_upstream_to: Option<String>,
+    args: Vec<String>,
+) -> Result<()> {
+    if let Some(upstream) = set_upstream_to {
+        let branch = match args.first() {
+            Some(branch) => branch.clone(),
+            None => match refs::current_branch()? {
+                Some(branch) => branch,
+                None => bail!("could not set upstream of HEAD when it does not point to any branch."),
+            },
+        };
+
+        return set_upstream(&branch, &upstream);
+    }
+
+    if delete || force_delete {
+        if args.is_empty() {
+            bail!("branch name required");
+        }
+        for name in &args {
+            delete_branch(name, force_delete).await?;
+        }
+
+        return Ok(());
+    }
+
+    if rename {
+        return match args.as_slice() {
+            [new] => match refs::current_branch()? {
+                Some(current) => rename_branch(&current, new),
+                None => bail!("cannot rename the current branch while not on any."),
+            },
+            [old, new] => rename_branch(old, new),
+            _ => bail!("too many arguments for a rename operation"),
+        };
+    }
+
+    match args.as_slice() {
+        [] => list_branches(verbose).await,
+        [name] => create_branch(name, None).await.map(|_| ()),
+        [name, start] => create_branch(name, Some(start)).await.map(|_| ()),
+        _ => bail!("too many arguments"),
+    }
+}
+
+/// How `tag --list` orders tags.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TagSort {
+    Refname,
+    Version,
+}
+
+impl TagSort {
+    /// Parses a `--sort` key such as `version:refname`; a leading `-`
+    /// reverses the order.
+    pub fn from_key(key: &str) -> Result<(Self, bool)> {
+        let (reverse, key) = match key.strip_prefix('-') {
+            Some(key) => (true, key),
+            None => (false, key),
+        };
+
+        match key {
+            "refname" => Ok((TagSort::Refname, reverse)),
+            "version:refname" | "v:refname" => Ok((TagSort::Version, reverse)),
+            _ => bail!("unsupported sort specification '{}'", key),
+        }
+    }
+}
+
+fn list_tags(patterns: &[String], sort: TagSort, reverse: bool) -> Result<()> {
+    let mut names: Vec<String> = refs::list_refs("refs/tags/")?
+        .iter()
+        .map(|(refname, _)| refs::shorten(refname).to_string())
+        .filter(|name| patterns.is_empty() || patterns.iter().any(|p| utils::glob_match(p, name)))
+        .collect();
+
+    if sort == TagSort::Version {
+        names.sort_by(|a, b| utils::version_cmp(a, b).then_with(|| a.cmp(b)));
+    }
+    if reverse {
+        names.reverse();
+    }
+
+    for name in names {
+        println!("{}", name);
+    }
+
+    Ok(())
+}
+
+fn delete_tag(name: &str) -> Result<()> {
+    let refname = format!("refs/tags/{}", name);
+    let sha1_hash = match refs::resolve(&refname)? {
+        Some(sha) => sha,
+        None => bail!("tag '{}' not found.", name),
+    };
+
+    refs::delete_ref(&refname)?;
+    println!("Deleted tag '{}' (was {})", name, short_sha(&sha1_hash));
+
+    Ok(())
+}
+
+// asks for an annotated tag's message in the editor
+fn edit_tag_message(name: &str) -> Result<String> {
+    let path = PathBuf::from(".git/TAG_EDITMSG");
+    std::fs::write(
+        &path,
+        format!(
+            "\n#\n# Write a message for tag:\n#   {}\n# Lines starting with '#' will be ignored.\n",
+            name
+        ),
+    )?;
+    sequencer::launch_editor(&path, false)?;
+
+    let message = sequencer::cleanup_message(&std::fs::read_to_string(&path)?);
+    if message.is_empty() {
+        bail!("no tag message?");
+    }
+
+    Ok(message)
+}
+
+async fn create_tag(name: &str, target: Option<&String>, annotate: bool, message: Option<String>, force: bool) -> Result<()> {
+    if !refs::is_valid_name(name) {
+        bail!("'{}' is not a valid tag name.", name);
+    }
+
+    let refname = format!("refs/tags/{}", name);
+    let previous = refs::resolve(&refname)?;
+    if previous.is_some() && !force {
+        bail!("tag '{}' already exists", name);
+    }
+
+    let target = target.map(String::as_str).unwrap_or("HEAD");
+    let mut sha1_hash = match revision::resolve(target).await {
+        Ok(sha) => sha,
+        Err(_) => bail!("Failed to resolve '{}' as a valid ref.", target),
+    };
+
+    if annotate || message.is_some() {
+        let message = match message {
+            Some(message) => sequencer::cleanup_message(&message),
+            None => edit_tag_message(name)?,
+        };
+        let (tagger_name, tagger_email) = config::identity("COMMITTER")?;
+
+        let tag = Tag::create(
+            sha1_hash,
+            revision::object_type(sha1_hash).await?,
+            name.to_string(),
+            Some(User::now(tagger_name, tagger_email)),
+            message,
+            None,
+        )?;
+        tag.write().await?;
+        sha1_hash = tag.sha1_hash();
+    }
+
+    refs::update_ref(&refname, sha1_hash)?;
+    if let Some(previous) = previous.filter(|previous| *previous != sha1_hash) {
+        println!("Updated tag '{}' (was {})", name, short_sha(&previous));
+    }
+
+    Ok(())
+}
+
+// checks a signed tag's signature with gpg, then shows the tag
+async fn verify_tag(name: &str) -> Result<()> {
+    let sha1_hash = match refs::resolve(&format!("refs/tags/{}", name))? {
+        Some(sha) => sha,
+        None => bail!("tag '{}' not found.", name),
+    };
+    let kind = revision::object_type(sha1_hash).await?;
+    if kind != "tag" {
+        bail!("{}: cannot verify a non-tag object of type {}.", name, kind);
+    }
+
+    let tag = Tag::from_object_sha(hex::encode(sha1_hash)).await?;
+    let signature = match tag.signature() {
+        Some(signature) => signature,
+        None => {
+            std::io::stdout().write_all(tag.payload())?;
+            bail!("no signature found");
+        }
+    };
+
+    let signature_path = PathBuf::from(".git/.tmp-tag-signature");
+    std::fs::write(&signature_path, signature)?;
+
+    let program = config::lookup("gpg.program")?.unwrap_or_else(|| String::from("gpg"));
+    let verified = std::process::Command::new(program)
+        .arg("--verify")
+        .arg(&signature_path)
+        .arg("-")
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .and_then(|mut gpg| {
+            gpg.stdin.take().unwrap().write_all(tag.payload())?;
+            gpg.wait()
+        });
+    let _ = std::fs::remove_file(&signature_path);
+
+    match verified {
+        Ok(status) if status.success() => {
+            std::io::stdout().write_all(tag.payload())?;
+            Ok(())
+        }
+        Ok(_) => bail!("could not verify the tag '{}'", name),
+        Err(err) => bail!("cannot run gpg: {}", err),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn tag(
+    annotate: bool,
+    message: Option<String>,
+    force: bool,
+    delete: bool,
+    list: bool,
+    verify: bool,
+    sort: Option<String>,
+    args: Vec<String>,
+) -> Result<()> {
+    if delete {
+        for name in &args {
+            delete_tag(name)?;
+        }
+        return Ok(());
+    }
+
+    if verify {
+        for name in &args {
+            verify_tag(name).await?;
+        }
+        return Ok(());
+    }
+
+    if list || args.is_empty() {
+        let (sort, reverse) = match sort {
+            Some(key) => TagSort::from_key(&key)?,
+            None => (TagSort::Refname, false),
+        };
+        return list_tags(&args, sort, reverse);
+    }
+
+    match args.as_slice() {
+        [name] => create_tag(name, None, annotate, message, force).await,
+        [name, target] => create_tag(name, Some(target), annotate, message, force).await,
+        _ => bail!("too many arguments"),
+    }
+}
+
+// checks out `target`'s tree over the current HEAD's, leaving HEAD itself alone
+async fn checkout_commit(target: ObjectId, force: bool) -> Result<Commit> {
+    let head_tree = match refs::head_sha()? {
+        Some(sha) => Some(Commit::from_object_sha(hex::encode(sha)).await?.tree_sha()),
+        None => None,
+    };
+
+    let commit = Commit::from_object_sha(hex::encode(target)).await?;
+
+    let mut checkout = Checkout::new()?;
+    checkout.switch_trees(head_tree, commit.tree_sha(), force).await?;
+    checkout.save()?;
+
+    Ok(commit)
+}
+
+async fn switch_to_new_branch(name: &str, start: Option<&String>, force: bool) -> Result<()> {
+    refs::check_branch_name(name)?;
+    if refs::exists(&format!("refs/heads/{}", name))? {
+        bail!("a branch named '{}' already exists", name);
+    }
+
+    let start_sha = revision::resolve_commit(start.map(String::as_str).unwrap_or("HEAD"))
+        .await?
+        .sha1_hash();
+    checkout_commit(start_sha, force).await?;
+
+    create_branch(name, start).await?;
+    refs::set_head_branch(&format!("refs/heads/{}", name))?;
+
+    println!("Switched to a new branch '{}'", name);
+
+    Ok(())
+}
+
+async fn switch_to_branch(name: &str, force: bool) -> Result<()> {
+    if refs::current_branch()?.as_deref() == Some(name) {
+        println!("Already on '{}'", name);
+        return Ok(());
+    }
+
+    let refname = format!("refs/heads/{}", name);
+    let sha1_hash = match refs::resolve(&refname)? {
+        Some(sha) => sha,
+        None => bail!("invalid reference: {}", name),
+    };
+
+    checkout_commit(sha1_hash, force).await?;
+    refs::set_head_branch(&refname)?;
+
+    println!("Switched to branch '{}'", name);
+
+    Ok(())
+}
+
+async fn detach_head(target: &str, force: bool) -> Result<()> {
+    let sha1_hash = revision::resolve_commit(target).await?.sha1_hash();
 
-    client.clone().await?;
+    let commit = checkout_commit(sha1_hash, force).await?;
+    refs::set_head_detached(sha1_hash)?;
+
+    println!("HEAD is now at {} {}", short_sha(&sha1_hash), commit.summary());
 
     Ok(())
 }
+
+pub async fn switch(
+    create: Option<String>,
+    detach: bool,
+    force: bool,
+    target: Option<String>,
+) -> Result<()> {
+    if let Some(name) = create {
+        return switch_to_new_branch(&name, target.as_ref(), force).await;
+    }
+
+    let target = match target {
+        Some(target) => target,
+        None => bail!("missing branch or commit argument"),
+    };
+
+    if detach {
+        return detach_head(&target, force).await;
+    }
+
+    if refs::exists(&format!("refs/heads/{}", target))? {
+        return switch_to_branch(&target, force).await;
+    }
+
+    if let Some(remote_ref) = guess_remote_branch(&target)? {
+        let start = refs::shorten(&remote_ref).to_string();
+        return switch_to_new_branch(&target, Some(&start), force).await;
+    }
+
+    if revision::resolve(&target).await.is_ok() {
+        bail!(
+            "a branch is expected, got '{}'\nIf you want to detach HEAD at the commit, try again with the --detach option.",
+            target
+        );
+    }
+
+    bail!("invalid reference: {}", target)
+}
+
+pub async fn checkout(new_branch: Option<String>, force: bool, target: Option<String>) -> Result<()> {
+    if let Some(name) = new_branch {
+        return switch_to_new_branch(&name, target.as_ref(), force).await;
+    }
+
+    let target = match target {
+        Some(target) => target,
+        None => bail!("missing branch or commit argument"),
+    };
+
+    if refs::exists(&format!("refs/heads/{}", target))? {
+        return switch_to_branch(&target, force).await;
+    }
+
+    if let Some(remote_ref) = guess_remote_branch(&target)? {
+        let start = refs::shorten(&remote_ref).to_string();
+        return switch_to_new_branch(&target, Some(&start), force).await;
+    }
+
+    detach_head(&target, force).await
+}
+
+pub fn tree_diff_options(
+    recursive: bool,
+    find_renames: Option<Option<String>>,
+    find_copies: Option<Option<String>>,
+    find_copies_harder: bool,
+) -> Result<TreeDiffOptions> {
+    let parse = |arg: Option<Option<String>>| -> Result<Option<u32>> {
+        match arg {
+            Some(value) => {
+                let value = value.unwrap_or_default();
+                match diff::parse_score(&value) {
+                    Some(score) => Ok(Some(score)),
+                    None => bail!("invalid similarity score: {}", value),
+                }
+            }
+            None => Ok(None),
+        }
+    };
+
+    let mut copies = parse(find_copies)?;
+    if find_copies_harder && copies.is_none() {
+        copies = diff::parse_score("");
+    }
+
+    Ok(TreeDiffOptions {
+        recursive,
+        renames: parse(find_renames)?,
+        copies,
+        find_copies_harder,
+    })
+}
+
+// one line of `diff-tree` output, without the newline
+fn format_change(change: &Change, name_only: bool, name_status: bool) -> String {
+    let paths = match (&change.old, &change.new) {
+        (Some(old), Some(new)) if old.path != new.path => {
+            format!("{}\t{}", diff::quote_path_bytes(&old.path), diff::quote_path_bytes(&new.path))
+        }
+        _ => diff::quote_path_bytes(change.path()),
+    };
+
+    if name_only {
+        diff::quote_path_bytes(change.path())
+    } else if name_status {
+        format!("{}\t{}", change.status_string(), paths)
+    } else {
+        let null = HashAlgorithm::current().null();
+        let (old_mode, old_sha) = match &change.old {
+            Some(old) => (old.mode.as_str(), old.sha1_hash),
+            None => ("0", null),
+        };
+        let (new_mode, new_sha) = match &change.new {
+            Some(new) => (new.mode.as_str(), new.sha1_hash),
+            None => ("0", null),
+        };
+
+        format!(
+            ":{:0>6} {:0>6} {} {} {}\t{}",
+            old_mode,
+            new_mode,
+            old_sha,
+            new_sha,
+            change.status_string(),
+            paths
+        )
+    }
+}
+
+pub async fn diff_tree(
+    options: TreeDiffOptions,
+    name_only: bool,
+    name_status: bool,
+    root: bool,
+    trees: Vec<String>,
+) -> Result<()> {
+    let (old_tree, new_tree) = match trees.as_slice() {
+        [commit] => {
+            let commit = revision::resolve_commit(commit).await?;
+
+            let parent_tree = match commit.parents().first() {
+                Some(parent) => Some(
+                    Commit::from_object_sha(hex::encode(parent))
+                        .await?
+                        .tree_sha(),
+                ),
+                None if root => None,
+                None => return Ok(()),
+            };
+
+            println!("{}", commit.encoded_sha());
+            (parent_tree, commit.tree_sha())
+        }
+        [old, new] => (
+            Some(revision::resolve_tree(old).await?),
+            revision::resolve_tree(new).await?,
+        ),
+        _ => bail!("usage: tgit diff-tree [<options>] <tree-ish> [<tree-ish>]"),
+    };
+
+    let changes = diff::diff_trees(old_tree, Some(new_tree), &options).await?;
+    for change in &changes {
+        println!("{}", format_change(change, name_only, name_status));
+    }
+
+    Ok(())
+}
+
+async fn commit_stat(commit: &Commit) -> Result<String> {
+    let parent_tree = match commit.parents().first() {
+        Some(parent) => Some(
+            Commit::from_object_sha(hex::encode(parent))
+                .await?
+                .tree_sha(),
+        ),
+        None => None,
+    };
+
+    let options = TreeDiffOptions {
+        recursive: true,
+        renames: diff::parse_score(""),
+        ..TreeDiffOptions::default()
+    };
+    let changes = diff::diff_trees(parent_tree, Some(commit.tree_sha()), &options).await?;
+
+    let mut cache = diff::BlobCache::default();
+    let files = diff::file_stats(&changes, &mut cache, &diff::DiffOptions::default()).await?;
+
+    Ok(diff::format_stat(&files, 80))
+}
+
+pub async fn log(
+    stat: bool,
+    oneline: bool,
+    max_count: Option<usize>,
+    revision: Option<String>,
+) -> Result<()> {
+    let start = revision::resolve(revision.as_deref().unwrap_or("HEAD")).await?;
+
+    let mut walk = revision::RevWalk::new();
+    walk.push(start).await?;
+
+    let mut shown = 0;
+    while let Some(commit) = walk.next().await? {
+        if max_count.map(|max| shown >= max).unwrap_or(false) {
+            break;
+        }
+
+        // merges have no single diff to summarize
+        let stat_output = if stat && commit.parents().len() < 2 {
+            commit_stat(&commit).await?
+        } else {
+            String::new()
+        };
+
+        if oneline {
+            println!("{} {}", short_sha(&commit.sha1_hash()), commit.summary());
+            print!("{}", stat_output);
+        } else {
+            if shown > 0 {
+                println!();
+            }
+
+            println!("commit {}", commit.encoded_sha());
+            if commit.parents().len() > 1 {
+                let parents: Vec<String> = commit.parents().iter().map(short_sha).collect();
+                println!("Merge: {}", parents.join(" "));
+            }
+
+            let author = commit.author();
+            println!("Author: {} <{}>", author.name, author.email);
+            println!(
+                "Date:   {}",
+                utils::format_date(&author.timestamp, &author.tz_offset)
+            );
+            println!();
+
+            for line in commit.message().trim_end().lines() {
+                println!("    {}", line);
+            }
+
+            if !stat_output.is_empty() {
+                println!();
+                print!("{}", stat_output);
+            }
+        }
+
+        shown += 1;
+    }
+
+    Ok(())
+}
+
+pub fn diff_algorithm(patience: bool, histogram: bool, name: Option<String>) -> Result<diff::Algorithm> {
+    if let Some(name) = name {
+        return match diff::Algorithm::from_name(&name) {
+            Some(algorithm) => Ok(algorithm),
+            None => bail!("unknown diff algorithm: {}", name),
+        };
+    }
+
+    Ok(if patience {
+        diff::Algorithm::Patience
+    } else if histogram {
+        diff::Algorithm::Histogram
+    } else {
+        diff::Algorithm::Myers
+    })
+}
+
+async fn head_listing() -> Result<diff::Listing> {
+    let tree = match refs::resolve("HEAD")? {
+        Some(head) => Some(Commit::from_object_sha(hex::encode(head)).await?.tree_sha()),
+        None => None,
+    };
+
+    diff::tree_listing(tree).await
+}
+
+async fn revision_listing(spec: &str) -> Result<diff::Listing> {
+    if spec.is_empty() {
+        return head_listing().await;
+    }
+
+    diff::tree_listing(Some(revision::resolve_tree(spec).await?)).await
+}
+
+pub async fn diff(
+    options: diff::DiffOptions,
+    renames: bool,
+    cached: bool,
+    stat: bool,
+    numstat: bool,
+    revisions: Vec<String>,
+    paths: Vec<String>,
+) -> Result<()> {
+    let mut cache = diff::BlobCache::default();
+
+    let (mut old, mut new) = match revisions.as_slice() {
+        [] if cached => (head_listing().await?, diff::index_listing(&Index::load()?)),
+        [] => {
+            let index = Index::load()?;
+            let worktree = diff::worktree_listing(&index, &mut cache).await?;
+            (diff::index_listing(&index), worktree)
+        }
+        [range] if range.contains("..") => {
+            let (old, new) = range.split_once("..").unwrap();
+            (revision_listing(old).await?, revision_listing(new).await?)
+        }
+        [commit] if cached => (revision_listing(commit).await?, diff::index_listing(&Index::load()?)),
+        [commit] => {
+            let index = Index::load()?;
+            (
+                revision_listing(commit).await?,
+                diff::worktree_listing(&index, &mut cache).await?,
+            )
+        }
+        [old, new] => (revision_listing(old).await?, revision_listing(new).await?),
+        _ => bail!("usage: tgit diff [<options>] [<commit> [<commit>]] [--] [<path>...]"),
+    };
+
+    old.retain(|path, _| diff::matches_pathspec(path, &paths));
+    new.retain(|path, _| diff::matches_pathspec(path, &paths));
+
+    let tree_options = TreeDiffOptions {
+        recursive: true,
+        renames: if renames { diff::parse_score("") } else { None },
+        ..TreeDiffOptions::default()
+    };
+    let changes = diff::diff_listings(&old, &new, &tree_options, &mut cache).await?;
+
+    if stat || numstat {
+        let files = diff::file_stats(&changes, &mut cache, &options).await?;
+        if numstat {
+            print!("{}", diff::format_numstat(&files));
+        } else {
+            print!("{}", diff::format_stat(&files, 80));
+        }
+        return Ok(());
+    }
+
+    let mut out = Vec::new();
+    for change in &changes {
+        let old = cache.contents(change.old.as_ref()).await?;
+        let new = cache.contents(change.new.as_ref()).await?;
+        diff::write_patch(&mut out, change, &old, &new, &options);
+    }
+    std::io::stdout().write_all(&out)?;
+
+    Ok(())
+}
+
+/// Whether `merge` may, must or must not fast-forward.
+#[derive(Clone, Copy, PartialEq)]
+pub enum FastForward {
+    Allow,
+    Never,
+    Only,
+}
+
+const MERGE_STATE: [&str; 3] = [".git/MERGE_HEAD", ".git/MERGE_MSG", ".git/MERGE_MODE"];
+
+async fn commit_merge(tree_sha: ObjectId, parents: Vec<ObjectId>, message: String) -> Result<Commit> {
+    let (name, email) = config::identity("AUTHOR")?;
+    let author = User::now(name, email);
+    let (name, email) = config::identity("COMMITTER")?;
+    let committer = User::now(name, email);
+
+    let commit = Commit::create(tree_sha, parents, message, author, committer)?;
+    commit.write().await?;
+    commit.update_refs()?;
+
+    Ok(commit)
+}
+
+async fn merge_stat(old_tree: Option<ObjectId>, new_tree: ObjectId) -> Result<String> {
+    let options = TreeDiffOptions {
+        recursive: true,
+        renames: diff::parse_score(""),
+        ..TreeDiffOptions::default()
+    };
+    let changes = diff::diff_trees(old_tree, Some(new_tree), &options).await?;
+
+    let mut cache = diff::BlobCache::default();
+    let files = diff::file_stats(&changes, &mut cache, &diff::DiffOptions::default()).await?;
+
+    Ok(format!("{}{}", diff::format_stat(&files, 80), diff::format_summary(&changes)))
+}
+
+// "Merge branch 'topic' into next", the way git words it for each kind of ref
+fn merge_message(name: &str) -> Result<String> {
+    let kind = match refs::dwim(name)? {
+        Some(refname) if refname.starts_with("refs/heads/") => "branch",
+        Some(refname) if refname.starts_with("refs/remotes/") => "remote-tracking branch",
+        Some(refname) if refname.starts_with("refs/tags/") => "tag",
+        _ => "commit",
+    };
+
+    let mut message = format!("Merge {} '{}'", kind, name);
+    match refs::current_branch()? {
+        Some(branch) if branch == "master" || branch == "main" => {}
+        Some(branch) => message.push_str(&format!(" into {}", branch)),
+        None => message.push_str(" into HEAD"),
+    }
+
+    Ok(message)
+}
+
+async fn fast_forward(head: Option<ObjectId>, target: ObjectId) -> Result<()> {
+    let head_tree = match head {
+        Some(sha) => Some(Commit::from_object_sha(hex::encode(sha)).await?.tree_sha()),
+        None => None,
+    };
+    let commit = Commit::from_object_sha(hex::encode(target)).await?;
+
+    let mut checkout = Checkout::new()?;
+    checkout.merge_trees(head_tree, commit.tree_sha()).await?;
+    checkout.save()?;
+
+    if let Some(head) = head {
+        println!("Updating {}..{}", short_sha(&head), short_sha(&target));
+        refs::update_ref("ORIG_HEAD", head)?;
+    }
+    println!("Fast-forward");
+    print!("{}", merge_stat(head_tree, commit.tree_sha()).await?);
+
+    commit.update_refs()
+}
+
+pub async fn merge(
+    name: String,
+    message: Option<String>,
+    fast_forward_mode: FastForward,
+    allow_unrelated_histories: bool,
+) -> Result<()> {
+    if PathBuf::from(MERGE_STATE[0]).exists() {
+        bail!("You have not concluded your merge (MERGE_HEAD exists).\nPlease, commit your changes before you merge.");
+    }
+
+    let target = revision::resolve_commit(&name).await?.sha1_hash();
+    let head = match refs::head_sha()? {
+        Some(head) => head,
+        None => return fast_forward(None, target).await,
+    };
+
+    let bases = CommitGraph::new().merge_bases(head, target).await?;
+    if bases.contains(&target) {
+        println!("Already up to date.");
+        return Ok(());
+    }
+    if bases.contains(&head) && fast_forward_mode != FastForward::Never {
+        return fast_forward(Some(head), target).await;
+    }
+    if fast_forward_mode == FastForward::Only {
+        bail!("Not possible to fast-forward, aborting.");
+    }
+    if bases.is_empty() && !allow_unrelated_histories {
+        bail!("refusing to merge unrelated histories");
+    }
+
+    let style = merge::conflict_style()?;
+    let options = merge::MergeOptions {
+        ours: String::from("HEAD"),
+        theirs: name.clone(),
+        base: String::new(),
+        style,
+        depth: 0,
+    };
+
+    let mut cache = diff::BlobCache::default();
+    let result = merge::merge_commits(head, target, &options, &mut cache).await?;
+    let head_tree = Commit::from_object_sha(hex::encode(head)).await?.tree_sha();
+    let tree_sha = merge::checkout_result(head_tree, &result).await?;
+    refs::update_ref("ORIG_HEAD", head)?;
+
+    for (_, line) in &result.messages {
+        println!("{}", line);
+    }
+
+    let message = match message {
+        Some(message) => format!("{}\n", message),
+        None => format!("{}\n", merge_message(&name)?),
+    };
+
+    if !result.conflicts.is_empty() {
+        let mut merge_msg = format!("{}\n# Conflicts:\n", message);
+        for path in result.conflicts.keys() {
+            merge_msg.push_str(&format!("#\t{}\n", String::from_utf8_lossy(path)));
+        }
+
+        fs::write(MERGE_STATE[0], format!("{}\n", hex::encode(target))).await?;
+        fs::write(MERGE_STATE[1], merge_msg).await?;
+        fs::write(MERGE_STATE[2], "").await?;
+
+        bail!("Automatic merge failed; fix conflicts and then commit the result.");
+    }
+
+    commit_merge(tree_sha, vec![head, target], message).await?;
+
+    println!("Merge made by the 'ort' strategy.");
+    print!("{}", merge_stat(Some(head_tree), tree_sha).await?);
+
+    Ok(())
+}
+
+/// Concludes a merge that stopped on conflicts, once they are resolved.
+pub async fn merge_continue() -> Result<()> {
+    let merge_head = match fs::read_to_string(MERGE_STATE[0]).await {
+        Ok(contents) => ObjectId::from_hex(contents.trim())?,
+        Err(_) => bail!("There is no merge in progress (MERGE_HEAD missing)."),
+    };
+
+    let index = Index::load()?;
+    if !index.conflicts().is_empty() {
+        bail!("Committing is not possible because you have unmerged files.");
+    }
+
+    let head = match refs::head_sha()? {
+        Some(head) => head,
+        None => bail!("cannot continue a merge on an unborn branch"),
+    };
+
+    let tree_sha = merge::write_tree(&diff::index_listing(&index)).await?;
+
+    // comment lines in MERGE_MSG are for whoever edits it
+    let merge_msg = fs::read_to_string(MERGE_STATE[1]).await.unwrap_or_default();
+    let mut message: String = merge_msg
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    message = format!("{}\n", message.trim_end());
+
+    let commit = commit_merge(tree_sha, vec![head, merge_head], message).await?;
+    for path in MERGE_STATE.iter() {
+        let _ = fs::remove_file(path).await;
+    }
+
+    println!(
+        "[{} {}] {}",
+        refs::current_branch()?.unwrap_or_else(|| String::from("detached HEAD")),
+        short_sha(&commit.sha1_hash()),
+        commit.summary()
+    );
+
+    Ok(())
+}
+
+/// Throws away a conflicted merge, going back to HEAD.
+pub async fn merge_abort() -> Result<()> {
+    if !PathBuf::from(MERGE_STATE[0]).exists() {
+        bail!("There is no merge to abort (MERGE_HEAD missing).");
+    }
+
+    let head = revision::resolve_commit("HEAD").await?;
+    let mut checkout = Checkout::new()?;
+    checkout.checkout_tree(head.tree_sha(), true).await?;
+    checkout.save()?;
+
+    for path in MERGE_STATE.iter() {
+        let _ = fs::remove_file(path).await;
+    }
+
+    Ok(())
+}
+
+// every commit a ref has pointed at according to its reflog, or just its
+// current value when it has none
+async fn ref_history(refname: &str) -> Result<Vec<ObjectId>> {
+    let mut candidates = Vec::new();
+    for (i, entry) in refs::read_reflog(refname)?.into_iter().enumerate() {
+        if i == 0 {
+            candidates.push(entry.old);
+        }
+        candidates.push(entry.new);
+    }
+    if candidates.is_empty() {
+        candidates.extend(refs::resolve(refname)?);
+    }
+
+    let mut history = Vec::new();
+    for sha1_hash in candidates {
+        if sha1_hash.is_null() || history.contains(&sha1_hash) {
+            continue;
+        }
+        // entries may name objects that are gone, or are not commits
+        if matches!(revision::object_type(sha1_hash).await.as_deref(), Ok("commit")) {
+            history.push(sha1_hash);
+        }
+    }
+
+    Ok(history)
+}
+
+/// Prints merge bases, or answers an ancestry question. Returns whether
+/// there was an answer; `merge-base` exits with 1 when there is not.
+pub async fn merge_base(
+    all: bool,
+    octopus: bool,
+    is_ancestor: bool,
+    fork_point: bool,
+    commits: Vec<String>,
+) -> Result<bool> {
+    let usage = "usage: tgit merge-base [-a | --all] <commit> <commit>...\n   or: tgit merge-base [-a | --all] --octopus <commit>...\n   or: tgit merge-base --is-ancestor <commit> <commit>\n   or: tgit merge-base --fork-point <ref> [<commit>]";
+
+    let modes: Vec<&str> = [("--octopus", octopus), ("--is-ancestor", is_ancestor), ("--fork-point", fork_point)]
+        .iter()
+        .filter(|(_, set)| *set)
+        .map(|(name, _)| *name)
+        .collect();
+    match modes.as_slice() {
+        [first, second, ..] => bail!("options '{}' and '{}' cannot be used together", first, second),
+        [mode] if all && *mode != "--octopus" => bail!("options '{}' and '--all' cannot be used together", mode),
+        _ => {}
+    }
+
+    let mut graph = CommitGraph::new();
+
+    if fork_point {
+        let (name, commit) = match commits.as_slice() {
+            [name] => (name, "HEAD"),
+            [name, commit] => (name, commit.as_str()),
+            _ => bail!("{}", usage),
+        };
+        let refname = match refs::dwim(name)? {
+            Some(refname) => refname,
+            None => bail!("No such ref: '{}'", name),
+        };
+
+        let commit = revision::resolve_commit(commit).await?.sha1_hash();
+        let history = ref_history(&refname).await?;
+
+        return Ok(match graph.fork_point(commit, &history).await? {
+            Some(base) => {
+                println!("{}", hex::encode(base));
+                true
+            }
+            None => false,
+        });
+    }
+
+    let mut shas = Vec::new();
+    for commit in &commits {
+        shas.push(revision::resolve_commit(commit).await?.sha1_hash());
+    }
+
+    let bases = if is_ancestor {
+        if shas.len() != 2 {
+            bail!("{}", usage);
+        }
+        return graph.is_ancestor(shas[0], shas[1]).await;
+    } else if octopus {
+        graph.octopus_merge_bases(&shas).await?
+    } else {
+        if shas.len() < 2 {
+            bail!("{}", usage);
+        }
+        graph.merge_bases_many(shas[0], &shas[1..]).await?
+    };
+
+    for base in &bases {
+        println!("{}", hex::encode(base));
+        if !all {
+            break;
+        }
+    }
+
+    Ok(!bases.is_empty())
+}
+
+/// Writes a commit-graph of everything reachable from the repository's refs.
+pub async fn commit_graph_write(changed_paths: bool) -> Result<()> {
+    let mut tips = Vec::new();
+    for (_, sha1_hash) in refs::list_refs("refs/")? {
+        // refs to anything but a commit have no history to record
+        if revision::object_type(sha1_hash).await? == "commit" && !tips.contains(&sha1_hash) {
+            tips.push(sha1_hash);
+        }
+    }
+
+    commit_graph::write(&tips, changed_paths).await
+}
+
+pub async fn commit_graph_verify() -> Result<()> {
+    let file = match CommitGraphFile::open()? {
+        Some(file) => file,
+        None => return Ok(()),
+    };
+
+    let problems = file.verify().await?;
+    if !problems.is_empty() {
+        bail!("{}", problems.join("\n"));
+    }
+
+    Ok(())
+}
+
+/// Returns whether the repository is free of errors; dangling objects and
+/// warnings do not count.
+pub async fn fsck(connectivity_only: bool, unreachable: bool) -> Result<bool> {
+    let mut fsck = Fsck::new(connectivity_only);
+
+    fsck.scan_loose()?;
+    fsck.scan_packs()?;
+    fsck.check_refs()?;
+    fsck.check_connectivity()?;
+    fsck.report_unreachable(unreachable);
+
+    Ok(fsck.ok())
+}
+
+// how long unreachable objects survive when nothing else says
+const DEFAULT_PRUNE_EXPIRE: &str = "2.weeks.ago";
+
+/// Packs refs and reachable objects, keeps recently written unreachable
+/// objects in a cruft pack, prunes the rest and writes a commit-graph.
+pub async fn gc(prune: Option<String>) -> Result<()> {
+    let expire = match prune {
+        Some(expire) => expire,
+        None => config::lookup("gc.pruneExpire")?.unwrap_or_else(|| DEFAULT_PRUNE_EXPIRE.to_string()),
+    };
+    let cutoff = utils::parse_expiry(&expire, chrono::Utc::now().timestamp())?;
+
+    gc::pack_refs().await?;
+    gc::repack(true, true, gc::Unreachable::Cruft(cutoff)).await?;
+    gc::prune(cutoff, false, false).await?;
+
+    commit_graph_write(false).await
+}
+
+pub async fn repack(all: bool, delete: bool) -> Result<()> {
+    gc::repack(all, delete, gc::Unreachable::Drop).await
+}
+
+pub async fn prune(expire: Option<String>, dry_run: bool, verbose: bool) -> Result<()> {
+    // without --expire everything unreachable goes, however recent
+    let cutoff = match expire {
+        Some(expire) => utils::parse_expiry(&expire, chrono::Utc::now().timestamp())?,
+        None => Some(i64::MAX),
+    };
+
+    gc::prune(cutoff, dry_run, verbose).await
+}
+
+fn same_listing(a: &diff::Listing, b: &diff::Listing) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b.iter()).all(|((a_path, a_entry), (b_path, b_entry))| {
+            a_path == b_path && a_entry.mode == b_entry.mode && a_entry.sha1_hash == b_entry.sha1_hash
+        })
+}
+
+// refuses to go on while the index or working tree has changes
+async fn require_clean_work_tree(action: &str) -> Result<()> {
+    let index = Index::load()?;
+    let mut cache = diff::BlobCache::default();
+
+    let staged = !same_listing(&diff::index_listing(&index), &head_listing().await?);
+    let unstaged = !index.conflicts().is_empty()
+        || !same_listing(&diff::worktree_listing(&index, &mut cache).await?, &diff::index_listing(&index));
+
+    if unstaged {
+        bail!("cannot {}: You have unstaged changes.\nPlease commit or stash them.", action);
+    }
+    if staged {
+        bail!("cannot {}: Your index contains uncommitted changes.\nPlease commit or stash them.", action);
+    }
+
+    Ok(())
+}
+
+/// What to do with a rebase, cherry-pick or revert that stopped.
+pub enum Resume {
+    Continue,
+    Skip,
+    Abort,
+}
+
+/// Replays the commits of HEAD that `upstream` lacks on top of `onto`
+/// (`upstream` itself by default), or carries on with a rebase that
+/// stopped.
+pub async fn rebase(
+    upstream: Option<String>,
+    onto: Option<String>,
+    interactive: bool,
+    autosquash: bool,
+    action: Option<Resume>,
+) -> Result<()> {
+    if let Some(action) = action {
+        if !Rebase::in_progress() {
+            bail!("No rebase in progress?");
+        }
+
+        let mut rebase = Rebase::load().await?;
+        return match action {
+            Resume::Continue => rebase.continue_rebase().await,
+            Resume::Skip => rebase.skip().await,
+            Resume::Abort => rebase.abort().await,
+        };
+    }
+
+    if Rebase::in_progress() {
+        bail!(
+            "It seems that there is already a rebase-merge directory, and\n\
+             I wonder if you are in the middle of another rebase.  If that is the\n\
+             case, please try\n\ttgit rebase (--continue | --abort | --skip)"
+        );
+    }
+
+    let head_name = match refs::read_head()? {
+        refs::Head::Branch(refname) => refname,
+        refs::Head::Detached(_) => String::from("detached HEAD"),
+    };
+    let head = match refs::head_sha()? {
+        Some(head) => head,
+        None => bail!("Could not resolve HEAD to a commit"),
+    };
+
+    let upstream = match upstream {
+        Some(upstream) => upstream,
+        None => {
+            let branch = refs::current_branch()?;
+            match branch.as_deref().map(upstream_ref).transpose()?.flatten() {
+                Some(upstream) => upstream,
+                None => bail!(
+                    "There is no tracking information for the current branch.\n\
+                     Please specify which branch you want to rebase against."
+                ),
+            }
+        }
+    };
+    let upstream_sha = match revision::resolve_commit(&upstream).await {
+        Ok(commit) => commit.sha1_hash(),
+        Err(_) => bail!("invalid upstream '{}'", upstream),
+    };
+    let onto_sha = match &onto {
+        Some(onto) => match revision::resolve_commit(onto).await {
+            Ok(commit) => commit.sha1_hash(),
+            Err(_) => bail!("Does not point to a valid commit '{}'", onto),
+        },
+        None => upstream_sha,
+    };
+
+    require_clean_work_tree("rebase").await?;
+
+    // nothing to replay when HEAD already sits on top of the new base
+    let mut graph = CommitGraph::new();
+    if !interactive
+        && graph.is_ancestor(onto_sha, head).await?
+        && graph.merge_bases(upstream_sha, head).await? == vec![onto_sha]
+    {
+        match refs::current_branch()? {
+            Some(branch) => println!("Current branch {} is up to date.", branch),
+            None => println!("HEAD is up to date."),
+        }
+        return Ok(());
+    }
+
+    // changes upstream already has are left out
+    let mut cache = diff::BlobCache::default();
+    let mut upstream_patches = Vec::new();
+    for sha1_hash in graph.range(&[upstream_sha], &[head]).await? {
+        let commit = Commit::from_object_sha(hex::encode(sha1_hash)).await?;
+        if commit.parents().len() < 2 {
+            upstream_patches.push(sequencer::patch_id(&commit, &mut cache).await?);
+        }
+    }
+
+    let mut steps = Vec::new();
+    let mut skipped = false;
+    for sha1_hash in graph.range(&[head], &[upstream_sha]).await?.into_iter().rev() {
+        let commit = Commit::from_object_sha(hex::encode(sha1_hash)).await?;
+        // merges are not replayed; their changes come with the rest
+        if commit.parents().len() > 1 {
+            continue;
+        }
+        if !upstream_patches.is_empty()
+            && upstream_patches.contains(&sequencer::patch_id(&commit, &mut cache).await?)
+        {
+            println!("warning: skipped previously applied commit {}", short_sha(&sha1_hash));
+            skipped = true;
+            continue;
+        }
+        steps.push(Step::pick(&commit));
+    }
+    if skipped {
+        println!("hint: use --reapply-cherry-picks to include skipped commits");
+    }
+    if autosquash {
+        steps = sequencer::autosquash(steps);
+    }
+
+    let mut rebase = Rebase::new(head_name, onto_sha, head, interactive);
+    rebase.set_todo(steps);
+    if interactive && !rebase.edit_todo(upstream_sha, head).await? {
+        let _ = fs::remove_dir_all(".git/rebase-merge").await;
+        bail!("nothing to do");
+    }
+
+    rebase.start().await
+}
+
+// the commits named by `cherry-pick` or `revert` arguments, where
+// "A..B" stands for the commits of B that A lacks, oldest first
+async fn sequence_commits(args: &[String]) -> Result<Vec<Commit>> {
+    let mut graph = CommitGraph::new();
+    let mut commits = Vec::new();
+
+    for arg in args {
+        match arg.split_once("..") {
+            Some((from, to)) => {
+                let from = revision::resolve_commit(if from.is_empty() { "HEAD" } else { from }).await?;
+                let to = revision::resolve_commit(if to.is_empty() { "HEAD" } else { to }).await?;
+                for sha1_hash in graph
+                    .range(&[to.sha1_hash()], &[from.sha1_hash()])
+                    .await?
+                    .into_iter()
+                    .rev()
+                {
+                    commits.push(Commit::from_object_sha(hex::encode(sha1_hash)).await?);
+                }
+            }
+            None => commits.push(revision::resolve_commit(arg).await?),
+        }
+    }
+
+    if commits.is_empty() {
+        bail!("empty commit set passed");
+    }
+
+    Ok(commits)
+}
+
+/// Applies (or, for a revert, undoes) the changes each of `args` made,
+/// or carries on with a run that stopped.
+pub async fn sequence(
+    revert: bool,
+    args: Vec<String>,
+    options: PickOptions,
+    action: Option<Resume>,
+) -> Result<()> {
+    let verb = if revert { "revert" } else { "cherry-pick" };
+
+    if let Some(action) = action {
+        if !Sequence::in_progress() {
+            bail!("no cherry-pick or revert in progress");
+        }
+
+        let mut sequence = Sequence::load().await?;
+        return match action {
+            Resume::Continue => sequence.continue_sequence().await,
+            Resume::Skip => sequence.skip().await,
+            Resume::Abort => sequence.abort().await,
+        };
+    }
+
+    if Sequence::in_progress() {
+        bail!(
+            "{} is already in progress\nhint: try \"tgit {} (--continue | --skip | --abort)\"",
+            verb,
+            verb
+        );
+    }
+
+    let head = match refs::head_sha()? {
+        Some(head) => head,
+        None => bail!("can't {} into an unborn branch", verb),
+    };
+    if !options.no_commit {
+        let index = Index::load()?;
+        if !index.conflicts().is_empty() {
+            bail!("{} is not possible because you have unmerged files.", verb);
+        }
+        if !same_listing(&diff::index_listing(&index), &head_listing().await?) {
+            bail!(
+                "your local changes would be overwritten by {}.\nhint: commit your changes or stash them to proceed.",
+                verb
+            );
+        }
+    }
+
+    let action = if revert { Action::Revert } else { Action::Pick };
+    let todo = sequence_commits(&args)
+        .await?
+        .iter()
+        .map(|commit| Step {
+            action,
+            ..Step::pick(commit)
+        })
+        .collect();
+
+    Sequence::new(options, head, todo).run().await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResetMode {
+    Soft,
+    Mixed,
+    Hard,
+}
+
+// what `reset` leaves behind in the working tree, in git's porcelain format
+async fn print_unstaged() -> Result<()> {
+    let index = Index::load()?;
+    let mut header = false;
+
+    for path in index.paths() {
+        let entry = match index.entry(&path) {
+            Some(entry) if !entry.skip_worktree => entry,
+            _ => continue,
+        };
+        let status = if std::fs::symlink_metadata(checkout::worktree_path(&path)).is_err() {
+            'D'
+        } else if checkout::is_modified(&path, entry).await? {
+            'M'
+        } else {
+            continue;
+        };
+
+        if !header {
+            println!("Unstaged changes after reset:");
+            header = true;
+        }
+        println!("{}\t{}", status, diff::quote_path_bytes(&path));
+    }
+
+    Ok(())
+}
+
+/// Moves the current branch to `<rev>`, resetting the index (and with
+/// `--hard` the working tree) to match, or with paths resets only those
+/// index entries.
+pub async fn reset(mode: Option<ResetMode>, args: Vec<String>, paths: Vec<String>) -> Result<()> {
+    let mut args = args.into_iter().peekable();
+    let rev = match args.peek() {
+        Some(first) if !paths.is_empty() || revision::resolve_commit(first).await.is_ok() => args.next(),
+        Some(first) if !PathBuf::from(first).exists() => bail!(
+            "ambiguous argument '{}': unknown revision or path not in the working tree.\nUse '--' to separate paths from revisions, like this:\n'tgit <command> [<revision>...] -- [<file>...]'",
+            first
+        ),
+        _ => None,
+    };
+    let paths: Vec<String> = args.chain(paths).collect();
+
+    // an unborn HEAD resets to the empty tree
+    let target = match rev.as_deref() {
+        Some(rev) if rev != "HEAD" => Some(revision::resolve_commit(rev).await?),
+        _ => match refs::head_sha()? {
+            Some(head) => Some(Commit::from_object_sha(hex::encode(head)).await?),
+            None => None,
+        },
+    };
+    let tree_sha = target.as_ref().map(|commit| commit.tree_sha());
+    let files = checkout::flatten(tree_sha).await?;
+    let mut checkout = Checkout::new()?;
+
+    if !paths.is_empty() {
+        match mode {
+            Some(ResetMode::Soft) => bail!("Cannot do soft reset with paths."),
+            Some(ResetMode::Hard) => bail!("Cannot do hard reset with paths."),
+            _ => {}
+        }
+
+        checkout.reset_index(&files, &paths).await?;
+        checkout.save()?;
+        return print_unstaged().await;
+    }
+
+    let mode = mode.unwrap_or(ResetMode::Mixed);
+    match mode {
+        ResetMode::Soft => {
+            if PathBuf::from(MERGE_STATE[0]).exists() || !checkout.index_mut().conflicts().is_empty() {
+                bail!("Cannot do a soft reset in the middle of a merge.");
+            }
+        }
+        ResetMode::Mixed => checkout.reset_index(&files, diff::ALL_PATHS).await?,
+        ResetMode::Hard => match tree_sha {
+            Some(tree_sha) => checkout.checkout_tree(tree_sha, true).await?,
+            None => {
+                checkout.restore_worktree(&files, diff::ALL_PATHS).await?;
+                checkout.reset_index(&files, diff::ALL_PATHS).await?;
+            }
+        },
+    }
+    checkout.save()?;
+
+    if let Some(target) = &target {
+        if let Some(head) = refs::head_sha()? {
+            refs::update_ref("ORIG_HEAD", head)?;
+        }
+        match refs::read_head()? {
+            refs::Head::Branch(refname) => refs::update_ref(&refname, target.sha1_hash())?,
+            refs::Head::Detached(_) => refs::set_head_detached(target.sha1_hash())?,
+        }
+    }
+
+    // whatever was being merged or picked is gone with the old index
+    for path in MERGE_STATE.iter().chain(&[".git/CHERRY_PICK_HEAD", ".git/REVERT_HEAD"]) {
+        let _ = fs::remove_file(path).await;
+    }
+
+    match (mode, &target) {
+        (ResetMode::Hard, Some(target)) => println!(
+            "HEAD is now at {} {}",
+            short_sha(&target.sha1_hash()),
+            target.summary()
+        ),
+        (ResetMode::Mixed, _) => print_unstaged().await?,
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Restores `paths` in the working tree and/or the index from `source`,
+/// which defaults to the index for the working tree and to HEAD for the
+/// index. Paths the source lacks are removed.
+pub async fn restore(source: Option<String>, staged: bool, worktree: bool, paths: Vec<String>) -> Result<()> {
+    if paths.is_empty() {
+        bail!("you must specify path(s) to restore");
+    }
+    let worktree = worktree || !staged;
+
+    let mut checkout = Checkout::new()?;
+    let files = match source {
+        Some(source) => checkout::flatten(Some(revision::resolve_tree(&source).await?)).await?,
+        None if staged => match refs::head_sha()? {
+            Some(head) => checkout::flatten(Some(Commit::from_object_sha(hex::encode(head)).await?.tree_sha())).await?,
+            None => checkout::flatten(None).await?,
+        },
+        None => {
+            if let Some(path) = checkout
+                .index_mut()
+                .conflicts()
+                .into_iter()
+                .find(|path| diff::matches_pathspec(path, &paths))
+            {
+                bail!("path '{}' is unmerged", String::from_utf8_lossy(&path));
+            }
+
+            checkout.staged_files()
+        }
+    };
+
+    let known = checkout.index_mut().paths();
+    for spec in &paths {
+        let spec = std::slice::from_ref(spec);
+        if !files.keys().chain(&known).any(|path| diff::matches_pathspec(path, spec)) {
+            bail!("pathspec '{}' did not match any file(s) known to git", spec[0]);
+        }
+    }
+
+    // the working tree goes first, while the index still says which paths
+    // are tracked
+    if worktree {
+        checkout.restore_worktree(&files, &paths).await?;
+    }
+    if staged {
+        checkout.reset_index(&files, &paths).await?;
+    }
+
+    checkout.save()
+}
+
+/// Stages the working tree's version of `paths`, resolving any conflicts
+/// they had.
+pub async fn add(paths: Vec<String>) -> Result<()> {
+    if paths.is_empty() {
+        bail!("Nothing specified, nothing added.");
+    }
+
+    let mut checkout = Checkout::new()?;
+    checkout.add(&paths).await?;
+    checkout.save()
+}
+
+/// Removes `paths` from the index, and from the working tree unless
+/// `cached` is set.
+pub async fn rm(cached: bool, force: bool, recursive: bool, paths: Vec<String>) -> Result<()> {
+    if paths.is_empty() {
+        bail!("No pathspec was given. Which files should I remove?");
+    }
+
+    let head = match refs::head_sha()? {
+        Some(head) => checkout::flatten(Some(Commit::from_object_sha(hex::encode(head)).await?.tree_sha())).await?,
+        None => checkout::flatten(None).await?,
+    };
+
+    let mut checkout = Checkout::new()?;
+    for path in checkout.remove(&head, &paths, cached, force, recursive).await? {
+        println!("rm '{}'", String::from_utf8_lossy(&path));
+    }
+    checkout.save()
+}
+
+pub async fn stash_push(options: stash::PushOptions) -> Result<()> {
+    match stash::push(&options).await? {
+        Some(message) => println!("Saved working directory and index state {}", message),
+        None => println!("No local changes to save"),
+    }
+
+    Ok(())
+}
+
+pub fn stash_list() -> Result<()> {
+    for (i, message) in stash::list()?.iter().enumerate() {
+        println!("stash@{{{}}}: {}", i, message);
+    }
+
+    Ok(())
+}
+
+/// Shows what a stash entry changed against the commit it was made on,
+/// as a diffstat or with `patch` as a diff.
+pub async fn stash_show(patch: bool, spec: Option<String>) -> Result<()> {
+    let stash = StashRef::lookup(spec).await?;
+    let commit = stash.commit.encoded_sha();
+    let revisions = vec![format!("{}^1", commit), commit];
+
+    diff(diff::DiffOptions::default(), true, false, !patch, false, revisions, Vec::new()).await
+}
+
+/// Applies a stash entry, dropping it afterwards for `pop`. Returns false
+/// when it conflicted, in which case the entry is kept.
+pub async fn stash_apply(restore_index: bool, pop: bool, spec: Option<String>) -> Result<bool> {
+    let stash = StashRef::lookup(spec).await?;
+    if pop {
+        stash.position()?;
+    }
+
+    let kept = "The stash entry is kept in case you need it again.";
+    match stash.apply(restore_index).await {
+        Ok(true) => {}
+        Ok(false) => {
+            if pop {
+                println!("{}", kept);
+            }
+            return Ok(false);
+        }
+        Err(err) if pop => bail!("{}\n{}", err, kept),
+        Err(err) => return Err(err),
+    }
+
+    if pop {
+        stash.drop()?;
+        println!("Dropped {} ({})", stash.name, stash.commit.encoded_sha());
+    }
+
+    Ok(true)
+}
+
+pub async fn stash_drop(spec: Option<String>) -> Result<()> {
+    let stash = StashRef::lookup(spec).await?;
+    stash.drop()?;
+    println!("Dropped {} ({})", stash.name, stash.commit.encoded_sha());
+
+    Ok(())
+}
+
+pub fn submodule_init(paths: Vec<String>) -> Result<()> {
+    init_submodules(&paths, "")
+}
+
+// `prefix` is where the repository sits in the outermost superproject, for
+// naming nested submodules by their full path
+fn init_submodules(paths: &[String], prefix: &str) -> Result<()> {
+    let mut config = Config::load()?;
+
+    for submodule in submodule::list(paths)? {
+        if submodule.configured_url(&config).is_some() {
+            continue;
+        }
+
+        let url = submodule.url()?;
+        config.set(&format!("submodule.{}.active", submodule.name), "true")?;
+        config.set(&format!("submodule.{}.url", submodule.name), &url)?;
+        println!(
+            "Submodule '{}' ({}) registered for path '{}{}'",
+            submodule.name, url, prefix, submodule.path
+        );
+    }
+
+    config.save()
+}
+
+pub async fn submodule_update(paths: Vec<String>, init: bool, recursive: bool) -> Result<()> {
+    update_submodules(&paths, init, recursive, "").await
+}
+
+/// Clones the initialized submodules under `paths` that are missing, then
+/// checks out the commit the superproject records in each, detaching its
+/// HEAD as git does.
+#[async_recursion]
+async fn update_submodules(paths: &[String], init: bool, recursive: bool, prefix: &str) -> Result<()> {
+    if init {
+        init_submodules(paths, prefix)?;
+    }
+
+    let config = Config::load()?;
+    let mut pending = Vec::new();
+    for submodule in submodule::list(paths)? {
+        let url = match submodule.configured_url(&config) {
+            Some(url) => url,
+            None => {
+                if !paths.is_empty() {
+                    println!("Submodule path '{}{}' not initialized", prefix, submodule.path);
+                    println!("Maybe you want to use 'update --init'?");
+                }
+                continue;
+            }
+        };
+        let sha1_hash = match submodule.sha1_hash {
+            Some(sha1_hash) => sha1_hash,
+            None => bail!("Skipping unmerged submodule {}{}", prefix, submodule.path),
+        };
+
+        // every clone happens before any checkout, as with git
+        let cloned = !submodule.is_populated();
+        if cloned {
+            let _saved = submodule::WorkingDir::save()?;
+            let clone_dir = std::env::current_dir()?.join(&submodule.path);
+            clone(url.clone(), clone_dir, false, None, Deepen::default()).await?;
+        }
+        pending.push((submodule, url, sha1_hash, cloned));
+    }
+
+    for (submodule, url, sha1_hash, cloned) in &pending {
+        let _entered = submodule::WorkingDir::enter(&submodule.path)?;
+        if !cloned && refs::head_sha()? == Some(*sha1_hash) {
+            continue;
+        }
+
+        if !objects::has_object(*sha1_hash)? {
+            fetch_submodule(url).await?;
+            if !objects::has_object(*sha1_hash)? {
+                bail!(
+                    "Fetched in submodule path '{}{}', but it did not contain {}. Direct fetching of that commit failed.",
+                    prefix,
+                    submodule.path,
+                    sha1_hash
+                );
+            }
+        }
+
+        checkout_commit(*sha1_hash, false).await?;
+        refs::set_head_detached(*sha1_hash)?;
+        println!("Submodule path '{}{}': checked out '{}'", prefix, submodule.path, sha1_hash);
+    }
+
+    if recursive {
+        for (submodule, ..) in &pending {
+            let _entered = submodule::WorkingDir::enter(&submodule.path)?;
+            let prefix = format!("{}{}/", prefix, submodule.path);
+            update_submodules(&[], init, true, &prefix).await?;
+        }
+    }
+
+    Ok(())
+}
+
+// downloads the remote's branches again into the submodule in the current
+// directory, for a recorded commit it does not have yet
+async fn fetch_submodule(url: &str) -> Result<()> {
+    let advertisement = CloneClient::new(url.to_string()).clone(PackRequest::default()).await?;
+
+    for remote_ref in &advertisement.refs {
+        if let Some(branch) = remote_ref.name.strip_prefix("refs/heads/") {
+            refs::update_ref(&format!("refs/remotes/origin/{}", branch), ObjectId::from_hex(&remote_ref.hash)?)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn submodule_status(paths: Vec<String>, recursive: bool) -> Result<()> {
+    submodule_status_in(&paths, recursive, "").await
+}
+
+/// Prints each submodule's checked out commit: `-` when it is not
+/// cloned, `+` when it is not the commit the superproject records, and
+/// `U` while the gitlink has conflicts.
+#[async_recursion]
+async fn submodule_status_in(paths: &[String], recursive: bool, prefix: &str) -> Result<()> {
+    for submodule in submodule::list(paths)? {
+        let path = format!("{}{}", prefix, submodule.path);
+        let recorded = match submodule.sha1_hash {
+            Some(sha1_hash) => sha1_hash,
+            None => {
+                println!("U{} {}", HashAlgorithm::current().null(), path);
+                continue;
+            }
+        };
+        if !submodule.is_populated() {
+            println!("-{} {}", recorded, path);
+            continue;
+        }
+
+        let _entered = submodule::WorkingDir::enter(&submodule.path)?;
+        let head = match refs::head_sha()? {
+            Some(head) => head,
+            None => bail!("submodule '{}' does not have a commit checked out", path),
+        };
+        let marker = if head == recorded { ' ' } else { '+' };
+        println!("{}{} {} ({})", marker, head, path, submodule::describe(head).await?);
+
+        if recursive {
+            submodule_status_in(&[], true, &format!("{}/", path)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn submodule_sync(paths: Vec<String>, recursive: bool) -> Result<()> {
+    sync_submodules(&paths, recursive, "")
+}
+
+/// Copies the URLs in `.gitmodules` to the registered submodules, and to
+/// the `origin` remote of those already cloned.
+fn sync_submodules(paths: &[String], recursive: bool, prefix: &str) -> Result<()> {
+    let mut config = Config::load()?;
+
+    for submodule in submodule::list(paths)? {
+        if config.get(&format!("submodule.{}.active", submodule.name)).is_none()
+            && submodule.configured_url(&config).is_none()
+        {
+            continue;
+        }
+
+        println!("Synchronizing submodule url for '{}{}'", prefix, submodule.path);
+        let url = submodule.url()?;
+        if submodule.configured_url(&config).is_some() {
+            config.set(&format!("submodule.{}.url", submodule.name), &url)?;
+        }
+
+        if submodule.is_populated() {
+            let git_dir = submodule::git_dir(std::path::Path::new(&submodule.path))?;
+            let mut remote_config = Config::load_from(git_dir.join("config"))?;
+            remote_config.set("remote.origin.url", &url)?;
+            remote_config.save()?;
+
+            if recursive {
+                let _entered = submodule::WorkingDir::enter(&submodule.path)?;
+                sync_submodules(&[], true, &format!("{}{}/", prefix, submodule.path))?;
+            }
+        }
+    }
+
+    config.save()
+}
+
+/// Limits the working tree to the directories `dirs` (and the files at the
+/// top level and on the way to them), as `sparse-checkout set` does in cone
+/// mode.
+pub async fn sparse_checkout_set(dirs: Vec<String>) -> Result<()> {
+    let index = Index::load()?;
+    for dir in &dirs {
+        let path = dir.trim_start_matches("./").trim_end_matches('/');
+        if index.entry(path).is_some() {
+            bail!("'{}' is not a directory; to treat it as a directory anyway, rerun with --skip-checks", dir);
+        }
+    }
+
+    apply_cone(Cone::new(dirs)).await
+}
+
+pub async fn sparse_checkout_add(dirs: Vec<String>) -> Result<()> {
+    let mut cone = match Cone::load()? {
+        Some(cone) => cone,
+        None => bail!("no sparse-checkout to add to"),
+    };
+    cone.add(dirs);
+
+    apply_cone(cone).await
+}
+
+pub fn sparse_checkout_list() -> Result<()> {
+    let cone = match Cone::load()? {
+        Some(cone) => cone,
+        None => bail!("this worktree is not sparse"),
+    };
+
+    for dir in cone.dirs() {
+        println!("{}", dir);
+    }
+
+    Ok(())
+}
+
+/// Applies the sparse checkout again, for files left behind earlier.
+pub async fn sparse_checkout_reapply() -> Result<()> {
+    match Cone::load()? {
+        Some(cone) => apply_cone(cone).await,
+        None => bail!("must be in a sparse-checkout to reapply sparsity patterns"),
+    }
+}
+
+// records the cone and makes the working tree match it
+async fn apply_cone(cone: Cone) -> Result<()> {
+    cone.save()?;
+
+    let mut checkout = Checkout::new()?;
+    let left = checkout.apply_sparsity().await?;
+    checkout.save()?;
+
+    if !left.is_empty() {
+        eprintln!(
+            "warning: The following paths are not up to date and were left despite sparse patterns:\n\t{}\n\nAfter fixing the above paths, you may want to run `git sparse-checkout reapply`.",
+            left.iter().map(|path| String::from_utf8_lossy(path)).collect::<Vec<_>>().join("\n\t")
+        );
+    }
+
+    Ok(())
+}
+
+pub async fn bundle_create(file: PathBuf, version: Option<u32>, all: bool, revs: Vec<String>) -> Result<()> {
+    bundle::create(&file, version, all, &revs).await
+}
+
+fn print_bundle_refs(refs: &[(ObjectId, String)]) {
+    for (sha1_hash, refname) in refs {
+        println!("{} {}", hex::encode(sha1_hash), refname);
+    }
+}
+
+/// Checks the repository has the commits the bundle in `file` builds on,
+/// reporting those it lacks as errors, or describing the bundle when it
+/// does. Returns whether the bundle can be unbundled here.
+pub fn bundle_verify(file: PathBuf, quiet: bool) -> Result<bool> {
+    let bundle = Bundle::open(&file)?;
+
+    let missing = bundle.missing_prerequisites()?;
+    if !missing.is_empty() {
+        eprintln!("error: Repository lacks these prerequisite commits:");
+        for sha1_hash in missing {
+            eprintln!("error: {} ", hex::encode(sha1_hash));
+        }
+        return Ok(false);
+    }
+
+    if !quiet {
+        match bundle.refs.len() {
+            1 => println!("The bundle contains this ref:"),
+            count => println!("The bundle contains these {} refs:", count),
+        }
+        print_bundle_refs(&bundle.refs);
+
+        match bundle.prerequisites.len() {
+            0 => println!("The bundle records a complete history."),
+            1 => println!("The bundle requires this ref:"),
+            count => println!("The bundle requires these {} refs:", count),
+        }
+        for (sha1_hash, _) in &bundle.prerequisites {
+            println!("{} ", hex::encode(sha1_hash));
+        }
+
+        println!("The bundle uses this hash algorithm: {}", bundle.object_format.name());
+    }
+    eprintln!("{} is okay", file.display());
+
+    Ok(true)
+}
+
+pub fn bundle_list_heads(file: PathBuf) -> Result<()> {
+    print_bundle_refs(&Bundle::open(&file)?.refs);
+
+    Ok(())
+}
+
+/// Stores the objects of the bundle in `file`, printing the refs it
+/// carries for the caller to update.
+pub fn bundle_unbundle(file: PathBuf) -> Result<()> {
+    let bundle = Bundle::open(&file)?;
+    bundle.unbundle()?;
+    print_bundle_refs(&bundle.refs);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clone::pkt_line;
+    use crate::packfile::{self, PackObject};
+    use crate::test_repo::{self, TestRepo};
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    #[tokio::test]
+    async fn test_cat_file_pretty_tree() -> Result<()> {
+        let repo = TestRepo::new("cat-file").await?;
+        fs::write("a", "a\n")?;
+        fs::set_permissions("a", fs::Permissions::from_mode(0o755))?;
+        fs::create_dir("dir")?;
+        fs::write("dir/b", "b\n")?;
+        symlink("a", "link")?;
+        let commit = repo.commit("files").await?;
+
+        // as `git cat-file -p` shows it
+        let mut out = Vec::new();
+        write_pretty(commit.tree_sha(), &mut out).await?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "100755 blob 78981922613b2afb6025042ff6bd878ac1994e85\ta\n\
+             040000 tree 6be660545b31f61a82a87d2b1915f0b88bb9f16f\tdir\n\
+             120000 blob 2e65efe2a145dda7ee51d1741299f848e5bf752e\tlink\n"
+        );
+
+        let mut out = Vec::new();
+        write_pretty(ObjectId::from_hex("78981922613b2afb6025042ff6bd878ac1994e85")?, &mut out).await?;
+        assert_eq!(out, b"a\n");
+
+        let mut out = Vec::new();
+        write_pretty(commit.sha1_hash(), &mut out).await?;
+        assert!(String::from_utf8(out)?.starts_with(&format!("tree {}\n", commit.tree_sha())));
+
+        Ok(())
+    }
+
+    // whether the index holds exactly the tree of `commit`
+    async fn index_matches(commit: &Commit) -> Result<bool> {
+        Ok(merge::write_tree(&diff::index_listing(&Index::load()?)).await? == commit.tree_sha())
+    }
+
+    #[tokio::test]
+    async fn test_reset() -> Result<()> {
+        let repo = TestRepo::new("reset").await?;
+        fs::create_dir("dir")?;
+        fs::write("a", "one\n")?;
+        fs::write("dir/b", "b\n")?;
+        let first = repo.commit("first").await?;
+
+        fs::write("a", "two\n")?;
+        fs::remove_dir_all("dir")?;
+        fs::write("c", "c\n")?;
+        let second = repo.commit("second").await?;
+
+        // local changes, staged and not, and a file git does not track
+        fs::write("a", "three\n")?;
+        fs::write("c", "c staged\n")?;
+        let mut checkout = Checkout::new()?;
+        checkout.add(&[String::from("c")]).await?;
+        checkout.save()?;
+        fs::write("untracked", "u\n")?;
+
+        reset(Some(ResetMode::Hard), vec![first.encoded_sha()], Vec::new()).await?;
+        assert_eq!(refs::resolve("refs/heads/master")?, Some(first.sha1_hash()));
+        assert_eq!(refs::resolve("ORIG_HEAD")?, Some(second.sha1_hash()));
+        assert!(index_matches(&first).await?);
+        assert_eq!(fs::read("a")?, b"one\n");
+        assert_eq!(fs::read("dir/b")?, b"b\n");
+        assert!(fs::symlink_metadata("c").is_err());
+        assert_eq!(fs::read("untracked")?, b"u\n");
+        let index = Index::load()?;
+        for entry in index.entries() {
+            assert!(!checkout::is_modified(&entry.path, entry).await?);
+        }
+
+        // --soft moves only the branch, --mixed the index too
+        reset(Some(ResetMode::Soft), vec![second.encoded_sha()], Vec::new()).await?;
+        assert_eq!(refs::head_sha()?, Some(second.sha1_hash()));
+        assert!(index_matches(&first).await?);
+        reset(Some(ResetMode::Mixed), vec![second.encoded_sha()], Vec::new()).await?;
+        assert!(index_matches(&second).await?);
+        assert_eq!(fs::read("a")?, b"one\n");
+
+        // paths reset just their index entries
+        reset(None, vec![first.encoded_sha()], vec![String::from("a")]).await?;
+        assert_eq!(refs::head_sha()?, Some(second.sha1_hash()));
+        let index = Index::load()?;
+        assert_eq!(index.entry("a").unwrap().sha1_hash, Blob::from_contents(b"one\n".to_vec())?.sha1_hash());
+        assert!(index.entry("c").is_some());
+        assert!(reset(Some(ResetMode::Hard), Vec::new(), vec![String::from("a")]).await.is_err());
+
+        // with no revision, --hard goes back to HEAD
+        reset(Some(ResetMode::Hard), Vec::new(), Vec::new()).await?;
+        assert!(index_matches(&second).await?);
+        assert_eq!(fs::read("a")?, b"two\n");
+        assert_eq!(fs::read("c")?, b"c\n");
+        // untracked since the mixed reset, so left alone
+        assert_eq!(fs::read("dir/b")?, b"b\n");
+
+        Ok(())
+    }
+
+    async fn head() -> Result<Commit> {
+        revision::resolve_commit("HEAD").await
+    }
+
+    fn pick_options(record_origin: bool, mainline: Option<usize>, no_commit: bool) -> PickOptions {
+        PickOptions {
+            record_origin,
+            mainline,
+            no_commit,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cherry_pick_and_revert() -> Result<()> {
+        let repo = TestRepo::new("cherry-pick").await?;
+        fs::write("f", "1\n2\n3\n")?;
+        let base = repo.commit("base").await?;
+        fs::write("f", "one\n2\n3\n")?;
+        fs::write("g", "g\n")?;
+        let first = repo.commit("first").await?;
+        fs::write("f", "one\n2\nthree\n")?;
+        let second = repo.commit("second").await?;
+
+        // a range, oldest first, each with where it came from
+        reset(Some(ResetMode::Hard), vec![base.encoded_sha()], Vec::new()).await?;
+        let range = format!("{}..{}", base.encoded_sha(), second.encoded_sha());
+        sequence(false, vec![range], pick_options(true, None, false), None).await?;
+        let picked = head().await?;
+        assert_eq!(picked.tree_sha(), second.tree_sha());
+        assert_ne!(picked.sha1_hash(), second.sha1_hash());
+        assert_eq!(picked.message(), format!("second\n\n(cherry picked from commit {})\n", second.encoded_sha()));
+        let parent = Commit::from_object_sha(hex::encode(picked.parents()[0])).await?;
+        assert_eq!(parent.tree_sha(), first.tree_sha());
+        assert_eq!(parent.parents(), &[base.sha1_hash()]);
+        assert!(!Sequence::in_progress());
+
+        sequence(true, vec![String::from("HEAD")], PickOptions::default(), None).await?;
+        let reverted = head().await?;
+        assert_eq!(reverted.tree_sha(), first.tree_sha());
+        assert_eq!(
+            reverted.message(),
+            format!("Revert \"second\"\n\nThis reverts commit {}.\n", picked.encoded_sha())
+        );
+        assert_eq!(fs::read("f")?, b"one\n2\n3\n");
+
+        // a merge needs -m to say which parent its change is against
+        let merge = Commit::create(
+            second.tree_sha(),
+            vec![first.sha1_hash(), base.sha1_hash()],
+            String::from("merge\n"),
+            first.author().clone(),
+            first.committer().clone(),
+        )?;
+        merge.write().await?;
+        reset(Some(ResetMode::Hard), vec![first.encoded_sha()], Vec::new()).await?;
+        assert!(sequence(false, vec![merge.encoded_sha()], PickOptions::default(), None).await.is_err());
+        sequence(false, vec![merge.encoded_sha()], pick_options(false, Some(1), false), None).await?;
+        assert_eq!(head().await?.tree_sha(), second.tree_sha());
+
+        // --no-commit leaves the change staged on top of HEAD
+        reset(Some(ResetMode::Hard), vec![first.encoded_sha()], Vec::new()).await?;
+        sequence(false, vec![second.encoded_sha()], pick_options(false, None, true), None).await?;
+        assert_eq!(refs::head_sha()?, Some(first.sha1_hash()));
+        assert!(index_matches(&second).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cherry_pick_conflict() -> Result<()> {
+        let repo = TestRepo::new("cherry-pick-conflict").await?;
+        fs::write("f", "1\n2\n3\n")?;
+        let base = repo.commit("base").await?;
+        fs::write("f", "one\n2\n3\n")?;
+        fs::write("g", "g\n")?;
+        let first = repo.commit("first").await?;
+        fs::write("f", "one\n2\nthree\n")?;
+        let second = repo.commit("second").await?;
+        reset(Some(ResetMode::Hard), vec![base.encoded_sha()], Vec::new()).await?;
+        fs::write("f", "uno\n2\n3\n")?;
+        let other = repo.commit("other").await?;
+        let commits = vec![first.encoded_sha(), second.encoded_sha()];
+
+        // stops at the first, which can then be abandoned
+        assert!(sequence(false, commits.clone(), PickOptions::default(), None).await.is_err());
+        assert!(Sequence::in_progress());
+        assert_eq!(Index::load()?.conflicts(), vec![b"f".to_vec()]);
+        assert!(sequence(false, commits.clone(), PickOptions::default(), None).await.is_err());
+        sequence(false, Vec::new(), PickOptions::default(), Some(Resume::Abort)).await?;
+        assert!(!Sequence::in_progress());
+        assert_eq!(refs::head_sha()?, Some(other.sha1_hash()));
+        assert!(index_matches(&other).await?);
+        assert_eq!(fs::read("f")?, b"uno\n2\n3\n");
+
+        // or resolved and continued, the rest following on
+        assert!(sequence(false, commits, PickOptions::default(), None).await.is_err());
+        fs::write("f", "uno\n2\n3\n")?;
+        add(vec![String::from("f")]).await?;
+        sequence(false, Vec::new(), PickOptions::default(), Some(Resume::Continue)).await?;
+        assert!(!Sequence::in_progress());
+        assert_eq!(fs::read("f")?, b"uno\n2\nthree\n");
+        assert_eq!(fs::read("g")?, b"g\n");
+        let resolved = Commit::from_object_sha(hex::encode(head().await?.parents()[0])).await?;
+        assert_eq!(resolved.message(), "first\n");
+        assert_eq!(resolved.parents(), &[other.sha1_hash()]);
+
+        Ok(())
+    }
+
+    // a pack of the commits, their trees and the blobs right under them
+    async fn pack_of(commits: &[Commit]) -> Result<Vec<u8>> {
+        let mut packed = Vec::new();
+        for commit in commits {
+            let tree = Tree::from_object_sha(hex::encode(commit.tree_sha())).await?;
+            let mut names = vec![commit.sha1_hash(), commit.tree_sha()];
+            names.extend(tree.entries().iter().map(|entry| entry.sha1_hash()));
+
+            for sha1_hash in names {
+                packed.push((PackObject::from_write_data(&objects::read_object(sha1_hash).await?)?, String::new()));
+            }
+        }
+
+        Ok(packfile::write_pack(packed)?.data)
+    }
+
+    #[tokio::test]
+    async fn test_shallow_clone_and_deepen() -> Result<()> {
+        let repo = TestRepo::new("shallow").await?;
+        let mut commits = Vec::new();
+        for n in 1..=4 {
+            fs::write("f", format!("{}\n", n))?;
+            commits.push(repo.commit(&format!("commit {}", n)).await?);
+        }
+        let names: Vec<String> = commits.iter().map(|commit| commit.encoded_sha()).collect();
+        let packs = [
+            pack_of(&commits[..1]).await?,
+            pack_of(&commits[1..2]).await?,
+            pack_of(&commits[2..]).await?,
+        ];
+
+        // a remote that cuts the history where the request says to
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        let served = names.clone();
+        let url = test_repo::serve(move |path, body| {
+            if path.ends_with("service=git-upload-pack") {
+                let mut out = pkt_line("# service=git-upload-pack\n") + "0000";
+                out += &pkt_line(&format!(
+                    "{} HEAD\0shallow deepen-relative symref=HEAD:refs/heads/master\n",
+                    served[3]
+                ));
+                out += &pkt_line(&format!("{} refs/heads/master\n", served[3]));
+                return (out + "0000").into_bytes();
+            }
+
+            let body = String::from_utf8_lossy(body).to_string();
+            let (lines, pack) = if body.contains("deepen 2147483647") {
+                (vec![format!("unshallow {}", served[1])], &packs[0])
+            } else if body.contains("deepen-relative") {
+                (vec![format!("shallow {}", served[1]), format!("unshallow {}", served[2])], &packs[1])
+            } else {
+                (vec![format!("shallow {}", served[2])], &packs[2])
+            };
+            seen.lock().unwrap().push(body);
+
+            let mut out: String = lines.iter().map(|line| pkt_line(&format!("{}\n", line))).collect();
+            out += "0000";
+            out += &pkt_line("NAK\n");
+            let mut out = out.into_bytes();
+            out.extend_from_slice(pack);
+            out
+        })?;
+
+        let depth = |depth: u32, relative: bool| Deepen {
+            depth: Some(depth),
+            relative,
+            ..Deepen::default()
+        };
+
+        // --depth 2 keeps the tip and its parent, with the parent cut off
+        clone(url, PathBuf::from("clone"), false, None, depth(2, false)).await?;
+        assert!(requests.lock().unwrap()[0].contains("deepen 2\n"));
+        assert_eq!(fs::read_to_string(".git/shallow")?, format!("{}\n", names[2]));
+        assert_eq!(fs::read("f")?, b"4\n");
+        assert!(!objects::has_object(commits[1].sha1_hash())?);
+        let mut walk = revision::RevWalk::new();
+        walk.push(commits[3].sha1_hash()).await?;
+        let mut walked = Vec::new();
+        while let Some(commit) = walk.next().await? {
+            walked.push(commit.encoded_sha());
+        }
+        assert_eq!(walked, names[2..].iter().rev().cloned().collect::<Vec<_>>());
+
+        // --deepen 1 tells the remote where the history stops and moves it
+        // back by one
+        fetch(None, Vec::new(), depth(1, true)).await?;
+        let request = requests.lock().unwrap()[1].clone();
+        assert!(request.contains(&format!("shallow {}\n", names[2])));
+        assert!(request.contains("deepen 1\n"));
+        assert_eq!(fs::read_to_string(".git/shallow")?, format!("{}\n", names[1]));
+        assert!(objects::has_object(commits[1].sha1_hash())?);
+        assert!(shallow::is_grafted(commits[1].sha1_hash()));
+        assert!(!shallow::is_grafted(commits[2].sha1_hash()));
+
+        // --unshallow leaves nothing cut off, and so no shallow file
+        let unshallow = deepen_options(None, None, true, None, Vec::new())?;
+        fetch(None, Vec::new(), unshallow).await?;
+        assert!(requests.lock().unwrap()[2].contains(&format!("shallow {}\n", names[1])));
+        assert!(!shallow::is_shallow());
+        assert!(shallow::load()?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bundle_round_trip() -> Result<()> {
+        let repo = TestRepo::new("bundle").await?;
+        // kept out of the commits' way
+        let root = std::env::current_dir()?.join(".git");
+        fs::write("f", "1\n")?;
+        let first = repo.commit("commit 1").await?;
+        bundle_create(root.join("base.bundle"), None, false, vec![String::from("HEAD"), String::from("master")]).await?;
+
+        fs::write("f", "2\n")?;
+        fs::write("g", "g\n")?;
+        repo.commit("commit 2").await?;
+        fs::write("f", "3\n")?;
+        let last = repo.commit("commit 3").await?;
+
+        // what the first commit already has is left to it
+        let range = format!("{}..master", first.encoded_sha());
+        bundle_create(root.join("more.bundle"), None, false, vec![range]).await?;
+        let bundle = Bundle::open(&root.join("more.bundle"))?;
+        assert_eq!(bundle.prerequisites, vec![(first.sha1_hash(), String::from("commit 1"))]);
+        assert_eq!(bundle.refs, vec![(last.sha1_hash(), String::from("refs/heads/master"))]);
+
+        // a repository without it cannot take the bundle
+        {
+            fs::create_dir("empty")?;
+            let _empty = submodule::WorkingDir::enter("empty")?;
+            init(None, None).await?;
+            assert!(!bundle_verify(root.join("more.bundle"), true)?);
+            assert!(bundle.unbundle().is_err());
+        }
+
+        let base = root.join("base.bundle").display().to_string();
+        clone(base, PathBuf::from("clone"), false, None, Deepen::default()).await?;
+        assert_eq!(refs::head_sha()?, Some(first.sha1_hash()));
+        assert_eq!(fs::read("f")?, b"1\n");
+        assert!(!objects::has_object(last.sha1_hash())?);
+
+        // and one that has it can
+        assert!(bundle_verify(root.join("more.bundle"), true)?);
+        let more = root.join("more.bundle").display().to_string();
+        let refspec = String::from("+refs/heads/*:refs/remotes/more/*");
+        fetch(Some(more), vec![refspec], Deepen::default()).await?;
+        assert_eq!(refs::resolve("refs/remotes/more/master")?, Some(last.sha1_hash()));
+        let fetched = Commit::from_object_sha(last.encoded_sha()).await?;
+        assert_eq!(fetched.tree_sha(), last.tree_sha());
+        let files = checkout::flatten(Some(fetched.tree_sha())).await?;
+        assert_eq!(files.keys().collect::<Vec<_>>(), vec![&b"f".to_vec(), &b"g".to_vec()]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sha256_diff_tree() -> Result<()> {
+        if test_repo::in_own_process("commands::tests::test_sha256_diff_tree")? {
+            return Ok(());
+        }
+
+        let repo = TestRepo::with_object_format("sha256", "sha256").await?;
+        fs::write("a", "a\n")?;
+        let first = repo.commit("a").await?;
+        fs::remove_file("a")?;
+        fs::write("b", "b\n")?;
+        let second = repo.commit("b").await?;
+        assert_eq!(second.encoded_sha().len(), 64);
+        assert_eq!(second.parents(), &[first.sha1_hash()]);
+
+        let options = TreeDiffOptions {
+            recursive: true,
+            ..TreeDiffOptions::default()
+        };
+        let changes = diff::diff_trees(Some(first.tree_sha()), Some(second.tree_sha()), &options).await?;
+        let lines: Vec<String> = changes.iter().map(|change| format_change(change, false, false)).collect();
+        let null = "0".repeat(64);
+        assert_eq!(
+            lines,
+            vec![
+                format!(
+                    ":100644 000000 f8625e43f9e04f24291f77cdbe4c71b3c2a3b0003f60419b3ed06a058d766c8b {} D\ta",
+                    null
+                ),
+                format!(
+                    ":000000 100644 {} 9b69d308c97f2c5933fdd0e8ce04acce91c09cb969e36a1f86756fc5a5d3323a A\tb",
+                    null
+                ),
+            ]
+        );
+
+        // the command resolves the commit by its 64-digit name
+        diff_tree(options, false, false, true, vec![second.encoded_sha()]).await?;
+
+        Ok(())
+    }
+}
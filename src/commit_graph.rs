@@ -0,0 +1,720 @@
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryInto;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+use crate::diff::{self, TreeDiffOptions};
+use crate::objects::{Commit, HashAlgorithm, Object, ObjectId};
+use crate::shallow;
+
+const SIGNATURE: &[u8; 4] = b"CGPH";
+const VERSION: u8 = 1;
+
+const CHUNK_FANOUT: &[u8; 4] = b"OIDF";
+const CHUNK_OIDS: &[u8; 4] = b"OIDL";
+const CHUNK_COMMITS: &[u8; 4] = b"CDAT";
+const CHUNK_GENERATIONS: &[u8; 4] = b"GDA2";
+const CHUNK_GENERATION_OVERFLOW: &[u8; 4] = b"GDO2";
+const CHUNK_EXTRA_EDGES: &[u8; 4] = b"EDGE";
+const CHUNK_BLOOM_INDEX: &[u8; 4] = b"BIDX";
+const CHUNK_BLOOM_DATA: &[u8; 4] = b"BDAT";
+
+// a commit's tree comes first, then 16 bytes of parents, generation and date
+const COMMIT_DATA_EXTRA: usize = 16;
+const PARENT_NONE: u32 = 0x7000_0000;
+// set on the second parent when the rest live in the extra edges chunk, and
+// on the last of those edges
+const EXTRA_EDGES: u32 = 0x8000_0000;
+const TOPO_LEVEL_MAX: u32 = 0x3fff_ffff;
+const OFFSET_OVERFLOW: u32 = 0x8000_0000;
+
+const BLOOM_VERSION: u32 = 1;
+const BLOOM_HASHES: u32 = 7;
+const BLOOM_BITS_PER_ENTRY: u32 = 10;
+const BLOOM_MAX_CHANGED_PATHS: usize = 512;
+const BLOOM_SEEDS: [u32; 2] = [0x293a_e76f, 0x7e64_6e2c];
+
+pub fn graph_path() -> PathBuf {
+    PathBuf::from(".git/objects/info/commit-graph")
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+// git's murmur3, including its habit of sign-extending bytes above 0x7f,
+// which its version 1 Bloom filters are defined by
+fn murmur3(mut seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+
+    let byte = |b: u8| b as i8 as u32;
+
+    let chunks = data.chunks_exact(4);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mut k = byte(chunk[0]) | byte(chunk[1]) << 8 | byte(chunk[2]) << 16 | byte(chunk[3]) << 24;
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        seed ^= k;
+        seed = seed.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+
+    let mut k = 0u32;
+    for (i, b) in tail.iter().enumerate() {
+        k ^= byte(*b) << (8 * i);
+    }
+    if !tail.is_empty() {
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        seed ^= k;
+    }
+
+    seed ^= data.len() as u32;
+    seed ^= seed >> 16;
+    seed = seed.wrapping_mul(0x85eb_ca6b);
+    seed ^= seed >> 13;
+    seed = seed.wrapping_mul(0xc2b2_ae35);
+    seed ^= seed >> 16;
+
+    seed
+}
+
+// the bit positions a path sets in a filter of `bits` bits
+fn bloom_positions(path: &[u8], bits: u64) -> Vec<u64> {
+    let hash0 = murmur3(BLOOM_SEEDS[0], path);
+    let hash1 = murmur3(BLOOM_SEEDS[1], path);
+
+    (0..BLOOM_HASHES)
+        .map(|i| hash0.wrapping_add(i.wrapping_mul(hash1)) as u64 % bits)
+        .collect()
+}
+
+/// Builds the changed-path Bloom filter for the paths a commit touched.
+/// Too many paths give a filter with every bit set; none give a single
+/// empty byte, as a zero length means no filter was computed.
+pub fn bloom_filter(changed: &[Vec<u8>]) -> Vec<u8> {
+    if changed.len() > BLOOM_MAX_CHANGED_PATHS {
+        return vec![0xff];
+    }
+
+    // every leading directory of a changed path counts as changed too
+    let mut paths = BTreeSet::new();
+    for path in changed {
+        let mut path = path.as_slice();
+        paths.insert(path);
+        while let Some(pos) = path.iter().rposition(|b| *b == b'/') {
+            path = &path[..pos];
+            paths.insert(path);
+        }
+    }
+    if paths.len() > BLOOM_MAX_CHANGED_PATHS {
+        return vec![0xff];
+    }
+
+    let len = (paths.len() * BLOOM_BITS_PER_ENTRY as usize).div_ceil(8).max(1);
+    let mut filter = vec![0u8; len];
+    for path in paths {
+        for pos in bloom_positions(path, len as u64 * 8) {
+            filter[(pos / 8) as usize] |= 1 << (pos % 8);
+        }
+    }
+
+    filter
+}
+
+/// What the commit-graph records about one commit.
+pub struct GraphCommit {
+    pub tree: ObjectId,
+    pub parents: Vec<ObjectId>,
+    pub date: u64,
+    /// The corrected commit date when the file has one, else the
+    /// topological level.
+    pub generation: u64,
+}
+
+/// A parsed `.git/objects/info/commit-graph` file.
+pub struct CommitGraphFile {
+    data: Vec<u8>,
+    hash_size: usize,
+    num_commits: usize,
+    chunks: HashMap<[u8; 4], (usize, usize)>,
+}
+
+impl CommitGraphFile {
+    /// Reads the repository's commit-graph, if it has one.
+    pub fn open() -> Result<Option<Self>> {
+        let path = graph_path();
+        // a shallow clone's commits have parents the graph cannot leave out
+        if !path.is_file() || shallow::is_shallow() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self::parse(fs::read(path)?)?))
+    }
+
+    pub fn parse(data: Vec<u8>) -> Result<Self> {
+        let algorithm = HashAlgorithm::current();
+        let hash_size = algorithm.size();
+        if data.len() < 8 + 12 + hash_size || &data[..4] != SIGNATURE {
+            bail!("commit-graph signature does not match");
+        }
+        if data[4] != VERSION {
+            bail!("commit-graph version {} does not match version {}", data[4], VERSION);
+        }
+        if data[5] != algorithm.format_id() {
+            bail!("commit-graph hash version {} does not match version {}", data[5], algorithm.format_id());
+        }
+
+        // the table of contents ends with an entry marking where the last
+        // chunk stops
+        let num_chunks = data[6] as usize;
+        let table_end = 8 + (num_chunks + 1) * 12;
+        if data.len() < table_end + hash_size {
+            bail!("commit-graph file is too small to hold {} chunks", num_chunks);
+        }
+
+        let mut chunks = HashMap::new();
+        for i in 0..num_chunks {
+            let entry = 8 + i * 12;
+            let id: [u8; 4] = data[entry..entry + 4].try_into()?;
+            let start = read_u64(&data, entry + 4) as usize;
+            let end = read_u64(&data, entry + 16) as usize;
+            if start < table_end || end < start || end > data.len() - hash_size {
+                bail!("improper chunk offset(s) {:x} and {:x}", start, end);
+            }
+            chunks.insert(id, (start, end - start));
+        }
+
+        let chunk_size = |id: &[u8; 4], name: &str| match chunks.get(id) {
+            Some((_, size)) => Ok(*size),
+            None => bail!("commit-graph required {} chunk missing or corrupted", name),
+        };
+        if chunk_size(CHUNK_FANOUT, "OID fanout")? != 256 * 4 {
+            bail!("commit-graph OID fanout chunk is the wrong size");
+        }
+        let num_commits = read_u32(&data, chunks[CHUNK_FANOUT].0 + 255 * 4) as usize;
+        if chunk_size(CHUNK_OIDS, "OID lookup")? != num_commits * hash_size {
+            bail!("commit-graph OID lookup chunk is the wrong size");
+        }
+        if chunk_size(CHUNK_COMMITS, "commit data")? != num_commits * (hash_size + COMMIT_DATA_EXTRA) {
+            bail!("commit-graph commit data chunk is the wrong size");
+        }
+        for (id, name) in &[(CHUNK_GENERATIONS, "generation data"), (CHUNK_BLOOM_INDEX, "changed-path index")] {
+            if chunks.get(*id).is_some_and(|(_, size)| *size != num_commits * 4) {
+                bail!("commit-graph {} chunk is the wrong size", name);
+            }
+        }
+        if chunks.get(CHUNK_BLOOM_DATA).is_some_and(|(_, size)| *size < 12) {
+            bail!("commit-graph changed-path data chunk is too small");
+        }
+
+        Ok(Self {
+            data,
+            hash_size,
+            num_commits,
+            chunks,
+        })
+    }
+
+    fn chunk(&self, id: &[u8; 4]) -> Option<&[u8]> {
+        self.chunks
+            .get(id)
+            .map(|(start, size)| &self.data[*start..*start + *size])
+    }
+
+    fn fanout(&self, byte: usize) -> usize {
+        read_u32(self.chunk(CHUNK_FANOUT).unwrap(), byte * 4) as usize
+    }
+
+    pub fn oid(&self, pos: usize) -> ObjectId {
+        let size = self.hash_size;
+        ObjectId::from_bytes(&self.chunk(CHUNK_OIDS).unwrap()[pos * size..(pos + 1) * size]).unwrap()
+    }
+
+    /// Where a commit sits in the file, found through the fanout table.
+    pub fn position(&self, sha1_hash: &ObjectId) -> Option<usize> {
+        let first = sha1_hash[0] as usize;
+        let start = if first == 0 { 0 } else { self.fanout(first - 1) };
+        let end = self.fanout(first);
+        if start > end || end > self.num_commits {
+            return None;
+        }
+
+        let oids = self.chunk(CHUNK_OIDS).unwrap();
+        let (mut low, mut high) = (start, end);
+        while low < high {
+            let mid = (low + high) / 2;
+            match oids[mid * self.hash_size..(mid + 1) * self.hash_size].cmp(&sha1_hash[..]) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(mid),
+            }
+        }
+
+        None
+    }
+
+    fn parent_oid(&self, pos: u32) -> Result<ObjectId> {
+        if pos as usize >= self.num_commits {
+            bail!("commit-graph parent position {} is out of range", pos);
+        }
+
+        Ok(self.oid(pos as usize))
+    }
+
+    pub fn commit(&self, pos: usize) -> Result<GraphCommit> {
+        let record_size = self.hash_size + COMMIT_DATA_EXTRA;
+        let (tree, record) = self.chunk(CHUNK_COMMITS).unwrap()[pos * record_size..(pos + 1) * record_size]
+            .split_at(self.hash_size);
+
+        let mut parents = Vec::new();
+        let first = read_u32(record, 0);
+        let second = read_u32(record, 4);
+        if first != PARENT_NONE {
+            parents.push(self.parent_oid(first)?);
+        }
+        if second & EXTRA_EDGES != 0 {
+            let edges = self
+                .chunk(CHUNK_EXTRA_EDGES)
+                .context("commit-graph extra edges chunk missing")?;
+            let mut i = (second & !EXTRA_EDGES) as usize;
+            loop {
+                if (i + 1) * 4 > edges.len() {
+                    bail!("commit-graph extra edges run past the end of the chunk");
+                }
+                let edge = read_u32(edges, i * 4);
+                parents.push(self.parent_oid(edge & !EXTRA_EDGES)?);
+                if edge & EXTRA_EDGES != 0 {
+                    break;
+                }
+                i += 1;
+            }
+        } else if second != PARENT_NONE {
+            parents.push(self.parent_oid(second)?);
+        }
+
+        let high = read_u32(record, 8);
+        let date = ((high as u64 & 3) << 32) | read_u32(record, 12) as u64;
+
+        let generation = match self.chunk(CHUNK_GENERATIONS) {
+            Some(generations) => {
+                let offset = read_u32(generations, pos * 4);
+                if offset & OFFSET_OVERFLOW != 0 {
+                    let overflow = self
+                        .chunk(CHUNK_GENERATION_OVERFLOW)
+                        .context("commit-graph generation overflow chunk missing")?;
+                    let i = (offset & !OFFSET_OVERFLOW) as usize;
+                    if (i + 1) * 8 > overflow.len() {
+                        bail!("commit-graph generation overflow index {} is out of range", i);
+                    }
+                    date + read_u64(overflow, i * 8)
+                } else {
+                    date + offset as u64
+                }
+            }
+            None => (high >> 2) as u64,
+        };
+
+        Ok(GraphCommit {
+            tree: ObjectId::from_bytes(tree)?,
+            parents,
+            date,
+            generation,
+        })
+    }
+
+    pub fn has_bloom_filters(&self) -> bool {
+        self.chunk(CHUNK_BLOOM_INDEX).is_some() && self.chunk(CHUNK_BLOOM_DATA).is_some()
+    }
+
+    /// The changed-path filter stored for the commit at `pos`, if any.
+    pub fn bloom_filter(&self, pos: usize) -> Option<&[u8]> {
+        let index = self.chunk(CHUNK_BLOOM_INDEX)?;
+        let data = &self.chunk(CHUNK_BLOOM_DATA)?[12..];
+
+        let start = if pos == 0 { 0 } else { read_u32(index, (pos - 1) * 4) as usize };
+        let end = read_u32(index, pos * 4) as usize;
+        if start > end || end > data.len() {
+            return None;
+        }
+
+        Some(&data[start..end])
+    }
+
+    /// Checks the file against itself and against the commits it
+    /// describes, returning a description of each problem found.
+    pub async fn verify(&self) -> Result<Vec<String>> {
+        let mut problems = Vec::new();
+
+        let checksum_at = self.data.len() - self.hash_size;
+        if HashAlgorithm::current().digest(&self.data[..checksum_at]).as_bytes() != &self.data[checksum_at..] {
+            problems.push(String::from(
+                "the commit-graph file has incorrect checksum and is likely corrupt",
+            ));
+        }
+
+        for pos in 1..self.num_commits {
+            if self.oid(pos - 1) >= self.oid(pos) {
+                problems.push(format!(
+                    "commit-graph has incorrect OID order: {} then {}",
+                    hex::encode(self.oid(pos - 1)),
+                    hex::encode(self.oid(pos))
+                ));
+            }
+        }
+
+        let mut expected = 0;
+        for byte in 0..256 {
+            while expected < self.num_commits && self.oid(expected)[0] as usize <= byte {
+                expected += 1;
+            }
+            if self.fanout(byte) != expected {
+                problems.push(format!(
+                    "commit-graph has incorrect fanout value: fanout[{}] = {} != {}",
+                    byte,
+                    self.fanout(byte),
+                    expected
+                ));
+            }
+        }
+        if !problems.is_empty() {
+            return Ok(problems);
+        }
+
+        for pos in 0..self.num_commits {
+            let oid = hex::encode(self.oid(pos));
+            let commit = match Commit::from_object_sha(oid.clone()).await {
+                Ok(commit) => commit,
+                Err(_) => {
+                    problems.push(format!(
+                        "failed to parse commit {} from object database for commit-graph",
+                        oid
+                    ));
+                    continue;
+                }
+            };
+            let graph_commit = match self.commit(pos) {
+                Ok(graph_commit) => graph_commit,
+                Err(e) => {
+                    problems.push(format!("{} for commit {}", e, oid));
+                    continue;
+                }
+            };
+
+            if graph_commit.tree != commit.tree_sha() {
+                problems.push(format!(
+                    "root tree OID for commit {} in commit-graph is {} != {}",
+                    oid,
+                    hex::encode(graph_commit.tree),
+                    hex::encode(commit.tree_sha())
+                ));
+            }
+
+            for (i, parent) in commit.parents().iter().enumerate() {
+                match graph_commit.parents.get(i) {
+                    Some(graph_parent) if graph_parent != parent => problems.push(format!(
+                        "commit-graph parent for {} is {} != {}",
+                        oid,
+                        hex::encode(graph_parent),
+                        hex::encode(parent)
+                    )),
+                    Some(_) => {}
+                    None => {
+                        problems.push(format!("commit-graph parent list for commit {} terminates early", oid));
+                        break;
+                    }
+                }
+            }
+            if graph_commit.parents.len() > commit.parents().len() {
+                problems.push(format!("commit-graph parent list for commit {} is too long", oid));
+            }
+
+            let mut max_generation = 0;
+            for parent in &graph_commit.parents {
+                if let Some(parent_pos) = self.position(parent) {
+                    max_generation = max_generation.max(self.commit(parent_pos)?.generation);
+                }
+            }
+            if graph_commit.generation < max_generation + 1 {
+                problems.push(format!(
+                    "commit-graph generation for commit {} is {} < {}",
+                    oid,
+                    graph_commit.generation,
+                    max_generation + 1
+                ));
+            }
+
+            let date: u64 = commit.committer().timestamp.parse().unwrap_or(0);
+            if graph_commit.date != date {
+                problems.push(format!(
+                    "commit date for commit {} in commit-graph is {} != {}",
+                    oid, graph_commit.date, date
+                ));
+            }
+        }
+
+        Ok(problems)
+    }
+}
+
+// what the writer needs to know about each commit
+struct Entry {
+    tree: ObjectId,
+    parents: Vec<ObjectId>,
+    date: u64,
+    topo_level: u32,
+    corrected_date: u64,
+}
+
+// the paths a commit changed relative to its first parent
+async fn changed_paths(entry: &Entry, commits: &HashMap<ObjectId, Entry>) -> Result<Vec<Vec<u8>>> {
+    let parent_tree = entry.parents.first().map(|parent| commits[parent].tree);
+    let options = TreeDiffOptions {
+        recursive: true,
+        ..TreeDiffOptions::default()
+    };
+
+    let mut paths = Vec::new();
+    for change in diff::diff_trees(parent_tree, Some(entry.tree), &options).await? {
+        for side in change.old.iter().chain(change.new.iter()) {
+            if !paths.contains(&side.path) {
+                paths.push(side.path.clone());
+            }
+        }
+    }
+
+    Ok(paths)
+}
+
+/// Writes a commit-graph covering every commit reachable from `tips`.
+/// Changed-path Bloom filters are written when asked for, or when the
+/// graph being replaced had them; filters it already holds are reused.
+/// A shallow clone gets no graph, as with git.
+pub async fn write(tips: &[ObjectId], bloom: bool) -> Result<()> {
+    if shallow::is_shallow() {
+        return Ok(());
+    }
+
+    let existing = CommitGraphFile::open().unwrap_or(None);
+    let bloom = bloom || existing.as_ref().is_some_and(|file| file.has_bloom_filters());
+
+    let mut commits: HashMap<ObjectId, Entry> = HashMap::new();
+    let mut pending: Vec<ObjectId> = tips.to_vec();
+    while let Some(sha1_hash) = pending.pop() {
+        if commits.contains_key(&sha1_hash) {
+            continue;
+        }
+
+        let commit = Commit::from_object_sha(hex::encode(sha1_hash)).await?;
+        pending.extend(commit.parents().iter().copied());
+        commits.insert(
+            sha1_hash,
+            Entry {
+                tree: commit.tree_sha(),
+                parents: commit.parents().to_vec(),
+                date: commit.committer().timestamp.parse().unwrap_or(0),
+                topo_level: 0,
+                corrected_date: 0,
+            },
+        );
+    }
+
+    // like git, leave no file behind for a history without commits
+    if commits.is_empty() {
+        return Ok(());
+    }
+
+    // levels and corrected dates, parents before children; a stack rather
+    // than recursion since histories can be very deep
+    let mut oids: Vec<ObjectId> = commits.keys().copied().collect();
+    oids.sort();
+    for oid in &oids {
+        let mut stack = vec![*oid];
+        while let Some(sha1_hash) = stack.last().copied() {
+            if commits[&sha1_hash].topo_level != 0 {
+                stack.pop();
+                continue;
+            }
+
+            let parents = commits[&sha1_hash].parents.clone();
+            let todo: Vec<ObjectId> = parents
+                .iter()
+                .filter(|p| commits[*p].topo_level == 0)
+                .copied()
+                .collect();
+            if !todo.is_empty() {
+                stack.extend(todo);
+                continue;
+            }
+
+            let max_level = parents.iter().map(|p| commits[p].topo_level).max().unwrap_or(0);
+            let mut max_corrected = parents.iter().map(|p| commits[p].corrected_date).max().unwrap_or(0);
+            let entry = commits.get_mut(&sha1_hash).unwrap();
+            if entry.date > 0 && entry.date > max_corrected {
+                max_corrected = entry.date - 1;
+            }
+            entry.topo_level = (max_level + 1).min(TOPO_LEVEL_MAX);
+            entry.corrected_date = max_corrected + 1;
+            stack.pop();
+        }
+    }
+
+    let positions: HashMap<ObjectId, u32> = oids
+        .iter()
+        .enumerate()
+        .map(|(i, oid)| (*oid, i as u32))
+        .collect();
+
+    let mut fanout = Vec::with_capacity(256 * 4);
+    let mut count = 0;
+    for byte in 0..256 {
+        while count < oids.len() && oids[count][0] as usize <= byte {
+            count += 1;
+        }
+        fanout.extend_from_slice(&(count as u32).to_be_bytes());
+    }
+
+    let algorithm = HashAlgorithm::current();
+    let mut oid_lookup = Vec::with_capacity(oids.len() * algorithm.size());
+    let mut commit_data = Vec::with_capacity(oids.len() * (algorithm.size() + COMMIT_DATA_EXTRA));
+    let mut generations = Vec::with_capacity(oids.len() * 4);
+    let mut overflow = Vec::new();
+    let mut extra_edges: Vec<u32> = Vec::new();
+
+    for oid in &oids {
+        let entry = &commits[oid];
+        oid_lookup.extend_from_slice(oid);
+        commit_data.extend_from_slice(&entry.tree);
+
+        let parents: Vec<u32> = entry.parents.iter().map(|p| positions[p]).collect();
+        let first = parents.first().copied().unwrap_or(PARENT_NONE);
+        let second = match parents.len() {
+            0 | 1 => PARENT_NONE,
+            2 => parents[1],
+            _ => {
+                let second = EXTRA_EDGES | extra_edges.len() as u32;
+                extra_edges.extend(&parents[1..]);
+                *extra_edges.last_mut().unwrap() |= EXTRA_EDGES;
+                second
+            }
+        };
+        commit_data.extend_from_slice(&first.to_be_bytes());
+        commit_data.extend_from_slice(&second.to_be_bytes());
+        commit_data.extend_from_slice(&((entry.topo_level << 2) | ((entry.date >> 32) as u32 & 3)).to_be_bytes());
+        commit_data.extend_from_slice(&(entry.date as u32).to_be_bytes());
+
+        let offset = entry.corrected_date - entry.date;
+        if offset >= OFFSET_OVERFLOW as u64 {
+            generations.extend_from_slice(&(OFFSET_OVERFLOW | (overflow.len() / 8) as u32).to_be_bytes());
+            overflow.extend_from_slice(&offset.to_be_bytes());
+        } else {
+            generations.extend_from_slice(&(offset as u32).to_be_bytes());
+        }
+    }
+
+    let mut chunks: Vec<(&[u8; 4], Vec<u8>)> = vec![
+        (CHUNK_FANOUT, fanout),
+        (CHUNK_OIDS, oid_lookup),
+        (CHUNK_COMMITS, commit_data),
+        (CHUNK_GENERATIONS, generations),
+    ];
+    if !overflow.is_empty() {
+        chunks.push((CHUNK_GENERATION_OVERFLOW, overflow));
+    }
+    if !extra_edges.is_empty() {
+        chunks.push((
+            CHUNK_EXTRA_EDGES,
+            extra_edges.iter().flat_map(|e| e.to_be_bytes().to_vec()).collect(),
+        ));
+    }
+
+    if bloom {
+        let mut index = Vec::with_capacity(oids.len() * 4);
+        let mut data = Vec::new();
+        for value in &[BLOOM_VERSION, BLOOM_HASHES, BLOOM_BITS_PER_ENTRY] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+
+        for oid in &oids {
+            let reused = existing.as_ref().and_then(|file| {
+                file.position(oid)
+                    .and_then(|pos| file.bloom_filter(pos))
+                    .filter(|filter| !filter.is_empty())
+                    .map(|filter| filter.to_vec())
+            });
+            let filter = match reused {
+                Some(filter) => filter,
+                None => bloom_filter(&changed_paths(&commits[oid], &commits).await?),
+            };
+
+            data.extend_from_slice(&filter);
+            index.extend_from_slice(&((data.len() - 12) as u32).to_be_bytes());
+        }
+
+        chunks.push((CHUNK_BLOOM_INDEX, index));
+        chunks.push((CHUNK_BLOOM_DATA, data));
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(SIGNATURE);
+    out.extend_from_slice(&[VERSION, algorithm.format_id(), chunks.len() as u8, 0]);
+
+    let mut offset = (8 + (chunks.len() + 1) * 12) as u64;
+    for (id, contents) in &chunks {
+        out.extend_from_slice(*id);
+        out.extend_from_slice(&offset.to_be_bytes());
+        offset += contents.len() as u64;
+    }
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&offset.to_be_bytes());
+
+    for (_, contents) in &chunks {
+        out.extend_from_slice(contents);
+    }
+    let checksum = algorithm.digest(&out);
+    out.extend_from_slice(&checksum);
+
+    // git leaves the file read-only; renaming over it still works
+    let path = graph_path();
+    fs::create_dir_all(path.parent().unwrap())?;
+    let lock = path.with_extension("lock");
+    fs::write(&lock, out)?;
+    fs::set_permissions(&lock, fs::Permissions::from_mode(0o444))?;
+    fs::rename(lock, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_murmur3() {
+        assert_eq!(murmur3(0, b""), 0);
+        assert_eq!(murmur3(0, b"Hello world!"), 0x627b_0c2c);
+        assert_eq!(murmur3(0, b"The quick brown fox jumps over the lazy dog"), 0x2e4f_f723);
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        assert_eq!(bloom_filter(&[]), vec![0]);
+
+        let filter = bloom_filter(&[b"src/main.rs".to_vec()]);
+        // two paths, "src" and "src/main.rs", at ten bits each
+        assert_eq!(filter.len(), 3);
+        for path in [&b"src"[..], b"src/main.rs"] {
+            for pos in bloom_positions(path, filter.len() as u64 * 8) {
+                assert_ne!(filter[(pos / 8) as usize] & (1 << (pos % 8)), 0);
+            }
+        }
+
+        let many: Vec<Vec<u8>> = (0..=BLOOM_MAX_CHANGED_PATHS).map(|i| i.to_string().into_bytes()).collect();
+        assert_eq!(bloom_filter(&many), vec![0xff]);
+    }
+}
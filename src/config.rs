@@ -0,0 +1,270 @@
+use anyhow::{bail, Result};
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone)]
+struct Section {
+    name: String,
+    subsection: Option<String>,
+    entries: Vec<(String, String)>,
+}
+
+impl Section {
+    fn matches(&self, name: &str, subsection: Option<&str>) -> bool {
+        self.name.eq_ignore_ascii_case(name) && self.subsection.as_deref() == subsection
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    path: PathBuf,
+    sections: Vec<Section>,
+}
+
+// "branch.topic/x.remote" -> ("branch", Some("topic/x"), "remote")
+fn split_key(key: &str) -> Result<(&str, Option<&str>, &str)> {
+    let first = match key.find('.') {
+        Some(pos) => pos,
+        None => bail!("key does not contain a section: {}", key),
+    };
+    let last = key.rfind('.').unwrap();
+
+    let section = &key[..first];
+    let name = &key[last + 1..];
+    let subsection = if first == last {
+        None
+    } else {
+        Some(&key[first + 1..last])
+    };
+
+    Ok((section, subsection, name))
+}
+
+fn parse_value(raw: &str) -> String {
+    let mut value = String::new();
+    let mut in_quotes = false;
+    let mut chars = raw.trim().chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '\\' => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some(other) => value.push(other),
+                None => {}
+            },
+            '#' | ';' if !in_quotes => break,
+            _ => value.push(c),
+        }
+    }
+
+    value.trim_end().to_string()
+}
+
+fn format_value(value: &str) -> String {
+    let needs_quotes = value.starts_with(' ')
+        || value.ends_with(' ')
+        || value.contains('#')
+        || value.contains(';');
+
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t");
+
+    if needs_quotes {
+        format!("\"{}\"", escaped)
+    } else {
+        escaped
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        Self::load_from(PathBuf::from(".git/config"))
+    }
+
+    pub fn load_from(path: PathBuf) -> Result<Self> {
+        let contents = if path.exists() {
+            fs::read_to_string(&path)?
+        } else {
+            String::new()
+        };
+
+        let mut sections: Vec<Section> = Vec::new();
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                let end = match line.find(']') {
+                    Some(end) => end,
+                    None => bail!("bad config line in {}: {}", path.display(), line),
+                };
+                let header = &line[1..end];
+
+                let section = match header.find(" \"") {
+                    Some(pos) => Section {
+                        name: header[..pos].to_lowercase(),
+                        subsection: Some(header[pos + 2..].trim_end_matches('"').to_string()),
+                        entries: Vec::new(),
+                    },
+                    None => Section {
+                        name: header.to_lowercase(),
+                        subsection: None,
+                        entries: Vec::new(),
+                    },
+                };
+                sections.push(section);
+                continue;
+            }
+
+            let section = match sections.last_mut() {
+                Some(section) => section,
+                None => bail!("bad config line in {}: {}", path.display(), line),
+            };
+
+            match line.find('=') {
+                Some(pos) => section.entries.push((
+                    line[..pos].trim().to_lowercase(),
+                    parse_value(&line[pos + 1..]),
+                )),
+                // a bare key is an implicit boolean true
+                None => section
+                    .entries
+                    .push((line.to_lowercase(), String::from("true"))),
+            }
+        }
+
+        Ok(Self { path, sections })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let mut out = String::new();
+
+        for section in &self.sections {
+            match &section.subsection {
+                Some(sub) => out.push_str(&format!("[{} \"{}\"]\n", section.name, sub)),
+                None => out.push_str(&format!("[{}]\n", section.name)),
+            }
+
+            for (key, value) in &section.entries {
+                out.push_str(&format!("\t{} = {}\n", key, format_value(value)));
+            }
+        }
+
+        fs::write(&self.path, out)?;
+
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.get_all(key).pop()
+    }
+
+    pub fn get_all(&self, key: &str) -> Vec<String> {
+        let (section, subsection, name) = match split_key(key) {
+            Ok(parts) => parts,
+            Err(_) => return Vec::new(),
+        };
+        let name = name.to_lowercase();
+
+        self.sections
+            .iter()
+            .filter(|s| s.matches(section, subsection))
+            .flat_map(|s| s.entries.iter())
+            .filter(|(k, _)| *k == name)
+            .map(|(_, v)| v.clone())
+            .collect()
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let (section, subsection, name) = split_key(key)?;
+        let name = name.to_lowercase();
+
+        let existing = self
+            .sections
+            .iter_mut()
+            .rev()
+            .find(|s| s.matches(section, subsection));
+
+        let target = match existing {
+            Some(target) => target,
+            None => {
+                self.sections.push(Section {
+                    name: section.to_lowercase(),
+                    subsection: subsection.map(String::from),
+                    entries: Vec::new(),
+                });
+                self.sections.last_mut().unwrap()
+            }
+        };
+
+        match target.entries.iter_mut().find(|(k, _)| *k == name) {
+            Some(entry) => entry.1 = value.to_string(),
+            None => target.entries.push((name, value.to_string())),
+        }
+
+        Ok(())
+    }
+
+    /// The subsections of `section` in the order they first appear, e.g. the
+    /// names in `[submodule "name"]`.
+    pub fn subsections(&self, section: &str) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for s in self.sections.iter().filter(|s| s.name.eq_ignore_ascii_case(section)) {
+            if let Some(sub) = &s.subsection {
+                if !names.contains(sub) {
+                    names.push(sub.clone());
+                }
+            }
+        }
+
+        names
+    }
+
+    pub fn remove_section(&mut self, section: &str, subsection: Option<&str>) {
+        self.sections.retain(|s| !s.matches(section, subsection));
+    }
+
+    pub fn rename_section(&mut self, section: &str, old: Option<&str>, new: Option<&str>) {
+        for s in self.sections.iter_mut().filter(|s| s.matches(section, old)) {
+            s.subsection = new.map(String::from);
+        }
+    }
+}
+
+/// Looks `key` up in the repository's config, then in ~/.gitconfig.
+pub fn lookup(key: &str) -> Result<Option<String>> {
+    if let Some(value) = Config::load()?.get(key) {
+        return Ok(Some(value));
+    }
+
+    match std::env::var("HOME") {
+        Ok(home) => Ok(Config::load_from(PathBuf::from(home).join(".gitconfig"))?.get(key)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// The name and email to record as `kind` (`AUTHOR` or `COMMITTER`), from
+/// the environment or user.* config.
+pub fn identity(kind: &str) -> Result<(String, String)> {
+    let name = match std::env::var(format!("GIT_{}_NAME", kind)) {
+        Ok(name) => Some(name),
+        Err(_) => lookup("user.name")?,
+    };
+    let email = match std::env::var(format!("GIT_{}_EMAIL", kind)) {
+        Ok(email) => Some(email),
+        Err(_) => lookup("user.email")?,
+    };
+
+    match (name, email) {
+        (Some(name), Some(email)) => Ok((name, email)),
+        _ => bail!("Author identity unknown\nPlease set user.name and user.email in your git config."),
+    }
+}
@@ -0,0 +1,325 @@
+use super::line::Side;
+
+// the indent heuristic's weights, tuned empirically by git (see xdiffi.c)
+const MAX_INDENT: i32 = 200;
+const MAX_BLANKS: i32 = 20;
+const START_OF_FILE_PENALTY: i32 = 1;
+const END_OF_FILE_PENALTY: i32 = 21;
+const TOTAL_BLANK_WEIGHT: i32 = -30;
+const POST_BLANK_WEIGHT: i32 = 6;
+const RELATIVE_INDENT_PENALTY: i32 = -4;
+const RELATIVE_INDENT_WITH_BLANK_PENALTY: i32 = 10;
+const RELATIVE_OUTDENT_PENALTY: i32 = 24;
+const RELATIVE_OUTDENT_WITH_BLANK_PENALTY: i32 = 17;
+const RELATIVE_DEDENT_PENALTY: i32 = 23;
+const RELATIVE_DEDENT_WITH_BLANK_PENALTY: i32 = 17;
+const INDENT_WEIGHT: i32 = 60;
+const INDENT_HEURISTIC_MAX_SLIDING: isize = 100;
+
+fn is_space(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\n' | b'\x0b' | b'\x0c' | b'\r')
+}
+
+// columns of indentation with tabs as 8, or -1 for a blank line
+fn get_indent(line: &[u8]) -> i32 {
+    let mut indent = 0;
+
+    for byte in line {
+        if !is_space(*byte) {
+            return indent;
+        } else if *byte == b' ' {
+            indent += 1;
+        } else if *byte == b'\t' {
+            indent += 8 - indent % 8;
+        }
+
+        if indent >= MAX_INDENT {
+            return MAX_INDENT;
+        }
+    }
+
+    -1
+}
+
+struct SplitMeasurement {
+    end_of_file: bool,
+    indent: i32,
+    pre_blank: i32,
+    pre_indent: i32,
+    post_blank: i32,
+    post_indent: i32,
+}
+
+#[derive(Clone, Copy, Default)]
+struct SplitScore {
+    effective_indent: i32,
+    penalty: i32,
+}
+
+fn measure_split(side: &Side, split: isize) -> SplitMeasurement {
+    let len = side.len() as isize;
+
+    let (end_of_file, indent) = if split >= len {
+        (true, -1)
+    } else {
+        (false, get_indent(side.lines[split as usize]))
+    };
+
+    let mut pre_blank = 0;
+    let mut pre_indent = -1;
+    let mut i = split - 1;
+    while i >= 0 {
+        pre_indent = get_indent(side.lines[i as usize]);
+        if pre_indent != -1 {
+            break;
+        }
+        pre_blank += 1;
+        if pre_blank == MAX_BLANKS {
+            pre_indent = 0;
+            break;
+        }
+        i -= 1;
+    }
+
+    let mut post_blank = 0;
+    let mut post_indent = -1;
+    let mut i = split + 1;
+    while i < len {
+        post_indent = get_indent(side.lines[i as usize]);
+        if post_indent != -1 {
+            break;
+        }
+        post_blank += 1;
+        if post_blank == MAX_BLANKS {
+            post_indent = 0;
+            break;
+        }
+        i += 1;
+    }
+
+    SplitMeasurement {
+        end_of_file,
+        indent,
+        pre_blank,
+        pre_indent,
+        post_blank,
+        post_indent,
+    }
+}
+
+fn score_add_split(m: &SplitMeasurement, s: &mut SplitScore) {
+    if m.pre_indent == -1 && m.pre_blank == 0 {
+        s.penalty += START_OF_FILE_PENALTY;
+    }
+    if m.end_of_file {
+        s.penalty += END_OF_FILE_PENALTY;
+    }
+
+    let post_blank = if m.indent == -1 { 1 + m.post_blank } else { 0 };
+    let total_blank = m.pre_blank + post_blank;
+
+    s.penalty += TOTAL_BLANK_WEIGHT * total_blank;
+    s.penalty += POST_BLANK_WEIGHT * post_blank;
+
+    let indent = if m.indent != -1 { m.indent } else { m.post_indent };
+    let any_blanks = total_blank != 0;
+
+    s.effective_indent += indent;
+
+    if indent == -1 || m.pre_indent == -1 {
+        // no adjustment
+    } else if indent > m.pre_indent {
+        s.penalty += if any_blanks {
+            RELATIVE_INDENT_WITH_BLANK_PENALTY
+        } else {
+            RELATIVE_INDENT_PENALTY
+        };
+    } else if indent == m.pre_indent {
+        // no adjustment
+    } else if m.post_indent != -1 && m.post_indent > indent {
+        // probably the start of a new block
+        s.penalty += if any_blanks {
+            RELATIVE_OUTDENT_WITH_BLANK_PENALTY
+        } else {
+            RELATIVE_OUTDENT_PENALTY
+        };
+    } else {
+        // probably the end of a block
+        s.penalty += if any_blanks {
+            RELATIVE_DEDENT_WITH_BLANK_PENALTY
+        } else {
+            RELATIVE_DEDENT_PENALTY
+        };
+    }
+}
+
+fn score_cmp(s1: &SplitScore, s2: &SplitScore) -> i32 {
+    let cmp_indents = (s1.effective_indent > s2.effective_indent) as i32
+        - (s1.effective_indent < s2.effective_indent) as i32;
+
+    INDENT_WEIGHT * cmp_indents + (s1.penalty - s2.penalty)
+}
+
+// a run of changed lines, start..end; empty groups sit between unchanged lines
+#[derive(Clone, Copy)]
+struct Group {
+    start: isize,
+    end: isize,
+}
+
+impl Group {
+    fn first(side: &Side) -> Self {
+        let mut end = 0;
+        while side.is_changed(end) {
+            end += 1;
+        }
+
+        Group { start: 0, end }
+    }
+
+    fn next(&mut self, side: &Side) -> bool {
+        if self.end == side.len() as isize {
+            return false;
+        }
+
+        self.start = self.end + 1;
+        self.end = self.start;
+        while side.is_changed(self.end) {
+            self.end += 1;
+        }
+
+        true
+    }
+
+    fn previous(&mut self, side: &Side) -> bool {
+        if self.start == 0 {
+            return false;
+        }
+
+        self.end = self.start - 1;
+        self.start = self.end;
+        while side.is_changed(self.start - 1) {
+            self.start -= 1;
+        }
+
+        true
+    }
+
+    fn slide_down(&mut self, side: &mut Side) -> bool {
+        if self.end < side.len() as isize
+            && side.classes[self.start as usize] == side.classes[self.end as usize]
+        {
+            side.set_changed(self.start, false);
+            side.set_changed(self.end, true);
+            self.start += 1;
+            self.end += 1;
+            while side.is_changed(self.end) {
+                self.end += 1;
+            }
+
+            return true;
+        }
+
+        false
+    }
+
+    fn slide_up(&mut self, side: &mut Side) -> bool {
+        if self.start > 0
+            && side.classes[self.start as usize - 1] == side.classes[self.end as usize - 1]
+        {
+            self.start -= 1;
+            self.end -= 1;
+            side.set_changed(self.start, true);
+            side.set_changed(self.end, false);
+            while side.is_changed(self.start - 1) {
+                self.start -= 1;
+            }
+
+            return true;
+        }
+
+        false
+    }
+}
+
+/// Slides groups of changed lines in `side` to where git would show them:
+/// merged with neighbouring changes, lined up with changes on the `other`
+/// side, or else at the position the indent heuristic scores best (as far
+/// down as possible without it).
+pub fn compact(side: &mut Side, other: &Side, indent_heuristic: bool) {
+    let mut g = Group::first(side);
+    let mut go = Group::first(other);
+
+    loop {
+        if g.end != g.start {
+            let mut groupsize;
+            let mut earliest_end;
+            let mut end_matching_other;
+
+            loop {
+                groupsize = g.end - g.start;
+                end_matching_other = -1;
+
+                while g.slide_up(side) {
+                    go.previous(other);
+                }
+                earliest_end = g.end;
+                if go.end > go.start {
+                    end_matching_other = g.end;
+                }
+
+                while g.slide_down(side) {
+                    go.next(other);
+                    if go.end > go.start {
+                        end_matching_other = g.end;
+                    }
+                }
+
+                if groupsize == g.end - g.start {
+                    break;
+                }
+            }
+
+            if g.end == earliest_end {
+                // no shifting was possible
+            } else if end_matching_other != -1 {
+                while go.end == go.start {
+                    g.slide_up(side);
+                    go.previous(other);
+                }
+            } else if indent_heuristic {
+                let mut shift = earliest_end;
+                if g.end - groupsize - 1 > shift {
+                    shift = g.end - groupsize - 1;
+                }
+                if g.end - INDENT_HEURISTIC_MAX_SLIDING > shift {
+                    shift = g.end - INDENT_HEURISTIC_MAX_SLIDING;
+                }
+
+                let mut best_shift = -1;
+                let mut best_score = SplitScore::default();
+                while shift <= g.end {
+                    let mut score = SplitScore::default();
+                    score_add_split(&measure_split(side, shift), &mut score);
+                    score_add_split(&measure_split(side, shift - groupsize), &mut score);
+
+                    if best_shift == -1 || score_cmp(&score, &best_score) <= 0 {
+                        best_score = score;
+                        best_shift = shift;
+                    }
+                    shift += 1;
+                }
+
+                while g.end > best_shift {
+                    g.slide_up(side);
+                    go.previous(other);
+                }
+            }
+        }
+
+        if !g.next(side) {
+            break;
+        }
+        go.next(other);
+    }
+}
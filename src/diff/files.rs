@@ -0,0 +1,155 @@
+use anyhow::Result;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+
+use super::tree::DiffEntry;
+use crate::checkout;
+use crate::index::Index;
+use crate::objects::{Blob, Object, ObjectId};
+
+/// Every file of a tree, the index or the working tree, by path.
+pub type Listing = BTreeMap<Vec<u8>, DiffEntry>;
+
+/// Blob contents by object id. Working tree files that are not in the
+/// object store are seeded in by `worktree_listing`.
+#[derive(Default)]
+pub struct BlobCache {
+    blobs: HashMap<ObjectId, Vec<u8>>,
+}
+
+impl BlobCache {
+    pub fn insert(&mut self, sha1_hash: ObjectId, contents: Vec<u8>) {
+        self.blobs.insert(sha1_hash, contents);
+    }
+
+    pub async fn get(&mut self, sha1_hash: ObjectId) -> Result<&[u8]> {
+        if let Entry::Vacant(entry) = self.blobs.entry(sha1_hash) {
+            let blob = Blob::from_object_sha(hex::encode(sha1_hash)).await?;
+            entry.insert(blob.contents().to_vec());
+        }
+
+        Ok(&self.blobs[&sha1_hash])
+    }
+
+    /// What a diff shows for an entry: blob contents, or for a submodule
+    /// the commit it points at.
+    pub async fn contents(&mut self, entry: Option<&DiffEntry>) -> Result<Vec<u8>> {
+        match entry {
+            Some(entry) if entry.mode == "160000" => {
+                Ok(format!("Subproject commit {}\n", hex::encode(entry.sha1_hash)).into_bytes())
+            }
+            Some(entry) => Ok(self.get(entry.sha1_hash).await?.to_vec()),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+pub async fn tree_listing(tree_sha: Option<ObjectId>) -> Result<Listing> {
+    Ok(checkout::flatten(tree_sha)
+        .await?
+        .into_iter()
+        .map(|(path, entry)| {
+            let entry = DiffEntry {
+                path: path.clone(),
+                mode: entry.mode().to_string(),
+                sha1_hash: entry.sha1_hash(),
+            };
+            (path, entry)
+        })
+        .collect())
+}
+
+/// The empty pathspec, which matches every path.
+pub const ALL_PATHS: &[&str] = &[];
+
+/// Whether `path` falls under any of `specs`, each a file or a directory
+/// (`.` being the whole tree). No specs at all match everything.
+pub fn matches_pathspec<S: AsRef<[u8]>>(path: impl AsRef<[u8]>, specs: &[S]) -> bool {
+    let path = path.as_ref();
+    specs.is_empty()
+        || specs.iter().any(|spec| {
+            let mut spec = spec.as_ref();
+            while let Some(rest) = spec.strip_prefix(b"./") {
+                spec = rest;
+            }
+            while let Some(rest) = spec.strip_suffix(b"/") {
+                spec = rest;
+            }
+            spec.is_empty()
+                || spec == b"."
+                || path == spec
+                || (path.starts_with(spec) && path.get(spec.len()) == Some(&b'/'))
+        })
+}
+
+/// The merged (stage 0) entries of the index.
+pub fn index_listing(index: &Index) -> Listing {
+    index
+        .paths()
+        .into_iter()
+        .filter_map(|path| {
+            let entry = index.entry(&path)?;
+            let entry = DiffEntry {
+                path: path.clone(),
+                mode: entry.tree_mode(),
+                sha1_hash: entry.sha1_hash,
+            };
+            Some((path, entry))
+        })
+        .collect()
+}
+
+/// The working tree versions of the files the index tracks; files that
+/// are gone are left out, unless a sparse checkout left them out. Modified files are hashed and their contents
+/// kept in `cache`.
+pub async fn worktree_listing(index: &Index, cache: &mut BlobCache) -> Result<Listing> {
+    let mut listing = Listing::new();
+
+    for (path, entry) in index_listing(index) {
+        let index_entry = index.entry(&path).unwrap();
+        // left out by a sparse checkout, which is not a deletion
+        if index_entry.skip_worktree {
+            listing.insert(path, entry);
+            continue;
+        }
+
+        let file_path = checkout::worktree_path(&path);
+        let metadata = match fs::symlink_metadata(file_path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        if !checkout::is_modified(&path, index_entry).await? {
+            listing.insert(path, entry);
+            continue;
+        }
+        if metadata.is_dir() {
+            continue;
+        }
+
+        let (mode, contents) = if metadata.file_type().is_symlink() {
+            ("120000", fs::read_link(file_path)?.as_os_str().as_bytes().to_vec())
+        } else if metadata.permissions().mode() & 0o100 != 0 {
+            ("100755", fs::read(file_path)?)
+        } else {
+            ("100644", fs::read(file_path)?)
+        };
+
+        let sha1_hash = Blob::from_contents(contents.clone())?.sha1_hash();
+        cache.insert(sha1_hash, contents);
+
+        listing.insert(
+            path.clone(),
+            DiffEntry {
+                path,
+                mode: mode.to_string(),
+                sha1_hash,
+            },
+        );
+    }
+
+    Ok(listing)
+}
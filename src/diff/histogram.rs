@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+
+use super::myers;
+
+// a line repeated more often than this is never used as an anchor; if that
+// leaves nothing to anchor on, the region falls back to Myers
+const MAX_CHAIN_LENGTH: usize = 64;
+
+// line numbers below are 1-based as in git's xhistogram.c, so 0 can mean "none"
+
+struct Record {
+    first: usize,
+    count: usize,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Region {
+    begin1: usize,
+    end1: usize,
+    begin2: usize,
+    end2: usize,
+}
+
+struct HistIndex<'a> {
+    a: &'a [usize],
+    b: &'a [usize],
+    records: HashMap<usize, Record>,
+    // next occurrence of the same line in `a`, indexed by line - shift
+    next: Vec<usize>,
+    shift: usize,
+    count: usize,
+    has_common: bool,
+}
+
+impl<'a> HistIndex<'a> {
+    fn new(a: &'a [usize], b: &'a [usize], line1: usize, count1: usize) -> Self {
+        let mut index = Self {
+            a,
+            b,
+            records: HashMap::new(),
+            next: vec![0; count1],
+            shift: line1,
+            count: MAX_CHAIN_LENGTH + 1,
+            has_common: false,
+        };
+
+        for line in (line1..line1 + count1).rev() {
+            let class = a[line - 1];
+            match index.records.get_mut(&class) {
+                Some(record) => {
+                    index.next[line - line1] = record.first;
+                    record.first = line;
+                    record.count += 1;
+                }
+                None => {
+                    index.records.insert(class, Record { first: line, count: 1 });
+                }
+            }
+        }
+
+        index
+    }
+
+    fn occurrences(&self, line: usize) -> usize {
+        self.records[&self.a[line - 1]].count
+    }
+
+    fn matches(&self, line1: usize, line2: usize) -> bool {
+        self.a[line1 - 1] == self.b[line2 - 1]
+    }
+
+    // tries every occurrence in `a` of line b_ptr as the seed of a common
+    // region, keeping the one built from the rarest lines
+    fn try_lcs(&mut self, lcs: &mut Region, b_ptr: usize, line1: usize, end1: usize, line2: usize, end2: usize) -> usize {
+        let mut b_next = b_ptr + 1;
+
+        let (first, count) = match self.records.get(&self.b[b_ptr - 1]) {
+            Some(record) => (record.first, record.count),
+            None => return b_next,
+        };
+
+        if count > self.count {
+            self.has_common = true;
+            return b_next;
+        }
+
+        self.has_common = true;
+        let mut as_ = first;
+        loop {
+            let mut np = self.next[as_ - self.shift];
+            let mut bs = b_ptr;
+            let mut ae = as_;
+            let mut be = bs;
+            let mut rc = count;
+
+            while line1 < as_ && line2 < bs && self.matches(as_ - 1, bs - 1) {
+                as_ -= 1;
+                bs -= 1;
+                if rc > 1 {
+                    rc = rc.min(self.occurrences(as_));
+                }
+            }
+            while ae < end1 && be < end2 && self.matches(ae + 1, be + 1) {
+                ae += 1;
+                be += 1;
+                if rc > 1 {
+                    rc = rc.min(self.occurrences(ae));
+                }
+            }
+
+            if b_next <= be {
+                b_next = be + 1;
+            }
+            if lcs.end1 - lcs.begin1 < ae - as_ || rc < self.count {
+                *lcs = Region {
+                    begin1: as_,
+                    end1: ae,
+                    begin2: bs,
+                    end2: be,
+                };
+                self.count = rc;
+            }
+
+            if np == 0 {
+                break;
+            }
+            while np <= ae {
+                np = self.next[np - self.shift];
+                if np == 0 {
+                    return b_next;
+                }
+            }
+            as_ = np;
+        }
+
+        b_next
+    }
+}
+
+enum Lcs {
+    Found(Region),
+    FallBack,
+}
+
+fn find_lcs(a: &[usize], b: &[usize], line1: usize, count1: usize, line2: usize, count2: usize) -> Lcs {
+    let mut index = HistIndex::new(a, b, line1, count1);
+    let mut lcs = Region::default();
+
+    let (end1, end2) = (line1 + count1 - 1, line2 + count2 - 1);
+    let mut b_ptr = line2;
+    while b_ptr <= end2 {
+        b_ptr = index.try_lcs(&mut lcs, b_ptr, line1, end1, line2, end2);
+    }
+
+    if index.has_common && MAX_CHAIN_LENGTH < index.count {
+        Lcs::FallBack
+    } else {
+        Lcs::Found(lcs)
+    }
+}
+
+fn mark(changed: &mut [bool], line: usize, count: usize) {
+    for flag in &mut changed[line - 1..line - 1 + count] {
+        *flag = true;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn histogram(
+    a: &[usize],
+    b: &[usize],
+    mut line1: usize,
+    mut count1: usize,
+    mut line2: usize,
+    mut count2: usize,
+    changed_a: &mut [bool],
+    changed_b: &mut [bool],
+) {
+    loop {
+        if count1 == 0 && count2 == 0 {
+            return;
+        }
+        if count1 == 0 {
+            mark(changed_b, line2, count2);
+            return;
+        }
+        if count2 == 0 {
+            mark(changed_a, line1, count1);
+            return;
+        }
+
+        let lcs = match find_lcs(a, b, line1, count1, line2, count2) {
+            Lcs::Found(lcs) => lcs,
+            Lcs::FallBack => {
+                let (start1, start2) = (line1 - 1, line2 - 1);
+                let (sub_a, sub_b) = myers::diff(&a[start1..start1 + count1], &b[start2..start2 + count2]);
+                changed_a[start1..start1 + count1].copy_from_slice(&sub_a);
+                changed_b[start2..start2 + count2].copy_from_slice(&sub_b);
+                return;
+            }
+        };
+
+        if lcs.begin1 == 0 && lcs.begin2 == 0 {
+            mark(changed_a, line1, count1);
+            mark(changed_b, line2, count2);
+            return;
+        }
+
+        histogram(
+            a,
+            b,
+            line1,
+            lcs.begin1 - line1,
+            line2,
+            lcs.begin2 - line2,
+            changed_a,
+            changed_b,
+        );
+
+        // the part after the common region, without recursing
+        count1 = line1 + count1 - 1 - lcs.end1;
+        line1 = lcs.end1 + 1;
+        count2 = line2 + count2 - 1 - lcs.end2;
+        line2 = lcs.end2 + 1;
+    }
+}
+
+/// Histogram diff: like patience, but anchors on the least frequent common
+/// lines instead of requiring them to be unique.
+pub fn diff(a: &[usize], b: &[usize]) -> (Vec<bool>, Vec<bool>) {
+    let mut changed_a = vec![false; a.len()];
+    let mut changed_b = vec![false; b.len()];
+
+    histogram(a, b, 1, a.len(), 1, b.len(), &mut changed_a, &mut changed_b);
+
+    (changed_a, changed_b)
+}
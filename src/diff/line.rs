@@ -0,0 +1,432 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use super::compact::compact;
+use super::{histogram, myers, patience};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    Myers,
+    Patience,
+    Histogram,
+}
+
+impl Algorithm {
+    /// Parses a `--diff-algorithm` name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "myers" | "default" => Some(Algorithm::Myers),
+            "patience" => Some(Algorithm::Patience),
+            "histogram" => Some(Algorithm::Histogram),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DiffOptions {
+    pub algorithm: Algorithm,
+    /// Lines of context around each hunk.
+    pub context: usize,
+    pub ignore_all_space: bool,
+    pub ignore_space_change: bool,
+    pub ignore_space_at_eol: bool,
+    pub ignore_cr_at_eol: bool,
+    /// Slide hunks to where indentation suggests a block starts or ends;
+    /// git's diffs do this by default, its merges do not.
+    pub indent_heuristic: bool,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::Myers,
+            context: 3,
+            ignore_all_space: false,
+            ignore_space_change: false,
+            ignore_space_at_eol: false,
+            ignore_cr_at_eol: false,
+            indent_heuristic: true,
+        }
+    }
+}
+
+impl DiffOptions {
+    pub fn ignores_whitespace(&self) -> bool {
+        self.ignore_all_space
+            || self.ignore_space_change
+            || self.ignore_space_at_eol
+            || self.ignore_cr_at_eol
+    }
+}
+
+fn is_space(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\n' | b'\x0b' | b'\x0c' | b'\r')
+}
+
+/// Splits `data` into lines, each keeping its trailing newline.
+pub fn split_lines(data: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+
+    for (i, byte) in data.iter().enumerate() {
+        if *byte == b'\n' {
+            lines.push(&data[start..=i]);
+            start = i + 1;
+        }
+    }
+    if start < data.len() {
+        lines.push(&data[start..]);
+    }
+
+    lines
+}
+
+/// Git's heuristic: a NUL byte in the first 8000 bytes means binary.
+pub fn is_binary(data: &[u8]) -> bool {
+    data.iter().take(8000).any(|b| *b == 0x00u8)
+}
+
+// the part of a line that decides equality under the whitespace options
+fn line_key<'a>(line: &'a [u8], options: &DiffOptions) -> Cow<'a, [u8]> {
+    if options.ignore_all_space {
+        Cow::Owned(line.iter().copied().filter(|b| !is_space(*b)).collect())
+    } else if options.ignore_space_change {
+        let mut key = Vec::with_capacity(line.len());
+        // runs of whitespace count as one space, and trailing ones not at all
+        let mut in_space = false;
+        for byte in line {
+            if is_space(*byte) {
+                in_space = true;
+                continue;
+            }
+            if in_space {
+                key.push(b' ');
+            }
+            in_space = false;
+            key.push(*byte);
+        }
+        Cow::Owned(key)
+    } else if options.ignore_space_at_eol {
+        let end = line.iter().rposition(|b| !is_space(*b)).map_or(0, |pos| pos + 1);
+        Cow::Borrowed(&line[..end])
+    } else if options.ignore_cr_at_eol {
+        match line.strip_suffix(b"\n") {
+            Some(body) => Cow::Borrowed(body.strip_suffix(b"\r").unwrap_or(body)),
+            None => Cow::Borrowed(line),
+        }
+    } else {
+        Cow::Borrowed(line)
+    }
+}
+
+/// Whether two lines compare equal under the whitespace options.
+pub fn lines_equal(a: &[u8], b: &[u8], options: &DiffOptions) -> bool {
+    line_key(a, options) == line_key(b, options)
+}
+
+/// One side of a line diff: its lines, the class of each (lines comparing
+/// equal share one) and which lines the diff marks as changed.
+pub struct Side<'a> {
+    pub lines: Vec<&'a [u8]>,
+    pub classes: Vec<usize>,
+    // padded with an unchanged line at each end
+    changed: Vec<bool>,
+}
+
+impl<'a> Side<'a> {
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_changed(&self, line: isize) -> bool {
+        line >= -1 && self.changed.get((line + 1) as usize).copied().unwrap_or(false)
+    }
+
+    pub fn set_changed(&mut self, line: isize, changed: bool) {
+        self.changed[(line + 1) as usize] = changed;
+    }
+}
+
+/// A run of removed and added lines; starts are 0-based line numbers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chunk {
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+}
+
+pub struct LineDiff<'a> {
+    old: Side<'a>,
+    new: Side<'a>,
+    chunks: Vec<Chunk>,
+}
+
+fn classify<'a>(old: &'a [u8], new: &'a [u8], options: &DiffOptions) -> (Side<'a>, Side<'a>) {
+    let mut ids: HashMap<Cow<'a, [u8]>, usize> = HashMap::new();
+
+    let mut side = |data: &'a [u8]| {
+        let lines = split_lines(data);
+        let classes = lines
+            .iter()
+            .map(|line| {
+                let next = ids.len();
+                *ids.entry(line_key(line, options)).or_insert(next)
+            })
+            .collect();
+        let changed = vec![false; lines.len() + 2];
+
+        Side {
+            lines,
+            classes,
+            changed,
+        }
+    };
+
+    let old = side(old);
+    let new = side(new);
+
+    (old, new)
+}
+
+/// Diffs two blobs line by line, with hunks placed the way git places them.
+pub fn diff_lines<'a>(old: &'a [u8], new: &'a [u8], options: &DiffOptions) -> LineDiff<'a> {
+    let (mut old, mut new) = classify(old, new, options);
+
+    let (changed_old, changed_new) = match options.algorithm {
+        Algorithm::Myers => myers::diff(&old.classes, &new.classes),
+        Algorithm::Patience => patience::diff(&old.classes, &new.classes),
+        Algorithm::Histogram => histogram::diff(&old.classes, &new.classes),
+    };
+    for (i, changed) in changed_old.into_iter().enumerate() {
+        old.set_changed(i as isize, changed);
+    }
+    for (i, changed) in changed_new.into_iter().enumerate() {
+        new.set_changed(i as isize, changed);
+    }
+
+    compact(&mut old, &new, options.indent_heuristic);
+    compact(&mut new, &old, options.indent_heuristic);
+
+    // walk both sides backwards collecting runs of changes
+    let mut chunks = Vec::new();
+    let mut i1 = old.len() as isize;
+    let mut i2 = new.len() as isize;
+    while i1 >= 0 || i2 >= 0 {
+        if old.is_changed(i1 - 1) || new.is_changed(i2 - 1) {
+            let (l1, l2) = (i1, i2);
+            while old.is_changed(i1 - 1) {
+                i1 -= 1;
+            }
+            while new.is_changed(i2 - 1) {
+                i2 -= 1;
+            }
+
+            chunks.push(Chunk {
+                old_start: i1 as usize,
+                old_len: (l1 - i1) as usize,
+                new_start: i2 as usize,
+                new_len: (l2 - i2) as usize,
+            });
+        }
+        i1 -= 1;
+        i2 -= 1;
+    }
+    chunks.reverse();
+
+    LineDiff { old, new, chunks }
+}
+
+// git's default funcname rule: a line starting with a letter, `_` or `$`,
+// cut to 80 bytes and stripped of trailing whitespace
+fn function_name(line: &[u8]) -> Option<&[u8]> {
+    let first = *line.first()?;
+    if !(first.is_ascii_alphabetic() || first == b'_' || first == b'$') {
+        return None;
+    }
+
+    let mut line = &line[..line.len().min(80)];
+    while let Some((last, rest)) = line.split_last() {
+        if !is_space(*last) {
+            break;
+        }
+        line = rest;
+    }
+
+    Some(line)
+}
+
+fn push_line(out: &mut Vec<u8>, prefix: u8, line: &[u8]) {
+    out.push(prefix);
+    out.extend_from_slice(line);
+    if !line.ends_with(b"\n") {
+        out.extend_from_slice(b"\n\\ No newline at end of file\n");
+    }
+}
+
+fn range(start: usize, len: usize) -> String {
+    // an empty range names the line before it
+    let start = if len == 0 { start } else { start + 1 };
+
+    if len == 1 {
+        start.to_string()
+    } else {
+        format!("{},{}", start, len)
+    }
+}
+
+impl<'a> LineDiff<'a> {
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+
+    /// (insertions, deletions)
+    pub fn counts(&self) -> (usize, usize) {
+        self.chunks
+            .iter()
+            .fold((0, 0), |(ins, del), c| (ins + c.new_len, del + c.old_len))
+    }
+
+    /// Renders the hunks in unified format with `context` lines around them.
+    pub fn unified(&self, context: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut function: &[u8] = &[];
+        let mut searched_to: isize = -1;
+
+        let mut first = 0;
+        while first < self.chunks.len() {
+            // changes closer than twice the context share a hunk
+            let mut last = first;
+            while last + 1 < self.chunks.len() {
+                let current = &self.chunks[last];
+                let next = &self.chunks[last + 1];
+                if next.old_start - (current.old_start + current.old_len) > 2 * context {
+                    break;
+                }
+                last += 1;
+            }
+
+            let start = &self.chunks[first];
+            let end = &self.chunks[last];
+
+            let s1 = start.old_start.saturating_sub(context);
+            let s2 = start.new_start.saturating_sub(context);
+            let post = context
+                .min(self.old.len() - (end.old_start + end.old_len))
+                .min(self.new.len() - (end.new_start + end.new_len));
+            let e1 = end.old_start + end.old_len + post;
+            let e2 = end.new_start + end.new_len + post;
+
+            // the nearest function line above the hunk, searching only
+            // the part not covered for the previous hunk
+            let mut line = s1 as isize - 1;
+            while line > searched_to && line >= 0 {
+                if let Some(name) = function_name(self.old.lines[line as usize]) {
+                    function = name;
+                    break;
+                }
+                line -= 1;
+            }
+            searched_to = s1 as isize - 1;
+
+            let mut header = format!("@@ -{} +{} @@", range(s1, e1 - s1), range(s2, e2 - s2)).into_bytes();
+            if !function.is_empty() {
+                header.push(b' ');
+                let room = 128 - header.len() - 1;
+                header.extend_from_slice(&function[..function.len().min(room)]);
+            }
+            out.extend_from_slice(&header);
+            out.push(b'\n');
+
+            let mut s2 = s2;
+            for chunk in &self.chunks[first..=last] {
+                while s2 < chunk.new_start {
+                    push_line(&mut out, b' ', self.new.lines[s2]);
+                    s2 += 1;
+                }
+                for line in &self.old.lines[chunk.old_start..chunk.old_start + chunk.old_len] {
+                    push_line(&mut out, b'-', line);
+                }
+                for line in &self.new.lines[chunk.new_start..chunk.new_start + chunk.new_len] {
+                    push_line(&mut out, b'+', line);
+                }
+                s2 = chunk.new_start + chunk.new_len;
+            }
+            for line in &self.new.lines[s2..e2] {
+                push_line(&mut out, b' ', line);
+            }
+
+            first = last + 1;
+        }
+
+        out
+    }
+}
+
+/// Line diff of two blobs as (insertions, deletions).
+pub fn count_changes(old: &[u8], new: &[u8], options: &DiffOptions) -> (usize, usize) {
+    diff_lines(old, new, options).counts()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_changes() {
+        let old = b"one\ntwo\nthree\n";
+        let new = b"one\n2\nthree\nfour\n";
+        let options = DiffOptions::default();
+
+        assert_eq!(count_changes(old, new, &options), (2, 1));
+        assert_eq!(count_changes(b"", b"a\nb", &options), (2, 0));
+    }
+
+    #[test]
+    fn test_unified() {
+        let old = b"fn main() {\n    a();\n    b();\n}\n";
+        let new = b"fn main() {\n    a();\n    c();\n}";
+
+        let diff = diff_lines(old, new, &DiffOptions::default());
+        assert_eq!(
+            String::from_utf8_lossy(&diff.unified(1)),
+            "@@ -2,3 +2,3 @@ fn main() {\n     a();\n-    b();\n-}\n+    c();\n+}\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn test_ignore_whitespace() {
+        let old = b"a b\nc\n";
+        let new = b"a  b \nc\r\n";
+
+        let mut options = DiffOptions::default();
+        assert_eq!(count_changes(old, new, &options), (2, 2));
+
+        options.ignore_space_change = true;
+        assert_eq!(count_changes(old, new, &options), (0, 0));
+
+        options = DiffOptions {
+            ignore_cr_at_eol: true,
+            ..DiffOptions::default()
+        };
+        assert_eq!(count_changes(old, new, &options), (1, 1));
+    }
+
+    #[test]
+    fn test_algorithms_agree_on_simple_edits() {
+        let old = b"a\nb\nc\nd\ne\n";
+        let new = b"a\nc\nd\nx\ne\n";
+
+        for algorithm in &[Algorithm::Myers, Algorithm::Patience, Algorithm::Histogram] {
+            let options = DiffOptions {
+                algorithm: *algorithm,
+                ..DiffOptions::default()
+            };
+            let diff = diff_lines(old, new, &options);
+
+            assert_eq!(diff.counts(), (1, 1), "{:?}", algorithm);
+            assert_eq!(diff.chunks().len(), 2, "{:?}", algorithm);
+        }
+    }
+}
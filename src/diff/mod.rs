@@ -0,0 +1,15 @@
+mod compact;
+mod files;
+mod histogram;
+mod line;
+mod myers;
+mod patch;
+mod patience;
+mod stat;
+mod tree;
+
+pub use files::{index_listing, matches_pathspec, tree_listing, worktree_listing, BlobCache, Listing, ALL_PATHS};
+pub use line::{diff_lines, is_binary, lines_equal, split_lines, Algorithm, DiffOptions};
+pub use patch::{quote_path_bytes, write_patch};
+pub use stat::{file_stats, format_numstat, format_stat, format_summary};
+pub use tree::{diff_listings, diff_trees, parse_score, Change, DiffEntry, Status, TreeDiffOptions, DEFAULT_SCORE};
@@ -0,0 +1,483 @@
+use std::collections::HashMap;
+use std::ops::{Index, IndexMut};
+
+// tuning constants from git's xdiff, which decide where the search gives up
+// on a minimal diff; matching them keeps our hunks identical to git's
+const SNAKE_CNT: isize = 20;
+const HEUR_MIN_COST: isize = 256;
+const MAX_COST_MIN: isize = 256;
+const K_HEUR: isize = 4;
+const MAX_EQLIMIT: usize = 1024;
+const SIMSCAN_WINDOW: isize = 100;
+const KPDIS_RUN: isize = 4;
+
+/// Integer square root approximation used by xdiff for its limits.
+pub fn bogosqrt(mut n: usize) -> usize {
+    let mut i = 1;
+    while n > 0 {
+        i <<= 1;
+        n >>= 2;
+    }
+
+    i
+}
+
+// furthest reaching x per diagonal; diagonals may be negative
+struct Diagonals {
+    v: Vec<isize>,
+    offset: isize,
+}
+
+impl Index<isize> for Diagonals {
+    type Output = isize;
+
+    fn index(&self, k: isize) -> &isize {
+        &self.v[(k + self.offset) as usize]
+    }
+}
+
+impl IndexMut<isize> for Diagonals {
+    fn index_mut(&mut self, k: isize) -> &mut isize {
+        &mut self.v[(k + self.offset) as usize]
+    }
+}
+
+struct Split {
+    i1: isize,
+    i2: isize,
+    min_lo: bool,
+    min_hi: bool,
+}
+
+struct Search<'a> {
+    a: &'a [usize],
+    b: &'a [usize],
+    forward: Diagonals,
+    backward: Diagonals,
+    max_cost: isize,
+}
+
+impl<'a> Search<'a> {
+    // finds the middle snake of the box, or a good enough split point once
+    // the edit cost gets too high (xdiff's xdl_split)
+    fn split(&mut self, off1: isize, lim1: isize, off2: isize, lim2: isize, need_min: bool) -> Split {
+        let (a, b) = (self.a, self.b);
+        let kvdf = &mut self.forward;
+        let kvdb = &mut self.backward;
+
+        let dmin = off1 - lim2;
+        let dmax = lim1 - off2;
+        let fmid = off1 - off2;
+        let bmid = lim1 - lim2;
+        let odd = (fmid - bmid) & 1 != 0;
+        let (mut fmin, mut fmax) = (fmid, fmid);
+        let (mut bmin, mut bmax) = (bmid, bmid);
+
+        kvdf[fmid] = off1;
+        kvdb[bmid] = lim1;
+
+        let mut ec = 1;
+        loop {
+            let mut got_snake = false;
+
+            if fmin > dmin {
+                fmin -= 1;
+                kvdf[fmin - 1] = -1;
+            } else {
+                fmin += 1;
+            }
+            if fmax < dmax {
+                fmax += 1;
+                kvdf[fmax + 1] = -1;
+            } else {
+                fmax -= 1;
+            }
+
+            let mut d = fmax;
+            while d >= fmin {
+                let mut i1 = if kvdf[d - 1] >= kvdf[d + 1] {
+                    kvdf[d - 1] + 1
+                } else {
+                    kvdf[d + 1]
+                };
+                let prev1 = i1;
+                let mut i2 = i1 - d;
+                while i1 < lim1 && i2 < lim2 && a[i1 as usize] == b[i2 as usize] {
+                    i1 += 1;
+                    i2 += 1;
+                }
+                if i1 - prev1 > SNAKE_CNT {
+                    got_snake = true;
+                }
+                kvdf[d] = i1;
+                if odd && bmin <= d && d <= bmax && kvdb[d] <= i1 {
+                    return Split {
+                        i1,
+                        i2,
+                        min_lo: true,
+                        min_hi: true,
+                    };
+                }
+                d -= 2;
+            }
+
+            if bmin > dmin {
+                bmin -= 1;
+                kvdb[bmin - 1] = isize::MAX;
+            } else {
+                bmin += 1;
+            }
+            if bmax < dmax {
+                bmax += 1;
+                kvdb[bmax + 1] = isize::MAX;
+            } else {
+                bmax -= 1;
+            }
+
+            let mut d = bmax;
+            while d >= bmin {
+                let mut i1 = if kvdb[d - 1] < kvdb[d + 1] {
+                    kvdb[d - 1]
+                } else {
+                    kvdb[d + 1] - 1
+                };
+                let prev1 = i1;
+                let mut i2 = i1 - d;
+                while i1 > off1 && i2 > off2 && a[i1 as usize - 1] == b[i2 as usize - 1] {
+                    i1 -= 1;
+                    i2 -= 1;
+                }
+                if prev1 - i1 > SNAKE_CNT {
+                    got_snake = true;
+                }
+                kvdb[d] = i1;
+                if !odd && fmin <= d && d <= fmax && i1 <= kvdf[d] {
+                    return Split {
+                        i1,
+                        i2,
+                        min_lo: true,
+                        min_hi: true,
+                    };
+                }
+                d -= 2;
+            }
+
+            if need_min {
+                ec += 1;
+                continue;
+            }
+
+            // past a certain cost, settle for a diagonal that has made good
+            // progress and ends in a long enough snake
+            if got_snake && ec > HEUR_MIN_COST {
+                let mut best = 0;
+                let mut split = (0, 0);
+
+                let mut d = fmax;
+                while d >= fmin {
+                    let dd = if d > fmid { d - fmid } else { fmid - d };
+                    let i1 = kvdf[d];
+                    let i2 = i1 - d;
+                    let v = (i1 - off1) + (i2 - off2) - dd;
+
+                    if v > K_HEUR * ec
+                        && v > best
+                        && off1 + SNAKE_CNT <= i1
+                        && i1 < lim1
+                        && off2 + SNAKE_CNT <= i2
+                        && i2 < lim2
+                    {
+                        let mut k = 1;
+                        while a[(i1 - k) as usize] == b[(i2 - k) as usize] {
+                            if k == SNAKE_CNT {
+                                best = v;
+                                split = (i1, i2);
+                                break;
+                            }
+                            k += 1;
+                        }
+                    }
+                    d -= 2;
+                }
+                if best > 0 {
+                    return Split {
+                        i1: split.0,
+                        i2: split.1,
+                        min_lo: true,
+                        min_hi: false,
+                    };
+                }
+
+                let mut d = bmax;
+                while d >= bmin {
+                    let dd = if d > bmid { d - bmid } else { bmid - d };
+                    let i1 = kvdb[d];
+                    let i2 = i1 - d;
+                    let v = (lim1 - i1) + (lim2 - i2) - dd;
+
+                    if v > K_HEUR * ec
+                        && v > best
+                        && off1 < i1
+                        && i1 <= lim1 - SNAKE_CNT
+                        && off2 < i2
+                        && i2 <= lim2 - SNAKE_CNT
+                    {
+                        let mut k = 0;
+                        while a[(i1 + k) as usize] == b[(i2 + k) as usize] {
+                            if k == SNAKE_CNT - 1 {
+                                best = v;
+                                split = (i1, i2);
+                                break;
+                            }
+                            k += 1;
+                        }
+                    }
+                    d -= 2;
+                }
+                if best > 0 {
+                    return Split {
+                        i1: split.0,
+                        i2: split.1,
+                        min_lo: false,
+                        min_hi: true,
+                    };
+                }
+            }
+
+            // enough is enough: take the furthest reaching path
+            if ec >= self.max_cost {
+                let mut fbest = -1;
+                let mut fbest1 = -1;
+                let mut d = fmax;
+                while d >= fmin {
+                    let mut i1 = kvdf[d].min(lim1);
+                    let mut i2 = i1 - d;
+                    if lim2 < i2 {
+                        i1 = lim2 + d;
+                        i2 = lim2;
+                    }
+                    if fbest < i1 + i2 {
+                        fbest = i1 + i2;
+                        fbest1 = i1;
+                    }
+                    d -= 2;
+                }
+
+                let mut bbest = isize::MAX;
+                let mut bbest1 = isize::MAX;
+                let mut d = bmax;
+                while d >= bmin {
+                    let mut i1 = off1.max(kvdb[d]);
+                    let mut i2 = i1 - d;
+                    if i2 < off2 {
+                        i1 = off2 + d;
+                        i2 = off2;
+                    }
+                    if i1 + i2 < bbest {
+                        bbest = i1 + i2;
+                        bbest1 = i1;
+                    }
+                    d -= 2;
+                }
+
+                return if (lim1 + lim2) - bbest < fbest - (off1 + off2) {
+                    Split {
+                        i1: fbest1,
+                        i2: fbest - fbest1,
+                        min_lo: true,
+                        min_hi: false,
+                    }
+                } else {
+                    Split {
+                        i1: bbest1,
+                        i2: bbest - bbest1,
+                        min_lo: false,
+                        min_hi: true,
+                    }
+                };
+            }
+
+            ec += 1;
+        }
+    }
+}
+
+fn class_counts(lines: &[usize]) -> HashMap<usize, usize> {
+    let mut counts = HashMap::new();
+    for class in lines {
+        *counts.entry(*class).or_insert(0) += 1;
+    }
+
+    counts
+}
+
+// whether a line matching many others sits in a run of unmatched lines and
+// can be treated as changed without searching for it
+fn clean_mmatch(dis: &[u8], i: isize, mut s: isize, mut e: isize) -> bool {
+    if i - s > SIMSCAN_WINDOW {
+        s = i - SIMSCAN_WINDOW;
+    }
+    if e - i > SIMSCAN_WINDOW {
+        e = i + SIMSCAN_WINDOW;
+    }
+
+    let (mut rdis0, mut rpdis0) = (0, 1);
+    let mut r = 1;
+    while i - r >= s {
+        match dis[(i - r) as usize] {
+            0 => rdis0 += 1,
+            2 => rpdis0 += 1,
+            _ => break,
+        }
+        r += 1;
+    }
+    if rdis0 == 0 {
+        return false;
+    }
+
+    let (mut rdis1, mut rpdis1) = (0, 1);
+    let mut r = 1;
+    while i + r <= e {
+        match dis[(i + r) as usize] {
+            0 => rdis1 += 1,
+            2 => rpdis1 += 1,
+            _ => break,
+        }
+        r += 1;
+    }
+    if rdis1 == 0 {
+        return false;
+    }
+
+    rdis1 += rdis0;
+    rpdis1 += rpdis0;
+
+    rpdis1 * KPDIS_RUN < rpdis1 + rdis1
+}
+
+// lines in start..end without a counterpart on the other side are changed
+// outright; returns the positions and classes of the ones left to search
+fn discard(
+    lines: &[usize],
+    start: usize,
+    end: usize,
+    other_counts: &HashMap<usize, usize>,
+    changed: &mut [bool],
+) -> (Vec<usize>, Vec<usize>) {
+    let limit = bogosqrt(lines.len()).min(MAX_EQLIMIT);
+
+    let dis: Vec<u8> = lines[start..end]
+        .iter()
+        .map(|class| match other_counts.get(class).copied().unwrap_or(0) {
+            0 => 0,
+            n if n >= limit => 2,
+            _ => 1,
+        })
+        .collect();
+
+    let mut positions = Vec::new();
+    let mut classes = Vec::new();
+    for (i, d) in dis.iter().enumerate() {
+        let keep = *d == 1
+            || (*d == 2 && !clean_mmatch(&dis, i as isize, 0, dis.len() as isize - 1));
+
+        if keep {
+            positions.push(start + i);
+            classes.push(lines[start + i]);
+        } else {
+            changed[start + i] = true;
+        }
+    }
+
+    (positions, classes)
+}
+
+/// Diffs two sequences of line classes the way git's default algorithm
+/// does, returning which lines of each side are changed.
+pub fn diff(a: &[usize], b: &[usize]) -> (Vec<bool>, Vec<bool>) {
+    let mut changed_a = vec![false; a.len()];
+    let mut changed_b = vec![false; b.len()];
+
+    let limit = a.len().min(b.len());
+    let mut start = 0;
+    while start < limit && a[start] == b[start] {
+        start += 1;
+    }
+    let mut end = 0;
+    while end < limit - start && a[a.len() - 1 - end] == b[b.len() - 1 - end] {
+        end += 1;
+    }
+
+    let counts_a = class_counts(a);
+    let counts_b = class_counts(b);
+    let (positions_a, classes_a) = discard(a, start, a.len() - end, &counts_b, &mut changed_a);
+    let (positions_b, classes_b) = discard(b, start, b.len() - end, &counts_a, &mut changed_b);
+
+    let n1 = classes_a.len() as isize;
+    let n2 = classes_b.len() as isize;
+    let ndiags = (n1 + n2 + 3) as usize;
+
+    let mut search = Search {
+        a: &classes_a,
+        b: &classes_b,
+        forward: Diagonals {
+            v: vec![0; ndiags],
+            offset: n2 + 1,
+        },
+        backward: Diagonals {
+            v: vec![0; ndiags],
+            offset: n2 + 1,
+        },
+        max_cost: (bogosqrt(ndiags) as isize).max(MAX_COST_MIN),
+    };
+
+    // divide and conquer; the boxes are independent, so a stack will do
+    let mut boxes = vec![(0, n1, 0, n2, false)];
+    while let Some((mut off1, mut lim1, mut off2, mut lim2, need_min)) = boxes.pop() {
+        while off1 < lim1 && off2 < lim2 && classes_a[off1 as usize] == classes_b[off2 as usize] {
+            off1 += 1;
+            off2 += 1;
+        }
+        while off1 < lim1
+            && off2 < lim2
+            && classes_a[lim1 as usize - 1] == classes_b[lim2 as usize - 1]
+        {
+            lim1 -= 1;
+            lim2 -= 1;
+        }
+
+        if off1 == lim1 {
+            for i in off2..lim2 {
+                changed_b[positions_b[i as usize]] = true;
+            }
+        } else if off2 == lim2 {
+            for i in off1..lim1 {
+                changed_a[positions_a[i as usize]] = true;
+            }
+        } else {
+            let split = search.split(off1, lim1, off2, lim2, need_min);
+            boxes.push((split.i1, lim1, split.i2, lim2, split.min_hi));
+            boxes.push((off1, split.i1, off2, split.i2, split.min_lo));
+        }
+    }
+
+    (changed_a, changed_b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_is_minimal_for_small_inputs() {
+        // ABCABBA -> CBABAC, the example from Myers' paper
+        let a = [0, 1, 2, 0, 1, 1, 0];
+        let b = [2, 1, 0, 1, 0, 2];
+
+        let (changed_a, changed_b) = diff(&a, &b);
+        let changes = changed_a.iter().chain(changed_b.iter()).filter(|c| **c).count();
+
+        assert_eq!(changes, 5);
+        assert_eq!(bogosqrt(7), 4);
+    }
+}
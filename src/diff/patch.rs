@@ -0,0 +1,193 @@
+use super::line::{diff_lines, is_binary, DiffOptions};
+use super::tree::{Change, Status, MAX_SCORE};
+use crate::objects::ObjectId;
+
+/// Quotes a path the way git does (core.quotePath) when it contains
+/// control characters, quotes, backslashes or non-ASCII bytes. Paths are
+/// raw bytes, which need not be valid UTF-8.
+pub fn quote_path_bytes(path: &[u8]) -> String {
+    let needs_quoting = path
+        .iter()
+        .any(|&b| b < 0x20 || b == b'"' || b == b'\\' || b >= 0x7f);
+    if !needs_quoting {
+        return path.iter().map(|&b| b as char).collect();
+    }
+
+    let mut quoted = String::from("\"");
+    for &byte in path {
+        match byte {
+            0x07 => quoted.push_str("\\a"),
+            0x08 => quoted.push_str("\\b"),
+            b'\t' => quoted.push_str("\\t"),
+            b'\n' => quoted.push_str("\\n"),
+            0x0b => quoted.push_str("\\v"),
+            0x0c => quoted.push_str("\\f"),
+            b'\r' => quoted.push_str("\\r"),
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            b if !(0x20..0x7f).contains(&b) => quoted.push_str(&format!("\\{:03o}", b)),
+            b => quoted.push(b as char),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+fn abbrev(sha1_hash: Option<ObjectId>) -> String {
+    match sha1_hash {
+        Some(sha1_hash) => hex::encode(sha1_hash)[..7].to_string(),
+        None => "0".repeat(7),
+    }
+}
+
+fn split_type_change(change: &Change) -> (Change, Change) {
+    let deletion = Change {
+        status: Status::Deleted,
+        score: 0,
+        old: change.old.clone(),
+        new: None,
+    };
+    let addition = Change {
+        status: Status::Added,
+        score: 0,
+        old: None,
+        new: change.new.clone(),
+    };
+
+    (deletion, addition)
+}
+
+/// Appends the `diff --git` patch for one change to `out`, given the
+/// contents of both sides (empty for a missing side).
+pub fn write_patch(out: &mut Vec<u8>, change: &Change, old: &[u8], new: &[u8], options: &DiffOptions) {
+    // a file turning into a symlink or back is shown as a deletion and a creation
+    if change.status == Status::TypeChanged {
+        let (deletion, addition) = split_type_change(change);
+        write_patch(out, &deletion, old, &[], options);
+        write_patch(out, &addition, &[], new, options);
+        return;
+    }
+
+    let old_path = change.old.as_ref().or(change.new.as_ref()).unwrap().path.as_slice();
+    let new_path = change.new.as_ref().or(change.old.as_ref()).unwrap().path.as_slice();
+
+    let mut header = format!(
+        "diff --git {} {}\n",
+        quote_path_bytes(&[b"a/", old_path].concat()),
+        quote_path_bytes(&[b"b/", new_path].concat())
+    );
+    let mut must_show_header = false;
+
+    match (&change.old, &change.new) {
+        (None, Some(new)) => {
+            header.push_str(&format!("new file mode {:0>6}\n", new.mode));
+            must_show_header = true;
+        }
+        (Some(old), None) => {
+            header.push_str(&format!("deleted file mode {:0>6}\n", old.mode));
+            must_show_header = true;
+        }
+        (Some(old), Some(new)) if old.mode != new.mode => {
+            header.push_str(&format!("old mode {:0>6}\nnew mode {:0>6}\n", old.mode, new.mode));
+            must_show_header = true;
+        }
+        _ => {}
+    }
+
+    if let Status::Renamed | Status::Copied = change.status {
+        let verb = if change.status == Status::Renamed { "rename" } else { "copy" };
+        header.push_str(&format!(
+            "similarity index {}%\n{} from {}\n{} to {}\n",
+            change.score as u64 * 100 / MAX_SCORE as u64,
+            verb,
+            quote_path_bytes(old_path),
+            verb,
+            quote_path_bytes(new_path)
+        ));
+        must_show_header = true;
+    }
+
+    let old_sha = change.old.as_ref().map(|e| e.sha1_hash);
+    let new_sha = change.new.as_ref().map(|e| e.sha1_hash);
+    if old_sha != new_sha {
+        header.push_str(&format!("index {}..{}", abbrev(old_sha), abbrev(new_sha)));
+        if let (Some(old), Some(new)) = (&change.old, &change.new) {
+            if old.mode == new.mode {
+                header.push_str(&format!(" {:0>6}", old.mode));
+            }
+        }
+        header.push('\n');
+    }
+
+    let old_label = match &change.old {
+        Some(_) => quote_path_bytes(&[b"a/", old_path].concat()),
+        None => String::from("/dev/null"),
+    };
+    let new_label = match &change.new {
+        Some(_) => quote_path_bytes(&[b"b/", new_path].concat()),
+        None => String::from("/dev/null"),
+    };
+
+    if is_binary(old) || is_binary(new) {
+        if old != new || must_show_header {
+            out.extend_from_slice(header.as_bytes());
+        }
+        if old != new {
+            out.extend_from_slice(format!("Binary files {} and {} differ\n", old_label, new_label).as_bytes());
+        }
+        return;
+    }
+
+    // with whitespace ignored, a file whose changes all vanish is not shown
+    let hunks = diff_lines(old, new, options).unified(options.context);
+    if !options.ignores_whitespace() || must_show_header || !hunks.is_empty() {
+        out.extend_from_slice(header.as_bytes());
+    }
+    if hunks.is_empty() {
+        return;
+    }
+
+    // git ends a name containing a space with a tab, for patch(1)'s sake
+    let tab = |label: &str| if label.contains(' ') { "\t" } else { "" };
+    out.extend_from_slice(format!("--- {}{}\n", old_label, tab(&old_label)).as_bytes());
+    out.extend_from_slice(format!("+++ {}{}\n", new_label, tab(&new_label)).as_bytes());
+    out.extend_from_slice(&hunks);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::tree::DiffEntry;
+
+    #[test]
+    fn test_quote_path() {
+        assert_eq!(quote_path_bytes("a/plain name".as_bytes()), "a/plain name");
+        assert_eq!(quote_path_bytes("a/tü".as_bytes()), "\"a/t\\303\\274\"");
+        assert_eq!(quote_path_bytes("tab\there".as_bytes()), "\"tab\\there\"");
+        assert_eq!(quote_path_bytes(b"bad\xff"), "\"bad\\377\"");
+    }
+
+    #[test]
+    fn test_write_patch_for_new_file() {
+        let change = Change {
+            status: Status::Added,
+            score: 0,
+            old: None,
+            new: Some(DiffEntry {
+                path: b"hello.txt".to_vec(),
+                mode: String::from("100644"),
+                sha1_hash: ObjectId::from([0xab; 20]),
+            }),
+        };
+
+        let mut out = Vec::new();
+        write_patch(&mut out, &change, b"", b"hi\n", &DiffOptions::default());
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "diff --git a/hello.txt b/hello.txt\nnew file mode 100644\nindex 0000000..abababa\n\
+             --- /dev/null\n+++ b/hello.txt\n@@ -0,0 +1 @@\n+hi\n"
+        );
+    }
+}
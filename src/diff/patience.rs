@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+
+use super::myers;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Occurrence {
+    None,
+    Once(usize),
+    Many,
+}
+
+struct Entry {
+    line1: usize,
+    line2: Occurrence,
+}
+
+fn mark(changed: &mut [bool], start: usize, count: usize) {
+    for flag in &mut changed[start..start + count] {
+        *flag = true;
+    }
+}
+
+// lines occurring exactly once on each side, in the order of `a`, cut down
+// to their longest common subsequence
+fn unique_common_lines(
+    a: &[usize],
+    b: &[usize],
+    line1: usize,
+    count1: usize,
+    line2: usize,
+    count2: usize,
+) -> Option<Vec<(usize, usize)>> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut by_class: HashMap<usize, usize> = HashMap::new();
+
+    for (i, class) in a.iter().enumerate().skip(line1).take(count1) {
+        match by_class.get(class) {
+            Some(&e) => entries[e].line2 = Occurrence::Many,
+            None => {
+                by_class.insert(*class, entries.len());
+                entries.push(Entry {
+                    line1: i,
+                    line2: Occurrence::None,
+                });
+            }
+        }
+    }
+
+    let mut has_matches = false;
+    for (j, class) in b.iter().enumerate().skip(line2).take(count2) {
+        if let Some(&e) = by_class.get(class) {
+            has_matches = true;
+            entries[e].line2 = match entries[e].line2 {
+                Occurrence::None => Occurrence::Once(j),
+                _ => Occurrence::Many,
+            };
+        }
+    }
+
+    if !has_matches {
+        return None;
+    }
+
+    // patience sorting: sequence[i] ends the best subsequence of length i + 1
+    let mut sequence: Vec<usize> = Vec::new();
+    let mut previous: Vec<Option<usize>> = vec![None; entries.len()];
+
+    for (e, entry) in entries.iter().enumerate() {
+        let line2 = match entry.line2 {
+            Occurrence::Once(line2) => line2,
+            _ => continue,
+        };
+
+        let mut left: isize = -1;
+        let mut right = sequence.len() as isize;
+        while left + 1 < right {
+            let middle = left + (right - left) / 2;
+            let middle_line2 = match entries[sequence[middle as usize]].line2 {
+                Occurrence::Once(l) => l,
+                _ => unreachable!(),
+            };
+            if middle_line2 > line2 {
+                right = middle;
+            } else {
+                left = middle;
+            }
+        }
+
+        previous[e] = if left < 0 { None } else { Some(sequence[left as usize]) };
+        let i = (left + 1) as usize;
+        if i == sequence.len() {
+            sequence.push(e);
+        } else {
+            sequence[i] = e;
+        }
+    }
+
+    let mut common = Vec::new();
+    let mut entry = sequence.last().copied();
+    while let Some(e) = entry {
+        if let Occurrence::Once(line2) = entries[e].line2 {
+            common.push((entries[e].line1, line2));
+        }
+        entry = previous[e];
+    }
+    common.reverse();
+
+    Some(common)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn patience(
+    a: &[usize],
+    b: &[usize],
+    line1: usize,
+    count1: usize,
+    line2: usize,
+    count2: usize,
+    changed_a: &mut [bool],
+    changed_b: &mut [bool],
+) {
+    if count1 == 0 {
+        mark(changed_b, line2, count2);
+        return;
+    }
+    if count2 == 0 {
+        mark(changed_a, line1, count1);
+        return;
+    }
+
+    let common = match unique_common_lines(a, b, line1, count1, line2, count2) {
+        Some(common) => common,
+        None => {
+            mark(changed_a, line1, count1);
+            mark(changed_b, line2, count2);
+            return;
+        }
+    };
+
+    if common.is_empty() {
+        let (sub_a, sub_b) = myers::diff(&a[line1..line1 + count1], &b[line2..line2 + count2]);
+        changed_a[line1..line1 + count1].copy_from_slice(&sub_a);
+        changed_b[line2..line2 + count2].copy_from_slice(&sub_b);
+        return;
+    }
+
+    // recurse into the gaps between the anchors, after growing each anchor
+    // with the matching lines around it
+    let (end1, end2) = (line1 + count1, line2 + count2);
+    let (mut line1, mut line2) = (line1, line2);
+    let mut next = 0;
+
+    loop {
+        let (next1, next2) = match common.get(next) {
+            Some(&(mut next1, mut next2)) => {
+                while next1 > line1 && next2 > line2 && a[next1 - 1] == b[next2 - 1] {
+                    next1 -= 1;
+                    next2 -= 1;
+                }
+                (next1, next2)
+            }
+            None => (end1, end2),
+        };
+
+        while line1 < next1 && line2 < next2 && a[line1] == b[line2] {
+            line1 += 1;
+            line2 += 1;
+        }
+
+        if next1 > line1 || next2 > line2 {
+            patience(
+                a,
+                b,
+                line1,
+                next1 - line1,
+                line2,
+                next2 - line2,
+                changed_a,
+                changed_b,
+            );
+        }
+
+        if next >= common.len() {
+            return;
+        }
+
+        while next + 1 < common.len()
+            && common[next + 1].0 == common[next].0 + 1
+            && common[next + 1].1 == common[next].1 + 1
+        {
+            next += 1;
+        }
+
+        line1 = common[next].0 + 1;
+        line2 = common[next].1 + 1;
+        next += 1;
+    }
+}
+
+/// Patience diff: anchors on lines that are unique on both sides and
+/// falls back to Myers between them when there are none.
+pub fn diff(a: &[usize], b: &[usize]) -> (Vec<bool>, Vec<bool>) {
+    let mut changed_a = vec![false; a.len()];
+    let mut changed_b = vec![false; b.len()];
+
+    patience(a, b, 0, a.len(), 0, b.len(), &mut changed_a, &mut changed_b);
+
+    (changed_a, changed_b)
+}
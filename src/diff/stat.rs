@@ -0,0 +1,343 @@
+use anyhow::Result;
+
+use super::files::BlobCache;
+use super::line::{count_changes, is_binary, DiffOptions};
+use super::patch::quote_path_bytes;
+use super::tree::{Change, Status, MAX_SCORE};
+
+/// One line of `--stat` output.
+pub struct FileStat {
+    pub name: String,
+    pub insertions: usize,
+    pub deletions: usize,
+    /// Old and new sizes, for binary files.
+    pub binary: Option<(usize, usize)>,
+}
+
+// "a/b/c => a/d/c" becomes "a/{b => d}/c", following git's pprint_rename
+pub fn rename_name(a: &[u8], b: &[u8]) -> String {
+    let len_a = a.len() as isize;
+    let len_b = b.len() as isize;
+
+    let mut pfx_length: isize = 0;
+    let mut i = 0;
+    while i < a.len() && i < b.len() && a[i] == b[i] {
+        if a[i] == b'/' {
+            pfx_length = i as isize + 1;
+        }
+        i += 1;
+    }
+
+    // with a common prefix the suffix scan may run into its trailing slash
+    let pfx_adjust = if pfx_length > 0 { 1 } else { 0 };
+    let mut sfx_length: isize = 0;
+    let mut old_pos = len_a - 1;
+    let mut new_pos = len_b - 1;
+    while old_pos >= pfx_length - pfx_adjust
+        && new_pos >= pfx_length - pfx_adjust
+        && old_pos >= 0
+        && new_pos >= 0
+        && a[old_pos as usize] == b[new_pos as usize]
+    {
+        if a[old_pos as usize] == b'/' {
+            sfx_length = len_a - old_pos;
+        }
+        old_pos -= 1;
+        new_pos -= 1;
+    }
+
+    let a_midlen = (len_a - pfx_length - sfx_length).max(0) as usize;
+    let b_midlen = (len_b - pfx_length - sfx_length).max(0) as usize;
+    let pfx = pfx_length as usize;
+    let sfx = sfx_length as usize;
+
+    let mut name = String::new();
+    if pfx + sfx > 0 {
+        name.push_str(&String::from_utf8_lossy(&a[..pfx]));
+        name.push('{');
+    }
+    name.push_str(&String::from_utf8_lossy(&a[pfx..pfx + a_midlen]));
+    name.push_str(" => ");
+    name.push_str(&String::from_utf8_lossy(&b[pfx..pfx + b_midlen]));
+    if pfx + sfx > 0 {
+        name.push('}');
+        name.push_str(&String::from_utf8_lossy(&a[a.len() - sfx..]));
+    }
+
+    name
+}
+
+/// Counts the lines each change adds and removes. Files whose changes
+/// all disappear (say under `-w`) are left out, as git does.
+pub async fn file_stats(changes: &[Change], cache: &mut BlobCache, options: &DiffOptions) -> Result<Vec<FileStat>> {
+    let mut files = Vec::new();
+
+    for change in changes {
+        let old = cache.contents(change.old.as_ref()).await?;
+        let new = cache.contents(change.new.as_ref()).await?;
+
+        let name = match (&change.old, &change.new) {
+            (Some(old), Some(new)) if change.status == Status::Renamed || change.status == Status::Copied => {
+                rename_name(&old.path, &new.path)
+            }
+            _ => quote_path_bytes(change.path()),
+        };
+
+        if is_binary(&old) || is_binary(&new) {
+            files.push(FileStat {
+                name,
+                insertions: 0,
+                deletions: 0,
+                binary: Some((old.len(), new.len())),
+            });
+            continue;
+        }
+
+        let (insertions, deletions) = count_changes(&old, &new, options);
+        let same_mode = match (&change.old, &change.new) {
+            (Some(old), Some(new)) => old.mode == new.mode,
+            _ => false,
+        };
+        if change.status == Status::Modified && same_mode && insertions + deletions == 0 {
+            continue;
+        }
+
+        files.push(FileStat {
+            name,
+            insertions,
+            deletions,
+            binary: None,
+        });
+    }
+
+    Ok(files)
+}
+
+/// Renders `--numstat` output: counts and name separated by tabs, with
+/// dashes for binary files.
+pub fn format_numstat(files: &[FileStat]) -> String {
+    files
+        .iter()
+        .map(|file| match file.binary {
+            Some(_) => format!("-\t-\t{}\n", file.name),
+            None => format!("{}\t{}\t{}\n", file.insertions, file.deletions, file.name),
+        })
+        .collect()
+}
+
+/// Renders `--summary` output: creations, deletions, renames and mode
+/// changes, one per line.
+pub fn format_summary(changes: &[Change]) -> String {
+    let mut out = String::new();
+
+    for change in changes {
+        match (&change.old, &change.new) {
+            (None, Some(new)) => {
+                out.push_str(&format!(" create mode {:0>6} {}\n", new.mode, quote_path_bytes(&new.path)));
+            }
+            (Some(old), None) => {
+                out.push_str(&format!(" delete mode {:0>6} {}\n", old.mode, quote_path_bytes(&old.path)));
+            }
+            (Some(old), Some(new)) => {
+                let renamed = change.status == Status::Renamed || change.status == Status::Copied;
+                if renamed {
+                    out.push_str(&format!(
+                        " {} {} ({}%)\n",
+                        if change.status == Status::Renamed { "rename" } else { "copy" },
+                        rename_name(&old.path, &new.path),
+                        change.score as u64 * 100 / MAX_SCORE as u64
+                    ));
+                }
+                // the rename line above already names the file
+                if old.mode != new.mode {
+                    out.push_str(&format!(" mode change {:0>6} => {:0>6}", old.mode, new.mode));
+                    if !renamed {
+                        out.push_str(&format!(" {}", quote_path_bytes(&new.path)));
+                    }
+                    out.push('\n');
+                }
+            }
+            (None, None) => {}
+        }
+    }
+
+    out
+}
+
+fn scale_linear(it: usize, width: usize, max_change: usize) -> usize {
+    if it == 0 {
+        return 0;
+    }
+
+    1 + (it * (width - 1) / max_change)
+}
+
+fn plural(count: usize, word: &str) -> String {
+    if count == 1 {
+        format!("{} {}", count, word)
+    } else {
+        format!("{} {}s", count, word)
+    }
+}
+
+/// Renders `--stat` output for the given total width, laid out like git's.
+pub fn format_stat(files: &[FileStat], width: usize) -> String {
+    let mut out = String::new();
+    if files.is_empty() {
+        return out;
+    }
+
+    let max_len = files.iter().map(|f| f.name.chars().count()).max().unwrap_or(0);
+    let max_change = files
+        .iter()
+        .filter(|f| f.binary.is_none())
+        .map(|f| f.insertions + f.deletions)
+        .max()
+        .unwrap_or(0);
+
+    let mut number_width = max_change.to_string().len();
+    let bin_width = if files.iter().any(|f| f.binary.is_some()) {
+        number_width = number_width.max(3);
+        files
+            .iter()
+            .filter_map(|f| f.binary)
+            .map(|(old, new)| format!("Bin {} -> {} bytes", old, new).len())
+            .max()
+            .unwrap_or(0)
+    } else {
+        0
+    };
+
+    let width = width.max(16 + 6 + number_width);
+
+    let mut graph_width = if max_change + 4 > bin_width {
+        max_change
+    } else {
+        bin_width - 4
+    };
+    let mut name_width = max_len;
+
+    if name_width + number_width + 6 + graph_width > width {
+        let limit = (width * 3 / 8).saturating_sub(number_width + 6);
+        if graph_width > limit {
+            graph_width = limit.max(6);
+        }
+
+        if name_width > width - number_width - 6 - graph_width {
+            name_width = width - number_width - 6 - graph_width;
+        } else {
+            graph_width = width - number_width - 6 - name_width;
+        }
+    }
+
+    let mut total_insertions = 0;
+    let mut total_deletions = 0;
+
+    for file in files {
+        let chars: Vec<char> = file.name.chars().collect();
+        let name = if chars.len() > name_width {
+            // keep the tail, starting at a directory boundary where possible
+            let tail: String = chars[chars.len() - (name_width - 3)..].iter().collect();
+            match tail.find('/') {
+                Some(pos) => format!(".../{}", &tail[pos + 1..]),
+                None => format!("...{}", tail),
+            }
+        } else {
+            file.name.clone()
+        };
+
+        if let Some((old, new)) = file.binary {
+            out.push_str(&format!(
+                " {:name_width$} | {:>number_width$} {} -> {} bytes\n",
+                name,
+                "Bin",
+                old,
+                new,
+                name_width = name_width,
+                number_width = number_width
+            ));
+            continue;
+        }
+
+        let added = file.insertions;
+        let deleted = file.deletions;
+        total_insertions += added;
+        total_deletions += deleted;
+
+        let mut add = added;
+        let mut del = deleted;
+        if graph_width <= max_change {
+            let mut total = scale_linear(add + del, graph_width, max_change);
+            if total < 2 && add > 0 && del > 0 {
+                total = 2;
+            }
+            if add < del {
+                add = scale_linear(add, graph_width, max_change);
+                del = total - add;
+            } else {
+                del = scale_linear(del, graph_width, max_change);
+                add = total - del;
+            }
+        }
+
+        out.push_str(&format!(
+            " {:name_width$} | {:>number_width$}{}{}{}\n",
+            name,
+            added + deleted,
+            if added + deleted > 0 { " " } else { "" },
+            "+".repeat(add),
+            "-".repeat(del),
+            name_width = name_width,
+            number_width = number_width
+        ));
+    }
+
+    out.push_str(&format!(" {} changed", plural(files.len(), "file")));
+    if total_insertions > 0 || total_deletions == 0 {
+        out.push_str(&format!(", {}(+)", plural(total_insertions, "insertion")));
+    }
+    if total_deletions > 0 || total_insertions == 0 {
+        out.push_str(&format!(", {}(-)", plural(total_deletions, "deletion")));
+    }
+    out.push('\n');
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rename_name() {
+        assert_eq!(rename_name(b"a.txt", b"b.txt"), "a.txt => b.txt");
+        assert_eq!(rename_name(b"src/a.rs", b"src/b.rs"), "src/{a.rs => b.rs}");
+        assert_eq!(rename_name(b"a/b/c", b"a/d/c"), "a/{b => d}/c");
+        assert_eq!(rename_name(b"x/file", b"file"), "x/file => file");
+        assert_eq!(rename_name(b"a/x/f", b"a/f"), "a/{x => }/f");
+    }
+
+    #[test]
+    fn test_format_stat() {
+        let files = vec![
+            FileStat {
+                name: String::from("a.txt"),
+                insertions: 1,
+                deletions: 1,
+                binary: None,
+            },
+            FileStat {
+                name: String::from("dir/new"),
+                insertions: 3,
+                deletions: 0,
+                binary: None,
+            },
+        ];
+
+        assert_eq!(
+            format_stat(&files, 80),
+            " a.txt   | 2 +-\n dir/new | 3 +++\n 2 files changed, 4 insertions(+), 1 deletion(-)\n"
+        );
+        assert_eq!(format_numstat(&files), "1\t1\ta.txt\n3\t0\tdir/new\n");
+    }
+}
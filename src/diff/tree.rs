@@ -0,0 +1,481 @@
+use anyhow::Result;
+use async_recursion::async_recursion;
+use std::collections::{BTreeMap, HashMap};
+
+use super::files::{tree_listing, BlobCache, Listing};
+use crate::objects::{Object, ObjectId, Tree, TreeEntry};
+
+/// Similarity scores are fractions of MAX_SCORE, as in git.
+pub const MAX_SCORE: u32 = 60000;
+pub const DEFAULT_SCORE: u32 = 30000;
+
+// pairing every source with every destination is quadratic; past this many
+// candidates only exact renames are detected
+const RENAME_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Added,
+    Deleted,
+    Modified,
+    TypeChanged,
+    Renamed,
+    Copied,
+}
+
+impl Status {
+    pub fn letter(&self) -> char {
+        match self {
+            Status::Added => 'A',
+            Status::Deleted => 'D',
+            Status::Modified => 'M',
+            Status::TypeChanged => 'T',
+            Status::Renamed => 'R',
+            Status::Copied => 'C',
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DiffEntry {
+    pub path: Vec<u8>,
+    pub mode: String,
+    pub sha1_hash: ObjectId,
+}
+
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub status: Status,
+    pub score: u32,
+    pub old: Option<DiffEntry>,
+    pub new: Option<DiffEntry>,
+}
+
+impl Change {
+    pub fn path(&self) -> &[u8] {
+        match (&self.new, &self.old) {
+            (Some(new), _) => &new.path,
+            (None, Some(old)) => &old.path,
+            (None, None) => b"",
+        }
+    }
+
+    /// `M`, `A`, ... or `R086` style status with the similarity percentage.
+    pub fn status_string(&self) -> String {
+        match self.status {
+            Status::Renamed | Status::Copied => format!(
+                "{}{:03}",
+                self.status.letter(),
+                self.score as u64 * 100 / MAX_SCORE as u64
+            ),
+            status => status.letter().to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TreeDiffOptions {
+    pub recursive: bool,
+    /// Minimum score for a deletion/addition pair to count as a rename.
+    pub renames: Option<u32>,
+    /// Minimum score for an addition to count as a copy of another file.
+    pub copies: Option<u32>,
+    /// Consider unmodified files as copy sources too.
+    pub find_copies_harder: bool,
+}
+
+/// Parses a `-M`/`-C` argument the way git does: `90%` is 90%, and a bare
+/// number is the digits after a decimal point, so `9` is 90% and `05` is 5%.
+pub fn parse_score(arg: &str) -> Option<u32> {
+    if arg.is_empty() {
+        return Some(DEFAULT_SCORE);
+    }
+
+    if let Some(percent) = arg.strip_suffix('%') {
+        let percent: f64 = percent.parse().ok()?;
+        return Some(((percent / 100.0) * MAX_SCORE as f64).min(MAX_SCORE as f64) as u32);
+    }
+
+    if !arg.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let fraction: f64 = format!("0.{}", arg).parse().ok()?;
+
+    Some((fraction * MAX_SCORE as f64) as u32)
+}
+
+fn mode_class(mode: &str) -> u8 {
+    match mode {
+        "120000" => 1,
+        "160000" => 2,
+        "40000" | "040000" => 3,
+        _ => 0,
+    }
+}
+
+fn diff_entry(path: Vec<u8>, entry: &TreeEntry) -> DiffEntry {
+    DiffEntry {
+        path,
+        mode: entry.mode().to_string(),
+        sha1_hash: entry.sha1_hash(),
+    }
+}
+
+// entries keyed the way git orders them, with trees sorting as "name/"
+async fn tree_entries(tree_sha: Option<ObjectId>) -> Result<BTreeMap<Vec<u8>, TreeEntry>> {
+    let mut entries = BTreeMap::new();
+
+    if let Some(sha) = tree_sha {
+        let tree = Tree::from_object_sha(hex::encode(sha)).await?;
+        for entry in tree.entries() {
+            let mut key = entry.name_bytes().to_vec();
+            if entry.is_tree() {
+                key.push(b'/');
+            }
+            entries.insert(key, entry.clone());
+        }
+    }
+
+    Ok(entries)
+}
+
+#[async_recursion]
+async fn walk(
+    old_tree: Option<ObjectId>,
+    new_tree: Option<ObjectId>,
+    prefix: Vec<u8>,
+    recursive: bool,
+    changes: &mut Vec<Change>,
+) -> Result<()> {
+    let old_entries = tree_entries(old_tree).await?;
+    let new_entries = tree_entries(new_tree).await?;
+
+    let mut keys: Vec<&Vec<u8>> = old_entries.keys().chain(new_entries.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    for key in keys {
+        let old = old_entries.get(key);
+        let new = new_entries.get(key);
+
+        if let (Some(old), Some(new)) = (old, new) {
+            if old.sha1_hash() == new.sha1_hash() && old.mode() == new.mode() {
+                continue;
+            }
+        }
+
+        let is_tree = old.or(new).map(|e| e.is_tree()).unwrap_or(false);
+        let mut path = prefix.clone();
+        path.extend_from_slice(key.strip_suffix(b"/").unwrap_or(key));
+
+        if is_tree && recursive {
+            let mut prefix = path;
+            prefix.push(b'/');
+            walk(
+                old.map(|e| e.sha1_hash()),
+                new.map(|e| e.sha1_hash()),
+                prefix,
+                recursive,
+                changes,
+            )
+            .await?;
+            continue;
+        }
+
+        let status = match (old, new) {
+            (None, Some(_)) => Status::Added,
+            (Some(_), None) => Status::Deleted,
+            (Some(old), Some(new)) if mode_class(old.mode().as_str()) != mode_class(new.mode().as_str()) => {
+                Status::TypeChanged
+            }
+            _ => Status::Modified,
+        };
+
+        changes.push(Change {
+            status,
+            score: 0,
+            old: old.map(|e| diff_entry(path.clone(), e)),
+            new: new.map(|e| diff_entry(path.clone(), e)),
+        });
+    }
+
+    Ok(())
+}
+
+// byte counts of each line-or-64-byte chunk, as git's diffcore-delta hashes them
+fn chunk_counts(data: &[u8]) -> HashMap<&[u8], usize> {
+    let mut counts = HashMap::new();
+    let mut start = 0;
+
+    for i in 0..data.len() {
+        if data[i] == b'\n' || i + 1 - start >= 64 {
+            *counts.entry(&data[start..=i]).or_insert(0) += i + 1 - start;
+            start = i + 1;
+        }
+    }
+    if start < data.len() {
+        *counts.entry(&data[start..]).or_insert(0) += data.len() - start;
+    }
+
+    counts
+}
+
+/// How much of `src` survives in `dst`, scaled to MAX_SCORE.
+pub fn similarity(src: &[u8], dst: &[u8], minimum_score: u32) -> u32 {
+    let max_size = src.len().max(dst.len());
+    let min_size = src.len().min(dst.len());
+    if max_size == 0 {
+        return MAX_SCORE;
+    }
+
+    // sizes too far apart can never reach the minimum score
+    let delta_size = (max_size - min_size) as u64;
+    if (max_size as u64) * ((MAX_SCORE - minimum_score) as u64) < delta_size * MAX_SCORE as u64 {
+        return 0;
+    }
+
+    let src_counts = chunk_counts(src);
+    let dst_counts = chunk_counts(dst);
+
+    let copied: usize = src_counts
+        .iter()
+        .map(|(chunk, count)| (*count).min(*dst_counts.get(chunk).unwrap_or(&0)))
+        .sum();
+
+    (copied as u64 * MAX_SCORE as u64 / max_size as u64) as u32
+}
+
+fn basename(path: &[u8]) -> &[u8] {
+    path.rsplit(|b| *b == b'/').next().unwrap_or(path)
+}
+
+// `unmodified` lists the old side's files, used as extra copy sources
+// with find_copies_harder
+async fn detect_renames(
+    changes: Vec<Change>,
+    unmodified: &Listing,
+    options: &TreeDiffOptions,
+    cache: &mut BlobCache,
+) -> Result<Vec<Change>> {
+    let minimum_score = match (options.renames, options.copies) {
+        (_, Some(score)) => score,
+        (Some(score), None) => score,
+        (None, None) => return Ok(changes),
+    };
+    let find_copies = options.copies.is_some();
+
+    // candidate sources: deletions, plus modified (or all) files for copies
+    let mut sources: Vec<(DiffEntry, bool)> = Vec::new();
+    for change in &changes {
+        match change.status {
+            Status::Deleted => sources.push((change.old.clone().unwrap(), true)),
+            Status::Modified if find_copies => sources.push((change.old.clone().unwrap(), false)),
+            _ => {}
+        }
+    }
+    if find_copies && options.find_copies_harder {
+        for (path, entry) in unmodified {
+            if !sources.iter().any(|(s, _)| s.path == *path) {
+                sources.push((entry.clone(), false));
+            }
+        }
+    }
+
+    let destinations: Vec<usize> = changes
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| c.status == Status::Added && mode_class(&c.new.as_ref().unwrap().mode) < 2)
+        .map(|(i, _)| i)
+        .collect();
+
+    // (destination change index, source index, score)
+    let mut pairs: HashMap<usize, (usize, u32)> = HashMap::new();
+    let mut used_sources: Vec<bool> = vec![false; sources.len()];
+
+    // exact matches first, preferring a source with the same file name
+    for &dst in &destinations {
+        let new = changes[dst].new.as_ref().unwrap();
+        let candidates: Vec<usize> = (0..sources.len())
+            .filter(|&s| {
+                sources[s].0.sha1_hash == new.sha1_hash
+                    && mode_class(&sources[s].0.mode) == mode_class(&new.mode)
+                    && (find_copies || !used_sources[s])
+            })
+            .collect();
+
+        let best = candidates
+            .iter()
+            .find(|&&s| basename(&sources[s].0.path) == basename(&new.path))
+            .or_else(|| candidates.first());
+
+        if let Some(&src) = best {
+            pairs.insert(dst, (src, MAX_SCORE));
+            used_sources[src] = true;
+        }
+    }
+
+    let remaining: Vec<usize> = destinations
+        .iter()
+        .copied()
+        .filter(|d| !pairs.contains_key(d))
+        .collect();
+
+    if !remaining.is_empty() && sources.len() * remaining.len() <= RENAME_LIMIT * RENAME_LIMIT {
+        let mut scored: Vec<(u32, usize, usize)> = Vec::new();
+
+        for &dst in &remaining {
+            let new = changes[dst].new.clone().unwrap();
+            if mode_class(&new.mode) != 0 {
+                continue;
+            }
+            let dst_data = cache.get(new.sha1_hash).await?.to_vec();
+
+            for (src, (source, _)) in sources.iter().enumerate() {
+                if mode_class(&source.mode) != 0 {
+                    continue;
+                }
+                let src_data = cache.get(source.sha1_hash).await?;
+
+                let score = similarity(src_data, &dst_data, minimum_score);
+                if score >= minimum_score {
+                    scored.push((score, dst, src));
+                }
+            }
+        }
+
+        // best scores first; ties go to the earlier destination and source
+        scored.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+
+        for (score, dst, src) in scored {
+            if pairs.contains_key(&dst) || (!find_copies && used_sources[src]) {
+                continue;
+            }
+            pairs.insert(dst, (src, score));
+            used_sources[src] = true;
+        }
+    }
+
+    if pairs.is_empty() {
+        return Ok(changes);
+    }
+
+    // a deleted source paired several times is renamed by its last pairing
+    // (in output order) and copied by the others
+    let mut last_use: HashMap<usize, usize> = HashMap::new();
+    for (i, _) in changes.iter().enumerate() {
+        if let Some((src, _)) = pairs.get(&i) {
+            last_use.insert(*src, i);
+        }
+    }
+
+    let renamed_sources: Vec<Vec<u8>> = last_use
+        .keys()
+        .filter(|&&s| sources[s].1)
+        .map(|&s| sources[s].0.path.clone())
+        .collect();
+
+    let mut result = Vec::new();
+    for (i, change) in changes.into_iter().enumerate() {
+        if change.status == Status::Deleted && renamed_sources.contains(&change.old.as_ref().unwrap().path) {
+            continue;
+        }
+
+        match pairs.get(&i) {
+            Some(&(src, score)) => {
+                let is_rename = sources[src].1 && last_use[&src] == i;
+                result.push(Change {
+                    status: if is_rename { Status::Renamed } else { Status::Copied },
+                    score,
+                    old: Some(sources[src].0.clone()),
+                    new: change.new,
+                });
+            }
+            None => result.push(change),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Compares two trees (either may be absent, standing for the empty tree).
+pub async fn diff_trees(
+    old_tree: Option<ObjectId>,
+    new_tree: Option<ObjectId>,
+    options: &TreeDiffOptions,
+) -> Result<Vec<Change>> {
+    let mut changes = Vec::new();
+    walk(old_tree, new_tree, Vec::new(), options.recursive, &mut changes).await?;
+
+    let unmodified = if options.copies.is_some() && options.find_copies_harder {
+        tree_listing(old_tree).await?
+    } else {
+        Listing::new()
+    };
+
+    detect_renames(changes, &unmodified, options, &mut BlobCache::default()).await
+}
+
+/// Compares two flat listings, such as the index and the working tree.
+/// Contents not in the object store must already be in `cache`.
+pub async fn diff_listings(
+    old: &Listing,
+    new: &Listing,
+    options: &TreeDiffOptions,
+    cache: &mut BlobCache,
+) -> Result<Vec<Change>> {
+    let mut paths: Vec<&Vec<u8>> = old.keys().chain(new.keys()).collect();
+    paths.sort();
+    paths.dedup();
+
+    let mut changes = Vec::new();
+    for path in paths {
+        let old = old.get(path);
+        let new = new.get(path);
+
+        let status = match (old, new) {
+            (Some(old), Some(new)) if old.sha1_hash == new.sha1_hash && old.mode == new.mode => {
+                continue
+            }
+            (Some(old), Some(new)) if mode_class(&old.mode) != mode_class(&new.mode) => {
+                Status::TypeChanged
+            }
+            (Some(_), Some(_)) => Status::Modified,
+            (Some(_), None) => Status::Deleted,
+            (None, _) => Status::Added,
+        };
+
+        changes.push(Change {
+            status,
+            score: 0,
+            old: old.cloned(),
+            new: new.cloned(),
+        });
+    }
+
+    detect_renames(changes, old, options, cache).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_score() {
+        assert_eq!(parse_score(""), Some(DEFAULT_SCORE));
+        assert_eq!(parse_score("90%"), Some(54000));
+        assert_eq!(parse_score("9"), Some(54000));
+        assert_eq!(parse_score("05"), Some(3000));
+        assert_eq!(parse_score("x"), None);
+    }
+
+    #[test]
+    fn test_similarity() {
+        let src = b"one\ntwo\nthree\nfour\n";
+        let dst = b"one\ntwo\nthree\nfive\n";
+
+        assert_eq!(similarity(src, src, DEFAULT_SCORE), MAX_SCORE);
+        // 14 of 19 bytes survive
+        assert_eq!(similarity(src, dst, DEFAULT_SCORE), 14 * MAX_SCORE / 19);
+        assert_eq!(similarity(src, b"x\n", DEFAULT_SCORE), 0);
+    }
+}
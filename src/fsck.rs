@@ -0,0 +1,635 @@
+use anyhow::Result;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::index::Index;
+use crate::objects::{self, EntryMode, HashAlgorithm, Object, ObjectId, Tree, TreeEntry};
+use crate::packfile::{PackIndex, Packfile};
+use crate::promisor;
+use crate::refs::{self, Head};
+use crate::shallow;
+use crate::utils;
+
+/// Something wrong with an object's content, named by git's fsck message id.
+#[derive(Debug, PartialEq)]
+struct Problem {
+    error: bool,
+    id: &'static str,
+    text: &'static str,
+}
+
+impl Problem {
+    fn error(id: &'static str, text: &'static str) -> Self {
+        Self { error: true, id, text }
+    }
+
+    fn warning(id: &'static str, text: &'static str) -> Self {
+        Self { error: false, id, text }
+    }
+}
+
+// an object pointed at, with the type it should have
+type Link = (ObjectId, &'static str);
+
+// what a stored object points at
+struct Node {
+    kind: &'static str,
+    links: Vec<Link>,
+}
+
+// an ID of the repository's algorithm, not just any hex
+fn parse_sha(hex: &str) -> Option<ObjectId> {
+    ObjectId::from_hex(hex)
+        .ok()
+        .filter(|sha1_hash| sha1_hash.algorithm() == HashAlgorithm::current())
+}
+
+fn object_kind(name: &str) -> Option<&'static str> {
+    ["blob", "tree", "commit", "tag"].iter().copied().find(|kind| *kind == name)
+}
+
+// checks a "Name <email> timestamp tz" identity
+fn check_ident(ident: &str) -> Option<Problem> {
+    let email_start = match ident.find('<') {
+        Some(pos) => pos,
+        None => return Some(Problem::error("missingEmail", "invalid author/committer line - missing email")),
+    };
+    let email_end = match ident[email_start..].find('>') {
+        Some(pos) => email_start + pos,
+        None => return Some(Problem::error("badEmail", "invalid author/committer line - bad email")),
+    };
+
+    let mut time = ident[email_end + 1..].strip_prefix(' ').unwrap_or("").splitn(2, ' ');
+    let timestamp = time.next().unwrap_or("");
+    if timestamp.is_empty() || !timestamp.bytes().all(|b| b.is_ascii_digit()) {
+        return Some(Problem::error("badDate", "invalid author/committer line - bad date"));
+    }
+
+    let tz = time.next().unwrap_or("");
+    let valid_tz = tz.len() == 5
+        && (tz.starts_with('+') || tz.starts_with('-'))
+        && tz[1..].bytes().all(|b| b.is_ascii_digit());
+    if !valid_tz {
+        return Some(Problem::error("badTimezone", "invalid author/committer line - bad time zone"));
+    }
+
+    None
+}
+
+// header lines of a commit or tag, continuation lines left out
+fn header_lines(body: &str) -> impl Iterator<Item = &str> {
+    let headers = match body.find("\n\n") {
+        Some(pos) => &body[..pos],
+        None => body,
+    };
+
+    headers.lines().filter(|line| !line.starts_with(' '))
+}
+
+// a commit's links, and the first problem with its headers if any
+fn check_commit(body: &str) -> (Vec<Link>, Option<Problem>) {
+    let mut links = Vec::new();
+    let mut lines = header_lines(body).peekable();
+
+    match lines.next().and_then(|line| line.strip_prefix("tree ")) {
+        Some(hex) => match parse_sha(hex) {
+            Some(sha) => links.push((sha, "tree")),
+            None => return (links, Some(Problem::error("badTreeSha1", "invalid 'tree' line format - bad sha1"))),
+        },
+        None => return (links, Some(Problem::error("missingTree", "invalid format - expected 'tree' line"))),
+    }
+
+    while let Some(hex) = lines.peek().and_then(|line| line.strip_prefix("parent ")) {
+        match parse_sha(hex) {
+            Some(sha) => links.push((sha, "commit")),
+            None => return (links, Some(Problem::error("badParentSha1", "invalid 'parent' line format - bad sha1"))),
+        }
+        lines.next();
+    }
+
+    let problem = match lines.next().and_then(|line| line.strip_prefix("author ")) {
+        Some(ident) => check_ident(ident),
+        None => Some(Problem::error("missingAuthor", "invalid format - expected 'author' line")),
+    };
+    if problem.is_some() {
+        return (links, problem);
+    }
+
+    let problem = match lines.next().and_then(|line| line.strip_prefix("committer ")) {
+        Some(ident) => check_ident(ident),
+        None => Some(Problem::error("missingCommitter", "invalid format - expected 'committer' line")),
+    };
+
+    (links, problem)
+}
+
+// a tag's link, and the first problem with its headers if any
+fn check_tag(body: &str) -> (Vec<Link>, Option<Problem>) {
+    let mut lines = header_lines(body);
+
+    let object = match lines.next().and_then(|line| line.strip_prefix("object ")) {
+        Some(hex) => match parse_sha(hex) {
+            Some(sha) => sha,
+            None => return (Vec::new(), Some(Problem::error("badObjectSha1", "invalid 'object' line format - bad sha1"))),
+        },
+        None => return (Vec::new(), Some(Problem::error("missingObject", "invalid format - expected 'object' line"))),
+    };
+
+    let kind = match lines.next().and_then(|line| line.strip_prefix("type ")) {
+        Some(kind) => match object_kind(kind) {
+            Some(kind) => kind,
+            None => return (Vec::new(), Some(Problem::error("badType", "invalid 'type' value"))),
+        },
+        None => return (Vec::new(), Some(Problem::error("missingTypeEntry", "invalid format - expected 'type' line"))),
+    };
+    let links = vec![(object, kind)];
+
+    if lines.next().and_then(|line| line.strip_prefix("tag ")).is_none() {
+        return (links, Some(Problem::error("missingTagEntry", "invalid format - expected 'tag' line")));
+    }
+
+    let problem = match lines.next().and_then(|line| line.strip_prefix("tagger ")) {
+        Some(ident) => check_ident(ident),
+        None => Some(Problem::warning("missingTaggerEntry", "invalid format - expected 'tagger' line")),
+    };
+
+    (links, problem)
+}
+
+// everything wrong with a tree's entries, in the order git reports it
+fn check_tree(entries: &[TreeEntry]) -> Vec<Problem> {
+    let mut null_sha = false;
+    let mut full_path = false;
+    let mut empty_name = false;
+    let mut has_dot = false;
+    let mut has_dotdot = false;
+    let mut has_dotgit = false;
+    let mut zero_padded = false;
+    let mut bad_mode = false;
+    let mut duplicates = false;
+    let mut unsorted = false;
+
+    let mut names = HashSet::new();
+    let mut previous: Option<&TreeEntry> = None;
+
+    for entry in entries {
+        let name = entry.name_bytes();
+
+        null_sha |= entry.sha1_hash().is_null();
+        full_path |= name.contains(&b'/');
+        empty_name |= name.is_empty();
+        has_dot |= name == b".";
+        has_dotdot |= name == b"..";
+        has_dotgit |= name.eq_ignore_ascii_case(b".git");
+
+        zero_padded |= entry.recorded_mode().starts_with('0');
+        // 100664 is an old permission git still accepts
+        let mode = entry.recorded_mode().trim_start_matches('0');
+        bad_mode |= !["100644", "100755", "100664", "120000", "40000", "160000"].contains(&mode);
+
+        duplicates |= !names.insert(name);
+        if let Some(previous) = previous {
+            unsorted |= previous.name_bytes() != name && previous.sort_key() >= entry.sort_key();
+        }
+        previous = Some(entry);
+    }
+
+    let checks = vec![
+        (null_sha, Problem::warning("nullSha1", "contains entries pointing to null sha1")),
+        (full_path, Problem::warning("fullPathname", "contains full pathnames")),
+        (empty_name, Problem::warning("emptyName", "contains empty pathname")),
+        (has_dot, Problem::warning("hasDot", "contains '.'")),
+        (has_dotdot, Problem::warning("hasDotdot", "contains '..'")),
+        (has_dotgit, Problem::warning("hasDotgit", "contains '.git'")),
+        (zero_padded, Problem::warning("zeroPaddedFilemode", "contains zero-padded file modes")),
+        (bad_mode, Problem::warning("badFilemode", "contains bad file modes")),
+        (duplicates, Problem::error("duplicateEntries", "contains duplicate file entries")),
+        (unsorted, Problem::error("treeNotSorted", "not properly sorted")),
+    ];
+
+    checks
+        .into_iter()
+        .filter(|(found, _)| *found)
+        .map(|(_, problem)| problem)
+        .collect()
+}
+
+fn tree_links(entries: &[TreeEntry]) -> Vec<Link> {
+    entries
+        .iter()
+        .filter_map(|entry| match entry.mode() {
+            // submodule commits live in another repository
+            EntryMode::Gitlink => None,
+            mode => Some((entry.sha1_hash(), mode.object_type())),
+        })
+        .collect()
+}
+
+fn sorted_dir(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        paths.push(entry?.path());
+    }
+    paths.sort();
+
+    Ok(paths)
+}
+
+/// Checks every stored object, then which of them the refs, reflogs and
+/// index reach. Problems are printed as they are found, the way git
+/// prints them.
+pub struct Fsck {
+    connectivity_only: bool,
+    objects: HashMap<ObjectId, Node>,
+    roots: Vec<ObjectId>,
+    reachable: HashSet<ObjectId>,
+    ok: bool,
+}
+
+impl Fsck {
+    /// With `connectivity_only`, objects are neither rehashed nor checked
+    /// for well-formedness, only followed.
+    pub fn new(connectivity_only: bool) -> Self {
+        Self {
+            connectivity_only,
+            objects: HashMap::new(),
+            roots: Vec::new(),
+            reachable: HashSet::new(),
+            ok: true,
+        }
+    }
+
+    /// Whether nothing worse than a warning or a dangling object was found.
+    pub fn ok(&self) -> bool {
+        self.ok
+    }
+
+    fn error(&mut self, message: String) {
+        eprintln!("error: {}", message);
+        self.ok = false;
+    }
+
+    fn report(&mut self, sha1_hash: ObjectId, kind: &str, problem: Problem) {
+        let severity = if problem.error { "error" } else { "warning" };
+        eprintln!(
+            "{} in {} {}: {}: {}",
+            severity,
+            kind,
+            hex::encode(sha1_hash),
+            problem.id,
+            problem.text
+        );
+        self.ok &= !problem.error;
+    }
+
+    /// Reads every loose object, rehashing it against its file name.
+    pub fn scan_loose(&mut self) -> Result<()> {
+        for dir in sorted_dir(Path::new(".git/objects"))? {
+            let prefix = dir.file_name().unwrap_or_default().to_string_lossy().to_string();
+            if prefix.len() != 2 || !dir.is_dir() {
+                continue;
+            }
+
+            for path in sorted_dir(&dir)? {
+                let name = format!("{}{}", prefix, path.file_name().unwrap_or_default().to_string_lossy());
+                let sha1_hash = match parse_sha(&name) {
+                    Some(sha) => sha,
+                    None => continue,
+                };
+
+                let write_data = match utils::zlib_decompress(fs::read(&path)?) {
+                    Ok(data) => data,
+                    Err(_) => {
+                        self.error(format!("{}: object corrupt or missing: {}", name, path.display()));
+                        continue;
+                    }
+                };
+
+                if !self.connectivity_only {
+                    let actual = HashAlgorithm::current().digest(&write_data);
+                    if actual != sha1_hash {
+                        self.error(format!(
+                            "{}: hash-path mismatch, found at: {}",
+                            hex::encode(actual),
+                            path.display()
+                        ));
+                        continue;
+                    }
+                }
+
+                self.add(sha1_hash, write_data, &path);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads every pack, checking its objects against its index.
+    pub fn scan_packs(&mut self) -> Result<()> {
+        let dir = Path::new(".git/objects/pack");
+        if !dir.is_dir() {
+            return Ok(());
+        }
+
+        for path in sorted_dir(dir)? {
+            if path.extension().and_then(|ext| ext.to_str()) != Some("pack") {
+                continue;
+            }
+
+            let packfile = match Packfile::parse_data(&fs::read(&path)?) {
+                Ok(packfile) => packfile,
+                Err(err) => {
+                    self.error(format!("{}: {}", path.display(), err));
+                    continue;
+                }
+            };
+
+            let mut contained = HashSet::new();
+            for pack_object in packfile.objects() {
+                let sha1_hash = pack_object.sha1_hash();
+                contained.insert(sha1_hash);
+                self.add(sha1_hash, pack_object.write_data(), &path);
+            }
+
+            let index_path = path.with_extension("idx");
+            if !index_path.is_file() {
+                continue;
+            }
+            match PackIndex::parse(&fs::read(&index_path)?) {
+                Ok(index) => {
+                    for name in index.names().iter().filter(|name| !contained.contains(*name)) {
+                        self.error(format!(
+                            "{}: listed in {} but missing from its pack",
+                            hex::encode(name),
+                            index_path.display()
+                        ));
+                    }
+                }
+                Err(err) => self.error(format!("{}: {}", index_path.display(), err)),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add(&mut self, sha1_hash: ObjectId, write_data: Vec<u8>, location: &Path) {
+        let (kind, body_start) = match objects::parse_header(&write_data) {
+            Ok((kind, body_start)) => (object_kind(kind).unwrap_or("blob"), body_start),
+            Err(_) => {
+                self.error(format!("{}: object corrupt or missing: {}", hex::encode(sha1_hash), location.display()));
+                return;
+            }
+        };
+
+        let (links, problems) = match kind {
+            "tree" => match Tree::from_write_data(sha1_hash, write_data) {
+                Ok(tree) => (tree_links(tree.entries()), check_tree(tree.entries())),
+                Err(_) => {
+                    self.error(format!(
+                        "{}: object could not be parsed: {}",
+                        hex::encode(sha1_hash),
+                        location.display()
+                    ));
+                    return;
+                }
+            },
+            "commit" | "tag" => {
+                let body = String::from_utf8_lossy(&write_data[body_start..]);
+                let (mut links, problem) = if kind == "commit" { check_commit(&body) } else { check_tag(&body) };
+                // a shallow clone does not have the parents of its boundary
+                if kind == "commit" && shallow::is_grafted(sha1_hash) {
+                    links.retain(|(_, expected)| *expected != "commit");
+                }
+                (links, problem.into_iter().collect())
+            }
+            _ => (Vec::new(), Vec::new()),
+        };
+
+        if !self.connectivity_only {
+            for problem in problems {
+                self.report(sha1_hash, kind, problem);
+            }
+        }
+
+        self.objects.insert(sha1_hash, Node { kind, links });
+    }
+
+    fn add_root(&mut self, name: &str, sha1_hash: ObjectId, what: &str) {
+        if self.objects.contains_key(&sha1_hash) {
+            self.roots.push(sha1_hash);
+        } else {
+            self.error(format!("{}: invalid {} {}", name, what, hex::encode(sha1_hash)));
+        }
+    }
+
+    /// Collects what HEAD, the refs and their reflogs point at, checking that
+    /// every ref resolves to an object that exists.
+    pub fn check_refs(&mut self) -> Result<()> {
+        let names = refs::list_ref_names("refs/")?;
+
+        match refs::read_head() {
+            Ok(Head::Detached(sha1_hash)) => self.add_root("HEAD", sha1_hash, "sha1 pointer"),
+            Ok(Head::Branch(refname)) => {
+                if refs::resolve(&refname).ok().flatten().is_none() {
+                    println!("notice: HEAD points to an unborn branch ({})", refs::shorten(&refname));
+                }
+            }
+            Err(_) => self.error(format!("HEAD: invalid sha1 pointer {}", HashAlgorithm::current().null())),
+        }
+        if names.is_empty() {
+            println!("notice: No default references");
+        }
+
+        for name in &names {
+            match refs::resolve(name) {
+                Ok(Some(sha1_hash)) => self.add_root(name, sha1_hash, "sha1 pointer"),
+                _ => self.error(format!("{}: invalid sha1 pointer {}", name, HashAlgorithm::current().null())),
+            }
+        }
+
+        for name in std::iter::once("HEAD").chain(names.iter().map(String::as_str)) {
+            let entries = match refs::read_reflog(name) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    self.error(format!("{}: {}", name, err));
+                    continue;
+                }
+            };
+
+            let shas: BTreeSet<ObjectId> = entries
+                .iter()
+                .flat_map(|entry| vec![entry.old, entry.new])
+                .filter(|sha| !sha.is_null())
+                .collect();
+            for sha1_hash in shas {
+                self.add_root(name, sha1_hash, "reflog entry");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every link points at an object of the type it should
+    /// have, then follows every link from the roots and the index,
+    /// reporting objects that are missing.
+    pub fn check_connectivity(&mut self) -> Result<()> {
+        let mut shas: Vec<ObjectId> = self.objects.keys().copied().collect();
+        shas.sort();
+
+        let mut mistyped = HashSet::new();
+        for sha1_hash in shas {
+            let node = &self.objects[&sha1_hash];
+            let mut broken = false;
+
+            for (link, expected) in &node.links {
+                let kind = match self.objects.get(link) {
+                    Some(target) if target.kind != *expected => target.kind,
+                    _ => continue,
+                };
+
+                if mistyped.insert((*link, *expected)) {
+                    eprintln!("error: object {} is a {}, not a {}", hex::encode(link), kind, expected);
+                }
+                if node.kind == "tag" {
+                    eprintln!("error: bad tag pointer to {} in {}", hex::encode(link), hex::encode(sha1_hash));
+                }
+                broken = true;
+            }
+
+            if broken && node.kind != "tag" {
+                eprintln!("error in {} {}: broken links", node.kind, hex::encode(sha1_hash));
+            }
+            self.ok &= !broken;
+        }
+
+        let mut pending: Vec<Link> = self.roots.iter().map(|root| (*root, "object")).collect();
+        for entry in Index::load()?.entries() {
+            if entry.mode != 0o160000 {
+                pending.push((entry.sha1_hash, "blob"));
+            }
+        }
+
+        let mut missing = BTreeSet::new();
+        while let Some((sha1_hash, expected)) = pending.pop() {
+            match self.objects.get(&sha1_hash) {
+                Some(node) => {
+                    if self.reachable.insert(sha1_hash) {
+                        pending.extend(node.links.iter().copied());
+                    }
+                }
+                None => {
+                    missing.insert((expected, sha1_hash));
+                }
+            }
+        }
+
+        // a partial clone is missing objects on purpose; its remote has them
+        if promisor::is_partial_clone()? {
+            return Ok(());
+        }
+
+        for (kind, sha1_hash) in missing {
+            println!("missing {} {}", kind, hex::encode(sha1_hash));
+            self.ok = false;
+        }
+
+        Ok(())
+    }
+
+    /// Lists the objects nothing reachable points at; unless `all`, only
+    /// those no other unreachable object points at either.
+    pub fn report_unreachable(&self, all: bool) {
+        let unreachable: BTreeSet<ObjectId> = self
+            .objects
+            .keys()
+            .filter(|sha| !self.reachable.contains(*sha))
+            .copied()
+            .collect();
+
+        let referenced: HashSet<ObjectId> = unreachable
+            .iter()
+            .flat_map(|sha| self.objects[sha].links.iter().map(|(link, _)| *link))
+            .collect();
+
+        for sha1_hash in unreachable {
+            let kind = self.objects[&sha1_hash].kind;
+            if all {
+                println!("unreachable {} {}", kind, hex::encode(sha1_hash));
+            } else if !referenced.contains(&sha1_hash) {
+                println!("dangling {} {}", kind, hex::encode(sha1_hash));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(mode: &str, name: &str) -> TreeEntry {
+        TreeEntry::parse(mode, name, ObjectId::from([1; 20])).unwrap()
+    }
+
+    fn ids(problems: &[Problem]) -> Vec<&str> {
+        problems.iter().map(|problem| problem.id).collect()
+    }
+
+    #[test]
+    fn test_check_tree() {
+        let sorted = [entry("100644", "a.txt"), entry("40000", "a"), entry("100755", "b")];
+        assert!(check_tree(&sorted).is_empty());
+
+        let unsorted = [entry("40000", "a"), entry("100644", "a.txt")];
+        assert_eq!(ids(&check_tree(&unsorted)), ["treeNotSorted"]);
+
+        let duplicated = [entry("100644", "a"), entry("100644", "a")];
+        assert_eq!(ids(&check_tree(&duplicated)), ["duplicateEntries"]);
+
+        let odd = [entry("100644", ".git"), entry("040000", "d"), entry("100600", "m")];
+        assert_eq!(ids(&check_tree(&odd)), ["hasDotgit", "zeroPaddedFilemode", "badFilemode"]);
+    }
+
+    #[test]
+    fn test_check_commit() {
+        let tree = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+
+        let body = format!("tree {}\nparent {}\nauthor A <a@x> 1 +0000\ncommitter C <c@x> 2 -0130\n\nmsg\n", tree, tree);
+        let (links, problem) = check_commit(&body);
+        assert_eq!(links.iter().map(|(_, kind)| *kind).collect::<Vec<_>>(), ["tree", "commit"]);
+        assert_eq!(problem, None);
+
+        let body = format!("tree {}\nauthor A <a@x> 1 +0000\n\nmsg\n", tree);
+        assert_eq!(check_commit(&body).1.map(|p| p.id), Some("missingCommitter"));
+
+        let body = format!("tree {}\nauthor A <a@x> soon +0000\ncommitter C <c@x> 1 +0000\n", tree);
+        assert_eq!(check_commit(&body).1.map(|p| p.id), Some("badDate"));
+
+        assert_eq!(check_commit("tree xyz\n").1.map(|p| p.id), Some("badTreeSha1"));
+        assert_eq!(check_commit("author A <a@x> 1 +0000\n").1.map(|p| p.id), Some("missingTree"));
+    }
+
+    #[test]
+    fn test_check_tag() {
+        let object = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+
+        let body = format!("object {}\ntype tree\ntag v1\ntagger C <c@x> 1 +0000\n\nmsg\n", object);
+        let (links, problem) = check_tag(&body);
+        assert_eq!(links, [(parse_sha(object).unwrap(), "tree")]);
+        assert_eq!(problem, None);
+
+        let body = format!("object {}\ntype tree\ntag v1\n\nmsg\n", object);
+        assert_eq!(check_tag(&body).1, Some(Problem::warning("missingTaggerEntry", "invalid format - expected 'tagger' line")));
+
+        let body = format!("object {}\ntype note\ntag v1\n", object);
+        assert_eq!(check_tag(&body).1.map(|p| p.id), Some("badType"));
+    }
+
+    #[test]
+    fn test_check_ident() {
+        assert_eq!(check_ident("A U Thor <a@x> 1700000000 +0100"), None);
+        assert_eq!(check_ident("A U Thor a@x 1 +0000").map(|p| p.id), Some("missingEmail"));
+        assert_eq!(check_ident("A <a@x 1 +0000").map(|p| p.id), Some("badEmail"));
+        assert_eq!(check_ident("A <a@x> 1 0000").map(|p| p.id), Some("badTimezone"));
+    }
+}
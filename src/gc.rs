@@ -0,0 +1,449 @@
+use anyhow::{bail, Result};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::index::Index;
+use crate::objects::{self, AnyObject, EntryMode, HashAlgorithm, Object, ObjectId};
+use crate::packfile::{self, PackIndex, PackObject};
+use crate::promisor;
+use crate::refs;
+use crate::revision;
+
+const MTIMES_SIGNATURE: &[u8] = b"MTME";
+
+/// What becomes of unreachable objects when everything is repacked.
+pub enum Unreachable {
+    /// Leave them out of the new pack.
+    Drop,
+    /// Keep those written since the cutoff (all of them, without one) in a
+    /// cruft pack that records when each was last written.
+    Cruft(Option<i64>),
+}
+
+// what HEAD, the refs, their reflogs and the index point at, with a name
+// hinting at what each is
+fn roots() -> Result<Vec<(ObjectId, String)>> {
+    let mut roots = Vec::new();
+
+    if let Some(sha1_hash) = refs::head_sha()? {
+        roots.push((sha1_hash, String::new()));
+    }
+
+    let names = refs::list_ref_names("refs/")?;
+    for name in &names {
+        if let Some(sha1_hash) = refs::resolve(name)? {
+            roots.push((sha1_hash, String::new()));
+        }
+    }
+
+    for name in std::iter::once("HEAD").chain(names.iter().map(String::as_str)) {
+        for entry in refs::read_reflog(name)? {
+            for sha1_hash in [entry.old, entry.new] {
+                if !sha1_hash.is_null() {
+                    roots.push((sha1_hash, String::new()));
+                }
+            }
+        }
+    }
+
+    for entry in Index::load()?.entries() {
+        if entry.mode != 0o160000 {
+            roots.push((entry.sha1_hash, String::from_utf8_lossy(&entry.path).into_owned()));
+        }
+    }
+
+    Ok(roots)
+}
+
+/// Every object reachable from the roots, with the path it was reached by
+/// to hint which objects delta well against each other.
+pub async fn reachable() -> Result<HashMap<ObjectId, String>> {
+    let mut pending = roots()?;
+    let mut seen = HashMap::new();
+    // what a partial clone left out stays out rather than being fetched
+    let partial = promisor::is_partial_clone()?;
+
+    while let Some((sha1_hash, name)) = pending.pop() {
+        if seen.contains_key(&sha1_hash) || (partial && !objects::has_object(sha1_hash)?) {
+            continue;
+        }
+
+        let write_data = objects::read_object(sha1_hash).await?;
+        match AnyObject::from_write_data(sha1_hash, write_data)? {
+            AnyObject::Commit(commit) => {
+                pending.push((commit.tree_sha(), String::new()));
+                pending.extend(commit.parents().iter().map(|parent| (*parent, String::new())));
+            }
+            AnyObject::Tree(tree) => {
+                for entry in tree.entries() {
+                    // submodule commits live in another repository
+                    if entry.mode() == EntryMode::Gitlink {
+                        continue;
+                    }
+
+                    let path = match name.as_str() {
+                        "" => entry.name().to_string(),
+                        dir => format!("{}/{}", dir, entry.name()),
+                    };
+                    pending.push((entry.sha1_hash(), path));
+                }
+            }
+            AnyObject::Tag(tag) => pending.push((tag.object(), name.clone())),
+            AnyObject::Blob(_) => {}
+        }
+
+        seen.insert(sha1_hash, name);
+    }
+
+    Ok(seen)
+}
+
+// every loose object, with the file it is stored in
+fn loose_objects() -> Result<Vec<(ObjectId, PathBuf)>> {
+    let mut loose = Vec::new();
+
+    for dir in fs::read_dir(".git/objects")? {
+        let dir = dir?.path();
+        let prefix = dir.file_name().unwrap_or_default().to_string_lossy().to_string();
+        if prefix.len() != 2 || !dir.is_dir() {
+            continue;
+        }
+
+        for file in fs::read_dir(&dir)? {
+            let path = file?.path();
+            let name = format!("{}{}", prefix, path.file_name().unwrap_or_default().to_string_lossy());
+
+            if let Ok(sha1_hash) = ObjectId::from_hex(&name) {
+                loose.push((sha1_hash, path));
+            }
+        }
+    }
+
+    Ok(loose)
+}
+
+// every pack that has an index
+fn pack_paths() -> Result<Vec<PathBuf>> {
+    let dir = Path::new(".git/objects/pack");
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) == Some("pack") && path.with_extension("idx").is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    Ok(paths)
+}
+
+fn mtime(path: &Path) -> Result<i64> {
+    let modified = fs::metadata(path)?.modified()?;
+
+    Ok(modified.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64))
+}
+
+// the write times a cruft pack records for its objects, in index order
+fn parse_mtimes(data: &[u8], count: usize) -> Option<Vec<u32>> {
+    if !data.starts_with(MTIMES_SIGNATURE) || data.len() < 12 + count * 4 {
+        return None;
+    }
+
+    Some(
+        data[12..12 + count * 4]
+            .chunks(4)
+            .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
+            .collect(),
+    )
+}
+
+fn write_mtimes(mtimes: &[u32], pack_checksum: &[u8]) -> Vec<u8> {
+    let algorithm = HashAlgorithm::current();
+
+    let mut out = MTIMES_SIGNATURE.to_vec();
+    out.extend_from_slice(&1u32.to_be_bytes());
+    out.extend_from_slice(&(algorithm.format_id() as u32).to_be_bytes());
+    for mtime in mtimes {
+        out.extend_from_slice(&mtime.to_be_bytes());
+    }
+
+    out.extend_from_slice(pack_checksum);
+    let checksum = algorithm.digest(&out);
+    out.extend_from_slice(&checksum);
+
+    out
+}
+
+pub async fn pack_object(sha1_hash: ObjectId) -> Result<PackObject> {
+    PackObject::from_write_data(&objects::read_object(sha1_hash).await?)
+}
+
+// writes a pack and its index (last, so readers never see half a pack),
+// returning the pack's path
+pub fn store_pack(objects: Vec<(PackObject, String)>, mtimes: Option<&HashMap<ObjectId, u32>>) -> Result<PathBuf> {
+    let count = objects.len();
+    let written = packfile::write_pack(objects)?;
+    eprintln!("Total {} (delta {})", count, written.deltas);
+
+    let dir = Path::new(".git/objects/pack");
+    fs::create_dir_all(dir)?;
+    let name = format!("pack-{}", hex::encode(written.checksum));
+    let path = dir.join(format!("{}.pack", name));
+
+    let mut files = vec![("pack", written.data)];
+    if let Some(mtimes) = mtimes {
+        let index = PackIndex::parse(&written.index)?;
+        let ordered: Vec<u32> = index.names().iter().map(|sha| mtimes[sha]).collect();
+        files.push(("mtimes", write_mtimes(&ordered, &written.checksum)));
+    }
+    files.push(("idx", written.index));
+
+    for (extension, data) in files {
+        let tmp = dir.join(format!("tmp_{}.{}", name, extension));
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, path.with_extension(extension))?;
+    }
+
+    Ok(path)
+}
+
+fn remove_pack(path: &Path) -> Result<()> {
+    // the index goes first so that readers stop looking in the pack
+    for extension in ["idx", "pack", "mtimes", "rev", "bitmap", "promisor"].iter() {
+        let file = path.with_extension(extension);
+        if file.exists() {
+            fs::remove_file(file)?;
+        }
+    }
+
+    Ok(())
+}
+
+// removes the now empty directories loose objects were fanned out into
+fn remove_empty_fanout() -> Result<()> {
+    for dir in fs::read_dir(".git/objects")? {
+        let dir = dir?.path();
+        if dir.file_name().is_some_and(|name| name.len() == 2) && dir.is_dir() {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes loose objects that are also packed.
+pub fn prune_packed() -> Result<()> {
+    let packed: HashSet<ObjectId> = objects::packed_objects()?.into_iter().collect();
+
+    for (sha1_hash, path) in loose_objects()? {
+        if packed.contains(&sha1_hash) {
+            fs::remove_file(path)?;
+        }
+    }
+
+    remove_empty_fanout()
+}
+
+/// Packs the reachable objects that are only loose or, with `all`, every
+/// reachable object into a single new pack. With `delete`, the packs and
+/// loose objects that made redundant are removed; cruft packs survive
+/// unless a new one takes their place.
+pub async fn repack(all: bool, delete: bool, unreachable: Unreachable) -> Result<()> {
+    let reachable = reachable().await?;
+    let existing = pack_paths()?;
+
+    let mut packed = HashSet::new();
+    for path in &existing {
+        packed.extend(PackIndex::parse(&fs::read(path.with_extension("idx"))?)?.names().to_vec());
+    }
+
+    let mut wanted: Vec<&ObjectId> = reachable
+        .keys()
+        .filter(|sha| all || !packed.contains(*sha))
+        .collect();
+    wanted.sort();
+
+    let mut keep = Vec::new();
+    if !wanted.is_empty() {
+        let mut pack = Vec::with_capacity(wanted.len());
+        for sha1_hash in wanted {
+            pack.push((pack_object(*sha1_hash).await?, reachable[sha1_hash].clone()));
+        }
+        let path = store_pack(pack, None)?;
+        // objects from the promisor remote stay marked as such
+        if existing.iter().any(|path| path.with_extension("promisor").exists()) {
+            fs::write(path.with_extension("promisor"), "")?;
+        }
+        keep.push(path);
+    } else if !all {
+        println!("Nothing new to pack.");
+    }
+
+    let cruft_cutoff = match unreachable {
+        Unreachable::Cruft(cutoff) if all => Some(cutoff),
+        _ => None,
+    };
+
+    if let Some(cutoff) = cruft_cutoff {
+        // when each unreachable object was last written, wherever it is
+        let mut mtimes: HashMap<ObjectId, i64> = HashMap::new();
+        let mut note = |sha1_hash: ObjectId, time: i64| {
+            if !reachable.contains_key(&sha1_hash) {
+                let entry = mtimes.entry(sha1_hash).or_insert(time);
+                *entry = (*entry).max(time);
+            }
+        };
+
+        for (sha1_hash, path) in loose_objects()? {
+            note(sha1_hash, mtime(&path)?);
+        }
+        for path in &existing {
+            let index = PackIndex::parse(&fs::read(path.with_extension("idx"))?)?;
+            let pack_time = mtime(path)?;
+
+            let recorded = fs::read(path.with_extension("mtimes")).ok();
+            match recorded.and_then(|data| parse_mtimes(&data, index.names().len())) {
+                Some(times) => {
+                    for (sha1_hash, time) in index.names().iter().zip(times) {
+                        note(*sha1_hash, time as i64);
+                    }
+                }
+                None => {
+                    for sha1_hash in index.names() {
+                        note(*sha1_hash, pack_time);
+                    }
+                }
+            }
+        }
+
+        let kept: HashMap<ObjectId, u32> = mtimes
+            .into_iter()
+            .filter(|(_, time)| cutoff.is_none_or(|cutoff| *time >= cutoff))
+            .map(|(sha1_hash, time)| (sha1_hash, time as u32))
+            .collect();
+
+        if !kept.is_empty() {
+            let mut names: Vec<&ObjectId> = kept.keys().collect();
+            names.sort();
+
+            let mut pack = Vec::with_capacity(names.len());
+            for sha1_hash in names {
+                pack.push((pack_object(*sha1_hash).await?, String::new()));
+            }
+            keep.push(store_pack(pack, Some(&kept))?);
+        }
+    }
+
+    if delete {
+        if all {
+            for path in &existing {
+                let cruft = path.with_extension("mtimes").exists();
+                if !keep.contains(path) && (cruft_cutoff.is_some() || !cruft) {
+                    remove_pack(path)?;
+                }
+            }
+        }
+
+        prune_packed()?;
+    }
+
+    Ok(())
+}
+
+/// Removes the unreachable loose objects last written before the cutoff,
+/// and loose objects that are also packed. With `dry_run` nothing is
+/// removed, but the unreachable objects that would be are listed.
+pub async fn prune(cutoff: Option<i64>, dry_run: bool, verbose: bool) -> Result<()> {
+    let reachable = reachable().await?;
+    let packed: HashSet<ObjectId> = objects::packed_objects()?.into_iter().collect();
+
+    let mut loose = loose_objects()?;
+    loose.sort();
+
+    for (sha1_hash, path) in loose {
+        let expired = match cutoff {
+            Some(cutoff) => !reachable.contains_key(&sha1_hash) && mtime(&path)? < cutoff,
+            None => false,
+        };
+
+        if expired {
+            if dry_run || verbose {
+                let write_data = objects::read_object(sha1_hash).await?;
+                println!("{} {}", hex::encode(sha1_hash), objects::parse_header(&write_data)?.0);
+            }
+        } else if !packed.contains(&sha1_hash) {
+            continue;
+        }
+
+        if !dry_run {
+            fs::remove_file(path)?;
+        }
+    }
+
+    // temporary files left by writers that stopped part way
+    if let Some(cutoff) = cutoff {
+        for entry in fs::read_dir(".git/objects")? {
+            let path = entry?.path();
+            let temporary = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("tmp_obj_"));
+
+            if temporary && mtime(&path)? < cutoff {
+                if dry_run || verbose {
+                    println!("Removing stale temporary file {}", path.display());
+                }
+                if !dry_run {
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+    }
+
+    if !dry_run {
+        remove_empty_fanout()?;
+    }
+
+    Ok(())
+}
+
+/// Moves the loose refs into `packed-refs`, along with what the annotated
+/// tags among them peel to.
+pub async fn pack_refs() -> Result<()> {
+    let mut peeled = HashMap::new();
+    for (name, sha1_hash) in refs::list_refs("refs/")? {
+        match revision::object_type(sha1_hash).await {
+            Ok(kind) if kind == "tag" => {
+                peeled.insert(name, revision::peel(sha1_hash).await?);
+            }
+            Ok(_) => {}
+            Err(_) => bail!("{} points at a missing object {}", name, hex::encode(sha1_hash)),
+        }
+    }
+
+    refs::pack_refs(&peeled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mtimes() {
+        let data = write_mtimes(&[1_700_000_000, 5], &[0xab; 20]);
+
+        assert_eq!(data.len(), 12 + 8 + 20 + 20);
+        assert_eq!(&data[..4], b"MTME");
+        assert_eq!(&data[20..40], &[0xab; 20]);
+        assert_eq!(parse_mtimes(&data, 2), Some(vec![1_700_000_000, 5]));
+        assert_eq!(parse_mtimes(&data, 20), None);
+        assert_eq!(parse_mtimes(b"PACK", 0), None);
+    }
+}
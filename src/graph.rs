@@ -0,0 +1,410 @@
+use anyhow::Result;
+use std::collections::hash_map::Entry;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::commit_graph::CommitGraphFile;
+use crate::objects::{Commit, Object, ObjectId};
+
+/// The generation of a commit whose generation is not known, which sorts
+/// after every known one.
+pub const GENERATION_INFINITY: u64 = u64::MAX;
+
+// flags painted onto commits while looking for merge bases
+const PARENT1: u8 = 1;
+const PARENT2: u8 = 2;
+const STALE: u8 = 4;
+const RESULT: u8 = 8;
+
+/// What ancestry queries need to know about a commit.
+struct Node {
+    parents: Vec<ObjectId>,
+    date: i64,
+    generation: u64,
+}
+
+/// Answers ancestry questions, parsing each commit at most once. Commits
+/// in the commit-graph file are read from it, generation numbers
+/// included, and walks are cut short using those.
+#[derive(Default)]
+pub struct CommitGraph {
+    nodes: HashMap<ObjectId, Node>,
+    file: Option<CommitGraphFile>,
+}
+
+impl CommitGraph {
+    pub fn new() -> Self {
+        Self {
+            nodes: HashMap::new(),
+            // a commit-graph that cannot be read just means parsing commits
+            file: CommitGraphFile::open().unwrap_or(None),
+        }
+    }
+
+    async fn load(&mut self, sha1_hash: ObjectId) -> Result<&Node> {
+        if let Entry::Vacant(entry) = self.nodes.entry(sha1_hash) {
+            let graph_commit = match &self.file {
+                Some(file) => match file.position(&sha1_hash) {
+                    Some(pos) => Some(file.commit(pos)?),
+                    None => None,
+                },
+                None => None,
+            };
+
+            let node = match graph_commit {
+                Some(graph_commit) => Node {
+                    parents: graph_commit.parents,
+                    date: graph_commit.date as i64,
+                    generation: graph_commit.generation,
+                },
+                None => {
+                    let commit = Commit::from_object_sha(hex::encode(sha1_hash)).await?;
+                    Node {
+                        parents: commit.parents().to_vec(),
+                        date: commit.committer().timestamp.parse().unwrap_or(0),
+                        generation: GENERATION_INFINITY,
+                    }
+                }
+            };
+            entry.insert(node);
+        }
+
+        Ok(&self.nodes[&sha1_hash])
+    }
+
+    pub async fn parents(&mut self, sha1_hash: ObjectId) -> Result<Vec<ObjectId>> {
+        Ok(self.load(sha1_hash).await?.parents.clone())
+    }
+
+    pub async fn date(&mut self, sha1_hash: ObjectId) -> Result<i64> {
+        Ok(self.load(sha1_hash).await?.date)
+    }
+
+    pub async fn generation(&mut self, sha1_hash: ObjectId) -> Result<u64> {
+        Ok(self.load(sha1_hash).await?.generation)
+    }
+
+    // git's paint_down_to_common: paints everything reachable from `one`
+    // and from `others`, highest generation and then newest first, and
+    // returns the commits reached from both. Commits below
+    // `min_generation` are not walked.
+    async fn paint_down_to_common(
+        &mut self,
+        one: ObjectId,
+        others: &[ObjectId],
+        min_generation: u64,
+    ) -> Result<(Vec<ObjectId>, HashMap<ObjectId, u8>)> {
+        let mut flags: HashMap<ObjectId, u8> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        let mut counter = 0u64;
+
+        for (sha1_hash, flag) in std::iter::once((one, PARENT1)).chain(others.iter().map(|o| (*o, PARENT2))) {
+            *flags.entry(sha1_hash).or_default() |= flag;
+            let node = self.load(sha1_hash).await?;
+            // among equal keys, commits queued earlier come out first
+            counter += 1;
+            queue.push((node.generation, node.date, u64::MAX - counter, sha1_hash));
+        }
+
+        let mut results = Vec::new();
+        while queue
+            .iter()
+            .any(|(_, _, _, sha1_hash)| flags[sha1_hash] & STALE == 0)
+        {
+            let (generation, _, _, sha1_hash) = queue.pop().unwrap();
+            if generation < min_generation {
+                break;
+            }
+
+            let mut flag = flags[&sha1_hash] & (PARENT1 | PARENT2 | STALE);
+            if flag == PARENT1 | PARENT2 {
+                if flags[&sha1_hash] & RESULT == 0 {
+                    *flags.get_mut(&sha1_hash).unwrap() |= RESULT;
+                    results.push(sha1_hash);
+                }
+                flag |= STALE;
+            }
+
+            for parent in self.parents(sha1_hash).await? {
+                let parent_flags = flags.entry(parent).or_default();
+                if *parent_flags & flag == flag {
+                    continue;
+                }
+                *parent_flags |= flag;
+
+                let node = self.load(parent).await?;
+                counter += 1;
+                queue.push((node.generation, node.date, u64::MAX - counter, parent));
+            }
+        }
+
+        Ok((results, flags))
+    }
+
+    /// Whether `ancestor` is reachable from `descendant` (a commit is its
+    /// own ancestor).
+    pub async fn is_ancestor(&mut self, ancestor: ObjectId, descendant: ObjectId) -> Result<bool> {
+        if ancestor == descendant {
+            return Ok(true);
+        }
+
+        // a commit never has a higher generation than its descendants
+        let generation = self.generation(ancestor).await?;
+        if generation != GENERATION_INFINITY && generation > self.generation(descendant).await? {
+            return Ok(false);
+        }
+
+        let (_, flags) = self
+            .paint_down_to_common(ancestor, &[descendant], generation)
+            .await?;
+
+        Ok(flags[&ancestor] & PARENT2 != 0)
+    }
+
+    /// Drops commits that are reachable from another of `commits`, and
+    /// duplicates, keeping the order of the rest.
+    pub async fn reduce_heads(&mut self, commits: &[ObjectId]) -> Result<Vec<ObjectId>> {
+        let mut unique: Vec<ObjectId> = Vec::new();
+        for commit in commits {
+            if !unique.contains(commit) {
+                unique.push(*commit);
+            }
+        }
+
+        let mut heads = Vec::new();
+        for candidate in &unique {
+            let mut redundant = false;
+            for other in &unique {
+                if other != candidate && self.is_ancestor(*candidate, *other).await? {
+                    redundant = true;
+                    break;
+                }
+            }
+            if !redundant {
+                heads.push(*candidate);
+            }
+        }
+
+        Ok(heads)
+    }
+
+    /// The best common ancestors of `one` and any of `others`: common
+    /// ancestors that are not themselves ancestors of another one. Newest
+    /// first; empty for unrelated histories.
+    pub async fn merge_bases_many(&mut self, one: ObjectId, others: &[ObjectId]) -> Result<Vec<ObjectId>> {
+        if others.contains(&one) {
+            return Ok(vec![one]);
+        }
+
+        let (candidates, _) = self.paint_down_to_common(one, others, 0).await?;
+        let bases = self.reduce_heads(&candidates).await?;
+
+        let mut dated = Vec::new();
+        for base in bases {
+            dated.push((self.date(base).await?, base));
+        }
+        dated.sort_by_key(|(date, _)| std::cmp::Reverse(*date));
+
+        Ok(dated.into_iter().map(|(_, base)| base).collect())
+    }
+
+    pub async fn merge_bases(&mut self, one: ObjectId, two: ObjectId) -> Result<Vec<ObjectId>> {
+        self.merge_bases_many(one, &[two]).await
+    }
+
+    /// The merge bases of all of `commits` at once, as an octopus merge
+    /// needs them: folds each commit in against the bases found so far.
+    pub async fn octopus_merge_bases(&mut self, commits: &[ObjectId]) -> Result<Vec<ObjectId>> {
+        let mut bases = match commits.first() {
+            Some(first) => vec![*first],
+            None => return Ok(Vec::new()),
+        };
+
+        for next in &commits[1..] {
+            let mut merged = Vec::new();
+            for base in &bases {
+                merged.extend(self.merge_bases(*next, *base).await?);
+            }
+            bases = merged;
+        }
+
+        self.reduce_heads(&bases).await
+    }
+
+    /// Where `commit` forked from the history of a ref, given everything
+    /// the ref has pointed at. Only a merge base that the ref once pointed
+    /// at counts.
+    pub async fn fork_point(&mut self, commit: ObjectId, history: &[ObjectId]) -> Result<Option<ObjectId>> {
+        let bases = self.merge_bases_many(commit, history).await?;
+
+        Ok(match bases.as_slice() {
+            [base] if history.contains(base) => Some(*base),
+            _ => None,
+        })
+    }
+
+    // marks `sha1_hash` as excluded, along with whatever below it was
+    // already walked
+    async fn mark_uninteresting(
+        &mut self,
+        sha1_hash: ObjectId,
+        uninteresting: &mut HashSet<ObjectId>,
+        walked: &HashSet<ObjectId>,
+    ) -> Result<()> {
+        let mut stack = vec![sha1_hash];
+        while let Some(sha1_hash) = stack.pop() {
+            if uninteresting.insert(sha1_hash) && walked.contains(&sha1_hash) {
+                stack.extend(self.parents(sha1_hash).await?);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The commits reachable from `include` but not from `exclude`, as
+    /// `git rev-list --topo-order` lists them: children before parents,
+    /// otherwise newest first.
+    pub async fn range(&mut self, include: &[ObjectId], exclude: &[ObjectId]) -> Result<Vec<ObjectId>> {
+        let mut uninteresting = HashSet::new();
+        let mut seen = HashSet::new();
+        let mut walked = HashSet::new();
+        let mut queue = BinaryHeap::new();
+        let mut counter = 0u64;
+
+        for (sha1_hash, excluded) in exclude.iter().map(|e| (*e, true)).chain(include.iter().map(|i| (*i, false))) {
+            if excluded {
+                uninteresting.insert(sha1_hash);
+            }
+            if seen.insert(sha1_hash) {
+                let date = self.date(sha1_hash).await?;
+                counter += 1;
+                queue.push((date, u64::MAX - counter, sha1_hash));
+            }
+        }
+
+        // newest first, until only excluded commits are left to walk
+        let mut walk_order = Vec::new();
+        while queue
+            .iter()
+            .any(|(_, _, sha1_hash)| !uninteresting.contains(sha1_hash))
+        {
+            let (_, _, sha1_hash) = queue.pop().unwrap();
+            walked.insert(sha1_hash);
+            let excluded = uninteresting.contains(&sha1_hash);
+            if !excluded {
+                walk_order.push(sha1_hash);
+            }
+
+            for parent in self.parents(sha1_hash).await? {
+                if excluded {
+                    self.mark_uninteresting(parent, &mut uninteresting, &walked)
+                        .await?;
+                }
+                if seen.insert(parent) {
+                    let date = self.date(parent).await?;
+                    counter += 1;
+                    queue.push((date, u64::MAX - counter, parent));
+                }
+            }
+        }
+        walk_order.retain(|sha1_hash| !uninteresting.contains(sha1_hash));
+
+        // git's sort_in_topological_order in graph order: a commit is shown
+        // once all of its children have been, the most recently freed first
+        let mut children: HashMap<ObjectId, usize> = walk_order.iter().map(|c| (*c, 0)).collect();
+        for commit in &walk_order {
+            for parent in self.parents(*commit).await? {
+                if let Some(count) = children.get_mut(&parent) {
+                    *count += 1;
+                }
+            }
+        }
+
+        let mut stack: Vec<ObjectId> = walk_order
+            .iter()
+            .rev()
+            .filter(|c| children[*c] == 0)
+            .copied()
+            .collect();
+        let mut sorted = Vec::new();
+        while let Some(commit) = stack.pop() {
+            for parent in self.parents(commit).await? {
+                if let Some(count) = children.get_mut(&parent) {
+                    *count -= 1;
+                    if *count == 0 {
+                        stack.push(parent);
+                    }
+                }
+            }
+            sorted.push(commit);
+        }
+
+        Ok(sorted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u8) -> ObjectId {
+        ObjectId::from([n; 20])
+    }
+
+    // a synthetic history, so the tests need no repository:
+    //
+    //   0 - 1 - 2 - 4
+    //        \     /
+    //         3 --
+    //
+    // plus 5, which is unrelated
+    fn graph() -> CommitGraph {
+        let mut graph = CommitGraph::default();
+        let parents: [&[u8]; 6] = [&[], &[0], &[1], &[1], &[2, 3], &[]];
+        for (i, parents) in parents.iter().enumerate() {
+            graph.nodes.insert(
+                id(i as u8),
+                Node {
+                    parents: parents.iter().map(|p| id(*p)).collect(),
+                    date: i as i64,
+                    generation: GENERATION_INFINITY,
+                },
+            );
+        }
+
+        graph
+    }
+
+    #[tokio::test]
+    async fn test_merge_bases() -> Result<()> {
+        let mut graph = graph();
+
+        assert_eq!(graph.merge_bases(id(2), id(3)).await?, vec![id(1)]);
+        assert_eq!(graph.merge_bases(id(4), id(3)).await?, vec![id(3)]);
+        assert!(graph.merge_bases(id(4), id(5)).await?.is_empty());
+
+        assert!(graph.is_ancestor(id(0), id(4)).await?);
+        assert!(!graph.is_ancestor(id(4), id(0)).await?);
+        assert!(!graph.is_ancestor(id(5), id(4)).await?);
+
+        assert_eq!(
+            graph.reduce_heads(&[id(1), id(3), id(2), id(3)]).await?,
+            vec![id(3), id(2)]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_range() -> Result<()> {
+        let mut graph = graph();
+
+        assert_eq!(graph.range(&[id(4)], &[id(2)]).await?, vec![id(4), id(3)]);
+        assert_eq!(
+            graph.range(&[id(4)], &[id(0)]).await?,
+            vec![id(4), id(3), id(2), id(1)]
+        );
+        assert!(graph.range(&[id(2)], &[id(4)]).await?.is_empty());
+
+        Ok(())
+    }
+}
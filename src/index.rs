@@ -0,0 +1,461 @@
+use anyhow::{bail, Context, Result};
+use std::convert::TryInto;
+use std::fs;
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+use crate::objects::{HashAlgorithm, ObjectId};
+
+const SIGNATURE: &[u8; 4] = b"DIRC";
+
+// an entry with the extended flags that version 3 adds
+const EXTENDED: u16 = 0x4000;
+const SKIP_WORKTREE: u16 = 0x4000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    pub ctime: (u32, u32),
+    pub mtime: (u32, u32),
+    pub dev: u32,
+    pub ino: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+    pub sha1_hash: ObjectId,
+    pub stage: u8,
+    // left out of the working tree by a sparse checkout
+    pub skip_worktree: bool,
+    // git keeps paths as raw bytes, which need not be UTF-8
+    pub path: Vec<u8>,
+    // modified no earlier than the index file was written, so the file may
+    // have changed again within the same timestamp tick
+    pub racy: bool,
+}
+
+impl IndexEntry {
+    pub fn new(path: impl Into<Vec<u8>>, mode: u32, sha1_hash: ObjectId) -> Self {
+        Self {
+            ctime: (0, 0),
+            mtime: (0, 0),
+            dev: 0,
+            ino: 0,
+            mode,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            sha1_hash,
+            stage: 0,
+            skip_worktree: false,
+            path: path.into(),
+            racy: false,
+        }
+    }
+
+    pub fn refresh_stat(&mut self, metadata: &fs::Metadata) {
+        self.ctime = (metadata.ctime() as u32, metadata.ctime_nsec() as u32);
+        self.mtime = (metadata.mtime() as u32, metadata.mtime_nsec() as u32);
+        self.dev = metadata.dev() as u32;
+        self.ino = metadata.ino() as u32;
+        self.uid = metadata.uid();
+        self.gid = metadata.gid();
+        self.size = metadata.size() as u32;
+    }
+
+    /// Whether the cached stat data still describes the file on disk. A match
+    /// means the file can be assumed unchanged without rehashing it; a racy
+    /// entry never matches.
+    pub fn stat_matches(&self, metadata: &fs::Metadata) -> bool {
+        !self.racy
+            && self.mtime == (metadata.mtime() as u32, metadata.mtime_nsec() as u32)
+            && self.ctime == (metadata.ctime() as u32, metadata.ctime_nsec() as u32)
+            && self.ino == metadata.ino() as u32
+            && self.size == metadata.size() as u32
+    }
+
+    /// The tree entry mode, e.g. `100644`.
+    pub fn tree_mode(&self) -> String {
+        format!("{:o}", self.mode)
+    }
+
+    fn data(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        for field in &[
+            self.ctime.0,
+            self.ctime.1,
+            self.mtime.0,
+            self.mtime.1,
+            self.dev,
+            self.ino,
+            self.mode,
+            self.uid,
+            self.gid,
+            self.size,
+        ] {
+            data.extend_from_slice(&field.to_be_bytes());
+        }
+
+        data.extend_from_slice(&self.sha1_hash);
+
+        let name_len = self.path.len().min(0xfff) as u16;
+        let mut flags = ((self.stage as u16 & 0x3) << 12) | name_len;
+        if self.skip_worktree {
+            flags |= EXTENDED;
+        }
+        data.extend_from_slice(&flags.to_be_bytes());
+        if self.skip_worktree {
+            data.extend_from_slice(&SKIP_WORKTREE.to_be_bytes());
+        }
+
+        data.extend_from_slice(&self.path);
+
+        // entries are NUL padded to a multiple of eight bytes, with at least one NUL
+        let padding = 8 - (data.len() % 8);
+        data.extend(std::iter::repeat_n(0u8, padding));
+
+        data
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Index {
+    entries: Vec<IndexEntry>,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .context("Unexpected end of index file")?;
+
+    Ok(u32::from_be_bytes(bytes.try_into()?))
+}
+
+impl Index {
+    fn path() -> PathBuf {
+        PathBuf::from(".git/index")
+    }
+
+    pub fn load() -> Result<Self> {
+        let path = Self::path();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let metadata = fs::metadata(&path)?;
+        let timestamp = (metadata.mtime() as u32, metadata.mtime_nsec() as u32);
+        let mut index = Self::parse(&fs::read(path)?)?;
+        for entry in &mut index.entries {
+            entry.racy = entry.mtime >= timestamp;
+        }
+
+        Ok(index)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < 32 || &data[..4] != SIGNATURE {
+            bail!("Invalid index file signature");
+        }
+
+        let algorithm = HashAlgorithm::current();
+        let (content, checksum) = data.split_at(data.len() - algorithm.size());
+        if algorithm.digest(content).as_bytes() != checksum {
+            bail!("Index file is corrupt: bad checksum");
+        }
+        // entries end where the checksum starts
+        let data = content;
+
+        let version = read_u32(data, 4)?;
+        if version != 2 && version != 3 {
+            bail!("Unsupported index version {}", version);
+        }
+
+        let count = read_u32(data, 8)?;
+        let mut offset = 12;
+        let mut entries = Vec::with_capacity(count as usize);
+
+        for _ in 0..count {
+            let start = offset;
+            let mut fields = [0u32; 10];
+            for (i, field) in fields.iter_mut().enumerate() {
+                *field = read_u32(data, offset + i * 4)?;
+            }
+            offset += 40;
+
+            let sha1_hash = ObjectId::from_bytes(
+                data.get(offset..offset + algorithm.size())
+                    .context("Unexpected end of index file")?,
+            )?;
+            offset += algorithm.size();
+
+            let flags = u16::from_be_bytes(
+                data.get(offset..offset + 2)
+                    .context("Unexpected end of index file")?
+                    .try_into()?,
+            );
+            offset += 2;
+
+            // extended flags are only present in version 3
+            let mut extended = 0;
+            if flags & EXTENDED != 0 {
+                extended = u16::from_be_bytes(
+                    data.get(offset..offset + 2)
+                        .context("Unexpected end of index file")?
+                        .try_into()?,
+                );
+                offset += 2;
+            }
+
+            let name_end = offset
+                + data[offset..]
+                    .iter()
+                    .position(|b| *b == 0x00u8)
+                    .context("Unterminated index entry path")?;
+            let path = data[offset..name_end].to_vec();
+
+            let entry_len = name_end - start;
+            offset = start + entry_len + (8 - entry_len % 8);
+
+            entries.push(IndexEntry {
+                ctime: (fields[0], fields[1]),
+                mtime: (fields[2], fields[3]),
+                dev: fields[4],
+                ino: fields[5],
+                mode: fields[6],
+                uid: fields[7],
+                gid: fields[8],
+                size: fields[9],
+                sha1_hash,
+                stage: ((flags >> 12) & 0x3) as u8,
+                skip_worktree: extended & SKIP_WORKTREE != 0,
+                path,
+                racy: false,
+            });
+        }
+
+        // extensions (cached trees, resolve-undo, ...) are dropped; they are
+        // optional and git regenerates them as needed
+
+        Ok(Self { entries })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let mut data = Vec::new();
+        data.extend_from_slice(SIGNATURE);
+        // version 3 only when some entry needs its extended flags
+        let version: u32 = if self.entries.iter().any(|entry| entry.skip_worktree) { 3 } else { 2 };
+        data.extend_from_slice(&version.to_be_bytes());
+        data.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+
+        for entry in &self.entries {
+            data.extend(entry.data());
+        }
+
+        let checksum = HashAlgorithm::current().digest(&data);
+        data.extend_from_slice(&checksum);
+
+        let path = Self::path();
+        let lock = path.with_extension("lock");
+        fs::write(&lock, data)?;
+        fs::rename(lock, path)?;
+
+        Ok(())
+    }
+
+    // the entries for `path`, one per stage, found by binary search as the
+    // entries are kept sorted
+    fn range(&self, path: &[u8]) -> Range<usize> {
+        let start = self.entries.partition_point(|e| e.path.as_slice() < path);
+        let end = start + self.entries[start..].iter().take_while(|e| e.path == path).count();
+
+        start..end
+    }
+
+    pub fn entry(&self, path: impl AsRef<[u8]>) -> Option<&IndexEntry> {
+        let range = self.range(path.as_ref());
+        self.entries[range].first().filter(|e| e.stage == 0)
+    }
+
+    pub fn entry_mut(&mut self, path: impl AsRef<[u8]>) -> Option<&mut IndexEntry> {
+        let range = self.range(path.as_ref());
+        self.entries[range].first_mut().filter(|e| e.stage == 0)
+    }
+
+    /// All entries, conflict stages included, sorted by path.
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    /// Every tracked path, once each regardless of conflict stages.
+    pub fn paths(&self) -> Vec<Vec<u8>> {
+        let mut paths: Vec<Vec<u8>> = self.entries.iter().map(|e| e.path.clone()).collect();
+        paths.dedup();
+
+        paths
+    }
+
+    /// Adds or replaces an entry. Staging a path at stage 0 resolves any
+    /// conflict entries for it, and vice versa.
+    pub fn add(&mut self, entry: IndexEntry) {
+        let range = self.range(&entry.path);
+        let start = range.start;
+
+        let mut stages: Vec<IndexEntry> = self
+            .entries
+            .drain(range)
+            .filter(|e| entry.stage != 0 && e.stage != 0 && e.stage != entry.stage)
+            .collect();
+        let pos = stages.iter().position(|e| e.stage > entry.stage).unwrap_or(stages.len());
+        stages.insert(pos, entry);
+
+        self.entries.splice(start..start, stages);
+    }
+
+    pub fn remove(&mut self, path: impl AsRef<[u8]>) {
+        let range = self.range(path.as_ref());
+        self.entries.drain(range);
+    }
+
+    /// Paths that still have unresolved conflict stages.
+    pub fn conflicts(&self) -> Vec<Vec<u8>> {
+        let mut paths: Vec<Vec<u8>> = self
+            .entries
+            .iter()
+            .filter(|e| e.stage > 0)
+            .map(|e| e.path.clone())
+            .collect();
+        paths.dedup();
+
+        paths
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkout::{is_modified, stage_worktree_path};
+    use crate::test_repo::TestRepo;
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_index_roundtrip() -> Result<()> {
+        let mut index = Index::default();
+        let mut skipped = IndexEntry::new(String::from("src/main.rs"), 0o100644, ObjectId::from([1; 20]));
+        skipped.skip_worktree = true;
+        index.add(skipped);
+        index.add(IndexEntry::new(String::from("README.md"), 0o100644, ObjectId::from([2; 20])));
+        index.add(IndexEntry::new(String::from("run.sh"), 0o100755, ObjectId::from([3; 20])));
+
+        let mut data = Vec::new();
+        data.extend_from_slice(SIGNATURE);
+        data.extend_from_slice(&3u32.to_be_bytes());
+        data.extend_from_slice(&3u32.to_be_bytes());
+        for entry in index.entries {
+            data.extend(entry.data());
+        }
+        let checksum = HashAlgorithm::Sha1.digest(&data);
+        data.extend_from_slice(&checksum);
+
+        let parsed = Index::parse(&data)?;
+        let paths: Vec<&[u8]> = parsed.entries.iter().map(|e| e.path.as_slice()).collect();
+
+        assert_eq!(paths, vec![&b"README.md"[..], b"run.sh", b"src/main.rs"]);
+        assert_eq!(parsed.entry("run.sh").unwrap().mode, 0o100755);
+        assert_eq!(parsed.entry("src/main.rs").unwrap().sha1_hash, ObjectId::from([1; 20]));
+        assert!(parsed.entry("src/main.rs").unwrap().skip_worktree);
+        assert!(!parsed.entry("run.sh").unwrap().skip_worktree);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict_stages() {
+        let mut index = Index::default();
+        index.add(IndexEntry::new(String::from("a"), 0o100644, ObjectId::from([0; 20])));
+
+        for stage in 1..=3 {
+            let mut entry = IndexEntry::new(String::from("a"), 0o100644, ObjectId::from([stage; 20]));
+            entry.stage = stage;
+            index.add(entry);
+        }
+
+        assert_eq!(index.entries.len(), 3);
+        assert!(index.entry("a").is_none());
+
+        index.add(IndexEntry::new(String::from("a"), 0o100644, ObjectId::from([9; 20])));
+        assert_eq!(index.entries.len(), 1);
+        assert_eq!(index.entry("a").unwrap().sha1_hash, ObjectId::from([9; 20]));
+    }
+
+    #[test]
+    fn test_sorted_lookup() {
+        let mut index = Index::default();
+        for path in &["b/c", "a", "b.txt", "b", "c"] {
+            index.add(IndexEntry::new(*path, 0o100644, ObjectId::from([1; 20])));
+        }
+        let mut entry = IndexEntry::new("b", 0o100644, ObjectId::from([2; 20]));
+        entry.stage = 2;
+        index.add(entry);
+
+        let paths: Vec<&[u8]> = index.entries.iter().map(|e| e.path.as_slice()).collect();
+        assert_eq!(paths, vec![&b"a"[..], b"b", b"b.txt", b"b/c", b"c"]);
+        assert!(index.entry("b").is_none());
+        assert_eq!(index.conflicts(), vec![b"b".to_vec()]);
+
+        index.remove("b");
+        index.remove("missing");
+        assert!(index.entry("b.txt").is_some());
+        assert!(index.entry_mut("c").is_some());
+        assert_eq!(index.entries.len(), 4);
+    }
+
+    // a file rewritten within the tick the index was written in keeps its
+    // stat data, so only its contents can tell it changed
+    #[tokio::test]
+    async fn test_racy_entry() -> Result<()> {
+        let _repo = TestRepo::new("racy").await?;
+        fs::write("file", "before\n")?;
+        let mut index = Index::default();
+        index.add(stage_worktree_path(b"file").await?);
+        index.save()?;
+
+        let metadata = fs::metadata("file")?;
+        File::options().write(true).open(".git/index")?.set_modified(metadata.modified()?)?;
+        let loaded = Index::load()?;
+        let entry = loaded.entry("file").unwrap();
+        assert!(entry.racy);
+        assert!(!entry.stat_matches(&metadata));
+        assert!(!is_modified(b"file", entry).await?);
+
+        fs::write("file", "after!\n")?;
+        File::options().write(true).open("file")?.set_modified(metadata.modified()?)?;
+        assert!(is_modified(b"file", entry).await?);
+
+        // written before the index, the stat data can be trusted
+        File::options().write(true).open(".git/index")?.set_modified(SystemTime::now() + Duration::from_secs(5))?;
+        assert!(!Index::load()?.entry("file").unwrap().racy);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_index() {
+        let entry = IndexEntry::new(String::from("a"), 0o100644, ObjectId::from([1; 20]));
+
+        // every cut through the entry errors, however short the rest is
+        for len in 0..entry.data().len() {
+            let mut data = Vec::new();
+            data.extend_from_slice(SIGNATURE);
+            data.extend_from_slice(&2u32.to_be_bytes());
+            data.extend_from_slice(&1u32.to_be_bytes());
+            data.extend_from_slice(&entry.data()[..len]);
+            let checksum = HashAlgorithm::Sha1.digest(&data);
+            data.extend_from_slice(&checksum);
+
+            assert!(Index::parse(&data).is_err(), "{}", len);
+        }
+    }
+}
@@ -1,8 +1,28 @@
+mod bundle;
+mod checkout;
 mod cli;
 mod clone;
 mod commands;
+mod commit_graph;
+mod config;
+mod diff;
+mod fsck;
+mod gc;
+mod graph;
+mod index;
+mod merge;
 mod objects;
 mod packfile;
+mod promisor;
+mod refs;
+mod revision;
+mod sequencer;
+mod shallow;
+mod sparse;
+mod stash;
+mod submodule;
+#[cfg(test)]
+mod test_repo;
 mod utils;
 
 use anyhow::Result;
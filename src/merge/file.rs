@@ -0,0 +1,500 @@
+use crate::diff::{diff_lines, lines_equal, split_lines, DiffOptions};
+
+const DEFAULT_MARKER_SIZE: usize = 7;
+
+/// How conflicted hunks are written out (`merge.conflictStyle`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictStyle {
+    /// Both sides only.
+    Merge,
+    /// Both sides and the base they started from.
+    Diff3,
+    /// Like diff3, with lines common to both sides moved out of the conflict.
+    ZealousDiff3,
+}
+
+impl ConflictStyle {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "merge" => Some(ConflictStyle::Merge),
+            "diff3" => Some(ConflictStyle::Diff3),
+            "zdiff3" => Some(ConflictStyle::ZealousDiff3),
+            _ => None,
+        }
+    }
+}
+
+/// The names written after the conflict markers.
+pub struct Labels<'a> {
+    pub ours: &'a str,
+    pub base: &'a str,
+    pub theirs: &'a str,
+}
+
+pub struct FileMerge {
+    pub contents: Vec<u8>,
+    /// Number of conflicted hunks; zero for a clean merge.
+    pub conflicts: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Resolution {
+    Conflict,
+    Ours,
+    Theirs,
+    // both sides made the same change
+    Identical,
+}
+
+// a region of the merge, in lines of the base (0), ours (1) and theirs (2)
+#[derive(Debug, Clone, Copy)]
+struct Hunk {
+    resolution: Resolution,
+    i0: isize,
+    chg0: isize,
+    i1: isize,
+    chg1: isize,
+    i2: isize,
+    chg2: isize,
+}
+
+// one change between the base and a side: base lines i1..i1+chg1 became
+// side lines i2..i2+chg2
+#[derive(Clone, Copy)]
+struct Change {
+    i1: isize,
+    chg1: isize,
+    i2: isize,
+    chg2: isize,
+}
+
+fn changes(old: &[u8], new: &[u8], options: &DiffOptions) -> Vec<Change> {
+    diff_lines(old, new, options)
+        .chunks()
+        .iter()
+        .map(|chunk| Change {
+            i1: chunk.old_start as isize,
+            chg1: chunk.old_len as isize,
+            i2: chunk.new_start as isize,
+            chg2: chunk.new_len as isize,
+        })
+        .collect()
+}
+
+struct Merger<'a> {
+    base: Vec<&'a [u8]>,
+    ours: Vec<&'a [u8]>,
+    theirs: Vec<&'a [u8]>,
+    options: &'a DiffOptions,
+}
+
+// adds a hunk, folding it into the previous one when they touch
+#[allow(clippy::too_many_arguments)]
+fn append(
+    hunks: &mut Vec<Hunk>,
+    resolution: Resolution,
+    i0: isize,
+    chg0: isize,
+    i1: isize,
+    chg1: isize,
+    i2: isize,
+    chg2: isize,
+) {
+    if let Some(m) = hunks.last_mut() {
+        if i1 <= m.i1 + m.chg1 || i2 <= m.i2 + m.chg2 {
+            if resolution != m.resolution {
+                m.resolution = Resolution::Conflict;
+            }
+            m.chg0 = i0 + chg0 - m.i0;
+            m.chg1 = i1 + chg1 - m.i1;
+            m.chg2 = i2 + chg2 - m.i2;
+            return;
+        }
+    }
+
+    hunks.push(Hunk {
+        resolution,
+        i0,
+        chg0,
+        i1,
+        chg1,
+        i2,
+        chg2,
+    });
+}
+
+// -1 when a file has no line endings to go by
+fn is_eol_crlf(lines: &[&[u8]], i: usize) -> i32 {
+    let crlf = |line: &[u8]| (line.len() > 1 && line[line.len() - 2] == b'\r') as i32;
+
+    if i + 1 < lines.len() {
+        return crlf(lines[i]);
+    }
+    if lines.is_empty() {
+        return -1;
+    }
+    if lines[i].ends_with(b"\n") {
+        return crlf(lines[i]);
+    }
+    if i == 0 {
+        return -1;
+    }
+
+    crlf(lines[i - 1])
+}
+
+impl<'a> Merger<'a> {
+    fn same_lines(&self, i1: isize, i2: isize, count: isize) -> bool {
+        (0..count).all(|k| {
+            lines_equal(
+                self.ours[(i1 + k) as usize],
+                self.theirs[(i2 + k) as usize],
+                self.options,
+            )
+        })
+    }
+
+    // pairs up the changes each side made to the base
+    fn hunks(&self, ours: &[Change], theirs: &[Change], style: ConflictStyle) -> Vec<Hunk> {
+        let mut hunks = Vec::new();
+        let (mut x1, mut x2) = (0, 0);
+
+        while x1 < ours.len() && x2 < theirs.len() {
+            let (c1, c2) = (ours[x1], theirs[x2]);
+
+            if c1.i1 + c1.chg1 < c2.i1 {
+                let i2 = c2.i2 - c2.i1 + c1.i1;
+                append(&mut hunks, Resolution::Ours, c1.i1, c1.chg1, c1.i2, c1.chg2, i2, c1.chg1);
+                x1 += 1;
+                continue;
+            }
+            if c2.i1 + c2.chg1 < c1.i1 {
+                let i1 = c1.i2 - c1.i1 + c2.i1;
+                append(&mut hunks, Resolution::Theirs, c2.i1, c2.chg1, i1, c2.chg1, c2.i2, c2.chg2);
+                x2 += 1;
+                continue;
+            }
+
+            // overlapping changes conflict, unless both sides made the same one
+            if c1.i1 != c2.i1
+                || c1.chg1 != c2.chg1
+                || c1.chg2 != c2.chg2
+                || !self.same_lines(c1.i2, c2.i2, c1.chg2)
+            {
+                let off = c1.i1 - c2.i1;
+                let ffo = off + c1.chg1 - c2.chg1;
+
+                let (mut i0, mut i1, mut i2) = (c1.i1, c1.i2, c2.i2);
+                if off > 0 {
+                    i0 -= off;
+                    i1 -= off;
+                } else {
+                    i2 += off;
+                }
+                let mut chg0 = c1.i1 + c1.chg1 - i0;
+                let mut chg1 = c1.i2 + c1.chg2 - i1;
+                let mut chg2 = c2.i2 + c2.chg2 - i2;
+                if ffo < 0 {
+                    chg0 -= ffo;
+                    chg1 -= ffo;
+                } else {
+                    chg2 += ffo;
+                }
+
+                append(&mut hunks, Resolution::Conflict, i0, chg0, i1, chg1, i2, chg2);
+            }
+
+            let end1 = c1.i1 + c1.chg1;
+            let end2 = c2.i1 + c2.chg1;
+            if end1 >= end2 {
+                x2 += 1;
+            }
+            if end2 >= end1 {
+                x1 += 1;
+            }
+        }
+
+        let base_len = self.base.len() as isize;
+        for c1 in &ours[x1..] {
+            let i2 = c1.i1 + self.theirs.len() as isize - base_len;
+            append(&mut hunks, Resolution::Ours, c1.i1, c1.chg1, c1.i2, c1.chg2, i2, c1.chg1);
+        }
+        for c2 in &theirs[x2..] {
+            let i1 = c2.i1 + self.ours.len() as isize - base_len;
+            append(&mut hunks, Resolution::Theirs, c2.i1, c2.chg1, i1, c2.chg1, c2.i2, c2.chg2);
+        }
+
+        match style {
+            ConflictStyle::ZealousDiff3 => self.trim_conflicts(&mut hunks),
+            ConflictStyle::Merge => {
+                hunks = self.refine_conflicts(hunks);
+                simplify_conflicts(&mut hunks);
+            }
+            // the base is shown, so the sides are left as they are
+            ConflictStyle::Diff3 => {}
+        }
+
+        hunks
+    }
+
+    // diffs the two sides of each conflict against each other, keeping
+    // only the parts where they really differ
+    fn refine_conflicts(&self, hunks: Vec<Hunk>) -> Vec<Hunk> {
+        let mut refined = Vec::new();
+
+        for m in hunks {
+            if m.resolution != Resolution::Conflict || m.chg1 == 0 || m.chg2 == 0 {
+                refined.push(m);
+                continue;
+            }
+
+            let ours = self.ours[m.i1 as usize..(m.i1 + m.chg1) as usize].concat();
+            let theirs = self.theirs[m.i2 as usize..(m.i2 + m.chg2) as usize].concat();
+            let changes = changes(&ours, &theirs, self.options);
+
+            if changes.is_empty() {
+                refined.push(Hunk {
+                    resolution: Resolution::Identical,
+                    ..m
+                });
+                continue;
+            }
+
+            for change in changes {
+                refined.push(Hunk {
+                    i1: m.i1 + change.i1,
+                    chg1: change.chg1,
+                    i2: m.i2 + change.i2,
+                    chg2: change.chg2,
+                    ..m
+                });
+            }
+        }
+
+        refined
+    }
+
+    // zdiff3 only moves lines common to both sides' ends out of a conflict
+    fn trim_conflicts(&self, hunks: &mut [Hunk]) {
+        for m in hunks.iter_mut().filter(|m| m.resolution == Resolution::Conflict) {
+            while m.chg1 > 0 && m.chg2 > 0 && self.ours[m.i1 as usize] == self.theirs[m.i2 as usize] {
+                m.chg1 -= 1;
+                m.chg2 -= 1;
+                m.i1 += 1;
+                m.i2 += 1;
+            }
+            while m.chg1 > 0
+                && m.chg2 > 0
+                && self.ours[(m.i1 + m.chg1 - 1) as usize] == self.theirs[(m.i2 + m.chg2 - 1) as usize]
+            {
+                m.chg1 -= 1;
+                m.chg2 -= 1;
+            }
+        }
+    }
+
+    fn needs_cr(&self, m: &Hunk) -> bool {
+        let preceding = |i: isize| if i > 0 { (i - 1) as usize } else { 0 };
+
+        // follow the line endings of both sides around the conflict, then the base
+        let mut needs_cr = is_eol_crlf(&self.ours, preceding(m.i1));
+        if needs_cr != 0 {
+            needs_cr = is_eol_crlf(&self.theirs, preceding(m.i2));
+        }
+        if needs_cr != 0 {
+            needs_cr = is_eol_crlf(&self.base, 0);
+        }
+
+        needs_cr > 0
+    }
+}
+
+// conflicts with at most three lines between them read better as one
+fn simplify_conflicts(hunks: &mut Vec<Hunk>) {
+    let mut k = 0;
+
+    while k + 1 < hunks.len() {
+        let (m, next) = (hunks[k], hunks[k + 1]);
+        let begin = m.i1 + m.chg1;
+        let end = next.i1;
+
+        if m.resolution != Resolution::Conflict
+            || next.resolution != Resolution::Conflict
+            || end - begin > 3
+        {
+            k += 1;
+        } else {
+            hunks[k].chg1 = next.i1 + next.chg1 - m.i1;
+            hunks[k].chg2 = next.i2 + next.chg2 - m.i2;
+            hunks.remove(k + 1);
+        }
+    }
+}
+
+// copies lines, making sure the last one ends in a newline if `add_nl`
+fn copy_lines(out: &mut Vec<u8>, lines: &[&[u8]], needs_cr: bool, add_nl: bool) {
+    for line in lines {
+        out.extend_from_slice(line);
+    }
+
+    if add_nl {
+        if let Some(last) = lines.last() {
+            if !last.ends_with(b"\n") {
+                if needs_cr {
+                    out.push(b'\r');
+                }
+                out.push(b'\n');
+            }
+        }
+    }
+}
+
+fn marker(out: &mut Vec<u8>, byte: u8, size: usize, label: &str, needs_cr: bool) {
+    out.resize(out.len() + size, byte);
+    if !label.is_empty() {
+        out.push(b' ');
+        out.extend_from_slice(label.as_bytes());
+    }
+    if needs_cr {
+        out.push(b'\r');
+    }
+    out.push(b'\n');
+}
+
+/// Three-way merges file contents line by line as git's xdiff does. Where
+/// both sides changed the same lines, the result holds a conflict between
+/// `<<<<<<<` and `>>>>>>>` markers; `extra_marker_size` lengthens them for
+/// merges nested inside another.
+pub fn merge_files(
+    base: &[u8],
+    ours: &[u8],
+    theirs: &[u8],
+    labels: &Labels,
+    style: ConflictStyle,
+    extra_marker_size: usize,
+    options: &DiffOptions,
+) -> FileMerge {
+    let ours_changes = changes(base, ours, options);
+    let theirs_changes = changes(base, theirs, options);
+
+    if ours_changes.is_empty() {
+        return FileMerge {
+            contents: theirs.to_vec(),
+            conflicts: 0,
+        };
+    }
+    if theirs_changes.is_empty() {
+        return FileMerge {
+            contents: ours.to_vec(),
+            conflicts: 0,
+        };
+    }
+
+    let merger = Merger {
+        base: split_lines(base),
+        ours: split_lines(ours),
+        theirs: split_lines(theirs),
+        options,
+    };
+    let hunks = merger.hunks(&ours_changes, &theirs_changes, style);
+    let marker_size = DEFAULT_MARKER_SIZE + extra_marker_size;
+
+    let mut out = Vec::new();
+    let mut conflicts = 0;
+    // the next line of ours not yet copied
+    let mut i = 0;
+
+    for m in &hunks {
+        let (i0, i1, i2) = (m.i0 as usize, m.i1 as usize, m.i2 as usize);
+        let (end0, end1, end2) = ((m.i0 + m.chg0) as usize, (m.i1 + m.chg1) as usize, (m.i2 + m.chg2) as usize);
+
+        match m.resolution {
+            Resolution::Conflict => {
+                conflicts += 1;
+                let needs_cr = merger.needs_cr(m);
+
+                copy_lines(&mut out, &merger.ours[i..i1], false, false);
+                marker(&mut out, b'<', marker_size, labels.ours, needs_cr);
+                copy_lines(&mut out, &merger.ours[i1..end1], needs_cr, true);
+                if style != ConflictStyle::Merge {
+                    marker(&mut out, b'|', marker_size, labels.base, needs_cr);
+                    copy_lines(&mut out, &merger.base[i0..end0], needs_cr, true);
+                }
+                marker(&mut out, b'=', marker_size, "", needs_cr);
+                copy_lines(&mut out, &merger.theirs[i2..end2], needs_cr, true);
+                marker(&mut out, b'>', marker_size, labels.theirs, needs_cr);
+            }
+            Resolution::Ours => {
+                copy_lines(&mut out, &merger.ours[i..end1], false, false);
+            }
+            Resolution::Theirs => {
+                copy_lines(&mut out, &merger.ours[i..i1], false, false);
+                copy_lines(&mut out, &merger.theirs[i2..end2], false, false);
+            }
+            // ours already holds the change; it is copied with what follows
+            Resolution::Identical => continue,
+        }
+
+        i = end1;
+    }
+    copy_lines(&mut out, &merger.ours[i..], false, false);
+
+    FileMerge {
+        contents: out,
+        conflicts,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LABELS: Labels = Labels {
+        ours: "ours",
+        base: "base",
+        theirs: "theirs",
+    };
+
+    fn merge(base: &str, ours: &str, theirs: &str, style: ConflictStyle) -> (String, usize) {
+        let result = merge_files(
+            base.as_bytes(),
+            ours.as_bytes(),
+            theirs.as_bytes(),
+            &LABELS,
+            style,
+            0,
+            &DiffOptions::default(),
+        );
+
+        (String::from_utf8(result.contents).unwrap(), result.conflicts)
+    }
+
+    #[test]
+    fn test_clean_merge() {
+        let (merged, conflicts) = merge("a\nb\nc\nd\ne\n", "A\nb\nc\nd\ne\n", "a\nb\nc\nd\nE\n", ConflictStyle::Merge);
+
+        assert_eq!(merged, "A\nb\nc\nd\nE\n");
+        assert_eq!(conflicts, 0);
+    }
+
+    #[test]
+    fn test_conflict_styles() {
+        let (merged, conflicts) = merge("a\nb\nc\n", "a\nX\nc\n", "a\nY\nc\n", ConflictStyle::Merge);
+        assert_eq!(merged, "a\n<<<<<<< ours\nX\n=======\nY\n>>>>>>> theirs\nc\n");
+        assert_eq!(conflicts, 1);
+
+        let (merged, _) = merge("a\nb\nc\n", "a\nX\nc\n", "a\nY\nc\n", ConflictStyle::Diff3);
+        assert_eq!(
+            merged,
+            "a\n<<<<<<< ours\nX\n||||||| base\nb\n=======\nY\n>>>>>>> theirs\nc\n"
+        );
+    }
+
+    #[test]
+    fn test_identical_changes_merge_cleanly() {
+        let (merged, conflicts) = merge("a\nb\nc\n", "a\nX\nc\nd\n", "a\nX\nc\n", ConflictStyle::Merge);
+
+        assert_eq!(merged, "a\nX\nc\nd\n");
+        assert_eq!(conflicts, 0);
+    }
+}
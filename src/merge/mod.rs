@@ -0,0 +1,143 @@
+mod file;
+mod tree;
+
+use anyhow::{bail, Result};
+use async_recursion::async_recursion;
+
+use crate::checkout::Checkout;
+use crate::config::Config;
+use crate::diff::{self, BlobCache};
+use crate::graph::CommitGraph;
+use crate::index::IndexEntry;
+use crate::objects::{Commit, Object, ObjectId};
+
+pub use file::ConflictStyle;
+pub use tree::{merge_trees, write_tree, MergeOptions, TreeMerge};
+
+async fn commit_tree(sha1_hash: ObjectId) -> Result<ObjectId> {
+    Ok(Commit::from_object_sha(hex::encode(sha1_hash)).await?.tree_sha())
+}
+
+/// What diff3 conflicts call the merge base.
+pub fn base_label(bases: &[ObjectId]) -> String {
+    match bases {
+        [] => String::from("empty tree"),
+        [base] => hex::encode(base)[..7].to_string(),
+        _ => String::from("merged common ancestors"),
+    }
+}
+
+/// The conflict style set by merge.conflictStyle.
+pub fn conflict_style() -> Result<ConflictStyle> {
+    match Config::load()?.get("merge.conflictStyle") {
+        Some(name) => match ConflictStyle::from_name(&name) {
+            Some(style) => Ok(style),
+            None => bail!("unknown style '{}' given for 'merge.conflictstyle'", name),
+        },
+        None => Ok(ConflictStyle::Merge),
+    }
+}
+
+/// Brings a merge result into the index and working tree on top of
+/// `head_tree`, staging each conflicted path's versions at stages 1 to 3.
+/// Returns the merged tree, conflict markers and all.
+pub async fn checkout_result(head_tree: ObjectId, result: &TreeMerge) -> Result<ObjectId> {
+    let tree_sha = write_tree(&result.files).await?;
+
+    let mut checkout = Checkout::new()?;
+    checkout.merge_trees(Some(head_tree), tree_sha).await?;
+
+    for (path, stages) in &result.conflicts {
+        let index = checkout.index_mut();
+        index.remove(path);
+        for (stage, entry) in stages.iter().enumerate() {
+            if let Some(entry) = entry {
+                let mut index_entry =
+                    IndexEntry::new(path.clone(), u32::from_str_radix(&entry.mode, 8)?, entry.sha1_hash);
+                index_entry.stage = stage as u8 + 1;
+                index.add(index_entry);
+            }
+        }
+    }
+    checkout.save()?;
+
+    Ok(tree_sha)
+}
+
+// with several merge bases, merges them into one virtual base tree the way
+// git's recursive and ort strategies do, oldest first
+#[async_recursion]
+async fn virtual_base(
+    bases: Vec<ObjectId>,
+    style: ConflictStyle,
+    depth: usize,
+    graph: &mut CommitGraph,
+    cache: &mut BlobCache,
+) -> Result<Option<ObjectId>> {
+    let mut bases = bases;
+    bases.reverse();
+
+    let first = match bases.first() {
+        Some(first) => *first,
+        None => return Ok(None),
+    };
+
+    let mut merged = vec![first];
+    let mut tree = commit_tree(first).await?;
+
+    for next in &bases[1..] {
+        let inner_bases = graph.merge_bases_many(*next, &merged).await?;
+        let options = MergeOptions {
+            ours: String::from("Temporary merge branch 1"),
+            theirs: String::from("Temporary merge branch 2"),
+            base: base_label(&inner_bases),
+            style,
+            depth: depth + 1,
+        };
+        let inner_base = virtual_base(inner_bases, style, depth + 1, graph, cache).await?;
+
+        let result = tree::merge_trees(
+            &diff::tree_listing(inner_base).await?,
+            &diff::tree_listing(Some(tree)).await?,
+            &diff::tree_listing(Some(commit_tree(*next).await?)).await?,
+            &options,
+            cache,
+        )
+        .await?;
+
+        tree = write_tree(&result.files).await?;
+        merged.push(*next);
+    }
+
+    Ok(Some(tree))
+}
+
+/// Three-way merges commit `theirs` into `ours`. The merge bases are
+/// looked up in the commit graph; when there are several they are first
+/// merged into a virtual one.
+pub async fn merge_commits(
+    ours: ObjectId,
+    theirs: ObjectId,
+    options: &MergeOptions,
+    cache: &mut BlobCache,
+) -> Result<TreeMerge> {
+    let mut graph = CommitGraph::new();
+    let bases = graph.merge_bases(ours, theirs).await?;
+    let options = MergeOptions {
+        ours: options.ours.clone(),
+        theirs: options.theirs.clone(),
+        base: base_label(&bases),
+        style: options.style,
+        depth: options.depth,
+    };
+    let base_tree = virtual_base(bases, options.style, options.depth, &mut graph, cache).await?;
+
+    tree::merge_trees(
+        &diff::tree_listing(base_tree).await?,
+        &diff::tree_listing(Some(commit_tree(ours).await?)).await?,
+        &diff::tree_listing(Some(commit_tree(theirs).await?)).await?,
+        &options,
+        cache,
+    )
+    .await
+}
@@ -0,0 +1,459 @@
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use super::file::{merge_files, ConflictStyle, Labels};
+use crate::diff::{self, BlobCache, Change, DiffEntry, DiffOptions, Listing, Status, TreeDiffOptions};
+use crate::objects::{Blob, EntryMode, Object, ObjectId, Tree, TreeEntry};
+
+pub struct MergeOptions {
+    pub ours: String,
+    pub theirs: String,
+    /// Names the merge base in diff3 conflicts.
+    pub base: String,
+    pub style: ConflictStyle,
+    /// How deep inside merges of merge bases this merge runs.
+    pub depth: usize,
+}
+
+/// The outcome of a three-way tree merge.
+pub struct TreeMerge {
+    /// Every file of the merged tree; conflicted files hold the conflict.
+    pub files: Listing,
+    /// The base, ours and theirs versions of each conflicted path.
+    pub conflicts: BTreeMap<Vec<u8>, [Option<DiffEntry>; 3]>,
+    /// What happened, by path: `Auto-merging` notes and `CONFLICT` reports.
+    pub messages: Vec<(Vec<u8>, String)>,
+}
+
+// the three versions of one file; their paths differ across renames
+struct Triple {
+    path: Vec<u8>,
+    base: Option<DiffEntry>,
+    ours: Option<DiffEntry>,
+    theirs: Option<DiffEntry>,
+}
+
+fn same(a: Option<&DiffEntry>, b: Option<&DiffEntry>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.mode == b.mode && a.sha1_hash == b.sha1_hash,
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+fn is_regular(entry: &DiffEntry) -> bool {
+    entry.mode.starts_with("100")
+}
+
+fn at_path(entry: &DiffEntry, path: &[u8]) -> DiffEntry {
+    DiffEntry {
+        path: path.to_vec(),
+        ..entry.clone()
+    }
+}
+
+async fn write_blob(contents: Vec<u8>, cache: &mut BlobCache) -> Result<ObjectId> {
+    let blob = Blob::from_contents(contents.clone())?;
+    blob.write().await?;
+    cache.insert(blob.sha1_hash(), contents);
+
+    Ok(blob.sha1_hash())
+}
+
+/// Writes the trees for a listing and returns the root tree's id.
+pub async fn write_tree(files: &Listing) -> Result<ObjectId> {
+    let mut entries = BTreeMap::new();
+    for (path, entry) in files {
+        let name = path.rsplit(|b| *b == b'/').next().unwrap_or(path).to_vec();
+        entries.insert(path.clone(), TreeEntry::new(EntryMode::parse(&entry.mode)?, name, entry.sha1_hash));
+    }
+
+    Tree::write_paths(&entries).await
+}
+
+// old path -> new path for each file a side renamed
+async fn renames(base: &Listing, side: &Listing, cache: &mut BlobCache) -> Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+    let options = TreeDiffOptions {
+        recursive: true,
+        renames: Some(diff::DEFAULT_SCORE),
+        ..TreeDiffOptions::default()
+    };
+    let changes: Vec<Change> = diff::diff_listings(base, side, &options, cache).await?;
+
+    Ok(changes
+        .into_iter()
+        .filter(|change| change.status == Status::Renamed)
+        .map(|change| (change.old.unwrap().path, change.new.unwrap().path))
+        .collect())
+}
+
+struct TreeMerger<'a> {
+    options: &'a MergeOptions,
+    result: TreeMerge,
+}
+
+impl<'a> TreeMerger<'a> {
+    fn message(&mut self, path: &[u8], message: String) {
+        self.result.messages.push((path.to_vec(), message));
+    }
+
+    fn conflict(&mut self, path: &[u8], stages: [Option<&DiffEntry>; 3]) {
+        let stages = stages.map(|entry| entry.map(|e| at_path(e, path)));
+        self.result.conflicts.insert(path.to_vec(), stages);
+    }
+
+    fn take(&mut self, path: &[u8], entry: Option<&DiffEntry>) {
+        if let Some(entry) = entry {
+            self.result.files.insert(path.to_vec(), at_path(entry, path));
+        }
+    }
+
+    // conflict markers name the path too when a side renamed the file
+    fn labels(&self, triple: &Triple) -> (String, String, String) {
+        let paths: BTreeSet<&[u8]> = [&triple.base, &triple.ours, &triple.theirs]
+            .iter()
+            .filter_map(|entry| entry.as_ref().map(|e| e.path.as_slice()))
+            .collect();
+
+        let label = |name: &str, entry: &Option<DiffEntry>| match entry {
+            Some(entry) if paths.len() > 1 => format!("{}:{}", name, String::from_utf8_lossy(&entry.path)),
+            _ => name.to_string(),
+        };
+
+        (
+            label(&self.options.ours, &triple.ours),
+            label(&self.options.base, &triple.base),
+            label(&self.options.theirs, &triple.theirs),
+        )
+    }
+
+    async fn merge(&mut self, triple: Triple, cache: &mut BlobCache) -> Result<()> {
+        let path = triple.path.clone();
+        let name = String::from_utf8_lossy(&path).into_owned();
+        let (base, ours, theirs) = (triple.base.as_ref(), triple.ours.as_ref(), triple.theirs.as_ref());
+
+        if same(ours, theirs) || same(base, theirs) {
+            self.take(&path, ours);
+            return Ok(());
+        }
+        if same(base, ours) {
+            self.take(&path, theirs);
+            return Ok(());
+        }
+
+        let (ours, theirs) = match (ours, theirs) {
+            (Some(ours), Some(theirs)) => (ours, theirs),
+            (None, kept) | (kept, None) => {
+                // one side deleted what the other changed; keep the change
+                let (deleted_in, modified_in) = if ours.is_none() {
+                    (&self.options.ours, &self.options.theirs)
+                } else {
+                    (&self.options.theirs, &self.options.ours)
+                };
+                self.message(
+                    &path,
+                    format!(
+                        "CONFLICT (modify/delete): {} deleted in {} and modified in {}.  Version {} of {} left in tree.",
+                        name, deleted_in, modified_in, modified_in, name
+                    ),
+                );
+                self.take(&path, kept);
+                self.conflict(&path, [base, triple.ours.as_ref(), triple.theirs.as_ref()]);
+                return Ok(());
+            }
+        };
+
+        if !is_regular(ours) || !is_regular(theirs) || !base.is_none_or(is_regular) {
+            // symlinks and submodules cannot be merged line by line
+            self.message(&path, format!("CONFLICT (content): Merge conflict in {}", name));
+            self.take(&path, Some(ours));
+            self.conflict(&path, [base, Some(ours), Some(theirs)]);
+            return Ok(());
+        }
+
+        let base_mode = base.map(|b| b.mode.as_str());
+        let (mode, mode_conflict) = if ours.mode == theirs.mode || base_mode == Some(theirs.mode.as_str()) {
+            (ours.mode.clone(), false)
+        } else if base_mode == Some(ours.mode.as_str()) {
+            (theirs.mode.clone(), false)
+        } else {
+            (ours.mode.clone(), true)
+        };
+
+        let base_sha = base.map(|b| b.sha1_hash);
+        let (sha1_hash, content_conflict) = if ours.sha1_hash == theirs.sha1_hash
+            || base_sha == Some(theirs.sha1_hash)
+        {
+            (ours.sha1_hash, false)
+        } else if base_sha == Some(ours.sha1_hash) {
+            (theirs.sha1_hash, false)
+        } else {
+            self.message(&path, format!("Auto-merging {}", name));
+
+            let base_data = cache.contents(base).await?;
+            let ours_data = cache.contents(Some(ours)).await?;
+            let theirs_data = cache.contents(Some(theirs)).await?;
+
+            if diff::is_binary(&base_data) || diff::is_binary(&ours_data) || diff::is_binary(&theirs_data) {
+                self.message(
+                    &path,
+                    format!(
+                        "warning: Cannot merge binary files: {} ({} vs. {})",
+                        name, self.options.ours, self.options.theirs
+                    ),
+                );
+                (ours.sha1_hash, true)
+            } else {
+                let (ours_label, base_label, theirs_label) = self.labels(&triple);
+                let labels = Labels {
+                    ours: &ours_label,
+                    base: &base_label,
+                    theirs: &theirs_label,
+                };
+                // ort diffs with histogram and without the indent heuristic
+                let diff_options = DiffOptions {
+                    algorithm: diff::Algorithm::Histogram,
+                    indent_heuristic: false,
+                    ..DiffOptions::default()
+                };
+
+                let merged = merge_files(
+                    &base_data,
+                    &ours_data,
+                    &theirs_data,
+                    &labels,
+                    self.options.style,
+                    self.options.depth * 2,
+                    &diff_options,
+                );
+                (write_blob(merged.contents, cache).await?, merged.conflicts > 0)
+            }
+        };
+
+        if content_conflict {
+            let kind = if base.is_none() { "add/add" } else { "content" };
+            self.message(&path, format!("CONFLICT ({}): Merge conflict in {}", kind, name));
+        }
+        if mode_conflict {
+            self.message(
+                &path,
+                format!(
+                    "CONFLICT (mode): {} has mode {} in {} and {} in {}",
+                    name, ours.mode, self.options.ours, theirs.mode, self.options.theirs
+                ),
+            );
+        }
+
+        self.result.files.insert(
+            path.clone(),
+            DiffEntry {
+                path: path.clone(),
+                mode,
+                sha1_hash,
+            },
+        );
+        if content_conflict || mode_conflict {
+            self.conflict(&path, [base, Some(ours), Some(theirs)]);
+        }
+
+        Ok(())
+    }
+
+    // a file where the merged tree needs a directory moves aside to
+    // `path~<side>`
+    fn resolve_directory_conflicts(&mut self, ours: &Listing) {
+        let paths: Vec<Vec<u8>> = self.result.files.keys().cloned().collect();
+
+        for path in paths {
+            let prefix = [&path[..], b"/"].concat();
+            let in_the_way = self
+                .result
+                .files
+                .range(prefix.clone()..)
+                .next()
+                .is_some_and(|(other, _)| other.starts_with(&prefix));
+            if !in_the_way {
+                continue;
+            }
+
+            let side = if ours.contains_key(&path) {
+                &self.options.ours
+            } else {
+                &self.options.theirs
+            };
+            let new_path = [&path[..], b"~", side.as_bytes()].concat();
+            self.message(
+                &path,
+                format!(
+                    "CONFLICT (file/directory): directory in the way of {} from {}; moving it to {} instead.",
+                    String::from_utf8_lossy(&path),
+                    side,
+                    String::from_utf8_lossy(&new_path)
+                ),
+            );
+
+            let entry = self.result.files.remove(&path).unwrap();
+            let stages = self.result.conflicts.remove(&path).unwrap_or_else(|| {
+                if ours.contains_key(&path) {
+                    [None, Some(entry.clone()), None]
+                } else {
+                    [None, None, Some(entry.clone())]
+                }
+            });
+
+            self.result.files.insert(new_path.clone(), at_path(&entry, &new_path));
+            self.conflict(&new_path, [stages[0].as_ref(), stages[1].as_ref(), stages[2].as_ref()]);
+        }
+    }
+}
+
+/// Merges `ours` and `theirs` against their common `base`, following
+/// renames either side made and recording conflicts instead of failing on
+/// them. Merged file contents are written to the object store.
+pub async fn merge_trees(
+    base: &Listing,
+    ours: &Listing,
+    theirs: &Listing,
+    options: &MergeOptions,
+    cache: &mut BlobCache,
+) -> Result<TreeMerge> {
+    let ours_renames = renames(base, ours, cache).await?;
+    let theirs_renames = renames(base, theirs, cache).await?;
+
+    let mut merger = TreeMerger {
+        options,
+        result: TreeMerge {
+            files: Listing::new(),
+            conflicts: BTreeMap::new(),
+            messages: Vec::new(),
+        },
+    };
+
+    // paths of each side already paired up through a rename
+    let mut done_base = HashSet::new();
+    let mut done_ours = HashSet::new();
+    let mut done_theirs = HashSet::new();
+    let mut triples = Vec::new();
+
+    for (old, new) in &ours_renames {
+        done_base.insert(old.clone());
+        done_ours.insert(new.clone());
+
+        match theirs_renames.get(old) {
+            Some(theirs_new) if theirs_new == new => {
+                done_theirs.insert(new.clone());
+                triples.push(Triple {
+                    path: new.clone(),
+                    base: base.get(old).cloned(),
+                    ours: ours.get(new).cloned(),
+                    theirs: theirs.get(new).cloned(),
+                });
+            }
+            Some(theirs_new) => {
+                done_theirs.insert(theirs_new.clone());
+                merger.message(
+                    old,
+                    format!(
+                        "CONFLICT (rename/rename): {} renamed to {} in {} and to {} in {}.",
+                        String::from_utf8_lossy(old),
+                        String::from_utf8_lossy(new),
+                        options.ours,
+                        String::from_utf8_lossy(theirs_new),
+                        options.theirs
+                    ),
+                );
+                // the base version stays staged under its old name
+                merger.conflict(old, [base.get(old), None, None]);
+                merger.take(new, ours.get(new));
+                merger.conflict(new, [None, ours.get(new), None]);
+                merger.take(theirs_new, theirs.get(theirs_new));
+                merger.conflict(theirs_new, [None, None, theirs.get(theirs_new)]);
+            }
+            None if theirs.contains_key(old) => {
+                done_theirs.insert(old.clone());
+                triples.push(Triple {
+                    path: new.clone(),
+                    base: base.get(old).cloned(),
+                    ours: ours.get(new).cloned(),
+                    theirs: theirs.get(old).cloned(),
+                });
+            }
+            None => {
+                merger.message(
+                    new,
+                    format!(
+                        "CONFLICT (rename/delete): {} renamed to {} in {}, but deleted in {}.",
+                        String::from_utf8_lossy(old),
+                        String::from_utf8_lossy(new),
+                        options.ours,
+                        options.theirs
+                    ),
+                );
+                merger.take(new, ours.get(new));
+                merger.conflict(new, [base.get(old), ours.get(new), None]);
+            }
+        }
+    }
+
+    for (old, new) in &theirs_renames {
+        if ours_renames.contains_key(old) {
+            continue;
+        }
+        done_base.insert(old.clone());
+        done_theirs.insert(new.clone());
+
+        if ours.contains_key(old) {
+            done_ours.insert(old.clone());
+            triples.push(Triple {
+                path: new.clone(),
+                base: base.get(old).cloned(),
+                ours: ours.get(old).cloned(),
+                theirs: theirs.get(new).cloned(),
+            });
+        } else {
+            merger.message(
+                new,
+                format!(
+                    "CONFLICT (rename/delete): {} renamed to {} in {}, but deleted in {}.",
+                    String::from_utf8_lossy(old),
+                    String::from_utf8_lossy(new),
+                    options.theirs,
+                    options.ours
+                ),
+            );
+            merger.take(new, theirs.get(new));
+            merger.conflict(new, [base.get(old), None, theirs.get(new)]);
+        }
+    }
+
+    let paths: BTreeSet<&Vec<u8>> = base.keys().chain(ours.keys()).chain(theirs.keys()).collect();
+    for path in paths {
+        let pick = |listing: &Listing, done: &HashSet<Vec<u8>>| {
+            if done.contains(path) {
+                None
+            } else {
+                listing.get(path).cloned()
+            }
+        };
+
+        let triple = Triple {
+            path: path.clone(),
+            base: pick(base, &done_base),
+            ours: pick(ours, &done_ours),
+            theirs: pick(theirs, &done_theirs),
+        };
+        if triple.base.is_some() || triple.ours.is_some() || triple.theirs.is_some() {
+            triples.push(triple);
+        }
+    }
+
+    for triple in triples {
+        merger.merge(triple, cache).await?;
+    }
+    merger.resolve_directory_conflicts(ours);
+
+    let mut result = merger.result;
+    result.messages.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Ok(result)
+}
@@ -0,0 +1,54 @@
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::objects::object::{parse_header, Object};
+use crate::objects::{Blob, Commit, HashAlgorithm, ObjectId, Tag, Tree};
+
+/// An object of whatever type its header names.
+pub enum AnyObject {
+    Blob(Blob),
+    Tree(Tree),
+    Commit(Commit),
+    Tag(Tag),
+}
+
+#[async_trait]
+impl Object for AnyObject {
+    fn from_write_data(sha1_hash: ObjectId, write_data: Vec<u8>) -> Result<Self> {
+        let (kind, _) = parse_header(&write_data)?;
+
+        Ok(match kind {
+            "blob" => AnyObject::Blob(Blob::from_write_data(sha1_hash, write_data)?),
+            "tree" => AnyObject::Tree(Tree::from_write_data(sha1_hash, write_data)?),
+            "commit" => AnyObject::Commit(Commit::from_write_data(sha1_hash, write_data)?),
+            _ => AnyObject::Tag(Tag::from_write_data(sha1_hash, write_data)?),
+        })
+    }
+
+    fn sha1_hash(&self) -> ObjectId {
+        self.inner().sha1_hash()
+    }
+
+    fn write_data(&self) -> &Vec<u8> {
+        self.inner().write_data()
+    }
+}
+
+impl AnyObject {
+    /// Parses serialized object data whose hash is not yet known, such as
+    /// objects received over the network.
+    pub fn parse(write_data: Vec<u8>) -> Result<Self> {
+        let sha1_hash = HashAlgorithm::current().digest(&write_data);
+
+        Self::from_write_data(sha1_hash, write_data)
+    }
+
+    fn inner(&self) -> &(dyn Object + Send + Sync) {
+        match self {
+            AnyObject::Blob(blob) => blob,
+            AnyObject::Tree(tree) => tree,
+            AnyObject::Commit(commit) => commit,
+            AnyObject::Tag(tag) => tag,
+        }
+    }
+}
@@ -1,60 +1,39 @@
-use crate::utils;
 use anyhow::{bail, Result};
-use sha1::{Digest, Sha1};
-use std::convert::TryInto;
 use std::fmt;
 use std::fmt::Display;
-use std::path::PathBuf;
-use tokio::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
 
-use crate::objects::object::Object;
+use crate::objects::object::{self, parse_header, Object};
+use crate::objects::{HashAlgorithm, ObjectId};
 use async_trait::async_trait;
 
-pub struct Blob { contents: Vec<u8>,
-    sha1_hash: [u8; 20],
+// how much of a file is read at a time when hashing or storing it
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct Blob {
+    sha1_hash: ObjectId,
     write_data: Vec<u8>,
+    body_start: usize,
 }
 
 #[async_trait]
 impl Object for Blob {
-    async fn from_object_sha(object_sha: String) -> Result<Self> {
-        if object_sha.len() != 40 {
-            bail!("Invalid SHA: {}", &object_sha);
-        }
-
-        let (dir, file) = object_sha.split_at(2);
-
-        let mut path_to_file = PathBuf::new();
-        path_to_file.push(".git/objects");
-        path_to_file.push(dir);
-        path_to_file.push(file);
-
-        let file = fs::read(path_to_file).await?;
-        let write_data = utils::zlib_decompress(file)?;
-
-        let contents_ref = write_data.split(|x| *x == 0x00u8).nth(1);
-
-        let contents: Vec<u8>;
-        if contents_ref.is_some() {
-            contents = contents_ref.unwrap().to_vec();
-        } else {
-            contents = Vec::new();
+    fn from_write_data(sha1_hash: ObjectId, write_data: Vec<u8>) -> Result<Self> {
+        let (kind, body_start) = parse_header(&write_data)?;
+        if kind != "blob" {
+            bail!("{} is not a blob", hex::encode(sha1_hash));
         }
 
-        let sha1_hash = utils::decode_hash(&object_sha);
-
         Ok(Self {
-            contents,
             sha1_hash,
             write_data,
+            body_start,
         })
     }
 
-    fn sha1_hash(&self) -> [u8; 20] {
-        let mut hash: [u8; 20] = [0; 20];
-        hash.copy_from_slice(&self.sha1_hash);
-
-        hash
+    fn sha1_hash(&self) -> ObjectId {
+        self.sha1_hash
     }
 
     fn write_data(&self) -> &Vec<u8> {
@@ -63,33 +42,73 @@ impl Object for Blob {
 }
 
 impl Blob {
-    pub async fn new(file: PathBuf) -> Result<Self> {
-        let mut file_data = fs::read(file).await?;
-        let size = file_data.len().to_string();
-
-        let contents = file_data.clone();
-
-        let mut write_data = String::new();
-        write_data.push_str("blob");
-        write_data.push(' ');
-        write_data.push_str(&size);
-        write_data.push('\0');
-        let mut write_data = write_data.into_bytes();
-        write_data.append(&mut file_data);
-        let sha1_hash = Sha1::digest(&write_data);
-        let sha1_hash: [u8; 20] = sha1_hash.try_into()?;
-
-        return Ok(Self {
-            contents,
+    pub fn from_contents(file_data: Vec<u8>) -> Result<Self> {
+        let header = format!("blob {}\0", file_data.len());
+
+        let mut write_data = Vec::with_capacity(header.len() + file_data.len());
+        write_data.extend_from_slice(header.as_bytes());
+        write_data.extend_from_slice(&file_data);
+        let sha1_hash = HashAlgorithm::current().digest(&write_data);
+
+        Ok(Self {
             sha1_hash,
             write_data,
-        });
+            body_start: header.len(),
+        })
+    }
+
+    /// The name a file would have as a blob, read a chunk at a time rather
+    /// than all at once.
+    pub fn hash_file(path: &Path) -> Result<ObjectId> {
+        Self::stream_file(path, &mut io::sink())
+    }
+
+    /// Stores a file as a loose blob without holding it in memory: it is
+    /// hashed and deflated a chunk at a time into a temporary file, which is
+    /// renamed into place once its name is known.
+    pub fn write_file(path: &Path) -> Result<ObjectId> {
+        object::write_loose_with(None, |out| Self::stream_file(path, out))
+    }
+
+    // hashes a file as a blob, copying the serialized blob to `out` as it goes
+    fn stream_file(path: &Path, out: &mut dyn Write) -> Result<ObjectId> {
+        let mut file = std::fs::File::open(path)?;
+        // the header comes first, so the size has to be known before reading
+        let size = file.metadata()?.len();
+        let header = format!("blob {}\0", size);
+
+        let mut hasher = HashAlgorithm::current().hasher();
+        hasher.update(header.as_bytes());
+        out.write_all(header.as_bytes())?;
+
+        let mut buffer = vec![0; CHUNK_SIZE];
+        let mut read = 0u64;
+        loop {
+            let count = file.read(&mut buffer)?;
+            if count == 0 {
+                break;
+            }
+
+            hasher.update(&buffer[..count]);
+            out.write_all(&buffer[..count])?;
+            read += count as u64;
+        }
+
+        if read != size {
+            bail!("{} changed while it was being read", path.display());
+        }
+
+        Ok(hasher.finish())
+    }
+
+    pub fn contents(&self) -> &[u8] {
+        &self.write_data[self.body_start..]
     }
 }
 
 impl Display for Blob {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let out = String::from_utf8_lossy(&self.contents);
+        let out = String::from_utf8_lossy(self.contents());
 
         f.write_fmt(format_args!("{}", out))
     }
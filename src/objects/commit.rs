@@ -1,41 +1,126 @@
 use crate::utils;
-use anyhow::Result;
-use sha1::{Digest, Sha1};
-use std::convert::TryInto;
-use std::fs;
-use std::path::PathBuf;
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
 
-use crate::objects::Object;
+use crate::objects::object::{parse_header, Object};
+use crate::objects::{HashAlgorithm, ObjectId};
+use crate::refs;
+use crate::shallow;
 
 pub struct Commit {
-    tree_sha: [u8; 20],
-    parent_sha: [u8; 20],
+    tree_sha: ObjectId,
+    parents: Vec<ObjectId>,
     message: String,
     committer: User,
     author: User,
-    sha1_hash: [u8; 20],
-    timestamp: String,
-    tz_offset: String,
-    write_data: Vec<u8>
+    sha1_hash: ObjectId,
+    write_data: Vec<u8>,
 }
 
 #[derive(Clone)]
 pub struct User {
     pub name: String,
     pub email: String,
+    pub timestamp: String,
+    pub tz_offset: String,
+}
+
+impl User {
+    /// An identity stamped with the current time.
+    pub fn now(name: String, email: String) -> Self {
+        let (timestamp, tz_offset) = utils::get_time_data();
+
+        Self {
+            name,
+            email,
+            timestamp,
+            tz_offset,
+        }
+    }
+
+    /// Parses an identity line such as a commit's `author` header.
+    pub fn parse(line: &str) -> Result<Self> {
+        let email_start = line.find('<').context("Malformed identity")?;
+        let email_end = line.find('>').context("Malformed identity")?;
+
+        let name = line[..email_start].trim_end().to_string();
+        let email = line[email_start + 1..email_end].to_string();
+
+        let mut time = line[email_end + 1..].split_whitespace();
+        let timestamp = time.next().unwrap_or("0").to_string();
+        let tz_offset = time.next().unwrap_or("+0000").to_string();
+
+        Ok(Self {
+            name,
+            email,
+            timestamp,
+            tz_offset,
+        })
+    }
 }
 
 #[async_trait]
 impl Object for Commit {
-    async fn from_object_sha(object_sha: String) -> Result<Self> {
-        unimplemented!()
+    fn from_write_data(sha1_hash: ObjectId, write_data: Vec<u8>) -> Result<Self> {
+        let (kind, body_start) = parse_header(&write_data)?;
+        if kind != "commit" {
+            bail!("{} is not a commit", hex::encode(sha1_hash));
+        }
+
+        let body = String::from_utf8_lossy(&write_data[body_start..]).to_string();
+
+        let mut tree_sha = None;
+        let mut parents = Vec::new();
+        let mut author = None;
+        let mut committer = None;
+
+        let (headers, message) = match body.find("\n\n") {
+            Some(pos) => (&body[..pos], &body[pos + 2..]),
+            None => (&body[..], ""),
+        };
+
+        for line in headers.lines() {
+            // continuation lines of multi-line headers (gpgsig, mergetag)
+            if line.starts_with(' ') {
+                continue;
+            }
+
+            let (key, value) = match line.find(' ') {
+                Some(pos) => (&line[..pos], &line[pos + 1..]),
+                None => (line, ""),
+            };
+
+            match key {
+                "tree" => tree_sha = Some(ObjectId::from_hex(value)?),
+                "parent" => parents.push(ObjectId::from_hex(value)?),
+                "author" => author = Some(User::parse(value)?),
+                "committer" => committer = Some(User::parse(value)?),
+                _ => {}
+            }
+        }
+
+        // a shallow clone's history stops at its boundary commits
+        if !parents.is_empty() && shallow::is_grafted(sha1_hash) {
+            parents.clear();
+        }
+
+        let tree_sha = tree_sha.context("Commit has no tree")?;
+        let author = author.context("Commit has no author")?;
+        let committer = committer.context("Commit has no committer")?;
+
+        Ok(Self {
+            tree_sha,
+            parents,
+            message: message.to_string(),
+            committer,
+            author,
+            sha1_hash,
+            write_data,
+        })
     }
 
-    fn sha1_hash(&self) -> [u8; 20] {
-        let mut hash: [u8; 20] = [0; 20];
-        hash.copy_from_slice(&self.sha1_hash);
-        hash
+    fn sha1_hash(&self) -> ObjectId {
+        self.sha1_hash
     }
 
     fn write_data(&self) -> &Vec<u8> {
@@ -44,38 +129,30 @@ impl Object for Commit {
 }
 
 impl Commit {
-    pub fn new(
-        tree_sha: String,
-        parent_sha: String,
+    /// Builds a commit with any number of parents, e.g. a merge.
+    pub fn create(
+        tree_sha: ObjectId,
+        parents: Vec<ObjectId>,
         message: String,
-        name: String,
-        email: String,
+        author: User,
+        committer: User,
     ) -> Result<Self> {
-        let tree_sha = utils::decode_hash(&tree_sha);
-        let parent_sha = utils::decode_hash(&parent_sha);
-
-        let encoded_tree_sha = hex::encode(tree_sha);
-        let encoded_parent_sha = hex::encode(parent_sha);
-
-        let (timestamp, tz_offset) = utils::get_time_data();
-
-        let author = User { name, email };
-        let committer = author.clone();
-
-        let formatted_string = format!(
-            "tree {}\nparent {}\nauthor {} <{}> {} {}\ncommitter {} <{}> {} {}\n\n{}",
-            encoded_tree_sha,
-            encoded_parent_sha,
+        let mut formatted_string = format!("tree {}\n", hex::encode(tree_sha));
+        for parent in &parents {
+            formatted_string.push_str(&format!("parent {}\n", hex::encode(parent)));
+        }
+        formatted_string.push_str(&format!(
+            "author {} <{}> {} {}\ncommitter {} <{}> {} {}\n\n{}",
             author.name,
             author.email,
-            timestamp,
-            tz_offset,
+            author.timestamp,
+            author.tz_offset,
             committer.name,
             committer.email,
-            timestamp,
-            tz_offset,
+            committer.timestamp,
+            committer.tz_offset,
             message
-        );
+        ));
 
         let length = formatted_string.len().to_string();
 
@@ -88,36 +165,55 @@ impl Commit {
 
         let write_data = data.clone();
 
-        let sha1_hash = Sha1::digest(&data);
-        let sha1_hash: [u8; 20] = sha1_hash.try_into()?;
+        let sha1_hash = HashAlgorithm::current().digest(&data);
 
         Ok(Self {
             tree_sha,
-            parent_sha,
+            parents,
             message,
             author,
             committer,
             sha1_hash,
-            timestamp,
-            tz_offset,
             write_data,
         })
     }
 
-
-
     pub fn update_refs(&self) -> Result<()> {
-        let mut path = PathBuf::from(".git/refs/heads");
-        fs::create_dir_all(&path)?;
-        path.push("master");
-
-        let contents = self.encoded_sha();
-        fs::write(path, contents)?;
+        // advance whatever HEAD points at, creating the branch if it is unborn
+        match refs::read_head()? {
+            refs::Head::Branch(refname) => refs::update_ref(&refname, self.sha1_hash)?,
+            refs::Head::Detached(_) => refs::set_head_detached(self.sha1_hash)?,
+        }
 
         Ok(())
     }
 
     pub fn encoded_sha(&self) -> String {
-        hex::encode(&self.sha1_hash)
+        self.sha1_hash.to_string()
     }
+
+    pub fn tree_sha(&self) -> ObjectId {
+        self.tree_sha
+    }
+
+    pub fn parents(&self) -> &[ObjectId] {
+        &self.parents
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn author(&self) -> &User {
+        &self.author
+    }
+
+    pub fn committer(&self) -> &User {
+        &self.committer
+    }
+
+    pub fn summary(&self) -> &str {
+        self.message.lines().next().unwrap_or("")
+    }
+
 }
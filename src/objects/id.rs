@@ -0,0 +1,275 @@
+use anyhow::{bail, Result};
+use sha1::{Digest, Sha1};
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::config::Config;
+use crate::objects::sha256::Sha256;
+
+/// The hash function a repository names its objects by, from its
+/// `extensions.objectFormat`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha1,
+    Sha256,
+}
+
+// the repository's algorithm: 0 until its config has been read
+static CURRENT: AtomicU8 = AtomicU8::new(0);
+
+impl HashAlgorithm {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "sha1" => Ok(HashAlgorithm::Sha1),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            _ => bail!("unknown hash algorithm '{}'", name),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    /// How many bytes an object ID takes.
+    pub fn size(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+        }
+    }
+
+    pub fn hex_size(&self) -> usize {
+        self.size() * 2
+    }
+
+    /// The number file formats such as the commit-graph record the
+    /// algorithm by.
+    pub fn format_id(&self) -> u8 {
+        match self {
+            HashAlgorithm::Sha1 => 1,
+            HashAlgorithm::Sha256 => 2,
+        }
+    }
+
+    /// The algorithm of the repository in the current directory.
+    pub fn current() -> Self {
+        match CURRENT.load(Ordering::Relaxed) {
+            1 => HashAlgorithm::Sha1,
+            2 => HashAlgorithm::Sha256,
+            _ => Self::reload(),
+        }
+    }
+
+    /// Reads the algorithm from the config again, for when the current
+    /// directory has moved to another repository.
+    pub fn reload() -> Self {
+        let algorithm = Config::load()
+            .ok()
+            .and_then(|config| config.get("extensions.objectFormat"))
+            .and_then(|name| Self::from_name(&name).ok())
+            .unwrap_or(HashAlgorithm::Sha1);
+        algorithm.make_current();
+
+        algorithm
+    }
+
+    /// Uses this algorithm from now on, for a repository just created.
+    pub fn make_current(self) {
+        CURRENT.store(self.format_id(), Ordering::Relaxed);
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    pub fn digest(&self, data: &[u8]) -> ObjectId {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+
+        hasher.finish()
+    }
+
+    /// The all-zero ID that stands for "no object".
+    pub fn null(&self) -> ObjectId {
+        ObjectId {
+            bytes: [0; 32],
+            len: self.size() as u8,
+        }
+    }
+
+    /// The name of the empty tree, which git knows without storing it.
+    pub fn empty_tree(&self) -> ObjectId {
+        self.digest(b"tree 0\0")
+    }
+}
+
+/// A hash computed a piece at a time.
+pub enum Hasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finish(self) -> ObjectId {
+        match self {
+            Hasher::Sha1(hasher) => ObjectId::from_slice(&hasher.finalize()),
+            Hasher::Sha256(hasher) => ObjectId::from_slice(&hasher.finalize()),
+        }
+    }
+}
+
+/// The name of an object: 20 bytes of SHA-1 or 32 of SHA-256.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ObjectId {
+    bytes: [u8; 32],
+    len: u8,
+}
+
+impl ObjectId {
+    // callers guarantee a length one of the algorithms produces
+    fn from_slice(bytes: &[u8]) -> Self {
+        let mut id = Self {
+            bytes: [0; 32],
+            len: bytes.len() as u8,
+        };
+        id.bytes[..bytes.len()].copy_from_slice(bytes);
+
+        id
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 20 && bytes.len() != 32 {
+            bail!("Invalid object ID length {}", bytes.len());
+        }
+
+        Ok(Self::from_slice(bytes))
+    }
+
+    /// Parses an ID of the repository's algorithm.
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        Self::from_hex_in(hex, HashAlgorithm::current())
+    }
+
+    /// Parses an ID of `algorithm`, such as one a remote or a bundle names
+    /// its objects by. An ID of the other algorithm is refused up front
+    /// rather than left to be missing later.
+    pub fn from_hex_in(hex: &str, algorithm: HashAlgorithm) -> Result<Self> {
+        if hex.len() != algorithm.hex_size() {
+            let other = match algorithm {
+                HashAlgorithm::Sha1 => HashAlgorithm::Sha256,
+                HashAlgorithm::Sha256 => HashAlgorithm::Sha1,
+            };
+            if hex.len() == other.hex_size() && hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                bail!("{} is a {} object ID, but {} is in use", hex, other.name(), algorithm.name());
+            }
+            bail!("Invalid SHA: {}", hex);
+        }
+
+        match hex::decode(hex) {
+            Ok(bytes) => Ok(Self::from_slice(&bytes)),
+            Err(_) => bail!("Invalid SHA: {}", hex),
+        }
+    }
+
+    /// Reads an ID of the repository's algorithm from the start of `data`.
+    pub fn read(data: &[u8]) -> Result<Self> {
+        match data.get(..HashAlgorithm::current().size()) {
+            Some(bytes) => Ok(Self::from_slice(bytes)),
+            None => bail!("Truncated object ID"),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        match self.len {
+            20 => HashAlgorithm::Sha1,
+            _ => HashAlgorithm::Sha256,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.as_bytes().iter().all(|b| *b == 0)
+    }
+}
+
+impl Deref for ObjectId {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl AsRef<[u8]> for ObjectId {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl From<[u8; 20]> for ObjectId {
+    fn from(bytes: [u8; 20]) -> Self {
+        Self::from_slice(&bytes)
+    }
+}
+
+impl From<[u8; 32]> for ObjectId {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self::from_slice(&bytes)
+    }
+}
+
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.as_bytes()))
+    }
+}
+
+impl fmt::Debug for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_id() -> Result<()> {
+        let sha1 = ObjectId::from_hex("4b825dc642cb6eb9a060e54bf8d69288fbee4904")?;
+        assert_eq!(sha1, HashAlgorithm::Sha1.empty_tree());
+        assert_eq!(sha1.algorithm(), HashAlgorithm::Sha1);
+        assert_eq!(sha1.len(), 20);
+
+        let sha256 = HashAlgorithm::Sha256.empty_tree();
+        assert_eq!(sha256.to_string().len(), 64);
+        assert_eq!(ObjectId::from_bytes(&sha256)?, sha256);
+
+        assert!(HashAlgorithm::Sha256.null().is_null());
+        assert!(ObjectId::from([1; 20]) < ObjectId::from([2; 20]));
+        assert!(ObjectId::from_hex("abc").is_err());
+        assert_eq!(ObjectId::from_hex_in(&sha256.to_string(), HashAlgorithm::Sha256)?, sha256);
+        assert!(ObjectId::from_hex_in(&sha256.to_string(), HashAlgorithm::Sha1).is_err());
+        assert!(ObjectId::from_hex_in(&sha1.to_string(), HashAlgorithm::Sha256).is_err());
+        assert!(ObjectId::from_bytes(&[0; 21]).is_err());
+
+        Ok(())
+    }
+}
@@ -2,9 +2,16 @@ mod object;
 mod blob;
 mod tree;
 mod commit;
+mod tag;
+mod any;
+mod id;
+mod sha256;
 
-pub use object::Object;
+pub use object::{has_object, packed_objects, parse_header, read_object, read_stored, stream_object, Object};
 pub use blob::Blob;
-pub use tree::Tree;
-pub use commit::Commit;
+pub use tree::{EntryMode, Tree, TreeEntry};
+pub use commit::{Commit, User};
+pub use tag::Tag;
+pub use any::AnyObject;
+pub use id::{HashAlgorithm, ObjectId};
 
@@ -1,45 +1,397 @@
 use crate::utils;
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
 use hex;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
 use std::fmt;
 use std::fmt::Display;
-use std::path::PathBuf;
+use std::io::{self, BufReader, Cursor, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
 use tokio::fs;
 
+use crate::config;
+use crate::objects::ObjectId;
+use crate::packfile::{ObjectType, Pack, PackIndex};
+use crate::promisor;
+
 #[async_trait]
 pub trait Object {
 
-    async fn from_object_sha(object_sha: String) -> Result<Self>
+    /// Parses an object from its serialized form, `"<type> <size>\0"` header
+    /// included, whether that came from a loose object, a pack or the network.
+    fn from_write_data(sha1_hash: ObjectId, write_data: Vec<u8>) -> Result<Self>
     where
         Self: Sized;
 
-    fn sha1_hash(&self) -> [u8; 20];
+    async fn from_object_sha(object_sha: String) -> Result<Self>
+    where
+        Self: Sized + Send,
+    {
+        let sha1_hash = ObjectId::from_hex(&object_sha)?;
+        let write_data = read_object(sha1_hash).await?;
+
+        Self::from_write_data(sha1_hash, write_data)
+    }
+
+    fn sha1_hash(&self) -> ObjectId;
 
     fn write_data(&self) -> &Vec<u8>;
 
-    async fn write(&self) -> Result<PathBuf> {
-        let mut path = PathBuf::from(".git/objects");
+    async fn write(&self) -> Result<()> {
+        write_loose(self.sha1_hash(), self.write_data()).await
+    }
 
-        let blob_hex = hex::encode(self.sha1_hash());
-        let (dirname, filename) = blob_hex.split_at(2);
+    fn encoded_hash(&self) -> String {
+        hex::encode(&self.sha1_hash())
+    }
 
-        path.push(dirname);
+}
 
-        fs::create_dir_all(&path).await?;
-        path.push(filename);
+/// Splits serialized object data into its type and the offset its body starts
+/// at, checking the size the header claims against the body.
+pub fn parse_header(write_data: &[u8]) -> Result<(&str, usize)> {
+    let header_end = write_data
+        .iter()
+        .position(|b| *b == 0x00u8)
+        .context("Malformed object header")?;
+    let (kind, size) = parse_type_and_size(&write_data[..header_end])?;
 
-        let encoded_content = utils::zlib_compress(&self.write_data())?;
+    let body_len = write_data.len() - header_end - 1;
+    if size != body_len as u64 {
+        bail!("Object size mismatch: header says {} bytes, found {}", size, body_len);
+    }
+
+    Ok((kind, header_end + 1))
+}
 
-        fs::write(&path, encoded_content).await?;
+// "<type> <size>", without the NUL that ends it
+fn parse_type_and_size(header: &[u8]) -> Result<(&'static str, u64)> {
+    let header = std::str::from_utf8(header).context("Malformed object header")?;
 
-        Ok(path)
+    let (kind, size) = header.split_once(' ').context("Malformed object header")?;
+    let size: u64 = size
+        .parse()
+        .with_context(|| format!("Malformed object size '{}'", size))?;
+
+    match kind {
+        "blob" | "tree" | "commit" | "tag" => Ok((ObjectType::from_name(kind)?.name(), size)),
+        _ => bail!("Unknown object type '{}'", kind),
     }
+}
 
-    fn encoded_hash(&self) -> String {
-        hex::encode(&self.sha1_hash())
+/// A reader over an object's body, so large blobs need not be held in
+/// memory.
+pub struct ObjectStream {
+    pub kind: &'static str,
+    pub size: u64,
+    body: Box<dyn Read + Send>,
+}
+
+impl ObjectStream {
+    /// Copies the body to `out`, checking it is as long as the header said.
+    pub fn copy_body(&mut self, out: &mut dyn Write) -> Result<()> {
+        let copied = io::copy(&mut self.body, out)?;
+        if copied != self.size {
+            bail!("Object size mismatch: header says {} bytes, found {}", self.size, copied);
+        }
+
+        Ok(())
+    }
+}
+
+fn loose_path(sha1_hash: ObjectId) -> PathBuf {
+    let object_hex = hex::encode(sha1_hash);
+    let (dirname, filename) = object_hex.split_at(2);
+
+    PathBuf::from(".git/objects").join(dirname).join(filename)
+}
+
+// packs already opened, so each index is parsed once per process
+static PACKS: Mutex<Vec<(PathBuf, Arc<Pack>)>> = Mutex::new(Vec::new());
+
+// the cached packs, after picking up new packs and forgetting deleted ones
+fn refresh_packs() -> Result<Vec<Arc<Pack>>> {
+    let dir = Path::new(".git/objects/pack");
+    let mut paths = Vec::new();
+    if dir.is_dir() {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            // a pack without its index is still being written
+            if path.extension().and_then(|ext| ext.to_str()) == Some("pack") && path.with_extension("idx").is_file() {
+                paths.push(path);
+            }
+        }
+    }
+    paths.sort();
+
+    let mut packs = PACKS.lock().unwrap();
+    packs.retain(|(path, _)| paths.contains(path));
+    for path in paths {
+        if !packs.iter().any(|(cached, _)| *cached == path) {
+            let index = PackIndex::parse(&std::fs::read(path.with_extension("idx"))?)
+                .with_context(|| format!("Invalid pack index for {}", path.display()))?;
+            let pack = Pack::new(std::fs::File::open(&path)?, index)?;
+            packs.push((path, Arc::new(pack)));
+        }
+    }
+
+    Ok(packs.iter().map(|(_, pack)| pack.clone()).collect())
+}
+
+fn cached_packs() -> Vec<Arc<Pack>> {
+    PACKS.lock().unwrap().iter().map(|(_, pack)| pack.clone()).collect()
+}
+
+// looks in the packs already loaded first, then in any new ones
+fn read_packed(sha1_hash: ObjectId) -> Result<Option<Vec<u8>>> {
+    for pack in cached_packs() {
+        if let Some(object) = pack.read(&sha1_hash)? {
+            return Ok(Some(object.write_data()));
+        }
+    }
+
+    for pack in refresh_packs()? {
+        if let Some(object) = pack.read(&sha1_hash)? {
+            return Ok(Some(object.write_data()));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Reads an object, loose or packed, header included. A partial clone
+/// fetches it from its promisor remote if need be.
+pub async fn read_object(sha1_hash: ObjectId) -> Result<Vec<u8>> {
+    let path = loose_path(sha1_hash);
+    if let Ok(file) = fs::read(&path).await {
+        return utils::zlib_decompress(file);
+    }
+
+    if let Some(write_data) = read_packed(sha1_hash)? {
+        return Ok(write_data);
+    }
+
+    // git knows the empty tree without storing it
+    if sha1_hash == sha1_hash.algorithm().empty_tree() {
+        return Ok(b"tree 0\0".to_vec());
+    }
+
+    // a partial clone fetches what it left out once something needs it
+    if promisor::fetch(&[sha1_hash]).await? {
+        if let Some(write_data) = read_packed(sha1_hash)? {
+            return Ok(write_data);
+        }
+    }
+
+    bail!("Object {} not found", hex::encode(sha1_hash))
+}
+
+/// Opens an object for reading its body a piece at a time. Loose objects and
+/// whole packed ones are inflated as they are read; only deltified objects
+/// are rebuilt in memory.
+pub async fn stream_object(sha1_hash: ObjectId) -> Result<ObjectStream> {
+    if let Ok(file) = std::fs::File::open(loose_path(sha1_hash)) {
+        let mut body = ZlibDecoder::new(BufReader::new(file));
+
+        let mut header = Vec::new();
+        let mut byte = [0u8];
+        loop {
+            body.read_exact(&mut byte)
+                .with_context(|| format!("Malformed object header in {}", sha1_hash))?;
+            if byte[0] == 0x00 {
+                break;
+            }
+            header.push(byte[0]);
+        }
+        let (kind, size) = parse_type_and_size(&header)?;
+
+        return Ok(ObjectStream {
+            kind,
+            size,
+            body: Box::new(body),
+        });
+    }
+
+    for pack in cached_packs().into_iter().chain(refresh_packs()?) {
+        if let Some((kind, size, body)) = pack.stream(&sha1_hash)? {
+            return Ok(ObjectStream {
+                kind: kind.name(),
+                size,
+                body,
+            });
+        }
     }
 
+    // the empty tree, or an object a partial clone has yet to fetch
+    let write_data = read_object(sha1_hash).await?;
+    let (kind, body_start) = parse_header(&write_data)?;
+    let kind = ObjectType::from_name(kind)?.name();
+    let size = (write_data.len() - body_start) as u64;
+
+    let mut body = Cursor::new(write_data);
+    body.set_position(body_start as u64);
+
+    Ok(ObjectStream {
+        kind,
+        size,
+        body: Box::new(body),
+    })
+}
+
+/// Reads an object, header included, only if it is stored here: what a
+/// partial clone left out is not fetched.
+pub fn read_stored(sha1_hash: ObjectId) -> Result<Option<Vec<u8>>> {
+    if let Ok(file) = std::fs::read(loose_path(sha1_hash)) {
+        return Ok(Some(utils::zlib_decompress(file)?));
+    }
+
+    read_packed(sha1_hash)
+}
+
+/// Whether an object is stored, loose or packed.
+pub fn has_object(sha1_hash: ObjectId) -> Result<bool> {
+    if loose_path(sha1_hash).is_file() || cached_packs().iter().any(|pack| pack.index().find(&sha1_hash).is_some()) {
+        return Ok(true);
+    }
+
+    Ok(refresh_packs()?.iter().any(|pack| pack.index().find(&sha1_hash).is_some()))
+}
+
+/// The names of every packed object.
+pub fn packed_objects() -> Result<Vec<ObjectId>> {
+    Ok(refresh_packs()?
+        .iter()
+        .flat_map(|pack| pack.index().names().to_vec())
+        .collect())
+}
+
+// distinguishes the temporary files of one process
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+// core.fsync components that include loose objects
+const LOOSE_OBJECT_COMPONENTS: [&str; 5] = ["loose-object", "objects", "committed", "added", "all"];
+
+// whether loose objects go to disk before they are renamed into place. git
+// leaves them out by default, so only naming a component that covers them in
+// core.fsync (or setting the older core.fsyncObjectFiles) turns it on;
+// "-component" and "none" only take away from the default.
+fn fsyncs_loose_objects(fsync: Option<&str>, fsync_object_files: Option<&str>) -> bool {
+    let listed = fsync
+        .unwrap_or("")
+        .split(',')
+        .any(|component| LOOSE_OBJECT_COMPONENTS.contains(&component.trim()));
+    let legacy = fsync_object_files
+        .is_some_and(|value| matches!(value.to_lowercase().as_str(), "true" | "yes" | "on" | "1"));
+
+    listed || legacy
+}
+
+fn fsync_loose_objects() -> bool {
+    static FSYNC: OnceLock<bool> = OnceLock::new();
+
+    *FSYNC.get_or_init(|| {
+        let fsync = config::lookup("core.fsync").ok().flatten();
+        let fsync_object_files = config::lookup("core.fsyncObjectFiles").ok().flatten();
+
+        fsyncs_loose_objects(fsync.as_deref(), fsync_object_files.as_deref())
+    })
+}
+
+// a file in .git/objects to write an object into before its name is known
+fn loose_temp_file() -> Result<(PathBuf, std::fs::File)> {
+    let dir = Path::new(".git/objects");
+    let count = TEMP_FILES.fetch_add(1, Ordering::Relaxed);
+    let path = dir.join(format!("tmp_obj_{}_{}", std::process::id(), count));
+
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .with_context(|| format!("Unable to create {}", path.display()))?;
+
+    Ok((path, file))
+}
+
+// makes a finished temporary file read-only, durable if configured, and
+// renames it into place: readers see the whole object or none of it
+fn install_loose(tmp: &Path, file: std::fs::File, sha1_hash: ObjectId) -> Result<()> {
+    if fsync_loose_objects() {
+        file.sync_all()?;
+    }
+    file.set_permissions(std::fs::Permissions::from_mode(0o444))?;
+    drop(file);
+
+    // another writer may have stored it since we started
+    if is_stored(sha1_hash)? {
+        std::fs::remove_file(tmp)?;
+        return Ok(());
+    }
+
+    let path = loose_path(sha1_hash);
+    std::fs::create_dir_all(path.parent().expect("loose objects live in a fanout directory"))?;
+    std::fs::rename(tmp, &path)?;
+
+    Ok(())
+}
+
+// whether an object is already stored, loose or packed; a loose copy has its
+// mtime bumped so that prune treats it as recently written
+fn is_stored(sha1_hash: ObjectId) -> Result<bool> {
+    let path = loose_path(sha1_hash);
+    if let Ok(file) = std::fs::File::open(&path) {
+        let _ = file.set_modified(SystemTime::now());
+        return Ok(true);
+    }
+
+    has_object(sha1_hash)
+}
+
+/// Stores a loose object whose zlib-compressed form `write` produces,
+/// returning its name. Nothing is written when `known` names an object that
+/// is already stored.
+pub fn write_loose_with<F>(known: Option<ObjectId>, write: F) -> Result<ObjectId>
+where
+    F: FnOnce(&mut dyn Write) -> Result<ObjectId>,
+{
+    if let Some(sha1_hash) = known {
+        if is_stored(sha1_hash)? {
+            return Ok(sha1_hash);
+        }
+    }
+
+    let (tmp, file) = loose_temp_file()?;
+    let written = (|| {
+        let mut encoder = ZlibEncoder::new(file, Compression::default());
+        let sha1_hash = write(&mut encoder)?;
+        let file = encoder.finish()?;
+
+        install_loose(&tmp, file, sha1_hash)?;
+
+        Ok(sha1_hash)
+    })();
+
+    if written.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+
+    written
+}
+
+/// Stores already serialized object data (header included) as a loose object.
+pub async fn write_loose(sha1_hash: ObjectId, write_data: &[u8]) -> Result<()> {
+    write_loose_with(Some(sha1_hash), |out| {
+        out.write_all(write_data)?;
+
+        Ok(sha1_hash)
+    })?;
+
+    Ok(())
 }
 
 impl Display for dyn Object {
@@ -47,3 +399,33 @@ impl Display for dyn Object {
         f.write_fmt(format_args!("{}", self.encoded_hash()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_header() -> Result<()> {
+        assert_eq!(parse_header(b"blob 3\0a\0b")?, ("blob", 7));
+        assert_eq!(parse_header(b"tree 0\0")?, ("tree", 7));
+
+        assert!(parse_header(b"blob 4\0abc").is_err());
+        assert!(parse_header(b"blob 3 abc").is_err());
+        assert!(parse_header(b"blob x\0").is_err());
+        assert!(parse_header(b"note 0\0").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fsyncs_loose_objects() {
+        assert!(!fsyncs_loose_objects(None, None));
+        assert!(!fsyncs_loose_objects(Some("reference,index"), None));
+        assert!(!fsyncs_loose_objects(Some("none"), None));
+        assert!(fsyncs_loose_objects(Some("objects"), None));
+        assert!(fsyncs_loose_objects(Some("all,-index"), None));
+        assert!(fsyncs_loose_objects(Some("-pack, loose-object"), None));
+        assert!(fsyncs_loose_objects(None, Some("true")));
+        assert!(!fsyncs_loose_objects(None, Some("false")));
+    }
+}
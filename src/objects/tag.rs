@@ -0,0 +1,209 @@
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use std::fmt::{self, Display};
+
+use crate::objects::object::{parse_header, Object};
+use crate::objects::{HashAlgorithm, ObjectId, User};
+
+// signatures are appended to the message of a signed tag
+const SIGNATURE_STARTS: [&str; 3] = [
+    "-----BEGIN PGP SIGNATURE-----",
+    "-----BEGIN PGP MESSAGE-----",
+    "-----BEGIN SSH SIGNATURE-----",
+];
+
+/// An annotated tag: a named, dated pointer at another object.
+pub struct Tag {
+    object: ObjectId,
+    kind: String,
+    name: String,
+    tagger: Option<User>,
+    message: String,
+    signature: Option<String>,
+    sha1_hash: ObjectId,
+    write_data: Vec<u8>,
+}
+
+// the start of a trailing signature in a tag's body, if it has one
+fn signature_start(body: &str) -> Option<usize> {
+    SIGNATURE_STARTS
+        .iter()
+        .filter_map(|start| {
+            if body.starts_with(start) {
+                Some(0)
+            } else {
+                body.find(&format!("\n{}", start)).map(|pos| pos + 1)
+            }
+        })
+        .min()
+}
+
+#[async_trait]
+impl Object for Tag {
+    fn from_write_data(sha1_hash: ObjectId, write_data: Vec<u8>) -> Result<Self> {
+        let (kind, body_start) = parse_header(&write_data)?;
+        if kind != "tag" {
+            bail!("{} is not a tag", hex::encode(sha1_hash));
+        }
+
+        let body = String::from_utf8_lossy(&write_data[body_start..]).to_string();
+
+        let mut object = None;
+        let mut kind = None;
+        let mut name = None;
+        let mut tagger = None;
+
+        let (headers, body) = match body.find("\n\n") {
+            Some(pos) => (&body[..pos], &body[pos + 2..]),
+            None => (&body[..], ""),
+        };
+
+        for line in headers.lines() {
+            let (key, value) = match line.find(' ') {
+                Some(pos) => (&line[..pos], &line[pos + 1..]),
+                None => (line, ""),
+            };
+
+            match key {
+                "object" => object = Some(ObjectId::from_hex(value)?),
+                "type" => kind = Some(value.to_string()),
+                "tag" => name = Some(value.to_string()),
+                "tagger" => tagger = Some(User::parse(value)?),
+                _ => {}
+            }
+        }
+
+        let (message, signature) = match signature_start(body) {
+            Some(pos) => (&body[..pos], Some(body[pos..].to_string())),
+            None => (body, None),
+        };
+
+        Ok(Self {
+            object: object.context("Tag has no object")?,
+            kind: kind.context("Tag has no type")?,
+            name: name.context("Tag has no name")?,
+            tagger,
+            message: message.to_string(),
+            signature,
+            sha1_hash,
+            write_data,
+        })
+    }
+
+    fn sha1_hash(&self) -> ObjectId {
+        self.sha1_hash
+    }
+
+    fn write_data(&self) -> &Vec<u8> {
+        &self.write_data
+    }
+}
+
+impl Tag {
+    /// Builds a tag named `name` pointing at `object`, whose type is `kind`.
+    pub fn create(
+        object: ObjectId,
+        kind: String,
+        name: String,
+        tagger: Option<User>,
+        message: String,
+        signature: Option<String>,
+    ) -> Result<Self> {
+        let mut formatted_string = format!("object {}\ntype {}\ntag {}\n", hex::encode(object), kind, name);
+        if let Some(tagger) = &tagger {
+            formatted_string.push_str(&format!(
+                "tagger {} <{}> {} {}\n",
+                tagger.name, tagger.email, tagger.timestamp, tagger.tz_offset
+            ));
+        }
+        formatted_string.push('\n');
+        formatted_string.push_str(&message);
+        if let Some(signature) = &signature {
+            formatted_string.push_str(signature);
+        }
+
+        let mut data = format!("tag {}\0", formatted_string.len()).into_bytes();
+        data.extend_from_slice(formatted_string.as_bytes());
+
+        let sha1_hash = HashAlgorithm::current().digest(&data);
+
+        Ok(Self {
+            object,
+            kind,
+            name,
+            tagger,
+            message,
+            signature,
+            sha1_hash,
+            write_data: data,
+        })
+    }
+
+    /// The object the tag points at.
+    pub fn object(&self) -> ObjectId {
+        self.object
+    }
+
+    pub fn signature(&self) -> Option<&str> {
+        self.signature.as_deref()
+    }
+
+    /// Everything a signature covers: the tag as stored, less the signature.
+    pub fn payload(&self) -> &[u8] {
+        let header_end = self.write_data.iter().position(|b| *b == 0).unwrap_or(0);
+        let body = &self.write_data[header_end + 1..];
+        let signature_len = self.signature.as_ref().map_or(0, |s| s.len());
+
+        &body[..body.len() - signature_len]
+    }
+}
+
+impl Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "object {}", hex::encode(self.object))?;
+        writeln!(f, "type {}", self.kind)?;
+        writeln!(f, "tag {}", self.name)?;
+        if let Some(tagger) = &self.tagger {
+            writeln!(f, "tagger {} <{}> {} {}", tagger.name, tagger.email, tagger.timestamp, tagger.tz_offset)?;
+        }
+        write!(f, "\n{}{}", self.message, self.signature.as_deref().unwrap_or(""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_start() {
+        let body = "v1\n-----BEGIN PGP SIGNATURE-----\nabc\n-----END PGP SIGNATURE-----\n";
+        assert_eq!(signature_start(body), Some(3));
+        assert_eq!(signature_start("-----BEGIN SSH SIGNATURE-----\n"), Some(0));
+        assert_eq!(signature_start("no signature\n"), None);
+    }
+
+    #[test]
+    fn test_create() -> Result<()> {
+        let tagger = User {
+            name: String::from("C"),
+            email: String::from("c@x"),
+            timestamp: String::from("1700000000"),
+            tz_offset: String::from("+0000"),
+        };
+        let tag = Tag::create(
+            ObjectId::from([0x11; 20]),
+            String::from("commit"),
+            String::from("v1"),
+            Some(tagger),
+            String::from("release\n"),
+            Some(String::from("-----BEGIN PGP SIGNATURE-----\nx\n-----END PGP SIGNATURE-----\n")),
+        )?;
+
+        let header = format!("tag {}\0", tag.write_data().len() - 8);
+        assert!(tag.write_data().starts_with(header.as_bytes()));
+        assert!(tag.payload().ends_with(b"tagger C <c@x> 1700000000 +0000\n\nrelease\n"));
+        assert!(tag.to_string().starts_with(&format!("object {}\ntype commit\ntag v1\n", "11".repeat(20))));
+
+        Ok(())
+    }
+}
@@ -1,69 +1,839 @@
-use anyhow::Result;
-use std::io::{BufRead, Cursor, Read};
-use u8;
+use anyhow::{bail, Context, Result};
+use flate2::bufread::ZlibDecoder;
+use flate2::Crc;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read};
+use std::os::unix::fs::FileExt;
 
-enum ObjectType {}
+use crate::objects::{self, HashAlgorithm, ObjectId};
+use crate::utils;
 
-fn read_object_size(c: &mut Cursor<&[u8]>) -> Result<usize> {
-    let mut size_vec: Vec<u8> = vec![];
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ObjectType {
+    Commit,
+    Tree,
+    Blob,
+    Tag,
+    OfsDelta,
+    RefDelta,
+}
+
+impl ObjectType {
+    fn from_code(code: u8) -> Result<Self> {
+        match code {
+            1 => Ok(ObjectType::Commit),
+            2 => Ok(ObjectType::Tree),
+            3 => Ok(ObjectType::Blob),
+            4 => Ok(ObjectType::Tag),
+            6 => Ok(ObjectType::OfsDelta),
+            7 => Ok(ObjectType::RefDelta),
+            _ => bail!("Invalid object type {} in packfile", code),
+        }
+    }
+
+    fn code(&self) -> u8 {
+        match self {
+            ObjectType::Commit => 1,
+            ObjectType::Tree => 2,
+            ObjectType::Blob => 3,
+            ObjectType::Tag => 4,
+            ObjectType::OfsDelta => 6,
+            ObjectType::RefDelta => 7,
+        }
+    }
+
+    /// The type of a whole object from the name in its header, e.g. `tree`.
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "commit" => Ok(ObjectType::Commit),
+            "tree" => Ok(ObjectType::Tree),
+            "blob" => Ok(ObjectType::Blob),
+            "tag" => Ok(ObjectType::Tag),
+            _ => bail!("Invalid object type {}", name),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ObjectType::Commit => "commit",
+            ObjectType::Tree => "tree",
+            ObjectType::Blob => "blob",
+            ObjectType::Tag => "tag",
+            ObjectType::OfsDelta => "ofs-delta",
+            ObjectType::RefDelta => "ref-delta",
+        }
+    }
+}
+
+/// A fully resolved (undeltified) object from a pack.
+pub struct PackObject {
+    pub kind: ObjectType,
+    pub data: Vec<u8>,
+}
+
+impl PackObject {
+    pub fn from_write_data(write_data: &[u8]) -> Result<Self> {
+        let (kind, body_start) = objects::parse_header(write_data)?;
+
+        Ok(Self {
+            kind: ObjectType::from_name(kind)?,
+            data: write_data[body_start..].to_vec(),
+        })
+    }
+
+    pub fn write_data(&self) -> Vec<u8> {
+        let mut write_data = format!("{} {}\0", self.kind.name(), self.data.len()).into_bytes();
+        write_data.extend_from_slice(&self.data);
+
+        write_data
+    }
+
+    pub fn sha1_hash(&self) -> ObjectId {
+        HashAlgorithm::current().digest(&self.write_data())
+    }
+}
+
+enum RawEntry {
+    Whole(ObjectType, Vec<u8>),
+    OfsDelta(usize, Vec<u8>),
+    RefDelta(ObjectId, Vec<u8>),
+}
+
+fn read_byte(data: &[u8], pos: &mut usize) -> Result<u8> {
+    let byte = *data.get(*pos).context("Unexpected end of packfile")?;
+    *pos += 1;
+
+    Ok(byte)
+}
 
-    let mut keep_reading = true;
+// type and inflated size: 1-bit continuation, 3-bit type, 4 bits of size,
+// then 7 bits of size per continuation byte, least significant first
+fn read_type_and_size(data: &[u8], pos: &mut usize) -> Result<(u8, usize)> {
+    let mut byte = read_byte(data, pos)?;
+
+    let kind = (byte >> 4) & 0b111;
+    let mut size = (byte & 0b1111) as usize;
+    let mut shift = 4;
+
+    while byte & 0x80 != 0 {
+        byte = read_byte(data, pos)?;
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+    }
+
+    Ok((kind, size))
+}
+
+// the base offset of an ofs-delta, most significant group first with an
+// implicit +1 per continuation byte
+fn read_offset(data: &[u8], pos: &mut usize) -> Result<usize> {
+    let mut byte = read_byte(data, pos)?;
+    let mut offset = (byte & 0x7f) as usize;
+
+    while byte & 0x80 != 0 {
+        byte = read_byte(data, pos)?;
+        offset = ((offset + 1) << 7) | (byte & 0x7f) as usize;
+    }
+
+    Ok(offset)
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<usize> {
+    let mut value = 0;
+    let mut shift = 0;
 
     loop {
-        if !keep_reading {
-            println!("LOOP BREAK");
-            break;
+        let byte = read_byte(data, pos)?;
+        value |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
         }
+    }
+}
+
+fn inflate(data: &[u8], pos: &mut usize, size: usize) -> Result<Vec<u8>> {
+    let mut decoder = ZlibDecoder::new(&data[*pos..]);
+    let mut out = Vec::with_capacity(size);
+    decoder
+        .read_to_end(&mut out)
+        .context("Failed to inflate packfile entry")?;
 
-        let mut one_byte: [u8; 1] = [0];
+    if out.len() != size {
+        bail!("Packfile entry has size {}, expected {}", out.len(), size);
+    }
+
+    *pos += decoder.total_in() as usize;
+
+    Ok(out)
+}
+
+/// Rebuilds an object from its base and a git delta (copy/insert instructions).
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut pos = 0;
+
+    let base_size = read_varint(delta, &mut pos)?;
+    if base_size != base.len() {
+        bail!("Delta base has size {}, expected {}", base.len(), base_size);
+    }
+    let result_size = read_varint(delta, &mut pos)?;
+
+    let mut result = Vec::with_capacity(result_size);
 
-        c.read_exact(&mut one_byte)?;
+    while pos < delta.len() {
+        let op = read_byte(delta, &mut pos)?;
 
-        println!("Before {:b}", one_byte[0]);
+        if op & 0x80 != 0 {
+            // copy from base: which offset/size bytes follow is given by the low bits
+            let mut offset = 0usize;
+            for i in 0..4 {
+                if op & (1 << i) != 0 {
+                    offset |= (read_byte(delta, &mut pos)? as usize) << (8 * i);
+                }
+            }
 
-        if one_byte[0] >> 7u8 == 0 {
-            keep_reading = false;
+            let mut size = 0usize;
+            for i in 0..3 {
+                if op & (0x10 << i) != 0 {
+                    size |= (read_byte(delta, &mut pos)? as usize) << (8 * i);
+                }
+            }
+            if size == 0 {
+                size = 0x10000;
+            }
+
+            let chunk = base
+                .get(offset..offset + size)
+                .context("Delta copies past the end of its base")?;
+            result.extend_from_slice(chunk);
+        } else if op != 0 {
+            let size = op as usize;
+            let chunk = delta
+                .get(pos..pos + size)
+                .context("Delta inserts past its end")?;
+            result.extend_from_slice(chunk);
+            pos += size;
         } else {
-            one_byte[0] = one_byte[0] - 0b10000000;
+            bail!("Invalid delta instruction");
         }
-        println!("After {:8b}", one_byte[0]);
-        size_vec.push(one_byte[0]);
     }
 
-    size_vec.reverse();
+    if result.len() != result_size {
+        bail!("Delta produced {} bytes, expected {}", result.len(), result_size);
+    }
 
-    println!("{:x?}", size_vec);
-    Ok(0usize)
+    Ok(result)
 }
 
-pub struct Packfile();
+const INDEX_SIGNATURE: &[u8; 4] = b"\xfftOc";
+
+/// A version 2 pack index (`.idx`): the objects a pack holds, sorted by
+/// name, and where each starts in the pack.
+pub struct PackIndex {
+    names: Vec<ObjectId>,
+    offsets: Vec<usize>,
+}
+
+impl PackIndex {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let algorithm = HashAlgorithm::current();
+        let size = algorithm.size();
+        if data.len() < 8 + 256 * 4 + 2 * size || &data[..4] != INDEX_SIGNATURE {
+            bail!("Invalid pack index signature");
+        }
+
+        let version = u32::from_be_bytes(data[4..8].try_into()?);
+        if version != 2 {
+            bail!("Unsupported pack index version {}", version);
+        }
+
+        let (content, checksum) = data.split_at(data.len() - size);
+        if algorithm.digest(content).as_bytes() != checksum {
+            bail!("Pack index checksum mismatch");
+        }
+
+        // the last fanout entry counts every object
+        let count = u32::from_be_bytes(data[8 + 255 * 4..8 + 256 * 4].try_into()?) as usize;
+        let names_at = 8 + 256 * 4;
+        // names, then a CRC32 per object
+        let offsets_at = names_at + count * (size + 4);
+        let large_at = offsets_at + count * 4;
+        if content.len() < large_at + size {
+            bail!("Unexpected end of pack index");
+        }
+
+        let names = data[names_at..names_at + count * size]
+            .chunks(size)
+            .map(ObjectId::from_bytes)
+            .collect::<Result<_>>()?;
+
+        let mut offsets = Vec::with_capacity(count);
+        for chunk in data[offsets_at..large_at].chunks(4) {
+            let offset = u32::from_be_bytes(chunk.try_into()?);
+
+            // offsets past 2GiB live in a table of 8-byte ones
+            if offset & 0x8000_0000 != 0 {
+                let at = large_at + (offset & 0x7fff_ffff) as usize * 8;
+                let large = content.get(at..at + 8).context("Unexpected end of pack index")?;
+                offsets.push(u64::from_be_bytes(large.try_into()?) as usize);
+            } else {
+                offsets.push(offset as usize);
+            }
+        }
+
+        Ok(Self { names, offsets })
+    }
+
+    /// Every object in the pack, in name order.
+    pub fn names(&self) -> &[ObjectId] {
+        &self.names
+    }
+
+    /// Where `sha1_hash` starts in the pack, if the pack holds it.
+    pub fn find(&self, sha1_hash: &ObjectId) -> Option<usize> {
+        self.names
+            .binary_search(sha1_hash)
+            .ok()
+            .map(|i| self.offsets[i])
+    }
+}
+
+/// Serializes an index for a pack whose objects start at the given offsets,
+/// with the CRC32 of each packed entry.
+pub fn write_index(entries: &[(ObjectId, usize, u32)], pack_checksum: &[u8]) -> Vec<u8> {
+    let mut entries = entries.to_vec();
+    entries.sort_by_key(|entry| entry.0);
+
+    let mut out = INDEX_SIGNATURE.to_vec();
+    out.extend_from_slice(&2u32.to_be_bytes());
+
+    for byte in 0..=255u8 {
+        let count = entries.iter().filter(|(name, _, _)| name[0] <= byte).count();
+        out.extend_from_slice(&(count as u32).to_be_bytes());
+    }
+    for (name, _, _) in &entries {
+        out.extend_from_slice(name);
+    }
+    for (_, _, crc) in &entries {
+        out.extend_from_slice(&crc.to_be_bytes());
+    }
+
+    let mut large = Vec::new();
+    for (_, offset, _) in &entries {
+        if *offset < 0x8000_0000 {
+            out.extend_from_slice(&(*offset as u32).to_be_bytes());
+        } else {
+            out.extend_from_slice(&(0x8000_0000 | (large.len() / 8) as u32).to_be_bytes());
+            large.extend_from_slice(&(*offset as u64).to_be_bytes());
+        }
+    }
+    out.extend(large);
+
+    out.extend_from_slice(pack_checksum);
+    let checksum = HashAlgorithm::current().digest(&out);
+    out.extend_from_slice(&checksum);
+
+    out
+}
+
+// reads a pack file onwards from an offset; positioned reads leave other
+// readers of the same file alone
+struct PackReader {
+    file: File,
+    pos: u64,
+}
+
+impl Read for PackReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.file.read_at(buf, self.pos)?;
+        self.pos += count as u64;
+
+        Ok(count)
+    }
+}
+
+/// A pack and its index, for reading objects by name. Each object is read
+/// from the file at its offset, so the pack itself is never held in memory.
+pub struct Pack {
+    index: PackIndex,
+    file: File,
+}
+
+/// An object's type and size, and a reader over its body.
+pub type PackStream = (ObjectType, u64, Box<dyn Read + Send>);
+
+// enough for any entry header: the type and size, then an ofs-delta's base
+// offset or a ref-delta's base name
+const ENTRY_HEADER_MAX: usize = 96;
+
+impl Pack {
+    pub fn new(file: File, index: PackIndex) -> Result<Self> {
+        let mut signature = [0u8; 4];
+        if file.metadata()?.len() < 32 || file.read_exact_at(&mut signature, 0).is_err() || &signature != b"PACK" {
+            bail!("Invalid packfile signature");
+        }
+
+        Ok(Self { index, file })
+    }
+
+    pub fn index(&self) -> &PackIndex {
+        &self.index
+    }
+
+    /// The object named `sha1_hash`, undeltified, if the pack holds it.
+    pub fn read(&self, sha1_hash: &ObjectId) -> Result<Option<PackObject>> {
+        match self.index.find(sha1_hash) {
+            Some(offset) => Ok(Some(self.read_at(offset)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The type, size and body of the object named `sha1_hash`, if the pack
+    /// holds it. A whole object is inflated as it is read; a deltified one
+    /// has to be rebuilt in memory from its base.
+    pub fn stream(&self, sha1_hash: &ObjectId) -> Result<Option<PackStream>> {
+        let offset = match self.index.find(sha1_hash) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+
+        let (kind, size, base, start) = self.entry_at(offset)?;
+        if base.is_some() {
+            let object = self.read_at(offset)?;
+            return Ok(Some((object.kind, object.data.len() as u64, Box::new(Cursor::new(object.data)))));
+        }
+
+        let body = ZlibDecoder::new(BufReader::new(self.reader(start)?));
+
+        Ok(Some((kind, size as u64, Box::new(body))))
+    }
+
+    fn reader(&self, offset: usize) -> Result<PackReader> {
+        Ok(PackReader {
+            file: self.file.try_clone()?,
+            pos: offset as u64,
+        })
+    }
+
+    // the header of the entry at `offset`: its type and inflated size, the
+    // offset of its delta base if it has one, and where its data starts
+    fn entry_at(&self, offset: usize) -> Result<(ObjectType, usize, Option<usize>, usize)> {
+        let mut header = Vec::with_capacity(ENTRY_HEADER_MAX);
+        self.reader(offset)?
+            .take(ENTRY_HEADER_MAX as u64)
+            .read_to_end(&mut header)?;
+
+        let mut pos = 0;
+        let (code, size) = read_type_and_size(&header, &mut pos)?;
+
+        let kind = ObjectType::from_code(code)?;
+        let base = match kind {
+            ObjectType::OfsDelta => {
+                let relative = read_offset(&header, &mut pos)?;
+                Some(offset.checked_sub(relative).context("Delta base offset out of range")?)
+            }
+            ObjectType::RefDelta => {
+                let name = ObjectId::read(header.get(pos..).unwrap_or_default())?;
+                pos += name.len();
+                Some(
+                    self.index
+                        .find(&name)
+                        .with_context(|| format!("Delta base {} is not in the pack", hex::encode(name)))?,
+                )
+            }
+            _ => None,
+        };
+
+        Ok((kind, size, base, offset + pos))
+    }
+
+    fn read_at(&self, offset: usize) -> Result<PackObject> {
+        let (kind, size, base, start) = self.entry_at(offset)?;
+
+        let mut data = Vec::with_capacity(size);
+        ZlibDecoder::new(BufReader::new(self.reader(start)?))
+            .read_to_end(&mut data)
+            .context("Failed to inflate packfile entry")?;
+        if data.len() != size {
+            bail!("Packfile entry has size {}, expected {}", data.len(), size);
+        }
+
+        match base {
+            Some(base) => {
+                let base = self.read_at(base)?;
+
+                Ok(PackObject {
+                    kind: base.kind,
+                    data: apply_delta(&base.data, &data)?,
+                })
+            }
+            None => Ok(PackObject { kind, data }),
+        }
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// stretches of the base this long are indexed for finding copies
+const DELTA_BLOCK: usize = 16;
+
+fn flush_insert(out: &mut Vec<u8>, insert: &mut Vec<u8>) {
+    for chunk in insert.chunks(0x7f) {
+        out.push(chunk.len() as u8);
+        out.extend_from_slice(chunk);
+    }
+    insert.clear();
+}
+
+fn push_copy(out: &mut Vec<u8>, offset: usize, size: usize) {
+    let mut op = 0x80u8;
+    let mut args = Vec::new();
+
+    // only the non-zero bytes of offset and size are stored
+    for i in 0..4 {
+        let byte = (offset >> (8 * i)) as u8;
+        if byte != 0 {
+            op |= 1 << i;
+            args.push(byte);
+        }
+    }
+    for i in 0..3 {
+        let byte = (size >> (8 * i)) as u8;
+        if byte != 0 {
+            op |= 0x10 << i;
+            args.push(byte);
+        }
+    }
+
+    out.push(op);
+    out.extend(args);
+}
+
+/// Encodes `target` as a git delta against `base`: copies of stretches
+/// the two share, and inserts of everything else.
+pub fn create_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_varint(&mut out, base.len());
+    write_varint(&mut out, target.len());
+
+    let mut blocks: HashMap<&[u8], usize> = HashMap::new();
+    for start in (0..base.len().saturating_sub(DELTA_BLOCK - 1)).step_by(DELTA_BLOCK) {
+        blocks.entry(&base[start..start + DELTA_BLOCK]).or_insert(start);
+    }
+
+    let mut insert = Vec::new();
+    let mut pos = 0;
+    while pos < target.len() {
+        let found = target
+            .get(pos..pos + DELTA_BLOCK)
+            .and_then(|block| blocks.get(block).copied());
+
+        let mut start = match found {
+            Some(start) => start,
+            None => {
+                insert.push(target[pos]);
+                pos += 1;
+                continue;
+            }
+        };
+
+        // grow the match both ways, taking back bytes queued for insertion
+        let mut len = DELTA_BLOCK;
+        while start + len < base.len() && pos + len < target.len() && base[start + len] == target[pos + len] {
+            len += 1;
+        }
+        pos += len;
+        while start > 0 && insert.last() == Some(&base[start - 1]) {
+            insert.pop();
+            start -= 1;
+            len += 1;
+        }
+
+        flush_insert(&mut out, &mut insert);
+        let mut copied = 0;
+        while copied < len {
+            let size = (len - copied).min(0xff_ffff);
+            push_copy(&mut out, start + copied, size);
+            copied += size;
+        }
+    }
+    flush_insert(&mut out, &mut insert);
+
+    out
+}
+
+// how many earlier objects each object is tried as a delta against
+const DELTA_WINDOW: usize = 10;
+const MAX_DELTA_DEPTH: usize = 50;
+
+/// A pack written by `write_pack`, with the index to store next to it.
+pub struct WrittenPack {
+    pub data: Vec<u8>,
+    pub index: Vec<u8>,
+    pub checksum: ObjectId,
+    pub deltas: usize,
+}
+
+/// Packs `objects`, each given with a path name that hints which other
+/// objects it is likely to share content with. Similar objects are stored
+/// as offset deltas against each other.
+pub fn write_pack(objects: Vec<(PackObject, String)>) -> Result<WrittenPack> {
+    let mut objects = objects;
+    // like objects next to each other, larger ones first to serve as bases
+    objects.sort_by(|(a, a_name), (b, b_name)| {
+        (a.kind.code(), a_name)
+            .cmp(&(b.kind.code(), b_name))
+            .then(b.data.len().cmp(&a.data.len()))
+    });
+
+    let mut data = b"PACK".to_vec();
+    data.extend_from_slice(&2u32.to_be_bytes());
+    data.extend_from_slice(&(objects.len() as u32).to_be_bytes());
+
+    let mut entries = Vec::with_capacity(objects.len());
+    let mut offsets: Vec<usize> = Vec::with_capacity(objects.len());
+    let mut depths: Vec<usize> = Vec::with_capacity(objects.len());
+    let mut deltas = 0;
+
+    for (i, (object, _)) in objects.iter().enumerate() {
+        let mut best: Option<(usize, Vec<u8>)> = None;
+        for j in (i.saturating_sub(DELTA_WINDOW)..i).rev() {
+            let base = &objects[j].0;
+            if base.kind != object.kind || depths[j] >= MAX_DELTA_DEPTH {
+                continue;
+            }
+
+            let delta = create_delta(&base.data, &object.data);
+            let limit = best.as_ref().map_or(object.data.len() / 2, |(_, best)| best.len());
+            if delta.len() < limit {
+                best = Some((j, delta));
+            }
+        }
+
+        let offset = data.len();
+        let mut entry = Vec::new();
+        let (code, content) = match &best {
+            Some((_, delta)) => (ObjectType::OfsDelta.code(), delta),
+            None => (object.kind.code(), &object.data),
+        };
+
+        let mut size = content.len();
+        let mut byte = (code << 4) | (size & 0b1111) as u8;
+        size >>= 4;
+        while size > 0 {
+            entry.push(byte | 0x80);
+            byte = (size & 0x7f) as u8;
+            size >>= 7;
+        }
+        entry.push(byte);
+
+        match &best {
+            Some((j, _)) => {
+                // the inverse of read_offset
+                let mut relative = offset - offsets[*j];
+                let mut encoded = vec![(relative & 0x7f) as u8];
+                relative >>= 7;
+                while relative > 0 {
+                    relative -= 1;
+                    encoded.push(0x80 | (relative & 0x7f) as u8);
+                    relative >>= 7;
+                }
+                encoded.reverse();
+                entry.extend(encoded);
+
+                depths.push(depths[*j] + 1);
+                deltas += 1;
+            }
+            None => depths.push(0),
+        }
+        entry.extend(utils::zlib_compress(content)?);
+
+        let mut crc = Crc::new();
+        crc.update(&entry);
+
+        entries.push((object.sha1_hash(), offset, crc.sum()));
+        offsets.push(offset);
+        data.extend(entry);
+    }
+
+    let checksum = HashAlgorithm::current().digest(&data);
+    data.extend_from_slice(&checksum);
+    let index = write_index(&entries, &checksum);
+
+    Ok(WrittenPack {
+        data,
+        index,
+        checksum,
+        deltas,
+    })
+}
+
+pub struct Packfile {
+    objects: Vec<PackObject>,
+}
 
 impl Packfile {
     pub fn parse_data(data: &[u8]) -> Result<Self> {
-        let mut cursor = Cursor::new(data.clone());
+        Self::parse_thin_data(data, |_| Ok(None))
+    }
+
+    /// Parses a thin pack, whose deltas may be against objects it leaves
+    /// out for the receiver to have, looking those up with `base`. Like
+    /// `git index-pack --fix-thin`, the bases found become part of the pack.
+    pub fn parse_thin_data<F>(data: &[u8], base: F) -> Result<Self>
+    where
+        F: FnMut(&ObjectId) -> Result<Option<PackObject>>,
+    {
+        if data.len() < 32 || &data[..4] != b"PACK" {
+            bail!("Invalid packfile signature");
+        }
+
+        let algorithm = HashAlgorithm::current();
+        let (content, checksum) = data.split_at(data.len() - algorithm.size());
+        if algorithm.digest(content).as_bytes() != checksum {
+            bail!("Packfile checksum mismatch");
+        }
+
+        let version = u32::from_be_bytes(data[4..8].try_into()?);
+        if version != 2 && version != 3 {
+            bail!("Unsupported packfile version {}", version);
+        }
+        let count = u32::from_be_bytes(data[8..12].try_into()?);
+
+        let mut pos = 12;
+        let mut entries: Vec<(usize, RawEntry)> = Vec::with_capacity(count as usize);
+
+        for _ in 0..count {
+            let offset = pos;
+            let (code, size) = read_type_and_size(content, &mut pos)?;
+
+            let entry = match ObjectType::from_code(code)? {
+                ObjectType::OfsDelta => {
+                    let relative = read_offset(content, &mut pos)?;
+                    let base = offset
+                        .checked_sub(relative)
+                        .context("Delta base offset out of range")?;
+                    RawEntry::OfsDelta(base, inflate(content, &mut pos, size)?)
+                }
+                ObjectType::RefDelta => {
+                    let base = ObjectId::read(content.get(pos..).unwrap_or_default())?;
+                    pos += base.len();
+                    RawEntry::RefDelta(base, inflate(content, &mut pos, size)?)
+                }
+                kind => RawEntry::Whole(kind, inflate(content, &mut pos, size)?),
+            };
+
+            entries.push((offset, entry));
+        }
+
+        Ok(Self {
+            objects: Self::resolve(entries, base)?,
+        })
+    }
+
+    // undeltify entries in passes, each resolving the deltas whose base is known
+    fn resolve<F>(entries: Vec<(usize, RawEntry)>, mut external: F) -> Result<Vec<PackObject>>
+    where
+        F: FnMut(&ObjectId) -> Result<Option<PackObject>>,
+    {
+        let mut resolved: HashMap<usize, usize> = HashMap::new();
+        let mut by_sha: HashMap<ObjectId, usize> = HashMap::new();
+        let mut objects: Vec<PackObject> = Vec::with_capacity(entries.len());
+        let mut pending: Vec<(usize, RawEntry)> = Vec::new();
+
+        for (offset, entry) in entries {
+            match entry {
+                RawEntry::Whole(kind, data) => {
+                    let object = PackObject { kind, data };
+                    by_sha.insert(object.sha1_hash(), objects.len());
+                    resolved.insert(offset, objects.len());
+                    objects.push(object);
+                }
+                delta => pending.push((offset, delta)),
+            }
+        }
 
-        let mut signature: [u8; 4] = [0; 4];
-        println!("{:x?}", signature);
+        while !pending.is_empty() {
+            let before = pending.len();
+            let mut still_pending = Vec::new();
 
-        cursor.read_exact(&mut signature)?;
+            for (offset, entry) in pending {
+                let base = match &entry {
+                    RawEntry::OfsDelta(base, _) => resolved.get(base).copied(),
+                    RawEntry::RefDelta(base, _) => by_sha.get(base).copied(),
+                    RawEntry::Whole(_, _) => unreachable!(),
+                };
 
-        let mut version_num: [u8; 4] = [0; 4];
+                let base = match base {
+                    Some(base) => base,
+                    None => {
+                        still_pending.push((offset, entry));
+                        continue;
+                    }
+                };
 
-        cursor.read_exact(&mut version_num)?;
+                let delta = match &entry {
+                    RawEntry::OfsDelta(_, delta) | RawEntry::RefDelta(_, delta) => delta,
+                    RawEntry::Whole(_, _) => unreachable!(),
+                };
 
-        loop {
-            let size = read_object_size(&mut cursor)?;
+                let object = PackObject {
+                    kind: objects[base].kind,
+                    data: apply_delta(&objects[base].data, delta)?,
+                };
+                by_sha.insert(object.sha1_hash(), objects.len());
+                resolved.insert(offset, objects.len());
+                objects.push(object);
+            }
 
-            break;
+            if still_pending.len() == before {
+                // only bases from outside the pack can help now
+                let mut found = false;
+                for (_, entry) in &still_pending {
+                    if let RawEntry::RefDelta(base, _) = entry {
+                        if by_sha.contains_key(base) {
+                            continue;
+                        }
+                        if let Some(object) = external(base)? {
+                            by_sha.insert(*base, objects.len());
+                            objects.push(object);
+                            found = true;
+                        }
+                    }
+                }
+
+                if !found {
+                    bail!("Packfile contains deltas against missing base objects");
+                }
+            }
+            pending = still_pending;
         }
 
-        Ok(Self())
+        Ok(objects)
+    }
+
+    pub fn objects(&self) -> &[PackObject] {
+        &self.objects
+    }
+
+    pub fn into_objects(self) -> Vec<PackObject> {
+        self.objects
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils;
     use std::fs;
 
     #[test]
@@ -76,4 +846,107 @@ mod tests {
 
         Ok(())
     }
+
+    fn entry_header(code: u8, size: usize) -> Vec<u8> {
+        let mut header = vec![(code << 4) | (size & 0b1111) as u8];
+        let mut rest = size >> 4;
+        while rest > 0 {
+            *header.last_mut().unwrap() |= 0x80;
+            header.push((rest & 0x7f) as u8);
+            rest >>= 7;
+        }
+
+        header
+    }
+
+    #[test]
+    fn test_parse_pack_with_ofs_delta() -> Result<()> {
+        let base = b"hello world, this is the base object\n".to_vec();
+
+        // copy the first 12 bytes of the base, then insert "delta!\n"
+        let mut delta = vec![base.len() as u8, 19];
+        delta.extend_from_slice(&[0x80 | 0x01 | 0x10, 0, 12]);
+        delta.push(7);
+        delta.extend_from_slice(b"delta!\n");
+
+        let mut pack = b"PACK".to_vec();
+        pack.extend_from_slice(&2u32.to_be_bytes());
+        pack.extend_from_slice(&2u32.to_be_bytes());
+
+        let base_offset = pack.len();
+        pack.extend(entry_header(3, base.len()));
+        pack.extend(utils::zlib_compress(&base)?);
+
+        let delta_offset = pack.len();
+        pack.extend(entry_header(6, delta.len()));
+        pack.push((delta_offset - base_offset) as u8);
+        pack.extend(utils::zlib_compress(&delta)?);
+
+        let checksum = HashAlgorithm::current().digest(&pack);
+        pack.extend_from_slice(&checksum);
+
+        let packfile = Packfile::parse_data(&pack)?;
+        let objects = packfile.objects();
+
+        assert_eq!(objects.len(), 2);
+        assert_eq!(objects[1].kind, ObjectType::Blob);
+        assert_eq!(objects[1].data, b"hello world,delta!\n".to_vec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_delta() -> Result<()> {
+        let base: Vec<u8> = (0..2000u32).flat_map(|i| format!("line {}\n", i).into_bytes()).collect();
+
+        let mut target = base.clone();
+        target.splice(5000..5010, b"changed!".iter().copied());
+        target.extend_from_slice(b"appended\n");
+
+        let delta = create_delta(&base, &target);
+        assert!(delta.len() < 100);
+        assert_eq!(apply_delta(&base, &delta)?, target);
+
+        assert_eq!(apply_delta(b"", &create_delta(b"", b"new"))?, b"new".to_vec());
+        assert_eq!(apply_delta(b"old", &create_delta(b"old", b""))?, b"".to_vec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_pack() -> Result<()> {
+        let base: Vec<u8> = (0..500u32).flat_map(|i| format!("row {}\n", i).into_bytes()).collect();
+        let mut changed = base.clone();
+        changed.extend_from_slice(b"one more row\n");
+
+        let objects = vec![
+            (PackObject { kind: ObjectType::Blob, data: base }, String::from("file")),
+            (PackObject { kind: ObjectType::Blob, data: changed }, String::from("file")),
+            (PackObject { kind: ObjectType::Commit, data: b"not really a commit".to_vec() }, String::new()),
+        ];
+        let names: Vec<ObjectId> = objects.iter().map(|(object, _)| object.sha1_hash()).collect();
+        let expected: Vec<Vec<u8>> = objects.iter().map(|(object, _)| object.write_data()).collect();
+
+        let written = write_pack(objects)?;
+        assert_eq!(written.deltas, 1);
+
+        let path = std::env::temp_dir().join(format!("tgit-pack-{}.pack", std::process::id()));
+        std::fs::write(&path, &written.data)?;
+        let pack = Pack::new(File::open(&path)?, PackIndex::parse(&written.index)?)?;
+        for (name, write_data) in names.iter().zip(&expected) {
+            assert_eq!(pack.read(name)?.map(|object| object.write_data()).as_ref(), Some(write_data));
+
+            // streamed, deltified or not, the body is the same
+            let (kind, size, mut body) = pack.stream(name)?.unwrap();
+            let mut streamed = format!("{} {}\0", kind.name(), size).into_bytes();
+            body.read_to_end(&mut streamed)?;
+            assert_eq!(&streamed, write_data);
+        }
+        assert!(pack.read(&HashAlgorithm::current().null())?.is_none());
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(Packfile::parse_data(&written.data)?.objects().len(), 3);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,116 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+
+use crate::clone::{CloneClient, PackRequest};
+use crate::config::Config;
+use crate::gc;
+use crate::objects::{self, HashAlgorithm, ObjectId};
+use crate::packfile::Packfile;
+
+// what lazy fetches ask for: whatever is named, without the blobs of trees
+const LAZY_FILTER: &str = "blob:none";
+
+/// Checks a `--filter` spec is one the protocol can carry: `blob:none`,
+/// `blob:limit=<n>[kmg]` or `tree:<depth>`.
+pub fn check_filter(spec: &str) -> Result<()> {
+    let valid = if spec == "blob:none" {
+        true
+    } else if let Some(limit) = spec.strip_prefix("blob:limit=") {
+        let digits = limit.strip_suffix(['k', 'm', 'g', 'K', 'M', 'G']).unwrap_or(limit);
+        !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+    } else if let Some(depth) = spec.strip_prefix("tree:") {
+        depth.parse::<u64>().is_ok()
+    } else {
+        false
+    };
+
+    if !valid {
+        bail!("invalid filter-spec '{}'", spec);
+    }
+
+    Ok(())
+}
+
+/// Whether the repository is a partial clone, whose missing objects its
+/// promisor remote can still provide.
+pub fn is_partial_clone() -> Result<bool> {
+    Ok(Config::load()?.get("extensions.partialclone").is_some())
+}
+
+// where the promisor remote lives, if there is one
+fn remote_url() -> Result<Option<String>> {
+    let config = Config::load()?;
+    let remote = match config.get("extensions.partialclone") {
+        Some(remote) => remote,
+        None => return Ok(None),
+    };
+
+    match config.get(&format!("remote.{}.url", remote)) {
+        Some(url) => Ok(Some(url)),
+        None => bail!("promisor remote '{}' has no url", remote),
+    }
+}
+
+/// Stores a pack received from the promisor remote, marked as such: what
+/// its objects refer to but it lacks can be fetched later.
+pub fn store_pack(data: &[u8]) -> Result<()> {
+    let packfile = Packfile::parse_data(data).context("Invalid packfile")?;
+    let objects = packfile.into_objects().into_iter().map(|object| (object, String::new())).collect();
+
+    let path = gc::store_pack(objects, None)?;
+    fs::write(path.with_extension("promisor"), "")?;
+
+    Ok(())
+}
+
+/// Fetches the objects of `missing` that are not stored from the promisor
+/// remote, in one request. Returns whether there is a promisor remote to
+/// ask at all.
+pub async fn fetch(missing: &[ObjectId]) -> Result<bool> {
+    let url = match remote_url()? {
+        Some(url) => url,
+        None => return Ok(false),
+    };
+
+    let mut wants = Vec::new();
+    for sha1_hash in missing {
+        let want = hex::encode(sha1_hash);
+        if !objects::has_object(*sha1_hash)? && !wants.contains(&want) {
+            wants.push(want);
+        }
+    }
+    if wants.is_empty() {
+        return Ok(true);
+    }
+
+    let client = CloneClient::new(url);
+    let advertisement = client.discover_refs().await?;
+    let request = PackRequest {
+        wants,
+        filter: Some(LAZY_FILTER.to_string()).filter(|_| advertisement.supports("filter")),
+        ..PackRequest::default()
+    };
+
+    let response = client
+        .request_pack(&request, HashAlgorithm::current())
+        .await
+        .with_context(|| format!("could not fetch {} from promisor remote", request.wants[0]))?;
+    store_pack(&response.pack)?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_filter() {
+        for spec in ["blob:none", "blob:limit=0", "blob:limit=10k", "blob:limit=1G", "tree:0", "tree:3"] {
+            assert!(check_filter(spec).is_ok(), "{}", spec);
+        }
+        for spec in ["blob:some", "blob:limit=", "blob:limit=k", "blob:limit=1kk", "tree:", "tree:-1", "sparse"] {
+            assert!(check_filter(spec).is_err(), "{}", spec);
+        }
+    }
+}
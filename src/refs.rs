@@ -0,0 +1,433 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::objects::{HashAlgorithm, ObjectId};
+
+pub enum Head {
+    Branch(String),
+    Detached(ObjectId),
+}
+
+fn ref_path(refname: &str) -> PathBuf {
+    Path::new(".git").join(refname)
+}
+
+fn parse_sha(contents: &str) -> Result<ObjectId> {
+    let contents = contents.trim();
+    if contents.len() != HashAlgorithm::current().hex_size() || !contents.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Invalid ref contents: {}", contents);
+    }
+
+    ObjectId::from_hex(contents)
+}
+
+pub fn read_head() -> Result<Head> {
+    let contents = fs::read_to_string(".git/HEAD").context("Not a git repository")?;
+
+    match contents.trim().strip_prefix("ref: ") {
+        Some(refname) => Ok(Head::Branch(refname.to_string())),
+        None => Ok(Head::Detached(parse_sha(&contents)?)),
+    }
+}
+
+pub fn set_head_branch(refname: &str) -> Result<()> {
+    write_symref("HEAD", refname)
+}
+
+pub fn write_symref(name: &str, target: &str) -> Result<()> {
+    let path = ref_path(name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::write(path, format!("ref: {}\n", target))?;
+
+    Ok(())
+}
+
+pub fn set_head_detached(sha1_hash: ObjectId) -> Result<()> {
+    fs::write(".git/HEAD", format!("{}\n", hex::encode(sha1_hash)))?;
+
+    Ok(())
+}
+
+/// The short name of the checked out branch, or `None` when HEAD is detached.
+pub fn current_branch() -> Result<Option<String>> {
+    match read_head()? {
+        Head::Branch(refname) => Ok(Some(shorten(&refname).to_string())),
+        Head::Detached(_) => Ok(None),
+    }
+}
+
+pub fn head_sha() -> Result<Option<ObjectId>> {
+    resolve("HEAD")
+}
+
+pub fn shorten(refname: &str) -> &str {
+    for prefix in &["refs/heads/", "refs/tags/", "refs/remotes/"] {
+        if let Some(short) = refname.strip_prefix(prefix) {
+            return short;
+        }
+    }
+
+    refname
+}
+
+pub fn read_packed_refs() -> Result<Vec<(String, ObjectId)>> {
+    let path = Path::new(".git/packed-refs");
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut refs = Vec::new();
+
+    for line in fs::read_to_string(path)?.lines() {
+        // header and peeled tag lines
+        if line.starts_with('#') || line.starts_with('^') || line.is_empty() {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, ' ');
+        let sha = parts.next().context("Malformed packed-refs")?;
+        let name = parts.next().context("Malformed packed-refs")?;
+
+        refs.push((name.to_string(), parse_sha(sha)?));
+    }
+
+    Ok(refs)
+}
+
+// what each packed tag peels to, from the `^` line following it
+fn read_packed_peeled() -> Result<HashMap<String, ObjectId>> {
+    let path = Path::new(".git/packed-refs");
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let mut peeled = HashMap::new();
+    let mut last = None;
+
+    for line in fs::read_to_string(path)?.lines() {
+        if let Some(sha) = line.strip_prefix('^') {
+            if let Some(name) = last.take() {
+                peeled.insert(name, parse_sha(sha)?);
+            }
+        } else if !line.starts_with('#') {
+            last = line.split_once(' ').map(|(_, name)| name.to_string());
+        }
+    }
+
+    Ok(peeled)
+}
+
+fn write_packed_refs(refs: &[(String, ObjectId)], peeled: &HashMap<String, ObjectId>) -> Result<()> {
+    let mut out = String::from("# pack-refs with: peeled fully-peeled sorted \n");
+    for (name, sha) in refs {
+        out.push_str(&format!("{} {}\n", hex::encode(sha), name));
+        if let Some(target) = peeled.get(name) {
+            out.push_str(&format!("^{}\n", hex::encode(target)));
+        }
+    }
+
+    let tmp = Path::new(".git/packed-refs.lock");
+    fs::write(tmp, out)?;
+    fs::rename(tmp, ".git/packed-refs")?;
+
+    Ok(())
+}
+
+/// Moves every loose ref into `packed-refs`, recording what the annotated
+/// tags among them peel to. Symbolic refs stay loose.
+pub fn pack_refs(peeled: &HashMap<String, ObjectId>) -> Result<()> {
+    let mut names = Vec::new();
+    collect_loose(&ref_path("refs/"), "refs/", &mut names)?;
+
+    let mut refs = read_packed_refs()?;
+    let mut packed = Vec::new();
+
+    for name in names {
+        let contents = fs::read_to_string(ref_path(&name))?;
+        if contents.starts_with("ref: ") {
+            continue;
+        }
+
+        let sha = parse_sha(&contents)?;
+        refs.retain(|(packed_name, _)| *packed_name != name);
+        refs.push((name.clone(), sha));
+        packed.push(name);
+    }
+
+    refs.sort();
+    write_packed_refs(&refs, peeled)?;
+
+    for name in packed {
+        let path = ref_path(&name);
+        fs::remove_file(&path)?;
+        prune_empty_dirs(&path);
+    }
+
+    Ok(())
+}
+
+/// Resolves a full refname (or `HEAD`), following symbolic refs.
+/// Returns `None` for refs that do not exist, such as an unborn branch.
+pub fn resolve(refname: &str) -> Result<Option<ObjectId>> {
+    let mut refname = refname.to_string();
+
+    for _ in 0..5 {
+        let path = ref_path(&refname);
+
+        if path.is_file() {
+            let contents = fs::read_to_string(&path)?;
+
+            match contents.trim().strip_prefix("ref: ") {
+                Some(target) => {
+                    refname = target.to_string();
+                    continue;
+                }
+                None => return Ok(Some(parse_sha(&contents)?)),
+            }
+        }
+
+        let packed = read_packed_refs()?;
+        return Ok(packed
+            .into_iter()
+            .find(|(name, _)| *name == refname)
+            .map(|(_, sha)| sha));
+    }
+
+    bail!("Symbolic ref loop at {}", refname)
+}
+
+pub fn exists(refname: &str) -> Result<bool> {
+    Ok(resolve(refname)?.is_some())
+}
+
+/// Expands a short name the way git does: `main` -> `refs/heads/main`,
+/// `origin/main` -> `refs/remotes/origin/main`, and so on.
+pub fn dwim(name: &str) -> Result<Option<String>> {
+    let candidates = [
+        name.to_string(),
+        format!("refs/{}", name),
+        format!("refs/tags/{}", name),
+        format!("refs/heads/{}", name),
+        format!("refs/remotes/{}", name),
+        format!("refs/remotes/{}/HEAD", name),
+    ];
+
+    for candidate in candidates.iter() {
+        if (candidate == "HEAD" || candidate.starts_with("refs/")) && exists(candidate)? {
+            return Ok(Some(candidate.clone()));
+        }
+    }
+
+    Ok(None)
+}
+
+pub fn update_ref(refname: &str, sha1_hash: ObjectId) -> Result<()> {
+    let path = ref_path(refname);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::write(path, format!("{}\n", hex::encode(sha1_hash)))?;
+
+    Ok(())
+}
+
+pub fn delete_ref(refname: &str) -> Result<()> {
+    let path = ref_path(refname);
+    if path.is_file() {
+        fs::remove_file(&path)?;
+        prune_empty_dirs(&path);
+    }
+
+    let packed = read_packed_refs()?;
+    if packed.iter().any(|(name, _)| name == refname) {
+        let remaining: Vec<_> = packed.into_iter().filter(|(name, _)| name != refname).collect();
+        write_packed_refs(&remaining, &read_packed_peeled()?)?;
+    }
+
+    write_reflog(refname, &[])
+}
+
+pub fn rename_ref(old: &str, new: &str) -> Result<()> {
+    let sha1_hash = match resolve(old)? {
+        Some(sha) => sha,
+        None => bail!("No such ref: {}", old),
+    };
+    if exists(new)? {
+        bail!("A ref named '{}' already exists", new);
+    }
+
+    update_ref(new, sha1_hash)?;
+    delete_ref(old)?;
+
+    Ok(())
+}
+
+/// One update recorded in a ref's reflog.
+pub struct ReflogEntry {
+    pub old: ObjectId,
+    pub new: ObjectId,
+    /// Who made the update and when, as in a commit's committer line.
+    pub identity: String,
+    pub message: String,
+}
+
+fn reflog_path(refname: &str) -> PathBuf {
+    Path::new(".git/logs").join(refname)
+}
+
+/// The updates recorded in a ref's reflog, oldest first. Refs without a
+/// reflog have an empty history.
+pub fn read_reflog(refname: &str) -> Result<Vec<ReflogEntry>> {
+    let path = reflog_path(refname);
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+
+    let mut entries = Vec::new();
+    for line in fs::read_to_string(&path)?.lines() {
+        let (line, message) = line.split_once('\t').unwrap_or((line, ""));
+        let mut fields = line.splitn(3, ' ');
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(old), Some(new), Some(identity)) => entries.push(ReflogEntry {
+                old: parse_sha(old)?,
+                new: parse_sha(new)?,
+                identity: identity.to_string(),
+                message: message.to_string(),
+            }),
+            _ => bail!("bad reflog entry in {}: {}", path.display(), line),
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Replaces a ref's reflog with `entries`, removing it when there are none.
+pub fn write_reflog(refname: &str, entries: &[ReflogEntry]) -> Result<()> {
+    let path = reflog_path(refname);
+    if entries.is_empty() {
+        if path.is_file() {
+            fs::remove_file(&path)?;
+            prune_empty_dirs(&path);
+        }
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut out = String::new();
+    for entry in entries {
+        out.push_str(&format!(
+            "{} {} {}\t{}\n",
+            hex::encode(entry.old),
+            hex::encode(entry.new),
+            entry.identity,
+            entry.message
+        ));
+    }
+    fs::write(path, out)?;
+
+    Ok(())
+}
+
+/// Points `refname` at `entry.new`, recording the update in its reflog.
+pub fn update_ref_logged(refname: &str, entry: ReflogEntry) -> Result<()> {
+    update_ref(refname, entry.new)?;
+
+    let mut entries = read_reflog(refname)?;
+    entries.push(entry);
+    write_reflog(refname, &entries)
+}
+
+// remove directories left empty by deleting refs/heads/topic/x style refs
+fn prune_empty_dirs(path: &Path) {
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        let top = d.ends_with("refs") || d.ends_with("refs/heads") || d.ends_with("refs/tags");
+        if top || fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
+
+fn collect_loose(dir: &Path, prefix: &str, names: &mut Vec<String>) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+
+        if entry.file_type()?.is_dir() {
+            collect_loose(&entry.path(), &format!("{}/", name), names)?;
+        } else {
+            names.push(name);
+        }
+    }
+
+    Ok(())
+}
+
+/// Names every ref below `prefix`, loose or packed, whether or not it resolves.
+pub fn list_ref_names(prefix: &str) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    collect_loose(&ref_path(prefix), prefix, &mut names)?;
+
+    for (name, _) in read_packed_refs()? {
+        if name.starts_with(prefix) && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    names.sort();
+
+    Ok(names)
+}
+
+/// Lists all refs below `prefix` (e.g. `refs/heads/`), loose refs shadowing packed ones.
+pub fn list_refs(prefix: &str) -> Result<Vec<(String, ObjectId)>> {
+    let mut refs = Vec::new();
+    for name in list_ref_names(prefix)? {
+        if let Some(sha) = resolve(&name)? {
+            refs.push((name, sha));
+        }
+    }
+
+    Ok(refs)
+}
+
+/// Whether `name` may go below `refs/heads/` or `refs/tags/`.
+pub fn is_valid_name(name: &str) -> bool {
+    let invalid = name.is_empty()
+        || name.starts_with('-')
+        || name.starts_with('/')
+        || name.ends_with('/')
+        || name.ends_with(".lock")
+        || name.ends_with('.')
+        || name.contains("..")
+        || name.contains("//")
+        || name.contains("@{")
+        || name == "HEAD"
+        || name
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c));
+
+    !invalid
+}
+
+pub fn check_branch_name(name: &str) -> Result<()> {
+    if !is_valid_name(name) {
+        bail!("'{}' is not a valid branch name", name);
+    }
+
+    Ok(())
+}
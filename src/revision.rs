@@ -0,0 +1,241 @@
+use anyhow::{bail, Context, Result};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use crate::objects::{self, Commit, HashAlgorithm, Object, ObjectId, Tag};
+use crate::refs;
+
+// "abc123" -> every object whose name starts with it
+fn expand_abbreviated(prefix: &str) -> Result<Vec<ObjectId>> {
+    let prefix = prefix.to_lowercase();
+
+    let mut matches = Vec::new();
+    let dir = Path::new(".git/objects").join(&prefix[..2]);
+    if dir.is_dir() {
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            let full = format!("{}{}", &prefix[..2], name);
+
+            if full.len() == HashAlgorithm::current().hex_size() && full.starts_with(&prefix) {
+                matches.push(ObjectId::from_hex(&full)?);
+            }
+        }
+    }
+
+    for sha1_hash in objects::packed_objects()? {
+        if hex::encode(sha1_hash).starts_with(&prefix) && !matches.contains(&sha1_hash) {
+            matches.push(sha1_hash);
+        }
+    }
+
+    Ok(matches)
+}
+
+fn is_hex(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// "stash@{1}" -> what the ref pointed at one update before its latest
+fn resolve_reflog(name: &str, nth: &str) -> Result<ObjectId> {
+    let name = if name.is_empty() { "HEAD" } else { name };
+    let nth: usize = match nth.parse() {
+        Ok(nth) => nth,
+        Err(_) => bail!("unknown revision: '{}@{{{}}}'", name, nth),
+    };
+
+    let refname = match refs::dwim(name)? {
+        Some(refname) => refname,
+        None => bail!("unknown revision: '{}@{{{}}}'", name, nth),
+    };
+    let entries = refs::read_reflog(&refname)?;
+    match entries.iter().rev().nth(nth) {
+        Some(entry) => Ok(entry.new),
+        None => bail!("log for '{}' only has {} entries", name, entries.len()),
+    }
+}
+
+async fn resolve_base(name: &str) -> Result<ObjectId> {
+    if let Some((name, nth)) = name.strip_suffix('}').and_then(|n| n.split_once("@{")) {
+        return resolve_reflog(name, nth);
+    }
+
+    let name = if name == "@" || name.is_empty() { "HEAD" } else { name };
+
+    if let Some(refname) = refs::dwim(name)? {
+        if let Some(sha) = refs::resolve(&refname)? {
+            return Ok(sha);
+        }
+    }
+
+    if is_hex(name) && name.len() == HashAlgorithm::current().hex_size() {
+        return ObjectId::from_hex(name);
+    }
+
+    if is_hex(name) && name.len() >= 4 {
+        let matches = expand_abbreviated(name)?;
+        match matches.len() {
+            0 => {}
+            1 => return Ok(matches[0]),
+            _ => bail!("short SHA1 {} is ambiguous", name),
+        }
+    }
+
+    if name == "HEAD" {
+        bail!("HEAD does not point to a commit yet");
+    }
+
+    bail!("unknown revision: '{}'", name)
+}
+
+/// Resolves a revision such as `main`, `HEAD~2`, `origin/main^2`,
+/// `stash@{1}`, `v1.0^{}` or an abbreviated SHA to an object id.
+pub async fn resolve(spec: &str) -> Result<ObjectId> {
+    let split = spec.find(['~', '^']).unwrap_or(spec.len());
+    let (base, mut suffix) = spec.split_at(split);
+
+    let mut sha1_hash = resolve_base(base).await?;
+
+    while !suffix.is_empty() {
+        let op = suffix.as_bytes()[0];
+        suffix = &suffix[1..];
+
+        // "v1^{}" peels a tag, "v1^{tree}" peels to an object type
+        if op == b'^' && suffix.starts_with('{') {
+            let end = suffix.find('}').with_context(|| format!("unknown revision: '{}'", spec))?;
+            sha1_hash = peel_to(sha1_hash, &suffix[1..end], spec).await?;
+            suffix = &suffix[end + 1..];
+            continue;
+        }
+        sha1_hash = peel(sha1_hash).await?;
+
+        let digits_end = suffix
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(suffix.len());
+        let count: usize = if digits_end == 0 {
+            1
+        } else {
+            suffix[..digits_end].parse()?
+        };
+        suffix = &suffix[digits_end..];
+
+        if op == b'~' {
+            for _ in 0..count {
+                let commit = Commit::from_object_sha(hex::encode(sha1_hash)).await?;
+                sha1_hash = *commit
+                    .parents()
+                    .first()
+                    .with_context(|| format!("{}: not enough ancestors", spec))?;
+            }
+        } else if count > 0 {
+            let commit = Commit::from_object_sha(hex::encode(sha1_hash)).await?;
+            sha1_hash = *commit
+                .parents()
+                .get(count - 1)
+                .with_context(|| format!("{}: no such parent", spec))?;
+        }
+    }
+
+    Ok(sha1_hash)
+}
+
+/// Follows annotated tags down to the object they finally point at.
+pub async fn peel(mut sha1_hash: ObjectId) -> Result<ObjectId> {
+    while object_type(sha1_hash).await? == "tag" {
+        sha1_hash = Tag::from_object_sha(hex::encode(sha1_hash)).await?.object();
+    }
+
+    Ok(sha1_hash)
+}
+
+async fn peel_to(sha1_hash: ObjectId, kind: &str, spec: &str) -> Result<ObjectId> {
+    match kind {
+        "object" => Ok(sha1_hash),
+        "tag" if object_type(sha1_hash).await? == "tag" => Ok(sha1_hash),
+        "" | "commit" | "tree" | "blob" => {
+            let mut peeled = peel(sha1_hash).await?;
+            if kind == "tree" && object_type(peeled).await? == "commit" {
+                peeled = Commit::from_object_sha(hex::encode(peeled)).await?.tree_sha();
+            }
+            if !kind.is_empty() && object_type(peeled).await? != kind {
+                bail!("{}: expected {} type, but the object dereferences to {} type", spec, kind, object_type(peeled).await?);
+            }
+            Ok(peeled)
+        }
+        _ => bail!("unknown revision: '{}'", spec),
+    }
+}
+
+pub async fn resolve_commit(spec: &str) -> Result<Commit> {
+    let sha1_hash = peel(resolve(spec).await?).await?;
+
+    Commit::from_object_sha(hex::encode(sha1_hash))
+        .await
+        .with_context(|| format!("{} is not a commit", spec))
+}
+
+/// The type named in a loose object's header, e.g. `commit`.
+pub async fn object_type(sha1_hash: ObjectId) -> Result<String> {
+    let write_data = objects::read_object(sha1_hash).await?;
+    let (kind, _) = objects::parse_header(&write_data)?;
+
+    Ok(kind.to_string())
+}
+
+/// Resolves a tree-ish: a tree, or a commit standing for its tree.
+pub async fn resolve_tree(spec: &str) -> Result<ObjectId> {
+    let sha1_hash = peel(resolve(spec).await?).await?;
+
+    match object_type(sha1_hash).await?.as_str() {
+        "tree" => Ok(sha1_hash),
+        "commit" => Ok(Commit::from_object_sha(hex::encode(sha1_hash))
+            .await?
+            .tree_sha()),
+        other => bail!("{} is a {}, not a tree-ish", spec, other),
+    }
+}
+
+/// Walks commits newest first by committer date, like `git log`.
+#[derive(Default)]
+pub struct RevWalk {
+    queue: BinaryHeap<(i64, u64, ObjectId)>,
+    commits: HashMap<ObjectId, Commit>,
+    seen: HashSet<ObjectId>,
+    counter: u64,
+}
+
+impl RevWalk {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn push(&mut self, sha1_hash: ObjectId) -> Result<()> {
+        if !self.seen.insert(sha1_hash) {
+            return Ok(());
+        }
+
+        let commit = Commit::from_object_sha(hex::encode(sha1_hash)).await?;
+        let timestamp = commit.committer().timestamp.parse().unwrap_or(0);
+
+        // among equal dates, commits queued earlier come out first
+        self.counter += 1;
+        self.queue.push((timestamp, u64::MAX - self.counter, sha1_hash));
+        self.commits.insert(sha1_hash, commit);
+
+        Ok(())
+    }
+
+    pub async fn next(&mut self) -> Result<Option<Commit>> {
+        let (_, _, sha1_hash) = match self.queue.pop() {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+
+        let commit = self.commits.remove(&sha1_hash).unwrap();
+        for parent in commit.parents() {
+            self.push(*parent).await?;
+        }
+
+        Ok(Some(commit))
+    }
+}
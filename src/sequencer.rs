@@ -0,0 +1,1363 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::checkout::Checkout;
+use crate::config::{self, Config};
+use crate::diff::{self, BlobCache, DiffOptions, TreeDiffOptions};
+use crate::index::Index;
+use crate::merge::{self, MergeOptions, TreeMerge};
+use crate::objects::{Commit, HashAlgorithm, Object, ObjectId, User};
+use crate::refs;
+use crate::revision;
+use crate::utils;
+
+const REBASE_DIR: &str = ".git/rebase-merge";
+
+/// What a todo list line does with its commit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Pick,
+    Reword,
+    Edit,
+    Squash,
+    Fixup,
+    Exec,
+    Drop,
+    Revert,
+}
+
+impl Action {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "pick" | "p" => Some(Self::Pick),
+            "reword" | "r" => Some(Self::Reword),
+            "edit" | "e" => Some(Self::Edit),
+            "squash" | "s" => Some(Self::Squash),
+            "fixup" | "f" => Some(Self::Fixup),
+            "exec" | "x" => Some(Self::Exec),
+            "drop" | "d" => Some(Self::Drop),
+            "revert" => Some(Self::Revert),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Pick => "pick",
+            Self::Reword => "reword",
+            Self::Edit => "edit",
+            Self::Squash => "squash",
+            Self::Fixup => "fixup",
+            Self::Exec => "exec",
+            Self::Drop => "drop",
+            Self::Revert => "revert",
+        }
+    }
+
+    fn melds(&self) -> bool {
+        matches!(self, Self::Squash | Self::Fixup)
+    }
+}
+
+/// One line of a todo list: an action and the commit it applies to, or
+/// for `exec` the command to run.
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub action: Action,
+    pub commit: Option<ObjectId>,
+    /// The commit's subject, or the command to run.
+    pub text: String,
+}
+
+impl Step {
+    pub fn pick(commit: &Commit) -> Self {
+        Self {
+            action: Action::Pick,
+            commit: Some(commit.sha1_hash()),
+            text: commit.summary().to_string(),
+        }
+    }
+
+    fn line(&self, abbreviate: bool) -> String {
+        match self.commit {
+            Some(commit) => {
+                let name = hex::encode(commit);
+                let name = if abbreviate { &name[..7] } else { &name[..] };
+                format!("{} {} {}", self.action.name(), name, self.text)
+            }
+            None => format!("{} {}", self.action.name(), self.text),
+        }
+    }
+}
+
+fn short_sha(sha1_hash: &ObjectId) -> String {
+    hex::encode(sha1_hash)[..7].to_string()
+}
+
+pub fn format_todo(steps: &[Step], abbreviate: bool) -> String {
+    steps
+        .iter()
+        .map(|step| format!("{}\n", step.line(abbreviate)))
+        .collect()
+}
+
+/// Parses an edited todo list, skipping blank and comment lines.
+pub async fn parse_todo(text: &str) -> Result<Vec<Step>> {
+    let mut steps = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (word, rest) = match line.find(char::is_whitespace) {
+            Some(pos) => (&line[..pos], line[pos..].trim_start()),
+            None => (line, ""),
+        };
+        let action = match Action::from_name(word) {
+            Some(action) => action,
+            None => bail!("invalid line {}: {}", number + 1, line),
+        };
+
+        if action == Action::Exec {
+            if rest.is_empty() {
+                bail!("missing command in line {}: {}", number + 1, line);
+            }
+            steps.push(Step {
+                action,
+                commit: None,
+                text: rest.to_string(),
+            });
+            continue;
+        }
+
+        let (name, subject) = match rest.find(char::is_whitespace) {
+            Some(pos) => (&rest[..pos], rest[pos..].trim_start()),
+            None => (rest, ""),
+        };
+        let commit = match revision::resolve_commit(name).await {
+            Ok(commit) if !name.is_empty() => commit,
+            _ => bail!("invalid line {}: {}", number + 1, line),
+        };
+
+        if action.melds() && !steps.iter().any(|s: &Step| s.commit.is_some() && s.action != Action::Drop) {
+            bail!("cannot '{}' without a previous commit", action.name());
+        }
+
+        steps.push(Step {
+            action,
+            commit: Some(commit.sha1_hash()),
+            text: subject.to_string(),
+        });
+    }
+
+    Ok(steps)
+}
+
+// "fixup! fixup! subject" -> Some((Fixup, "subject"))
+fn fixup_target(subject: &str) -> Option<(Action, &str)> {
+    let action = if subject.starts_with("fixup! ") {
+        Action::Fixup
+    } else if subject.starts_with("squash! ") {
+        Action::Squash
+    } else {
+        return None;
+    };
+
+    let mut rest = subject;
+    while let Some(stripped) = rest
+        .strip_prefix("fixup! ")
+        .or_else(|| rest.strip_prefix("squash! "))
+    {
+        rest = stripped;
+    }
+
+    Some((action, rest))
+}
+
+// the message a squash contributes to the combined one; like git, the
+// subject of a `squash! ...` commit is commented out, as it only named
+// the commit to squash into
+fn squash_message(message: &str) -> String {
+    if fixup_target(message).is_none() && !message.starts_with("amend! ") {
+        return message.to_string();
+    }
+
+    let mut out = String::new();
+    let mut in_subject = true;
+    for line in message.lines() {
+        in_subject &= !line.trim().is_empty();
+        if in_subject {
+            out.push_str("# ");
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Identifies the change a commit makes regardless of where it applies,
+/// like `git patch-id`: whitespace and hunk line numbers do not count.
+pub async fn patch_id(commit: &Commit, cache: &mut BlobCache) -> Result<ObjectId> {
+    let parent_tree = match commit.parents().first() {
+        Some(parent) => Some(Commit::from_object_sha(hex::encode(parent)).await?.tree_sha()),
+        None => None,
+    };
+    let options = TreeDiffOptions {
+        recursive: true,
+        ..TreeDiffOptions::default()
+    };
+
+    let algorithm = HashAlgorithm::current();
+    let mut hasher = algorithm.hasher();
+    for change in diff::diff_trees(parent_tree, Some(commit.tree_sha()), &options).await? {
+        let old = cache.contents(change.old.as_ref()).await?;
+        let new = cache.contents(change.new.as_ref()).await?;
+
+        // binary patches say nothing about their contents
+        if diff::is_binary(&old) || diff::is_binary(&new) {
+            hasher.update(change.path());
+            for side in [&change.old, &change.new].iter() {
+                hasher.update(&side.as_ref().map_or(algorithm.null(), |entry| entry.sha1_hash));
+            }
+            continue;
+        }
+
+        let mut patch = Vec::new();
+        diff::write_patch(&mut patch, &change, &old, &new, &DiffOptions::default());
+        for line in patch.split(|b| *b == b'\n') {
+            if line.starts_with(b"index ") || line.starts_with(b"@@") {
+                continue;
+            }
+            let line: Vec<u8> = line.iter().filter(|b| !b.is_ascii_whitespace()).copied().collect();
+            hasher.update(&line);
+        }
+    }
+
+    Ok(hasher.finish())
+}
+
+/// Moves `fixup! ...` and `squash! ...` commits right after the commit they
+/// name, by subject or hash, turning them into fixups and squashes.
+pub fn autosquash(steps: Vec<Step>) -> Vec<Step> {
+    let mut steps = steps;
+    let mut followers: Vec<Vec<usize>> = vec![Vec::new(); steps.len()];
+    let mut moved = vec![false; steps.len()];
+
+    for i in 0..steps.len() {
+        let (action, target) = match steps[i].commit.and(fixup_target(&steps[i].text)) {
+            Some(found) => found,
+            None => continue,
+        };
+
+        let earlier = &steps[..i];
+        let found = earlier
+            .iter()
+            .position(|s| s.commit.is_some() && s.text == target)
+            .or_else(|| {
+                earlier.iter().position(|s| {
+                    target.len() >= 4 && s.commit.is_some_and(|c| hex::encode(c).starts_with(target))
+                })
+            })
+            .or_else(|| {
+                earlier
+                    .iter()
+                    .position(|s| s.commit.is_some() && s.text.starts_with(target))
+            });
+
+        if let Some(j) = found {
+            steps[i].action = action;
+            followers[j].push(i);
+            moved[i] = true;
+        }
+    }
+
+    fn place(i: usize, steps: &[Step], followers: &[Vec<usize>], out: &mut Vec<Step>) {
+        out.push(steps[i].clone());
+        for follower in &followers[i] {
+            place(*follower, steps, followers, out);
+        }
+    }
+
+    let mut out = Vec::new();
+    for (i, moved) in moved.iter().enumerate() {
+        if !moved {
+            place(i, &steps, &followers, &mut out);
+        }
+    }
+
+    out
+}
+
+/// Opens `path` in the user's editor; todo lists honour the sequence
+/// editor first.
+pub fn launch_editor(path: &Path, sequence: bool) -> Result<()> {
+    let mut editor = None;
+    if sequence {
+        editor = match std::env::var("GIT_SEQUENCE_EDITOR") {
+            Ok(editor) => Some(editor),
+            Err(_) => config::lookup("sequence.editor")?,
+        };
+    }
+    if editor.is_none() {
+        editor = match std::env::var("GIT_EDITOR") {
+            Ok(editor) => Some(editor),
+            Err(_) => config::lookup("core.editor")?,
+        };
+    }
+    let editor = editor
+        .or_else(|| std::env::var("VISUAL").ok())
+        .or_else(|| std::env::var("EDITOR").ok())
+        .unwrap_or_else(|| String::from("vi"));
+
+    if editor == ":" {
+        return Ok(());
+    }
+
+    let path = std::env::current_dir()?.join(path);
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(&editor)
+        .arg(path)
+        .status()?;
+    if !status.success() {
+        bail!("there was a problem with the editor '{}'", editor);
+    }
+
+    Ok(())
+}
+
+/// Cleans up an edited commit message the way `git commit` does: comment
+/// lines and trailing whitespace go, and runs of blank lines collapse.
+pub fn cleanup_message(message: &str) -> String {
+    let mut out = String::new();
+    let mut blank = false;
+
+    for line in message.lines() {
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            blank = !out.is_empty();
+            continue;
+        }
+        if blank {
+            out.push('\n');
+            blank = false;
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+
+    out
+}
+
+// lets the user edit `message`, refusing to go on without one
+fn edit_message(message: &str) -> Result<String> {
+    let path = PathBuf::from(".git/COMMIT_EDITMSG");
+    fs::write(&path, message)?;
+    launch_editor(&path, false)?;
+
+    let message = cleanup_message(&fs::read_to_string(&path)?);
+    if message.is_empty() {
+        bail!("Aborting commit due to empty commit message.");
+    }
+
+    Ok(message)
+}
+
+fn state_path(name: &str) -> PathBuf {
+    PathBuf::from(REBASE_DIR).join(name)
+}
+
+fn read_state(name: &str) -> Result<String> {
+    let path = state_path(name);
+    let contents = fs::read_to_string(&path).with_context(|| format!("could not read '{}'", path.display()))?;
+
+    Ok(contents.trim_end().to_string())
+}
+
+fn read_state_sha(name: &str) -> Result<Option<ObjectId>> {
+    if !state_path(name).exists() {
+        return Ok(None);
+    }
+
+    match ObjectId::from_hex(&read_state(name)?) {
+        Ok(sha1_hash) => Ok(Some(sha1_hash)),
+        Err(_) => bail!("invalid object name in '{}'", state_path(name).display()),
+    }
+}
+
+fn remove_state(name: &str) {
+    let _ = fs::remove_file(state_path(name));
+}
+
+async fn head_commit() -> Result<Commit> {
+    match refs::head_sha()? {
+        Some(head) => Commit::from_object_sha(hex::encode(head)).await,
+        None => bail!("HEAD does not point at a commit"),
+    }
+}
+
+// writes a commit and moves the detached HEAD onto it
+async fn commit_on_head(
+    tree_sha: ObjectId,
+    parents: Vec<ObjectId>,
+    message: String,
+    author: User,
+) -> Result<Commit> {
+    let (name, email) = config::identity("COMMITTER")?;
+    let commit = Commit::create(tree_sha, parents, message, author, User::now(name, email))?;
+    commit.write().await?;
+    commit.update_refs()?;
+
+    Ok(commit)
+}
+
+// git's print_commit_summary: what was committed where, and a shortstat
+async fn report(commit: &Commit) -> Result<()> {
+    let branch = refs::current_branch()?.unwrap_or_else(|| String::from("detached HEAD"));
+    let root = if commit.parents().is_empty() { " (root-commit)" } else { "" };
+    println!("[{}{} {}] {}", branch, root, short_sha(&commit.sha1_hash()), commit.summary());
+
+    let (author, committer) = (commit.author(), commit.committer());
+    if (&author.name, &author.email) != (&committer.name, &committer.email) {
+        println!(" Author: {} <{}>", author.name, author.email);
+    }
+    if author.timestamp != committer.timestamp {
+        println!(" Date: {}", utils::format_date(&author.timestamp, &author.tz_offset));
+    }
+
+    let parent_tree = match commit.parents().first() {
+        Some(parent) => Some(Commit::from_object_sha(hex::encode(parent)).await?.tree_sha()),
+        None => None,
+    };
+    let options = TreeDiffOptions {
+        recursive: true,
+        renames: diff::parse_score(""),
+        ..TreeDiffOptions::default()
+    };
+    let changes = diff::diff_trees(parent_tree, Some(commit.tree_sha()), &options).await?;
+    let files = diff::file_stats(&changes, &mut BlobCache::default(), &DiffOptions::default()).await?;
+
+    if let Some(shortstat) = diff::format_stat(&files, 80).lines().last() {
+        println!("{}", shortstat);
+    }
+    print!("{}", diff::format_summary(&changes));
+
+    Ok(())
+}
+
+// "1234567 (subject)", as conflicts name a picked commit
+fn commit_label(commit: &Commit) -> String {
+    format!("{} ({})", short_sha(&commit.sha1_hash()), commit.summary())
+}
+
+// merges the change `commit` makes relative to `parent` into `ours_tree`,
+// or undoes it for a revert, bringing the result into the index and
+// working tree
+async fn apply_change(
+    commit: &Commit,
+    parent: Option<ObjectId>,
+    revert: bool,
+    ours_tree: ObjectId,
+) -> Result<(TreeMerge, ObjectId)> {
+    let parent_tree = match parent {
+        Some(parent) => Some(Commit::from_object_sha(hex::encode(parent)).await?.tree_sha()),
+        None => None,
+    };
+    let label = commit_label(commit);
+    let parent_label = format!("parent of {}", label);
+
+    let (base, theirs, base_label, theirs_label) = if revert {
+        (Some(commit.tree_sha()), parent_tree, label, parent_label)
+    } else {
+        (parent_tree, Some(commit.tree_sha()), parent_label, label)
+    };
+    let options = MergeOptions {
+        ours: String::from("HEAD"),
+        theirs: theirs_label,
+        base: base_label,
+        style: merge::conflict_style()?,
+        depth: 0,
+    };
+
+    let mut cache = BlobCache::default();
+    let result = merge::merge_trees(
+        &diff::tree_listing(base).await?,
+        &diff::tree_listing(Some(ours_tree)).await?,
+        &diff::tree_listing(theirs).await?,
+        &options,
+        &mut cache,
+    )
+    .await?;
+    let tree_sha = merge::checkout_result(ours_tree, &result).await?;
+
+    Ok((result, tree_sha))
+}
+
+// replaces HEAD with a commit of `tree_sha`, keeping its parents and author
+async fn amend_head(tree_sha: ObjectId, message: String) -> Result<Commit> {
+    let head = head_commit().await?;
+    commit_on_head(tree_sha, head.parents().to_vec(), message, head.author().clone()).await
+}
+
+/// A rebase in progress: where it is going, and the steps left to run.
+/// Everything lives in .git/rebase-merge, so a rebase that stops can be
+/// picked up again by a later command.
+pub struct Rebase {
+    /// The branch being rebased, e.g. `refs/heads/topic`, or
+    /// `detached HEAD`.
+    pub head_name: String,
+    pub onto: ObjectId,
+    pub orig_head: ObjectId,
+    pub interactive: bool,
+    todo: Vec<Step>,
+    done: Vec<Step>,
+}
+
+impl Rebase {
+    pub fn in_progress() -> bool {
+        PathBuf::from(REBASE_DIR).is_dir()
+    }
+
+    pub fn new(head_name: String, onto: ObjectId, orig_head: ObjectId, interactive: bool) -> Self {
+        Self {
+            head_name,
+            onto,
+            orig_head,
+            interactive,
+            todo: Vec::new(),
+            done: Vec::new(),
+        }
+    }
+
+    pub async fn load() -> Result<Self> {
+        let sha = |name: &str| -> Result<ObjectId> {
+            read_state_sha(name)?.with_context(|| format!("could not read '{}'", state_path(name).display()))
+        };
+
+        Ok(Self {
+            head_name: read_state("head-name")?,
+            onto: sha("onto")?,
+            orig_head: sha("orig-head")?,
+            interactive: state_path("interactive").exists(),
+            todo: parse_todo(&read_state("git-rebase-todo")?).await?,
+            done: parse_todo(&read_state("done").unwrap_or_default()).await?,
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        fs::create_dir_all(REBASE_DIR)?;
+        fs::write(state_path("head-name"), format!("{}\n", self.head_name))?;
+        fs::write(state_path("onto"), format!("{}\n", hex::encode(self.onto)))?;
+        fs::write(state_path("orig-head"), format!("{}\n", hex::encode(self.orig_head)))?;
+        if self.interactive {
+            fs::write(state_path("interactive"), "")?;
+        }
+        fs::write(state_path("git-rebase-todo"), format_todo(&self.todo, false))?;
+        fs::write(state_path("done"), format_todo(&self.done, false))?;
+
+        Ok(())
+    }
+
+    /// Lets the user edit the todo list before anything runs; `upstream`
+    /// and `head` only go into its help text. Returns false when the list
+    /// was emptied.
+    pub async fn edit_todo(&mut self, upstream: ObjectId, head: ObjectId) -> Result<bool> {
+        fs::create_dir_all(REBASE_DIR)?;
+
+        let count = self.todo.len();
+        let mut text = format_todo(&self.todo, true);
+        text.push_str(&format!(
+            "\n# Rebase {}..{} onto {} ({} command{})\n",
+            short_sha(&upstream),
+            short_sha(&head),
+            short_sha(&self.onto),
+            count,
+            if count == 1 { "" } else { "s" }
+        ));
+        text.push_str(TODO_HELP);
+
+        let path = state_path("git-rebase-todo");
+        fs::write(&path, text)?;
+        launch_editor(&path, true)?;
+        self.todo = parse_todo(&fs::read_to_string(&path)?).await?;
+        if let Some(step) = self.todo.iter().find(|step| step.action == Action::Revert) {
+            bail!("invalid line: {}", step.line(true));
+        }
+
+        Ok(!self.todo.is_empty())
+    }
+
+    pub fn set_todo(&mut self, todo: Vec<Step>) {
+        self.todo = todo;
+    }
+
+    /// Detaches HEAD at the new base and runs the todo list.
+    pub async fn start(&mut self) -> Result<()> {
+        self.save()?;
+
+        let head = head_commit().await?;
+        let onto = Commit::from_object_sha(hex::encode(self.onto)).await?;
+        let mut checkout = Checkout::new()?;
+        checkout.switch_trees(Some(head.tree_sha()), onto.tree_sha(), false).await?;
+        checkout.save()?;
+        refs::set_head_detached(self.onto)?;
+        refs::update_ref("ORIG_HEAD", self.orig_head)?;
+
+        self.run().await
+    }
+
+    // runs steps until the list is done or one of them stops
+    async fn run(&mut self) -> Result<()> {
+        while !self.todo.is_empty() {
+            let step = self.todo.remove(0);
+            self.done.push(step.clone());
+            self.save()?;
+
+            match (step.action, step.commit) {
+                (Action::Drop, _) => {}
+                (Action::Exec, _) => {
+                    println!("Executing: {}", step.text);
+                    let status = Command::new("sh").arg("-c").arg(&step.text).status()?;
+                    if !status.success() {
+                        bail!(
+                            "execution failed: {}\nYou can fix the problem, and then run\n\n  tgit rebase --continue\n",
+                            step.text
+                        );
+                    }
+                }
+                (_, Some(commit)) => {
+                    let commit = Commit::from_object_sha(hex::encode(commit)).await?;
+                    self.pick(step.action, &commit).await?;
+
+                    if step.action == Action::Edit {
+                        let head = refs::head_sha()?.unwrap();
+                        fs::write(state_path("amend"), format!("{}\n", hex::encode(head)))?;
+                        println!(
+                            "Stopped at {}...  {}\nYou can amend the commit now: stage your changes, then run\n\n  tgit rebase --continue\n",
+                            short_sha(&commit.sha1_hash()),
+                            commit.summary()
+                        );
+                        return Ok(());
+                    }
+                }
+                (_, None) => bail!("missing commit for '{}'", step.action.name()),
+            }
+        }
+
+        self.finish()
+    }
+
+    // applies `commit`'s changes on top of HEAD, stopping on conflicts
+    async fn pick(&mut self, action: Action, commit: &Commit) -> Result<()> {
+        let head = head_commit().await?;
+        let parent = commit.parents().first().copied();
+
+        // a commit whose parent is already HEAD can be reused as it is
+        if !action.melds() && parent == Some(head.sha1_hash()) {
+            let mut checkout = Checkout::new()?;
+            checkout.merge_trees(Some(head.tree_sha()), commit.tree_sha()).await?;
+            checkout.save()?;
+            refs::set_head_detached(commit.sha1_hash())?;
+
+            if action == Action::Reword {
+                let message = edit_message(commit.message())?;
+                amend_head(commit.tree_sha(), message).await?;
+            }
+            return Ok(());
+        }
+
+        let (result, tree_sha) = apply_change(commit, parent, false, head.tree_sha()).await?;
+
+        if !result.conflicts.is_empty() {
+            // a rebase only reports on the merges that need a hand
+            for (_, line) in &result.messages {
+                println!("{}", line);
+            }
+
+            fs::write(state_path("stopped-sha"), format!("{}\n", hex::encode(commit.sha1_hash())))?;
+            bail!(
+                "could not apply {}... {}\n\
+                 hint: Resolve all conflicts manually, mark them as resolved with\n\
+                 hint: \"tgit add/rm <conflicted_files>\", then run \"tgit rebase --continue\".\n\
+                 hint: You can instead skip this commit: run \"tgit rebase --skip\".\n\
+                 hint: To abort and get back to the state before \"tgit rebase\", run \"tgit rebase --abort\".",
+                short_sha(&commit.sha1_hash()),
+                commit.summary()
+            );
+        }
+
+        let base_tree = match parent {
+            Some(parent) => Some(Commit::from_object_sha(hex::encode(parent)).await?.tree_sha()),
+            None => None,
+        };
+        self.record(action, commit, tree_sha, base_tree, action == Action::Reword)
+            .await
+    }
+
+    // commits the picked `tree_sha` the way `action` asks, letting the user
+    // edit the message when `edit` is set
+    async fn record(
+        &mut self,
+        action: Action,
+        commit: &Commit,
+        tree_sha: ObjectId,
+        base_tree: Option<ObjectId>,
+        edit: bool,
+    ) -> Result<()> {
+        if action.melds() {
+            return self.meld(action, commit, tree_sha).await;
+        }
+
+        // commits that were empty to begin with are kept, ones that became
+        // empty are dropped
+        let head = head_commit().await?;
+        let was_empty = base_tree == Some(commit.tree_sha());
+        if tree_sha == head.tree_sha() && !was_empty {
+            return Ok(());
+        }
+
+        let message = if edit {
+            edit_message(commit.message())?
+        } else {
+            commit.message().to_string()
+        };
+        commit_on_head(tree_sha, vec![head.sha1_hash()], message, commit.author().clone()).await?;
+
+        Ok(())
+    }
+
+    // folds `commit` into HEAD, gathering the messages of a squash chain
+    // and asking for the final one once the chain ends
+    async fn meld(&mut self, action: Action, commit: &Commit, tree_sha: ObjectId) -> Result<()> {
+        let head = head_commit().await?;
+
+        let (count, body) = match read_state("message-squash") {
+            Ok(contents) => {
+                let count = contents
+                    .lines()
+                    .next()
+                    .and_then(|line| line.split_whitespace().nth(6))
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or(1);
+                let body = contents.split_once('\n').map(|(_, body)| body).unwrap_or("");
+                (count, format!("{}\n", body))
+            }
+            Err(_) => (
+                1,
+                format!("# This is the 1st commit message:\n\n{}", head.message()),
+            ),
+        };
+        let count = count + 1;
+
+        let mut body = body;
+        if action == Action::Squash {
+            body.push_str(&format!("\n# This is the commit message #{}:\n\n{}", count, squash_message(commit.message())));
+        } else {
+            body.push_str(&format!("\n# The commit message #{} will be skipped:\n\n", count));
+            for line in commit.message().lines() {
+                body.push_str(&if line.is_empty() {
+                    String::from("#\n")
+                } else {
+                    format!("# {}\n", line)
+                });
+            }
+        }
+        let combined = format!("# This is a combination of {} commits.\n{}", count, body);
+
+        let squashing = combined.contains("\n# This is the commit message #");
+        let chain_ends = !self.todo.first().is_some_and(|step| step.action.melds());
+
+        let message = if !squashing {
+            head.message().to_string()
+        } else if chain_ends {
+            edit_message(&combined)?
+        } else {
+            cleanup_message(&combined)
+        };
+
+        if chain_ends {
+            remove_state("message-squash");
+        } else {
+            fs::write(state_path("message-squash"), &combined)?;
+        }
+
+        amend_head(tree_sha, message).await?;
+
+        Ok(())
+    }
+
+    /// Commits the resolution of the step that stopped, or what was staged
+    /// while stopped at an `edit`, and carries on.
+    pub async fn continue_rebase(&mut self) -> Result<()> {
+        let index = Index::load()?;
+        if !index.conflicts().is_empty() {
+            bail!("Committing is not possible because you have unmerged files.");
+        }
+        let tree_sha = merge::write_tree(&diff::index_listing(&index)).await?;
+
+        if let Some(stopped) = read_state_sha("stopped-sha")? {
+            let commit = Commit::from_object_sha(hex::encode(stopped)).await?;
+            let action = self.done.last().map(|step| step.action).unwrap_or(Action::Pick);
+            let base_tree = match commit.parents().first() {
+                Some(parent) => Some(Commit::from_object_sha(hex::encode(parent)).await?.tree_sha()),
+                None => None,
+            };
+
+            let head = refs::head_sha()?;
+            // a resolved conflict gets a look at the message, as with `git commit`
+            self.record(action, &commit, tree_sha, base_tree, true).await?;
+            remove_state("stopped-sha");
+
+            if refs::head_sha()? != head {
+                report(&head_commit().await?).await?;
+            }
+        } else if let Some(amend) = read_state_sha("amend")? {
+            let head = head_commit().await?;
+            // staged changes go into the commit, as `git commit --amend`
+            if head.sha1_hash() == amend && head.tree_sha() != tree_sha {
+                let message = edit_message(head.message())?;
+                report(&amend_head(tree_sha, message).await?).await?;
+            }
+            remove_state("amend");
+        }
+
+        self.run().await
+    }
+
+    /// Drops the step that stopped, along with its changes, and carries on.
+    pub async fn skip(&mut self) -> Result<()> {
+        let head = head_commit().await?;
+        let mut checkout = Checkout::new()?;
+        checkout.checkout_tree(head.tree_sha(), true).await?;
+        checkout.save()?;
+
+        remove_state("stopped-sha");
+        remove_state("amend");
+
+        self.run().await
+    }
+
+    /// Goes back to the branch as it was before the rebase started.
+    pub async fn abort(&self) -> Result<()> {
+        let orig_head = Commit::from_object_sha(hex::encode(self.orig_head)).await?;
+        let mut checkout = Checkout::new()?;
+        checkout.checkout_tree(orig_head.tree_sha(), true).await?;
+        checkout.save()?;
+
+        if self.head_name.starts_with("refs/") {
+            refs::update_ref(&self.head_name, self.orig_head)?;
+            refs::set_head_branch(&self.head_name)?;
+        } else {
+            refs::set_head_detached(self.orig_head)?;
+        }
+
+        fs::remove_dir_all(REBASE_DIR)?;
+
+        Ok(())
+    }
+
+    // points the rebased branch at the result and puts HEAD back on it
+    fn finish(&self) -> Result<()> {
+        let head = refs::head_sha()?.context("HEAD does not point at a commit")?;
+        if self.head_name.starts_with("refs/") {
+            refs::update_ref(&self.head_name, head)?;
+            refs::set_head_branch(&self.head_name)?;
+        }
+
+        fs::remove_dir_all(REBASE_DIR)?;
+        println!("Successfully rebased and updated {}.", self.head_name);
+
+        Ok(())
+    }
+}
+
+const SEQUENCER_DIR: &str = ".git/sequencer";
+const MERGE_MSG: &str = ".git/MERGE_MSG";
+
+/// How `cherry-pick` and `revert` treat each commit.
+#[derive(Debug, Clone, Default)]
+pub struct PickOptions {
+    /// Appends a "(cherry picked from commit ...)" line to the message.
+    pub record_origin: bool,
+    /// Which parent of a merge to take the change against, from 1.
+    pub mainline: Option<usize>,
+    /// Leaves the changes in the index and working tree uncommitted.
+    pub no_commit: bool,
+}
+
+// whether the last paragraph of `message` is made of trailers, so that
+// another one can go right below it
+fn has_trailers(message: &str) -> bool {
+    let paragraphs: Vec<&str> = message.trim_end().split("\n\n").collect();
+    if paragraphs.len() < 2 {
+        return false;
+    }
+
+    paragraphs[paragraphs.len() - 1].lines().all(|line| {
+        line.starts_with("(cherry picked from commit ")
+            || match line.find(": ") {
+                Some(pos) => {
+                    pos > 0
+                        && line[..pos]
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+                }
+                None => false,
+            }
+    })
+}
+
+fn pick_head_path(action: Action) -> &'static str {
+    if action == Action::Revert {
+        ".git/REVERT_HEAD"
+    } else {
+        ".git/CHERRY_PICK_HEAD"
+    }
+}
+
+fn sequencer_path(name: &str) -> PathBuf {
+    PathBuf::from(SEQUENCER_DIR).join(name)
+}
+
+/// A run of `cherry-pick` or `revert` over several commits. Its state is
+/// kept in .git/sequencer while it runs, so a stop on conflicts can be
+/// continued, skipped past or aborted.
+pub struct Sequence {
+    options: PickOptions,
+    /// Where HEAD was before the first commit, for `--abort`.
+    head: ObjectId,
+    /// The steps left to run, the current one first.
+    todo: Vec<Step>,
+    /// Whether to keep .git/sequencer; like git, a single commit only
+    /// leaves CHERRY_PICK_HEAD or REVERT_HEAD behind when it stops.
+    persist: bool,
+}
+
+impl Sequence {
+    pub fn in_progress() -> bool {
+        PathBuf::from(SEQUENCER_DIR).is_dir()
+            || [Action::Pick, Action::Revert]
+                .iter()
+                .any(|action| PathBuf::from(pick_head_path(*action)).exists())
+    }
+
+    pub fn new(options: PickOptions, head: ObjectId, todo: Vec<Step>) -> Self {
+        let persist = todo.len() > 1;
+        Self {
+            options,
+            head,
+            todo,
+            persist,
+        }
+    }
+
+    pub async fn load() -> Result<Self> {
+        if !PathBuf::from(SEQUENCER_DIR).is_dir() {
+            return Self::load_single().await;
+        }
+
+        let opts = Config::load_from(sequencer_path("opts"))?;
+        let mainline = match opts.get("options.mainline") {
+            Some(mainline) => Some(mainline.parse().context("invalid mainline in the sequencer options")?),
+            None => None,
+        };
+        let head = fs::read_to_string(sequencer_path("head")).context("could not read the sequencer state")?;
+
+        Ok(Self {
+            options: PickOptions {
+                record_origin: opts.get("options.record-origin").as_deref() == Some("true"),
+                mainline,
+                no_commit: opts.get("options.no-commit").as_deref() == Some("true"),
+            },
+            head: ObjectId::from_hex(head.trim())?,
+            todo: parse_todo(&fs::read_to_string(sequencer_path("todo"))?).await?,
+            persist: true,
+        })
+    }
+
+    // a single commit that stopped, known only from its pick head
+    async fn load_single() -> Result<Self> {
+        for action in [Action::Pick, Action::Revert].iter() {
+            if let Ok(contents) = fs::read_to_string(pick_head_path(*action)) {
+                let commit = revision::resolve_commit(contents.trim()).await?;
+                let head = refs::head_sha()?.context("HEAD does not point at a commit")?;
+
+                return Ok(Self {
+                    options: PickOptions::default(),
+                    head,
+                    todo: vec![Step {
+                        action: *action,
+                        ..Step::pick(&commit)
+                    }],
+                    persist: false,
+                });
+            }
+        }
+
+        bail!("no cherry-pick or revert in progress");
+    }
+
+    fn save(&self) -> Result<()> {
+        fs::create_dir_all(SEQUENCER_DIR)?;
+        fs::write(sequencer_path("head"), format!("{}\n", hex::encode(self.head)))?;
+        fs::write(sequencer_path("todo"), format_todo(&self.todo, false))?;
+
+        let defaults = PickOptions::default();
+        if (self.options.record_origin, self.options.mainline, self.options.no_commit)
+            == (defaults.record_origin, defaults.mainline, defaults.no_commit)
+        {
+            return Ok(());
+        }
+
+        let mut opts = Config::load_from(sequencer_path("opts"))?;
+        if self.options.record_origin {
+            opts.set("options.record-origin", "true")?;
+        }
+        if let Some(mainline) = self.options.mainline {
+            opts.set("options.mainline", &mainline.to_string())?;
+        }
+        if self.options.no_commit {
+            opts.set("options.no-commit", "true")?;
+        }
+        opts.save()?;
+
+        Ok(())
+    }
+
+    fn verb(&self) -> &'static str {
+        match self.todo.first() {
+            Some(step) if step.action == Action::Revert => "revert",
+            _ => "cherry-pick",
+        }
+    }
+
+    // forgets the step that stopped, and anything it left behind
+    fn clear_stop(&mut self) {
+        for action in [Action::Pick, Action::Revert].iter() {
+            let _ = fs::remove_file(pick_head_path(*action));
+        }
+        let _ = fs::remove_file(MERGE_MSG);
+        if !self.todo.is_empty() {
+            self.todo.remove(0);
+        }
+    }
+
+    /// Applies each commit in turn, committing as it goes unless asked
+    /// not to.
+    pub async fn run(&mut self) -> Result<()> {
+        while let Some(step) = self.todo.first().cloned() {
+            if self.persist {
+                self.save()?;
+            }
+
+            let commit = match step.commit {
+                Some(commit) => Commit::from_object_sha(hex::encode(commit)).await?,
+                None => bail!("missing commit for '{}'", step.action.name()),
+            };
+            self.pick(step.action, &commit).await?;
+            self.todo.remove(0);
+        }
+
+        let _ = fs::remove_dir_all(SEQUENCER_DIR);
+
+        Ok(())
+    }
+
+    // the parent a commit's change is taken against
+    fn parent(&self, commit: &Commit) -> Result<Option<ObjectId>> {
+        let name = hex::encode(commit.sha1_hash());
+        match (commit.parents(), self.options.mainline) {
+            ([_, _, ..], None) => bail!("commit {} is a merge but no -m option was given.", name),
+            ([_, _, ..], Some(mainline)) => match commit.parents().get(mainline.wrapping_sub(1)) {
+                Some(parent) => Ok(Some(*parent)),
+                None => bail!("commit {} does not have parent {}", name, mainline),
+            },
+            // the first parent is the only mainline a plain commit has
+            (parents, Some(1)) | (parents, None) => Ok(parents.first().copied()),
+            (_, Some(mainline)) => bail!("commit {} does not have parent {}", name, mainline),
+        }
+    }
+
+    fn message(&self, action: Action, commit: &Commit, parent: Option<ObjectId>) -> String {
+        let name = hex::encode(commit.sha1_hash());
+
+        if action == Action::Revert {
+            let mut message = format!("Revert \"{}\"\n\nThis reverts commit {}", commit.summary(), name);
+            if let (true, Some(parent)) = (commit.parents().len() > 1, parent) {
+                message.push_str(&format!(", reversing\nchanges made to {}", hex::encode(parent)));
+            }
+            message.push_str(".\n");
+            return message;
+        }
+
+        let mut message = commit.message().to_string();
+        if self.options.record_origin {
+            if !message.ends_with('\n') {
+                message.push('\n');
+            }
+            if !has_trailers(&message) {
+                message.push('\n');
+            }
+            message.push_str(&format!("(cherry picked from commit {})\n", name));
+        }
+
+        message
+    }
+
+    async fn pick(&mut self, action: Action, commit: &Commit) -> Result<()> {
+        let revert = action == Action::Revert;
+        let parent = self.parent(commit)?;
+
+        // without committing, changes pile up on top of the index
+        let ours_tree = if self.options.no_commit {
+            merge::write_tree(&diff::index_listing(&Index::load()?)).await?
+        } else {
+            head_commit().await?.tree_sha()
+        };
+
+        let (result, tree_sha) = apply_change(commit, parent, revert, ours_tree).await?;
+        for (_, line) in &result.messages {
+            println!("{}", line);
+        }
+
+        let message = self.message(action, commit, parent);
+        if !result.conflicts.is_empty() {
+            let mut merge_msg = format!("{}\n# Conflicts:\n", message);
+            for path in result.conflicts.keys() {
+                merge_msg.push_str(&format!("#\t{}\n", String::from_utf8_lossy(path)));
+            }
+            fs::write(MERGE_MSG, merge_msg)?;
+            if !self.options.no_commit {
+                fs::write(pick_head_path(action), format!("{}\n", hex::encode(commit.sha1_hash())))?;
+            }
+
+            let verb = self.verb();
+            if self.options.no_commit {
+                bail!(
+                    "could not {} {}... {}\n\
+                     hint: after resolving the conflicts, mark the corrected paths\n\
+                     hint: with 'tgit add <paths>' or 'tgit rm <paths>'",
+                    if revert { "revert" } else { "apply" },
+                    short_sha(&commit.sha1_hash()),
+                    commit.summary()
+                );
+            }
+            bail!(
+                "could not {} {}... {}\n\
+                 hint: After resolving the conflicts, mark them with\n\
+                 hint: \"tgit add/rm <pathspec>\", then run\n\
+                 hint: \"tgit {} --continue\".\n\
+                 hint: You can instead skip this commit with \"tgit {} --skip\".\n\
+                 hint: To abort and get back to the state before \"tgit {}\",\n\
+                 hint: run \"tgit {} --abort\".",
+                if revert { "revert" } else { "apply" },
+                short_sha(&commit.sha1_hash()),
+                commit.summary(),
+                verb,
+                verb,
+                verb,
+                verb
+            );
+        }
+
+        if self.options.no_commit {
+            return Ok(());
+        }
+
+        self.commit(action, commit, tree_sha, message).await
+    }
+
+    // commits a picked or reverted `tree_sha` on top of HEAD
+    async fn commit(&mut self, action: Action, commit: &Commit, tree_sha: ObjectId, message: String) -> Result<()> {
+        let head = head_commit().await?;
+        if tree_sha == head.tree_sha() {
+            fs::write(MERGE_MSG, &message)?;
+            if action == Action::Revert {
+                bail!("nothing to commit, working tree clean");
+            }
+
+            fs::write(pick_head_path(action), format!("{}\n", hex::encode(commit.sha1_hash())))?;
+            bail!(
+                "The previous {} is now empty, possibly due to conflict resolution.\n\
+                 Please use 'tgit {} --skip' to move on.",
+                self.verb(),
+                self.verb()
+            );
+        }
+
+        // a revert is new work; a cherry-pick keeps its author
+        let author = if action == Action::Revert {
+            let (name, email) = config::identity("AUTHOR")?;
+            User::now(name, email)
+        } else {
+            commit.author().clone()
+        };
+
+        let new_commit = commit_on_head(tree_sha, vec![head.sha1_hash()], message, author).await?;
+        report(&new_commit).await
+    }
+
+    /// Commits the resolution of the commit that stopped and goes on with
+    /// the rest.
+    pub async fn continue_sequence(&mut self) -> Result<()> {
+        let index = Index::load()?;
+        if !index.conflicts().is_empty() {
+            bail!("Committing is not possible because you have unmerged files.");
+        }
+
+        let step = self.todo.first().cloned();
+        if let Some(Step {
+            action,
+            commit: Some(commit),
+            ..
+        }) = step
+        {
+            if PathBuf::from(pick_head_path(action)).exists() {
+                let commit = Commit::from_object_sha(hex::encode(commit)).await?;
+                let tree_sha = merge::write_tree(&diff::index_listing(&index)).await?;
+                let merge_msg = match fs::read_to_string(MERGE_MSG) {
+                    Ok(merge_msg) => merge_msg,
+                    Err(_) => self.message(action, &commit, self.parent(&commit)?),
+                };
+
+                // unlike a rebase, the message is taken as it stands
+                self.commit(action, &commit, tree_sha, cleanup_message(&merge_msg))
+                    .await?;
+            }
+        }
+
+        self.clear_stop();
+        self.run().await
+    }
+
+    /// Drops the commit that stopped, along with its changes, and goes on
+    /// with the rest.
+    pub async fn skip(&mut self) -> Result<()> {
+        let head = head_commit().await?;
+        let mut checkout = Checkout::new()?;
+        checkout.checkout_tree(head.tree_sha(), true).await?;
+        checkout.save()?;
+        refs::update_ref("ORIG_HEAD", head.sha1_hash())?;
+
+        self.clear_stop();
+        self.run().await
+    }
+
+    /// Goes back to where HEAD was before the first commit was applied.
+    pub async fn abort(&mut self) -> Result<()> {
+        let head = Commit::from_object_sha(hex::encode(self.head)).await?;
+        let mut checkout = Checkout::new()?;
+        checkout.checkout_tree(head.tree_sha(), true).await?;
+        checkout.save()?;
+
+        if let Some(current) = refs::head_sha()? {
+            refs::update_ref("ORIG_HEAD", current)?;
+        }
+        match refs::read_head()? {
+            refs::Head::Branch(refname) => refs::update_ref(&refname, self.head)?,
+            refs::Head::Detached(_) => refs::set_head_detached(self.head)?,
+        }
+
+        self.clear_stop();
+        let _ = fs::remove_dir_all(SEQUENCER_DIR);
+
+        Ok(())
+    }
+}
+
+const TODO_HELP: &str = "#
+# Commands:
+# p, pick <commit> = use commit
+# r, reword <commit> = use commit, but edit the commit message
+# e, edit <commit> = use commit, but stop for amending
+# s, squash <commit> = use commit, but meld into previous commit
+# f, fixup <commit> = like \"squash\" but keep only the previous
+#                    commit's log message
+# x, exec <command> = run command (the rest of the line) using shell
+# d, drop <commit> = remove commit
+#
+# These lines can be re-ordered; they are executed from top to bottom.
+#
+# If you remove a line here THAT COMMIT WILL BE LOST.
+#
+# However, if you remove everything, the rebase will be aborted.
+#
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(sha: u8, text: &str) -> Step {
+        Step {
+            action: Action::Pick,
+            commit: Some(ObjectId::from([sha; 20])),
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_autosquash() {
+        let steps = vec![
+            step(1, "add a"),
+            step(2, "add b"),
+            step(3, "fixup! add a"),
+            step(4, "squash! fixup! add a"),
+            step(5, "fixup! 0202"),
+        ];
+
+        let squashed: Vec<(Action, u8)> = autosquash(steps)
+            .iter()
+            .map(|s| (s.action, s.commit.unwrap()[0]))
+            .collect();
+
+        assert_eq!(
+            squashed,
+            vec![
+                (Action::Pick, 1),
+                (Action::Fixup, 3),
+                (Action::Squash, 4),
+                (Action::Pick, 2),
+                (Action::Fixup, 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_squash_message() {
+        assert_eq!(squash_message("squash! topic2\n"), "# squash! topic2\n");
+        assert_eq!(cleanup_message(&squash_message("squash! topic2\n")), "");
+        assert_eq!(
+            squash_message("squash! topic2\nmore subject\n\nbody\n"),
+            "# squash! topic2\n# more subject\n\nbody\n"
+        );
+        assert_eq!(squash_message("topic3\n\nbody\n"), "topic3\n\nbody\n");
+    }
+
+    #[test]
+    fn test_has_trailers() {
+        assert!(!has_trailers("subject\n"));
+        assert!(has_trailers("subject\n\nbody\n\nSigned-off-by: A <a@x>\n"));
+        assert!(has_trailers("subject\n\n(cherry picked from commit 1234)\n"));
+        assert!(!has_trailers("subject\n\nnot a trailer\n"));
+    }
+
+    #[test]
+    fn test_cleanup_message() {
+        assert_eq!(
+            cleanup_message("\n# comment\nsubject  \n\n\n\nbody\n# more\n\n"),
+            "subject\n\nbody\n"
+        );
+        assert_eq!(cleanup_message("# only comments\n"), "");
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::Result;
+use std::collections::BTreeSet;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::objects::ObjectId;
+
+fn shallow_path() -> PathBuf {
+    PathBuf::from(".git/shallow")
+}
+
+// what identifies one version of the shallow file, so that it is only read
+// again once it changes (or the current directory moves to another repository)
+type Stamp = Option<(u64, i64, i64, u64)>;
+
+fn stamp() -> Stamp {
+    fs::metadata(shallow_path())
+        .ok()
+        .map(|metadata| (metadata.ino(), metadata.mtime(), metadata.mtime_nsec(), metadata.len()))
+}
+
+static GRAFTS: Mutex<Option<(Stamp, BTreeSet<ObjectId>)>> = Mutex::new(None);
+
+/// The commits the history of a shallow clone is cut off at, whose parents
+/// it does not have.
+pub fn load() -> Result<BTreeSet<ObjectId>> {
+    let contents = match fs::read_to_string(shallow_path()) {
+        Ok(contents) => contents,
+        Err(_) => return Ok(BTreeSet::new()),
+    };
+
+    contents.lines().filter(|line| !line.is_empty()).map(ObjectId::from_hex).collect()
+}
+
+pub fn is_shallow() -> bool {
+    shallow_path().is_file()
+}
+
+/// Whether `sha1_hash` is one of the commits the history is cut off at,
+/// which are then taken to have no parents.
+pub fn is_grafted(sha1_hash: ObjectId) -> bool {
+    let current = stamp();
+    let mut grafts = GRAFTS.lock().unwrap();
+
+    match &*grafts {
+        Some((stamp, commits)) if *stamp == current => commits.contains(&sha1_hash),
+        _ => {
+            // an unreadable shallow file cuts nothing off
+            let commits = if current.is_some() { load().unwrap_or_default() } else { BTreeSet::new() };
+            let grafted = commits.contains(&sha1_hash);
+            *grafts = Some((current, commits));
+
+            grafted
+        }
+    }
+}
+
+/// Records the new boundary the remote reported: `shallow` commits are cut
+/// off, `unshallow` ones have their parents now. Once nothing is cut off the
+/// repository is complete and the file goes.
+pub fn update(shallow: &[ObjectId], unshallow: &[ObjectId]) -> Result<()> {
+    let mut commits = load()?;
+    commits.extend(shallow.iter().copied());
+    for sha1_hash in unshallow {
+        commits.remove(sha1_hash);
+    }
+
+    let path = shallow_path();
+    if commits.is_empty() {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        return Ok(());
+    }
+
+    let contents: String = commits.iter().map(|sha1_hash| format!("{}\n", hex::encode(sha1_hash))).collect();
+    let tmp = path.with_extension("lock");
+    fs::write(&tmp, contents)?;
+    fs::rename(tmp, path)?;
+
+    Ok(())
+}
@@ -0,0 +1,193 @@
+use anyhow::{bail, Result};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::config::Config;
+
+fn patterns_path() -> PathBuf {
+    PathBuf::from(".git/info/sparse-checkout")
+}
+
+// cone patterns escape the characters gitignore patterns treat specially
+fn escape(dir: &str) -> String {
+    let mut escaped = String::new();
+    for c in dir.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+fn unescape(pattern: &str) -> String {
+    let mut dir = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => dir.extend(chars.next()),
+            c => dir.push(c),
+        }
+    }
+
+    dir
+}
+
+/// Where a cone-mode sparse checkout is limited to: everything below its
+/// directories, plus the files directly inside the top level and inside
+/// each directory leading to one of them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Cone {
+    dirs: BTreeSet<String>,
+}
+
+impl Cone {
+    /// The cone the working tree is limited to, or `None` when it is not
+    /// sparse.
+    pub fn load() -> Result<Option<Self>> {
+        if Config::load()?.get("core.sparseCheckout").as_deref() != Some("true") {
+            return Ok(None);
+        }
+
+        match fs::read_to_string(patterns_path()) {
+            Ok(patterns) => Ok(Some(Self::parse(&patterns)?)),
+            Err(_) => Ok(Some(Self::default())),
+        }
+    }
+
+    /// Reads `.git/info/sparse-checkout` as `sparse-checkout set` writes it.
+    pub fn parse(patterns: &str) -> Result<Self> {
+        let mut included = BTreeSet::new();
+        let mut parents = BTreeSet::new();
+
+        for line in patterns.lines().map(str::trim_end).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            if line == "/*" || line == "!/*/" {
+                continue;
+            }
+
+            let (dir, parent) = match line.strip_prefix("!/").and_then(|rest| rest.strip_suffix("/*/")) {
+                Some(dir) => (dir, true),
+                None => match line.strip_prefix('/').and_then(|rest| rest.strip_suffix('/')) {
+                    Some(dir) if !dir.is_empty() => (dir, false),
+                    _ => bail!("sparse-checkout pattern '{}' is not in cone mode", line),
+                },
+            };
+            if parent {
+                parents.insert(unescape(dir));
+            } else {
+                included.insert(unescape(dir));
+            }
+        }
+
+        // a directory excluded below itself is only there to lead to others
+        Ok(Self::new(included.difference(&parents).cloned()))
+    }
+
+    /// A cone of `dirs`, given the way `sparse-checkout set` takes them.
+    pub fn new<I: IntoIterator<Item = String>>(dirs: I) -> Self {
+        let mut cone = Self::default();
+        cone.add(dirs);
+
+        cone
+    }
+
+    pub fn add<I: IntoIterator<Item = String>>(&mut self, dirs: I) {
+        for dir in dirs {
+            let dir = dir.trim_start_matches("./").trim_matches('/');
+            if !dir.is_empty() {
+                self.dirs.insert(dir.to_string());
+            }
+        }
+
+        // below a directory already in full, nothing needs naming
+        let dirs: Vec<String> = self.dirs.iter().cloned().collect();
+        self.dirs
+            .retain(|dir| !dirs.iter().any(|other| dir.starts_with(&format!("{}/", other))));
+    }
+
+    /// The directories included in full, in order.
+    pub fn dirs(&self) -> impl Iterator<Item = &String> {
+        self.dirs.iter()
+    }
+
+    // the directories leading to those included in full
+    fn parents(&self) -> BTreeSet<String> {
+        let mut parents = BTreeSet::new();
+        for dir in &self.dirs {
+            let mut parent = dir.as_str();
+            while let Some((up, _)) = parent.rsplit_once('/') {
+                parents.insert(up.to_string());
+                parent = up;
+            }
+        }
+
+        parents
+    }
+
+    /// The patterns git reads this cone back from.
+    pub fn patterns(&self) -> String {
+        let mut patterns = String::from("/*\n!/*/\n");
+        let parents = self.parents();
+
+        let all: BTreeSet<&String> = self.dirs.iter().chain(parents.iter()).collect();
+        for dir in all {
+            patterns.push_str(&format!("/{}/\n", escape(dir)));
+            if parents.contains(dir) {
+                patterns.push_str(&format!("!/{}/*/\n", escape(dir)));
+            }
+        }
+
+        patterns
+    }
+
+    /// Writes the patterns and turns the sparse checkout on.
+    pub fn save(&self) -> Result<()> {
+        let path = patterns_path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, self.patterns())?;
+
+        let mut config = Config::load()?;
+        config.set("core.sparseCheckout", "true")?;
+        config.set("core.sparseCheckoutCone", "true")?;
+        config.save()
+    }
+
+    /// Whether the file at `path` belongs in the working tree.
+    pub fn includes(&self, path: impl AsRef<[u8]>) -> bool {
+        let path = path.as_ref();
+        let parent = match path.iter().rposition(|b| *b == b'/') {
+            Some(pos) => &path[..pos],
+            None => return true,
+        };
+        let under = |path: &[u8], dir: &[u8]| path.starts_with(dir) && path.get(dir.len()) == Some(&b'/');
+
+        self.dirs.iter().any(|dir| under(path, dir.as_bytes()) || under(dir.as_bytes(), parent))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cone_patterns() -> Result<()> {
+        let cone = Cone::new(vec![String::from("a/b/c/"), String::from("./d"), String::from("d/e")]);
+        assert_eq!(cone.patterns(), "/*\n!/*/\n/a/\n!/a/*/\n/a/b/\n!/a/b/*/\n/a/b/c/\n/d/\n");
+        assert_eq!(Cone::parse(&cone.patterns())?, cone);
+
+        assert!(cone.includes("top"));
+        assert!(cone.includes("a/x"));
+        assert!(cone.includes("a/b/c/d/e/f"));
+        assert!(cone.includes("d/y"));
+        assert!(!cone.includes("a/other/x"));
+        assert!(!cone.includes("e/y"));
+
+        assert!(Cone::parse("*.txt\n").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,587 @@
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::checkout::{self, Checkout};
+use crate::config;
+use crate::diff::{self, BlobCache, DiffEntry, Listing};
+use crate::index::Index;
+use crate::merge::{self, MergeOptions};
+use crate::objects::{Commit, HashAlgorithm, Object, ObjectId, TreeEntry, User};
+use crate::refs::{self, ReflogEntry};
+use crate::revision;
+
+// a stash is a commit of the working tree whose parents are HEAD, a
+// commit of the index and optionally one of the untracked files, with
+// refs/stash and its reflog as the stack of entries
+const STASH_REF: &str = "refs/stash";
+
+fn short_sha(sha1_hash: &ObjectId) -> String {
+    hex::encode(sha1_hash)[..7].to_string()
+}
+
+/// What `stash push` saves.
+#[derive(Default)]
+pub struct PushOptions {
+    /// Limits the stash to these paths; everything else stays put.
+    pub paths: Vec<String>,
+    /// Leaves what was staged in the index and working tree.
+    pub keep_index: bool,
+    /// Also stashes, and then removes, untracked files.
+    pub include_untracked: bool,
+    pub message: Option<String>,
+}
+
+// every file below `dir` that the index does not track, `.git` aside
+fn untracked_files(tracked: &HashSet<Vec<u8>>, dir: &Path, files: &mut Vec<Vec<u8>>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name() == ".git" {
+            continue;
+        }
+
+        let path = entry.path();
+        let name = path.strip_prefix(".").unwrap_or(&path).as_os_str().as_bytes().to_vec();
+        if tracked.contains(&name) {
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            untracked_files(tracked, &path, files)?;
+        } else {
+            files.push(name);
+        }
+    }
+
+    Ok(())
+}
+
+// hashes a working tree file into the object store
+async fn store_worktree_file(path: &[u8]) -> Result<DiffEntry> {
+    let entry = checkout::stage_worktree_path(path).await?;
+
+    Ok(DiffEntry {
+        path: path.to_vec(),
+        mode: entry.tree_mode(),
+        sha1_hash: entry.sha1_hash,
+    })
+}
+
+fn same_entry(a: Option<&DiffEntry>, b: Option<&DiffEntry>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.mode == b.mode && a.sha1_hash == b.sha1_hash,
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+async fn create_commit(tree_sha: ObjectId, parents: Vec<ObjectId>, message: String) -> Result<Commit> {
+    let (name, email) = config::identity("AUTHOR")?;
+    let author = User::now(name, email);
+    let (name, email) = config::identity("COMMITTER")?;
+    let commit = Commit::create(tree_sha, parents, message, author, User::now(name, email))?;
+    commit.write().await?;
+
+    Ok(commit)
+}
+
+async fn commit_files(commit: ObjectId) -> Result<BTreeMap<Vec<u8>, TreeEntry>> {
+    checkout::flatten(Some(Commit::from_object_sha(hex::encode(commit)).await?.tree_sha())).await
+}
+
+async fn commit_listing(commit: ObjectId) -> Result<Listing> {
+    diff::tree_listing(Some(Commit::from_object_sha(hex::encode(commit)).await?.tree_sha())).await
+}
+
+/// Saves local changes as a new stash entry and resets them away.
+/// Returns the entry's message, or `None` when there was nothing to save.
+pub async fn push(options: &PushOptions) -> Result<Option<String>> {
+    let head = match refs::head_sha()? {
+        Some(head) => Commit::from_object_sha(hex::encode(head)).await?,
+        None => bail!("You do not have the initial commit yet"),
+    };
+    let paths = &options.paths;
+
+    let index = Index::load()?;
+    if !index.conflicts().is_empty() {
+        bail!("could not save index tree");
+    }
+
+    let tracked: HashSet<Vec<u8>> = index.paths().into_iter().collect();
+    let mut untracked = Vec::new();
+    if options.include_untracked {
+        untracked_files(&tracked, Path::new("."), &mut untracked)?;
+        untracked.retain(|path| diff::matches_pathspec(path, paths));
+        untracked.sort();
+    }
+
+    // like git, paths are only checked against the index when untracked
+    // files are not being stashed
+    for spec in paths.iter().filter(|_| !options.include_untracked) {
+        let spec = std::slice::from_ref(spec);
+        if !tracked.iter().any(|path| diff::matches_pathspec(path, spec)) {
+            bail!("pathspec '{}' did not match any file(s) known to git\nDid you forget to 'git add'?", spec[0]);
+        }
+    }
+
+    // the index with the working tree's version of every path stashed
+    let head_files = diff::tree_listing(Some(head.tree_sha())).await?;
+    let index_files = diff::index_listing(&index);
+    let mut worktree_files = index_files.clone();
+    let mut changed = false;
+
+    let candidates: BTreeSet<&Vec<u8>> = head_files.keys().chain(index_files.keys()).collect();
+    for path in candidates.into_iter().filter(|p| diff::matches_pathspec(p, paths)) {
+        let staged = index_files.get(path);
+        let current = match index.entry(path) {
+            Some(entry) if !checkout::is_modified(path, entry).await? => staged.cloned(),
+            Some(_) if fs::symlink_metadata(checkout::worktree_path(path)).is_ok_and(|m| !m.is_dir()) => {
+                Some(store_worktree_file(path).await?)
+            }
+            _ => None,
+        };
+
+        changed |= !same_entry(staged, head_files.get(path)) || !same_entry(current.as_ref(), staged);
+        match current {
+            Some(entry) => worktree_files.insert(path.clone(), entry),
+            None => worktree_files.remove(path),
+        };
+    }
+
+    if !changed && untracked.is_empty() {
+        return Ok(None);
+    }
+
+    let branch = refs::current_branch()?.unwrap_or_else(|| String::from("(no branch)"));
+    let description = format!("{}: {} {}", branch, short_sha(&head.sha1_hash()), head.summary());
+
+    let index_tree = merge::write_tree(&index_files).await?;
+    let index_commit = create_commit(index_tree, vec![head.sha1_hash()], format!("index on {}\n", description)).await?;
+    let mut parents = vec![head.sha1_hash(), index_commit.sha1_hash()];
+
+    // git records the untracked files even when there are none
+    if options.include_untracked {
+        let mut files = Listing::new();
+        for path in &untracked {
+            files.insert(path.clone(), store_worktree_file(path).await?);
+        }
+
+        let tree_sha = merge::write_tree(&files).await?;
+        let commit = create_commit(tree_sha, Vec::new(), format!("untracked files on {}\n", description)).await?;
+        parents.push(commit.sha1_hash());
+    }
+
+    let message = match &options.message {
+        Some(message) => format!("On {}: {}", branch, message),
+        None => format!("WIP on {}", description),
+    };
+    let tree_sha = merge::write_tree(&worktree_files).await?;
+    let stash = create_commit(tree_sha, parents, message.clone()).await?;
+    store(stash.sha1_hash(), &message)?;
+
+    // back to HEAD, for the stashed paths only
+    let mut checkout = Checkout::new()?;
+    if paths.is_empty() {
+        checkout.checkout_tree(head.tree_sha(), true).await?;
+        refs::update_ref("ORIG_HEAD", head.sha1_hash())?;
+    } else {
+        let files = checkout::flatten(Some(head.tree_sha())).await?;
+        checkout.restore_worktree(&files, paths).await?;
+        checkout.reset_index(&files, paths).await?;
+    }
+    for path in &untracked {
+        checkout::remove_path(path)?;
+    }
+
+    if options.keep_index {
+        let files = checkout::flatten(Some(index_tree)).await?;
+        checkout.restore_worktree(&files, paths).await?;
+        checkout.reset_index(&files, paths).await?;
+    }
+    checkout.save()?;
+
+    Ok(Some(message))
+}
+
+/// Pushes `stash` onto the stack of entries.
+pub fn store(stash: ObjectId, message: &str) -> Result<()> {
+    let (name, email) = config::identity("COMMITTER")?;
+    let user = User::now(name, email);
+
+    refs::update_ref_logged(
+        STASH_REF,
+        ReflogEntry {
+            old: refs::resolve(STASH_REF)?.unwrap_or_else(|| HashAlgorithm::current().null()),
+            new: stash,
+            identity: format!("{} <{}> {} {}", user.name, user.email, user.timestamp, user.tz_offset),
+            message: message.to_string(),
+        },
+    )
+}
+
+/// The message of each entry, newest first.
+pub fn list() -> Result<Vec<String>> {
+    Ok(refs::read_reflog(STASH_REF)?
+        .into_iter()
+        .rev()
+        .map(|entry| entry.message)
+        .collect())
+}
+
+/// A stash entry named on the command line.
+pub struct StashRef {
+    /// The name as git would show it, e.g. `refs/stash@{0}`.
+    pub name: String,
+    pub commit: Commit,
+    /// Where the entry is on the stack, when it was named as one.
+    position: Option<usize>,
+}
+
+impl StashRef {
+    /// Looks up `spec`, the latest entry by default. A bare number `n`
+    /// stands for `stash@{n}`.
+    pub async fn lookup(spec: Option<String>) -> Result<Self> {
+        let entries = refs::read_reflog(STASH_REF)?;
+        let name = match spec {
+            None if entries.is_empty() => bail!("No stash entries found."),
+            None => format!("{}@{{0}}", STASH_REF),
+            Some(spec) if !spec.is_empty() && spec.chars().all(|c| c.is_ascii_digit()) => {
+                format!("{}@{{{}}}", STASH_REF, spec)
+            }
+            Some(spec) => spec,
+        };
+
+        let position = name
+            .strip_suffix('}')
+            .and_then(|n| n.split_once("@{"))
+            .filter(|(refname, _)| *refname == "stash" || *refname == STASH_REF)
+            .and_then(|(_, nth)| nth.parse().ok());
+        if position.is_some() && entries.is_empty() {
+            bail!("{} is not a valid reference", name);
+        }
+
+        let commit = revision::resolve_commit(&name).await?;
+        if !(2..=3).contains(&commit.parents().len()) {
+            bail!("'{}' is not a stash-like commit", name);
+        }
+
+        Ok(Self {
+            name,
+            commit,
+            position,
+        })
+    }
+
+    /// Applies the stashed changes on top of the index and working tree,
+    /// restoring what was staged as well when `restore_index` is set.
+    /// Returns false if they conflicted, leaving the conflicts in place.
+    pub async fn apply(&self, restore_index: bool) -> Result<bool> {
+        let index = Index::load()?;
+        if !index.conflicts().is_empty() {
+            bail!("Cannot apply a stash in the middle of a merge");
+        }
+
+        let current = diff::index_listing(&index);
+        let current_tree = merge::write_tree(&current).await?;
+        let parents = self.commit.parents();
+        let base = commit_listing(parents[0]).await?;
+        let stashed_index = commit_listing(parents[1]).await?;
+        let base_tree = merge::write_tree(&base).await?;
+        let stashed_index_tree = merge::write_tree(&stashed_index).await?;
+
+        let mut cache = BlobCache::default();
+        let style = merge::conflict_style()?;
+
+        // what was staged, replayed onto what is staged now
+        let mut index_tree = None;
+        if restore_index && stashed_index_tree != base_tree && stashed_index_tree != current_tree {
+            let options = MergeOptions {
+                ours: String::from("HEAD"),
+                theirs: String::from("stash index"),
+                base: String::from("base"),
+                style,
+                depth: 0,
+            };
+            let result = merge::merge_trees(&base, &current, &stashed_index, &options, &mut cache).await?;
+            if !result.conflicts.is_empty() {
+                bail!("Conflicts in index. Try without --index.");
+            }
+            index_tree = Some(merge::write_tree(&result.files).await?);
+        }
+
+        let options = MergeOptions {
+            ours: String::from("Updated upstream"),
+            theirs: String::from("Stashed changes"),
+            base: String::from("Version stash was based on"),
+            style,
+            depth: 0,
+        };
+        let worktree = diff::tree_listing(Some(self.commit.tree_sha())).await?;
+        let result = merge::merge_trees(&base, &current, &worktree, &options, &mut cache).await?;
+        merge::checkout_result(current_tree, &result).await?;
+
+        for (_, line) in &result.messages {
+            println!("{}", line);
+        }
+        let clean = result.conflicts.is_empty();
+        if !clean && restore_index {
+            eprintln!("Index was not unstashed.");
+        }
+
+        // the changes stay unstaged, apart from files the stash added
+        let mut checkout = Checkout::new()?;
+        match index_tree {
+            _ if !clean => {}
+            Some(index_tree) => {
+                checkout.reset_index(&checkout::flatten(Some(index_tree)).await?, diff::ALL_PATHS).await?;
+            }
+            None => {
+                let merged = checkout.staged_files();
+                let added: Vec<Vec<u8>> = merged.keys().filter(|p| !current.contains_key(*p)).cloned().collect();
+
+                checkout.reset_index(&checkout::flatten(Some(current_tree)).await?, diff::ALL_PATHS).await?;
+                if !added.is_empty() {
+                    checkout.reset_index(&merged, &added).await?;
+                }
+            }
+        }
+        checkout.save()?;
+
+        // untracked files come back last, conflicts or not, without
+        // replacing anything already there
+        if let Some(untracked) = parents.get(2) {
+            let mut files = commit_files(*untracked).await?;
+            let existing: Vec<Vec<u8>> = files
+                .keys()
+                .filter(|path| fs::symlink_metadata(checkout::worktree_path(path)).is_ok())
+                .cloned()
+                .collect();
+            for path in &existing {
+                eprintln!("{} already exists, no checkout", String::from_utf8_lossy(path));
+                files.remove(path);
+            }
+
+            let paths: Vec<Vec<u8>> = files.keys().cloned().collect();
+            if !paths.is_empty() {
+                checkout.restore_worktree(&files, &paths).await?;
+            }
+            if !existing.is_empty() {
+                bail!("could not restore untracked files from stash");
+            }
+        }
+
+        Ok(clean)
+    }
+
+    /// Where the entry is on the stack; only entries named that way can
+    /// be dropped.
+    pub fn position(&self) -> Result<usize> {
+        match self.position {
+            Some(position) => Ok(position),
+            None => bail!("'{}' is not a stash reference", self.name),
+        }
+    }
+
+    /// Removes the entry from the stack.
+    pub fn drop(&self) -> Result<()> {
+        let mut entries = refs::read_reflog(STASH_REF)?;
+        let i = entries.len() - 1 - self.position()?;
+        let removed = entries.remove(i);
+        // keep the chain of old and new values unbroken
+        if let Some(next) = entries.get_mut(i) {
+            next.old = removed.old;
+        }
+
+        match entries.last() {
+            Some(latest) => {
+                refs::update_ref(STASH_REF, latest.new)?;
+                refs::write_reflog(STASH_REF, &entries)
+            }
+            None => refs::delete_ref(STASH_REF),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::Blob;
+    use crate::test_repo::TestRepo;
+
+    fn options(paths: &[&str], include_untracked: bool) -> PushOptions {
+        PushOptions {
+            paths: paths.iter().map(|path| path.to_string()).collect(),
+            keep_index: false,
+            include_untracked,
+            message: None,
+        }
+    }
+
+    async fn stage(path: &str) -> Result<()> {
+        let mut checkout = Checkout::new()?;
+        checkout.add(&[path.to_string()]).await?;
+        checkout.save()
+    }
+
+    async fn contents(commit: ObjectId, path: &str) -> Result<Option<Vec<u8>>> {
+        Ok(match commit_files(commit).await?.get(path.as_bytes()) {
+            Some(entry) => Some(Blob::from_object_sha(hex::encode(entry.sha1_hash())).await?.contents().to_vec()),
+            None => None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_push_and_pop() -> Result<()> {
+        let repo = TestRepo::new("stash-push").await?;
+        fs::write("a", "a\n")?;
+        fs::write("b", "b\n")?;
+        let head = repo.commit("initial").await?;
+
+        fs::write("a", "a changed\n")?;
+        fs::write("b", "b staged\n")?;
+        stage("b").await?;
+        fs::write("b", "b staged, then changed\n")?;
+
+        let message = push(&options(&[], false)).await?.unwrap();
+        assert_eq!(message, format!("WIP on master: {} initial", short_sha(&head.sha1_hash())));
+
+        // HEAD, then the index
+        let stash = StashRef::lookup(None).await?;
+        let parents = stash.commit.parents().to_vec();
+        assert_eq!(parents.len(), 2);
+        assert_eq!(parents[0], head.sha1_hash());
+        assert_eq!(contents(parents[1], "b").await?.unwrap(), b"b staged\n");
+        assert_eq!(contents(parents[1], "a").await?.unwrap(), b"a\n");
+        assert_eq!(contents(stash.commit.sha1_hash(), "a").await?.unwrap(), b"a changed\n");
+        assert_eq!(contents(stash.commit.sha1_hash(), "b").await?.unwrap(), b"b staged, then changed\n");
+
+        // the entry is on refs/stash and its reflog
+        assert_eq!(refs::resolve(STASH_REF)?, Some(stash.commit.sha1_hash()));
+        let reflog = refs::read_reflog(STASH_REF)?;
+        assert_eq!(reflog.len(), 1);
+        assert_eq!(reflog[0].old, HashAlgorithm::current().null());
+        assert_eq!(reflog[0].message, message);
+
+        assert_eq!(fs::read("a")?, b"a\n");
+        assert_eq!(fs::read("b")?, b"b\n");
+        assert!(push(&options(&[], false)).await?.is_none());
+
+        // pop with --index
+        assert!(stash.apply(true).await?);
+        stash.drop()?;
+        assert_eq!(fs::read("a")?, b"a changed\n");
+        assert_eq!(fs::read("b")?, b"b staged, then changed\n");
+        let index = Index::load()?;
+        assert_eq!(index.entry("b").unwrap().sha1_hash, Blob::from_contents(b"b staged\n".to_vec())?.sha1_hash());
+        assert_eq!(index.entry("a").unwrap().sha1_hash, Blob::from_contents(b"a\n".to_vec())?.sha1_hash());
+        assert!(refs::resolve(STASH_REF)?.is_none());
+        assert!(list()?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_untracked_and_stack() -> Result<()> {
+        let repo = TestRepo::new("stash-untracked").await?;
+        fs::write("a", "a\n")?;
+        repo.commit("initial").await?;
+
+        fs::write("new", "untracked\n")?;
+        push(&options(&[], true)).await?.unwrap();
+        assert!(fs::symlink_metadata("new").is_err());
+
+        // HEAD, the index, then a root commit of the untracked files
+        let first = StashRef::lookup(None).await?;
+        let parents = first.commit.parents().to_vec();
+        assert_eq!(parents.len(), 3);
+        let untracked = Commit::from_object_sha(hex::encode(parents[2])).await?;
+        assert!(untracked.parents().is_empty());
+        assert_eq!(contents(parents[2], "new").await?.unwrap(), b"untracked\n");
+        assert!(contents(parents[2], "a").await?.is_none());
+
+        fs::write("a", "a changed\n")?;
+        let message = push(&options(&[], false)).await?.unwrap();
+
+        // a second entry goes on top, its reflog entry chained to the first
+        assert_eq!(list()?.len(), 2);
+        assert_eq!(list()?[0], message);
+        let reflog = refs::read_reflog(STASH_REF)?;
+        assert_eq!(reflog[1].old, first.commit.sha1_hash());
+        let older = StashRef::lookup(Some(String::from("1"))).await?;
+        assert_eq!(older.commit.sha1_hash(), first.commit.sha1_hash());
+
+        // popping the older one leaves the newer as the only entry
+        assert!(older.apply(false).await?);
+        older.drop()?;
+        assert_eq!(fs::read("new")?, b"untracked\n");
+        assert_eq!(list()?, vec![message]);
+        let reflog = refs::read_reflog(STASH_REF)?;
+        assert_eq!(reflog[0].old, HashAlgorithm::current().null());
+        assert_eq!(refs::resolve(STASH_REF)?, Some(reflog[0].new));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pathspec() -> Result<()> {
+        let repo = TestRepo::new("stash-pathspec").await?;
+        fs::create_dir("dir")?;
+        fs::write("a", "a\n")?;
+        fs::write("dir/b", "b\n")?;
+        repo.commit("initial").await?;
+
+        fs::write("a", "a changed\n")?;
+        fs::write("dir/b", "b changed\n")?;
+        push(&options(&["dir"], false)).await?.unwrap();
+
+        // only the named paths are stashed and reset
+        assert_eq!(fs::read("a")?, b"a changed\n");
+        assert_eq!(fs::read("dir/b")?, b"b\n");
+        let stash = StashRef::lookup(None).await?;
+        assert_eq!(contents(stash.commit.sha1_hash(), "dir/b").await?.unwrap(), b"b changed\n");
+        assert_eq!(contents(stash.commit.sha1_hash(), "a").await?.unwrap(), b"a\n");
+
+        assert!(push(&options(&["missing"], false)).await.is_err());
+
+        assert!(stash.apply(false).await?);
+        stash.drop()?;
+        assert_eq!(fs::read("a")?, b"a changed\n");
+        assert_eq!(fs::read("dir/b")?, b"b changed\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_untracked_pathspec() -> Result<()> {
+        let repo = TestRepo::new("stash-untracked-pathspec").await?;
+        fs::write("a", "a\n")?;
+        repo.commit("initial").await?;
+
+        fs::write("a", "a changed\n")?;
+        fs::write("one", "1\n")?;
+        fs::write("two", "2\n")?;
+        push(&options(&["one"], true)).await?.unwrap();
+
+        // the untracked file named goes, the rest stay
+        assert!(fs::symlink_metadata("one").is_err());
+        assert_eq!(fs::read("two")?, b"2\n");
+        assert_eq!(fs::read("a")?, b"a changed\n");
+        let stash = StashRef::lookup(None).await?;
+        let untracked = stash.commit.parents()[2];
+        assert!(contents(untracked, "one").await?.is_some());
+        assert!(contents(untracked, "two").await?.is_none());
+
+        // an untracked file already back in the way keeps the entry
+        fs::write("one", "in the way\n")?;
+        assert!(stash.apply(false).await.is_err());
+        fs::remove_file("one")?;
+        assert!(stash.apply(false).await?);
+        stash.drop()?;
+        assert_eq!(fs::read("one")?, b"1\n");
+        assert_eq!(fs::read("two")?, b"2\n");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+use anyhow::{bail, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::diff::matches_pathspec;
+use crate::index::Index;
+use crate::objects::{HashAlgorithm, Object, ObjectId};
+use crate::refs;
+use crate::revision::{self, RevWalk};
+
+const GITLINK_MODE: u32 = 0o160000;
+
+/// A nested repository the index records as a gitlink, as `.gitmodules`
+/// describes it.
+pub struct Submodule {
+    pub name: String,
+    pub path: String,
+    // the commit the superproject records; `None` while it has conflicts
+    pub sha1_hash: Option<ObjectId>,
+    url: Option<String>,
+}
+
+impl Submodule {
+    /// The URL to clone from: the one in `.gitmodules`, with a relative one
+    /// taken against the superproject's own remote.
+    pub fn url(&self) -> Result<String> {
+        match &self.url {
+            Some(url) => Ok(resolve_url(&remote_url()?, url)),
+            None => bail!("No url found for submodule path '{}' in .gitmodules", self.path),
+        }
+    }
+
+    /// The URL `submodule init` registered, once it has been.
+    pub fn configured_url(&self, config: &Config) -> Option<String> {
+        config.get(&format!("submodule.{}.url", self.name))
+    }
+
+    /// Whether the submodule has been cloned into its path.
+    pub fn is_populated(&self) -> bool {
+        Path::new(&self.path).join(".git").exists()
+    }
+}
+
+/// The submodules the index records under `paths`, in path order.
+pub fn list(paths: &[String]) -> Result<Vec<Submodule>> {
+    let index = Index::load()?;
+    let gitmodules = Config::load_from(PathBuf::from(".gitmodules"))?;
+    let names = gitmodules.subsections("submodule");
+
+    for spec in paths {
+        if !index.paths().iter().any(|path| matches_pathspec(path, std::slice::from_ref(spec))) {
+            bail!("pathspec '{}' did not match any file(s) known to git", spec);
+        }
+    }
+
+    let mut submodules: Vec<Submodule> = Vec::new();
+    for entry in index.entries() {
+        if entry.mode != GITLINK_MODE || !matches_pathspec(&entry.path, paths) {
+            continue;
+        }
+        // .gitmodules maps names to paths as text
+        let path = String::from_utf8_lossy(&entry.path).into_owned();
+        // a conflicted gitlink shows up once, whatever its stages
+        if submodules.last().is_some_and(|last| last.path == path) {
+            continue;
+        }
+
+        let name = names
+            .iter()
+            .find(|name| gitmodules.get(&format!("submodule.{}.path", name)).as_deref() == Some(path.as_str()));
+        let name = match name {
+            Some(name) => name.clone(),
+            None => bail!("no submodule mapping found in .gitmodules for path '{}'", path),
+        };
+
+        submodules.push(Submodule {
+            url: gitmodules.get(&format!("submodule.{}.url", name)),
+            name,
+            path,
+            sha1_hash: if entry.stage == 0 { Some(entry.sha1_hash) } else { None },
+        });
+    }
+
+    Ok(submodules)
+}
+
+// what relative submodule URLs are taken against: the remote the
+// superproject came from, or failing that where it lives
+fn remote_url() -> Result<String> {
+    match Config::load()?.get("remote.origin.url") {
+        Some(url) => Ok(url),
+        None => Ok(std::env::current_dir()?.display().to_string()),
+    }
+}
+
+/// Resolves a `.gitmodules` URL such as `../lib.git` against `base`, the
+/// way git does: each `../` drops one component of `base`.
+pub fn resolve_url(base: &str, url: &str) -> String {
+    if !url.starts_with("./") && !url.starts_with("../") {
+        return url.to_string();
+    }
+
+    let mut base = base.trim_end_matches('/').to_string();
+    let mut rest = url;
+    loop {
+        if let Some(after) = rest.strip_prefix("./") {
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("../") {
+            if let Some(pos) = base.rfind('/') {
+                base.truncate(pos);
+            }
+            rest = after;
+        } else {
+            break;
+        }
+    }
+
+    format!("{}/{}", base, rest)
+}
+
+/// The repository a working tree keeps in `.git`, which may be a file
+/// pointing elsewhere (`gitdir: ...`), as git writes for submodules.
+pub fn git_dir(worktree: &Path) -> Result<PathBuf> {
+    let dot_git = worktree.join(".git");
+    if dot_git.is_dir() {
+        return Ok(dot_git);
+    }
+
+    let contents = fs::read_to_string(&dot_git).with_context(|| format!("Not a git repository: {}", worktree.display()))?;
+    match contents.trim_end().strip_prefix("gitdir: ") {
+        Some(dir) => Ok(worktree.join(dir)),
+        None => bail!("invalid gitfile format: {}", dot_git.display()),
+    }
+}
+
+/// The commit checked out in the repository at `worktree`, or `None`
+/// while its branch has no commits. Read directly, so it works from the
+/// superproject.
+pub fn head_of(worktree: &Path) -> Result<Option<ObjectId>> {
+    let git_dir = git_dir(worktree)?;
+    let mut refname = String::from("HEAD");
+
+    // symbolic refs may point at further symbolic refs, but not forever
+    for _ in 0..5 {
+        let contents = match fs::read_to_string(git_dir.join(&refname)) {
+            Ok(contents) => contents,
+            Err(_) => return packed_ref(&git_dir, &refname),
+        };
+
+        match contents.trim().strip_prefix("ref: ") {
+            Some(target) => refname = target.to_string(),
+            None => return Ok(Some(ObjectId::from_hex(contents.trim())?)),
+        }
+    }
+
+    bail!("symbolic ref loop at {} in {}", refname, git_dir.display())
+}
+
+fn packed_ref(git_dir: &Path, refname: &str) -> Result<Option<ObjectId>> {
+    let contents = match fs::read_to_string(git_dir.join("packed-refs")) {
+        Ok(contents) => contents,
+        Err(_) => return Ok(None),
+    };
+
+    for line in contents.lines() {
+        if let Some((sha, name)) = line.split_once(' ') {
+            if name == refname {
+                return Ok(Some(ObjectId::from_hex(sha)?));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Puts the current directory (and so the repository everything works on)
+/// back where it was when dropped.
+pub struct WorkingDir {
+    previous: PathBuf,
+}
+
+impl WorkingDir {
+    pub fn save() -> Result<Self> {
+        Ok(Self {
+            previous: std::env::current_dir()?,
+        })
+    }
+
+    /// Moves into the submodule at `path` until the guard is dropped.
+    pub fn enter(path: &str) -> Result<Self> {
+        let saved = Self::save()?;
+        std::env::set_current_dir(path)?;
+        HashAlgorithm::reload();
+
+        Ok(saved)
+    }
+}
+
+impl Drop for WorkingDir {
+    fn drop(&mut self) {
+        let _ = std::env::set_current_dir(&self.previous);
+        HashAlgorithm::reload();
+    }
+}
+
+async fn ancestors(sha1_hash: ObjectId) -> Result<HashSet<ObjectId>> {
+    let mut walk = RevWalk::new();
+    walk.push(sha1_hash).await?;
+
+    let mut seen = HashSet::new();
+    while let Some(commit) = walk.next().await? {
+        seen.insert(commit.sha1_hash());
+    }
+
+    Ok(seen)
+}
+
+// the nearest of `names` in the history of `sha1_hash`, as `name` or
+// `name-<commits since>-g<abbreviated hash>`
+async fn describe_with(sha1_hash: ObjectId, names: &HashMap<ObjectId, String>) -> Result<Option<String>> {
+    if let Some(name) = names.get(&sha1_hash) {
+        return Ok(Some(name.clone()));
+    }
+
+    let mut walk = RevWalk::new();
+    walk.push(sha1_hash).await?;
+    while let Some(commit) = walk.next().await? {
+        if let Some(name) = names.get(&commit.sha1_hash()) {
+            let behind = ancestors(commit.sha1_hash()).await?;
+            let count = ancestors(sha1_hash).await?.difference(&behind).count();
+            return Ok(Some(format!("{}-{}-g{}", name, count, &hex::encode(sha1_hash)[..7])));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Names a commit of the current repository the way `submodule status`
+/// does: by annotated tags, then any tags, then any refs, and at worst by
+/// its abbreviated hash.
+pub async fn describe(sha1_hash: ObjectId) -> Result<String> {
+    let mut annotated = HashMap::new();
+    let mut tags = HashMap::new();
+    for (refname, target) in refs::list_refs("refs/tags/")? {
+        let name = refs::shorten(&refname).to_string();
+        let commit = revision::peel(target).await?;
+        if revision::object_type(target).await? == "tag" {
+            annotated.entry(commit).or_insert_with(|| name.clone());
+        }
+        tags.entry(commit).or_insert(name);
+    }
+
+    let mut all = HashMap::new();
+    for (refname, target) in refs::list_refs("refs/")? {
+        if let Ok(commit) = revision::peel(target).await {
+            all.entry(commit).or_insert_with(|| refname["refs/".len()..].to_string());
+        }
+    }
+
+    for names in [&annotated, &tags, &all] {
+        if let Some(name) = describe_with(sha1_hash, names).await? {
+            return Ok(name);
+        }
+    }
+
+    Ok(hex::encode(sha1_hash)[..7].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_url() {
+        let base = "https://example.com/group/super.git";
+        assert_eq!(resolve_url(base, "../lib.git"), "https://example.com/group/lib.git");
+        assert_eq!(resolve_url(base, "../../other/lib"), "https://example.com/other/lib");
+        assert_eq!(resolve_url(base, "./lib"), "https://example.com/group/super.git/lib");
+        assert_eq!(resolve_url("/srv/super/", "../lib"), "/srv/lib");
+        assert_eq!(resolve_url(base, "https://elsewhere/lib.git"), "https://elsewhere/lib.git");
+    }
+}
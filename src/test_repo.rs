@@ -0,0 +1,160 @@
+//! Scratch repositories for end-to-end tests.
+
+use anyhow::{ensure, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::checkout::Checkout;
+use crate::commands;
+use crate::config::Config;
+use crate::diff;
+use crate::index::Index;
+use crate::merge;
+use crate::objects::{Commit, Object, User};
+use crate::refs;
+use crate::submodule::WorkingDir;
+
+// everything works on the repository in the current directory, which all
+// test threads share, so tests that move into one take turns
+static CURRENT_DIR: Mutex<()> = Mutex::new(());
+
+/// A freshly initialized repository, entered as the current directory
+/// until dropped, when it is deleted.
+pub struct TestRepo {
+    root: PathBuf,
+    _dir: WorkingDir,
+    _turn: MutexGuard<'static, ()>,
+}
+
+impl TestRepo {
+    pub async fn new(name: &str) -> Result<Self> {
+        Self::init(name, None).await
+    }
+
+    /// A repository naming its objects by `object_format`, as
+    /// `init --object-format` makes.
+    pub async fn with_object_format(name: &str, object_format: &str) -> Result<Self> {
+        Self::init(name, Some(String::from(object_format))).await
+    }
+
+    // each #[tokio::test] runs on its own thread and runtime, so holding a
+    // plain lock across awaits blocks nothing but the other tests
+    #[allow(clippy::await_holding_lock)]
+    async fn init(name: &str, object_format: Option<String>) -> Result<Self> {
+        // a test that failed while holding its turn has still left
+        let turn = CURRENT_DIR.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let root = std::env::temp_dir().join(format!("tgit-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root)?;
+        let dir = WorkingDir::enter(&root.display().to_string())?;
+
+        commands::init(None, object_format).await?;
+        let mut config = Config::load()?;
+        config.set("user.name", "Test")?;
+        config.set("user.email", "test@example.com")?;
+        config.save()?;
+
+        Ok(Self {
+            root,
+            _dir: dir,
+            _turn: turn,
+        })
+    }
+
+    /// Stages the whole working tree and commits it onto HEAD.
+    pub async fn commit(&self, message: &str) -> Result<Commit> {
+        let mut checkout = Checkout::new()?;
+        checkout.add(&[String::from(".")]).await?;
+        checkout.save()?;
+
+        let tree_sha = merge::write_tree(&diff::index_listing(&Index::load()?)).await?;
+        let parents = refs::head_sha()?.into_iter().collect();
+        let user = User::now(String::from("Test"), String::from("test@example.com"));
+        let commit = Commit::create(tree_sha, parents, format!("{}\n", message), user.clone(), user)?;
+        commit.write().await?;
+        commit.update_refs()?;
+
+        Ok(commit)
+    }
+}
+
+impl Drop for TestRepo {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+// set in the process `in_own_process` starts
+const OWN_PROCESS: &str = "TGIT_TEST_OWN_PROCESS";
+
+/// Runs the test at `path` again in a process of its own, for one that
+/// changes what every thread shares, such as the hash algorithm objects are
+/// named by. Returns true in the calling test, which has nothing left to do,
+/// and false in that process, where the test goes on.
+pub fn in_own_process(path: &str) -> Result<bool> {
+    if std::env::var_os(OWN_PROCESS).is_some() {
+        return Ok(false);
+    }
+
+    let output = Command::new(std::env::current_exe()?)
+        .args(["--exact", path])
+        .env(OWN_PROCESS, "1")
+        .output()?;
+    ensure!(
+        output.status.success(),
+        "{} failed in its own process:\n{}",
+        path,
+        String::from_utf8_lossy(&output.stdout)
+    );
+
+    Ok(true)
+}
+
+/// Serves HTTP on a local port for the rest of the test run, answering each
+/// request with what `respond` gives for its path and body. Returns the URL
+/// of the repository it stands in for.
+pub fn serve(respond: impl Fn(&str, &[u8]) -> Vec<u8> + Send + 'static) -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let url = format!("http://{}/repo.git", listener.local_addr()?);
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let _ = stream.map_err(anyhow::Error::from).and_then(|stream| answer(stream, &respond));
+        }
+    });
+
+    Ok(url)
+}
+
+// one request per connection, which the response closes
+fn answer(mut stream: TcpStream, respond: &dyn Fn(&str, &[u8]) -> Vec<u8>) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let path = request_line.split(' ').nth(1).unwrap_or_default().to_string();
+
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse()?;
+            }
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    let response = respond(&path, &body);
+    write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", response.len())?;
+    stream.write_all(&response)?;
+
+    Ok(())
+}
@@ -1,11 +1,12 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use flate2::read::ZlibDecoder;
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
-use hex;
+use std::cmp::Ordering;
 use std::io::{Read, Write};
 
 use chrono;
+use chrono::TimeZone;
 
 pub fn zlib_decompress(bytes: Vec<u8>) -> Result<Vec<u8>> {
     let mut z = ZlibDecoder::new(&bytes[..]);
@@ -24,17 +25,222 @@ pub fn zlib_compress(content: &[u8]) -> Result<Vec<u8>> {
     Ok(compressed)
 }
 
-pub fn decode_hash(sha1_hash: &str) -> [u8; 20] {
-    let mut hash_decoded: [u8; 20] = [0; 20];
-    hex::decode_to_slice(sha1_hash, &mut hash_decoded[..]).expect("Invalid hex");
-
-    return hash_decoded;
-}
-
 pub fn get_time_data() -> (String, String) {
     let now = chrono::Local::now();
     let timestamp = now.timestamp().to_string();
-    let offset = now.offset().utc_minus_local().to_string();
+    // git records the zone as e.g. "+0530"
+    let seconds = now.offset().local_minus_utc();
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let minutes = seconds.abs() / 60;
+    let offset = format!("{}{:02}{:02}", sign, minutes / 60, minutes % 60);
 
     return (timestamp, offset);
 }
+
+// "+0530" -> seconds east of UTC
+fn parse_tz_offset(tz_offset: &str) -> i32 {
+    let sign = if tz_offset.starts_with('-') { -1 } else { 1 };
+    let digits = tz_offset.trim_start_matches(['+', '-']);
+
+    let value: i32 = digits.parse().unwrap_or(0);
+    sign * ((value / 100) * 3600 + (value % 100) * 60)
+}
+
+/// Formats a commit timestamp the way `git log` does by default,
+/// e.g. `Mon Oct 19 08:12:51 2026 +0000`.
+pub fn format_date(timestamp: &str, tz_offset: &str) -> String {
+    let seconds: i64 = timestamp.parse().unwrap_or(0);
+    let offset = chrono::FixedOffset::east(parse_tz_offset(tz_offset));
+    let time = offset.timestamp(seconds, 0);
+
+    format!("{} {}", time.format("%a %b %-d %H:%M:%S %Y"), tz_offset)
+}
+
+// where a `[...]` class starting at `pattern[0]` ends; a `]` right after
+// the opening bracket (or its negation) is a member, not the end
+fn class_end(pattern: &[u8]) -> Option<usize> {
+    let mut start = 1;
+    if matches!(pattern.get(start), Some(b'!') | Some(b'^')) {
+        start += 1;
+    }
+    if pattern.get(start) == Some(&b']') {
+        start += 1;
+    }
+
+    pattern[start..].iter().position(|b| *b == b']').map(|pos| start + pos)
+}
+
+fn class_matches(class: &[u8], c: u8) -> bool {
+    let (negated, class) = match class.first() {
+        Some(b'!') | Some(b'^') => (true, &class[1..]),
+        _ => (false, class),
+    };
+
+    let mut matched = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            matched |= (class[i]..=class[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            matched |= class[i] == c;
+            i += 1;
+        }
+    }
+
+    matched != negated
+}
+
+fn glob_match_bytes(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some(b'*') => (0..=name.len()).any(|i| glob_match_bytes(&pattern[1..], &name[i..])),
+        Some(b'?') => !name.is_empty() && glob_match_bytes(&pattern[1..], &name[1..]),
+        Some(b'[') if class_end(pattern).is_some() => {
+            let end = class_end(pattern).unwrap();
+            match name.first() {
+                Some(c) => class_matches(&pattern[1..end], *c) && glob_match_bytes(&pattern[end + 1..], &name[1..]),
+                None => false,
+            }
+        }
+        Some(b'\\') if pattern.len() > 1 => {
+            name.first() == Some(&pattern[1]) && glob_match_bytes(&pattern[2..], &name[1..])
+        }
+        Some(c) => name.first() == Some(c) && glob_match_bytes(&pattern[1..], &name[1..]),
+    }
+}
+
+/// Matches `name` against a shell glob of `*`, `?` and `[...]`, the way
+/// `tag --list` patterns match; `*` also matches slashes.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    glob_match_bytes(pattern.as_bytes(), name.as_bytes())
+}
+
+/// Orders names the way `--sort=version:refname` does: runs of digits
+/// compare by value, so `v1.9` comes before `v1.10`.
+pub fn version_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let a_len = a.iter().take_while(|c| c.is_ascii_digit()).count();
+                let b_len = b.iter().take_while(|c| c.is_ascii_digit()).count();
+
+                let skip_zeros = |digits: &'_ [u8]| {
+                    let zeros = digits.iter().take_while(|c| **c == b'0').count();
+                    digits[zeros..].to_vec()
+                };
+                let (x, y) = (skip_zeros(&a[..a_len]), skip_zeros(&b[..b_len]));
+
+                let order = x.len().cmp(&y.len()).then_with(|| x.cmp(&y));
+                if order != Ordering::Equal {
+                    return order;
+                }
+                a = &a[a_len..];
+                b = &b[b_len..];
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(y);
+                }
+                a = &a[1..];
+                b = &b[1..];
+            }
+        }
+    }
+}
+
+/// Turns an expiry such as `2.weeks.ago`, `3 days ago`, `now` or
+/// `never` into the unix time before which things have expired, or
+/// `None` when nothing ever does.
+pub fn parse_expiry(spec: &str, now: i64) -> Result<Option<i64>> {
+    match spec {
+        "now" | "all" => return Ok(Some(i64::MAX)),
+        "never" | "false" => return Ok(None),
+        _ => {}
+    }
+
+    let words: Vec<&str> = spec.split(['.', ' ']).filter(|w| !w.is_empty()).collect();
+    if let [count, unit, "ago"] = words[..] {
+        let count: i64 = match count.parse() {
+            Ok(count) => count,
+            Err(_) => bail!("invalid expiry date '{}'", spec),
+        };
+        let seconds = match unit.trim_end_matches('s') {
+            "second" => 1,
+            "minute" => 60,
+            "hour" => 60 * 60,
+            "day" => 24 * 60 * 60,
+            "week" => 7 * 24 * 60 * 60,
+            "month" => 30 * 24 * 60 * 60,
+            "year" => 365 * 24 * 60 * 60,
+            _ => bail!("invalid expiry date '{}'", spec),
+        };
+
+        return Ok(Some(now - count * seconds));
+    }
+
+    match chrono::NaiveDate::parse_from_str(spec, "%Y-%m-%d") {
+        Ok(date) => Ok(Some(date.and_hms(0, 0, 0).timestamp())),
+        Err(_) => bail!("invalid expiry date '{}'", spec),
+    }
+}
+
+/// Turns a date such as `2020-01-31`, `3 days ago` or a unix timestamp
+/// into unix time.
+pub fn parse_date(spec: &str, now: i64) -> Result<i64> {
+    if let Ok(timestamp) = spec.trim_start_matches('@').parse() {
+        return Ok(timestamp);
+    }
+
+    match parse_expiry(spec, now) {
+        Ok(Some(time)) if time != i64::MAX => Ok(time),
+        _ => bail!("invalid date '{}'", spec),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("v1.*", "v1.2.3"));
+        assert!(glob_match("*", "release/1.0"));
+        assert!(glob_match("v?", "v2"));
+        assert!(!glob_match("v?", "v10"));
+        assert!(glob_match("v[0-9]", "v7"));
+        assert!(!glob_match("v[!0-9]", "v7"));
+        assert!(glob_match("a[]]b", "a]b"));
+        assert!(glob_match("v\\*", "v*"));
+        assert!(!glob_match("v1", "v1.0"));
+    }
+
+    #[test]
+    fn test_version_cmp() {
+        assert_eq!(version_cmp("v1.9", "v1.10"), Ordering::Less);
+        assert_eq!(version_cmp("v2.0", "v1.10"), Ordering::Greater);
+        assert_eq!(version_cmp("v1.01", "v1.1"), Ordering::Equal);
+        assert_eq!(version_cmp("v1", "v1.0"), Ordering::Less);
+        assert_eq!(version_cmp("a", "b"), Ordering::Less);
+    }
+
+    #[test]
+    fn test_parse_expiry() -> Result<()> {
+        assert_eq!(parse_expiry("2.weeks.ago", 2_000_000)?, Some(2_000_000 - 14 * 86400));
+        assert_eq!(parse_expiry("1 day ago", 100_000)?, Some(100_000 - 86400));
+        assert_eq!(parse_expiry("now", 5)?, Some(i64::MAX));
+        assert_eq!(parse_expiry("never", 5)?, None);
+        assert_eq!(parse_expiry("1970-01-02", 5)?, Some(86400));
+        assert!(parse_expiry("soon", 5).is_err());
+        assert_eq!(parse_date("@86400", 5)?, 86400);
+        assert_eq!(parse_date("1970-01-03", 5)?, 2 * 86400);
+        assert!(parse_date("never", 5).is_err());
+
+        Ok(())
+    }
+}